@@ -0,0 +1,250 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::collections::BTreeMap;
+use std::io::{ Read, Seek, SeekFrom, Result };
+use std::ops::Range;
+use tokio::io::{ AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt };
+use crate::{ CancellationToken, MergeOptions, MergeReport, OutputFormat, scan_files, sequential_layout };
+use crate::diagnostics::diag;
+use crate::progress_stream::{ self, emit_event, ProgressPhase };
+
+/// Bytes loaded at the start and the end of each input, covering the fingerprint and camera trailers
+const EDGE_SIZE: u64 = 64 * 1024;
+/// Missing ranges are loaded in blocks of this size
+const LOAD_BLOCK: u64 = 64 * 1024;
+/// Scans until every range read by the merge is loaded, e.g. telemetry samples found in the previous scan
+const MAX_PASSES: usize = 8;
+
+/// Merge into an async output, reading the inputs with async I/O and without blocking threads.
+/// The output is written front to back like `join_file_streams_sequential`. The boxes outside of the mdat are loaded into memory
+/// and scanned with the sync code, the sample data read by the scan (e.g. GPMF telemetry) is loaded in additional passes.
+/// Note: GoPro files which contain chapter numbers in udta are merged in the chapter order.
+pub async fn join_file_streams_async<F: Fn(f64), I: AsyncRead + AsyncSeek + Unpin, O: AsyncWrite + Unpin>(files: &mut [(I, usize)], mut output_file: O, file_metadata: &[Option<std::time::SystemTime>], options: &MergeOptions, progress_cb: F) -> Result<MergeReport> {
+    if options.max_output_size.is_some() || options.output_format != OutputFormat::Mp4 {
+        return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "The async merge writes a single MP4 output"));
+    }
+    options.validate_stream()?;
+    let _diagnostics = crate::diagnostics::scope(options.diagnostics.as_ref());
+    let _events = progress_stream::event_scope(options.progress_events.as_ref());
+    let mut sparse = Vec::with_capacity(files.len());
+    for (input, (file, size)) in files.iter_mut().enumerate() {
+        sparse.push((SparseFile::load(file, input).await?, *size));
+    }
+
+    let mut pass = 0;
+    let (scan, layout, trailer) = loop {
+        pass += 1;
+        // The scan sorts the files by chapter, start from the input order again
+        sparse.sort_by_key(|x| x.0.input);
+        let result = scan_files(&mut sparse, file_metadata, options, &progress_cb).and_then(|mut scan| {
+            let (layout, trailer) = sequential_layout(&mut sparse, &scan.first_boxes, &mut scan.desc, scan.insta360_max_read, scan.gpmf_detected)?;
+            Ok((scan, layout, trailer))
+        });
+        let missing = sparse.iter_mut().map(|x| std::mem::take(&mut x.0.misses)).collect::<Vec<_>>();
+        if missing.iter().all(|x| x.is_empty()) || pass == MAX_PASSES {
+            break result?;
+        }
+        // Results computed from missing data are discarded, including errors
+        for ((file, _), misses) in sparse.iter_mut().zip(missing) {
+            diag!(Debug, "Loading {} ranges of input {} read by the scan", misses.len(), file.input);
+            for range in misses {
+                file.load_range(&mut files[file.input].0, range).await?;
+            }
+        }
+    };
+    CancellationToken::check(options.cancellation.as_ref())?;
+
+    let desc = &scan.desc;
+    let total_size = scan.total_size.max(1);
+    let mut written = 0u64;
+    let progress = |written: u64| progress_cb((0.1 + (written as f64 / total_size as f64) * 0.9).min(0.9999));
+    let (before_data, after_data) = layout.split_at(desc.mdat_final_position as usize);
+    output_file.write_all(before_data).await?;
+    written += before_data.len() as u64;
+    let data_size = desc.mdat_position.iter().filter(|x| x.0.is_none_or(|i| sparse.get(i).is_some())).map(|x| x.2).sum::<u64>();
+    let copied = |written: u64| written - before_data.len() as u64;
+
+    let mut buf = vec![0u8; desc.copy.block_size()];
+    for &(file_index, offset, size) in &desc.mdat_position {
+        match file_index {
+            Some(file_index) => {
+                let Some(input) = sparse.get(file_index).map(|x| x.0.input) else { continue; };
+                let reader = &mut files[input].0;
+                reader.seek(SeekFrom::Start(offset)).await?;
+                let mut remaining = size;
+                while remaining > 0 {
+                    CancellationToken::check(options.cancellation.as_ref())?;
+                    let block_size = remaining.min(buf.len() as u64) as usize;
+                    let block = &mut buf[..block_size];
+                    reader.read_exact(block).await?;
+                    output_file.write_all(block).await?;
+                    remaining -= block.len() as u64;
+                    written += block.len() as u64;
+                    progress(written);
+                    emit_event(ProgressPhase::Copying, Some(file_index), copied(written), data_size);
+                }
+            },
+            None => {
+                let data = desc.synthesized_data.get(offset as usize..(offset + size) as usize)
+                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Synthesized sample data out of range"))?;
+                output_file.write_all(data).await?;
+                written += size;
+                emit_event(ProgressPhase::Copying, None, copied(written), data_size);
+            }
+        }
+    }
+    output_file.write_all(after_data).await?;
+    output_file.write_all(&trailer).await?;
+    output_file.flush().await?;
+
+    // Fail if an input was modified during the merge
+    for (file, _) in &mut sparse {
+        let input = file.input;
+        *file = SparseFile::load_edges(&mut files[input].0, input).await?;
+    }
+    scan.verify_inputs(&mut sparse)?;
+
+    progress_cb(1.0);
+
+    Ok(MergeReport::from_desc(&scan.desc, &scan.input_order))
+}
+
+/// Input of which only some ranges are loaded. Reads outside of them return zeros and are recorded, to be loaded before the next pass
+struct SparseFile {
+    input: usize,
+    size: u64,
+    /// Loaded data by start offset, the ranges don't overlap
+    ranges: BTreeMap<u64, Vec<u8>>,
+    position: u64,
+    misses: Vec<Range<u64>>,
+}
+
+impl SparseFile {
+    /// Load the start and the end of the file
+    async fn load_edges<R: AsyncRead + AsyncSeek + Unpin>(reader: &mut R, input: usize) -> Result<Self> {
+        let size = reader.seek(SeekFrom::End(0)).await?;
+        let mut file = Self { input, size, ranges: BTreeMap::new(), position: 0, misses: Vec::new() };
+        file.load_range(reader, 0..EDGE_SIZE).await?;
+        file.load_range(reader, size.saturating_sub(EDGE_SIZE)..size).await?;
+        Ok(file)
+    }
+
+    /// Load the edges and every top-level box except the mdat data
+    async fn load<R: AsyncRead + AsyncSeek + Unpin>(reader: &mut R, input: usize) -> Result<Self> {
+        let mut file = Self::load_edges(reader, input).await?;
+        let mut pos = 0;
+        while pos + 8 <= file.size {
+            let mut header = [0u8; 16];
+            reader.seek(SeekFrom::Start(pos)).await?;
+            reader.read_exact(&mut header[..8]).await?;
+            let mut size = u32::from_be_bytes(header[..4].try_into().unwrap()) as u64;
+            let is_mdat = &header[4..8] == b"mdat";
+            let mut header_size = 8;
+            if size == 1 && pos + 16 <= file.size {
+                reader.read_exact(&mut header[8..]).await?;
+                size = u64::from_be_bytes(header[8..].try_into().unwrap());
+                header_size = 16;
+            } else if size == 0 {
+                size = file.size - pos;
+            }
+            // Not a box, e.g. a trailer, which is within the loaded end of the file
+            if size < header_size || pos + size > file.size { break; }
+            let end = if is_mdat { pos + header_size } else { pos + size };
+            file.load_range(reader, pos..end).await?;
+            pos += size;
+        }
+        Ok(file)
+    }
+
+    /// Load `range`, extended to whole blocks and merged with the loaded ranges it touches
+    async fn load_range<R: AsyncRead + AsyncSeek + Unpin>(&mut self, reader: &mut R, range: Range<u64>) -> Result<()> {
+        let mut start = range.start / LOAD_BLOCK * LOAD_BLOCK;
+        let mut end = range.end.div_ceil(LOAD_BLOCK).saturating_mul(LOAD_BLOCK).min(self.size);
+        let touching = self.ranges.range(..=end).filter(|(x, data)| **x + data.len() as u64 >= start).map(|x| *x.0).collect::<Vec<_>>();
+        for x in touching {
+            let data = self.ranges.remove(&x).unwrap();
+            start = start.min(x);
+            end = end.max(x + data.len() as u64);
+        }
+        let mut data = vec![0u8; (end - start) as usize];
+        reader.seek(SeekFrom::Start(start)).await?;
+        reader.read_exact(&mut data).await?;
+        self.ranges.insert(start, data);
+        Ok(())
+    }
+}
+
+impl Read for SparseFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.position >= self.size { return Ok(0); }
+        let len = buf.len().min((self.size - self.position) as usize);
+        if let Some((start, data)) = self.ranges.range(..=self.position).next_back() {
+            let offset = (self.position - start) as usize;
+            if offset < data.len() {
+                let n = len.min(data.len() - offset);
+                buf[..n].copy_from_slice(&data[offset..offset + n]);
+                self.position += n as u64;
+                return Ok(n);
+            }
+        }
+        let next = self.ranges.range(self.position + 1..).next().map(|x| *x.0).unwrap_or(self.size);
+        let n = len.min((next - self.position) as usize);
+        buf[..n].fill(0);
+        self.misses.push(self.position..self.position + n as u64);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for SparseFile {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::End(x) => self.size.checked_add_signed(x),
+            SeekFrom::Current(x) => self.position.checked_add_signed(x),
+        };
+        self.position = position.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid seek to a negative position"))?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+
+    #[tokio::test]
+    async fn test_join_file_streams_async() {
+        let chapter = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 3000)).track(SyntheticTrack::audio(48000, 94)).track(SyntheticTrack::metadata(*b"gpmd", 2));
+        let mut expected = Vec::new();
+        crate::join_file_streams_sequential(&mut [chapter.cursor(), chapter.cursor()], &mut expected, &[None, None], &MergeOptions::default(), |_| {}).unwrap();
+
+        let mut files = [chapter.cursor(), chapter.cursor()];
+        let mut output = Vec::new();
+        join_file_streams_async(&mut files, &mut output, &[None, None], &MergeOptions::default(), |_| {}).await.unwrap();
+        assert!(output == expected);
+    }
+
+    #[tokio::test]
+    async fn test_sparse_file() {
+        let data = (0..300_000u32).map(|x| x as u8).collect::<Vec<_>>();
+        let mut file = SparseFile::load_edges(&mut std::io::Cursor::new(&data), 0).await.unwrap();
+        let mut buf = [0u8; 16];
+        file.seek(SeekFrom::Start(150_000)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0; 16]);
+        assert_eq!(file.misses, vec![150_000..150_016]);
+
+        file.load_range(&mut std::io::Cursor::new(&data), 150_000..150_016).await.unwrap();
+        file.seek(SeekFrom::Start(150_000)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[..], data[150_000..150_016]);
+        // Extended to whole blocks, which reach the loaded end of the file
+        assert_eq!(file.ranges.keys().copied().collect::<Vec<_>>(), vec![0, 131_072]);
+        file.seek(SeekFrom::Start(230_000)).unwrap();
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, data[230_000..]);
+    }
+}
@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, Result, SeekFrom };
+use std::path::Path;
+use crate::desc_reader::{ self, Desc, EditListEntry };
+use crate::boxes::{ read_trak, set_track_id };
+use crate::{ stsd, diagnostics::diag };
+
+/// Replace the audio tracks of the merged output with the first audio track of an MP4/M4A file, e.g. a mixed soundtrack.
+/// Its samples are read into the synthesized data at the end of the mdat, up to the end of the merged movie
+pub(crate) fn replace_audio<R: Read + Seek>(desc: &mut Desc, reader: &mut R, name: &str) -> Result<()> {
+    let source = desc_reader::read_file_desc(reader)?;
+    let Some(index) = source.moov_tracks.iter().position(|t| t.handler_type == "soun") else {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{name} has no audio track")));
+    };
+    let mut trak = read_trak(reader, index)?.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("trak of the audio track of {name} not found")))?;
+    let entries = stsd::read_sample_entries(reader)?.into_iter().nth(index).unwrap_or_default();
+    if let Some(entry) = entries.first().filter(|x| x.codec != "mp4a") {
+        diag!(Warn, "The replacement audio of {name} is {}, not AAC", entry.codec);
+    }
+
+    let mut track = desc_reader::TrackDesc {
+        handler_type: source.moov_tracks[index].handler_type.clone(),
+        track_id: desc.moov_tracks.iter().map(|x| x.track_id).max().unwrap_or(0) + 1,
+        sample_entries: entries,
+        ..source.moov_tracks[index].clone()
+    };
+    track.file_sample_ranges.clear();
+    let media_time = track.file_edit.map(|x| x.0).unwrap_or(0).max(0);
+    let timescale = track.mdhd_timescale.max(1) as f64;
+
+    // Samples presented after the end of the other tracks are dropped
+    let kept_duration = desc.moov_tracks.iter().filter(|x| !x.dropped && !x.skip && !x.handler_type.is_empty() && x.handler_type != "soun")
+        .map(|x| x.tkhd_duration).max().unwrap_or(desc.moov_mvhd_duration);
+    let movie_duration = kept_duration as f64 / desc.moov_mvhd_timescale.max(1) as f64;
+    let samples = track.sample_infos();
+    let count = samples.iter().take_while(|x| (x.decode_time as i64 - media_time) as f64 / timescale < movie_duration).count();
+    diag!(Debug, "Replacing the audio with {count} of the {} samples of {name}", samples.len());
+
+    let mut chunks: Vec<(u64, u32, u32)> = Vec::new();
+    for chunk in samples[..count].chunk_by(|a, b| a.chunk == b.chunk) {
+        let size = chunk.iter().map(|x| x.size as u64).sum::<u64>();
+        let mut data = vec![0; size as usize];
+        reader.seek(SeekFrom::Start(source.mdat_final_position + chunk[0].offset))?;
+        reader.read_exact(&mut data)?;
+        chunks.push((desc.append_synthesized(&data), chunk.len() as u32, chunk[0].description_index));
+    }
+    track.truncate_samples(count as u32);
+    track.set_chunks(&chunks);
+
+    let media_duration = (track.mdhd_duration as i64 - media_time).max(0) as u64;
+    track.tkhd_duration = desc_reader::convert_duration_ceil(media_duration, track.mdhd_timescale, desc.moov_mvhd_timescale)
+        .min(kept_duration);
+    track.elst_segment_duration = track.tkhd_duration;
+    // Keep the encoder delay trimmed by the edit list of the source
+    track.elst_entries = if media_time > 0 { vec![EditListEntry { segment_duration: track.tkhd_duration, media_time, ..Default::default() }] } else { Vec::new() };
+
+    for audio in desc.moov_tracks.iter_mut().filter(|x| x.handler_type == "soun" && !x.dropped) {
+        diag!(Debug, "Dropping the audio track {}", audio.track_id);
+        audio.dropped = true;
+    }
+    desc.moov_mvhd_duration = kept_duration.max(track.tkhd_duration);
+    set_track_id(&mut trak, track.track_id);
+    desc.append_track(track, trak);
+    Ok(())
+}
+
+/// Open the replacement audio file and replace the audio tracks with it
+pub(crate) fn replace_audio_from_file(desc: &mut Desc, path: &Path) -> Result<()> {
+    let mut reader = std::io::BufReader::with_capacity(64*1024, std::fs::File::open(path)?);
+    replace_audio(desc, &mut reader, &path.display().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+
+    #[test]
+    fn test_replace_audio() {
+        // Two 2-second chapters with audio, and a 6.4-second soundtrack
+        let chapter = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50)).track(SyntheticTrack::audio(48000, 94));
+        let soundtrack = SyntheticMp4::new().track(SyntheticTrack { edit_list_media_time: Some(1024), ..SyntheticTrack::audio(48000, 300) });
+        let path = std::env::temp_dir().join(format!("mp4_merge_audio_{}.m4a", std::process::id()));
+        std::fs::write(&path, soundtrack.build()).unwrap();
+
+        let mut files = [chapter.cursor(), chapter.cursor()];
+        let mut output = std::io::Cursor::new(Vec::new());
+        let options = crate::MergeOptions::default().replace_audio(&path);
+        crate::join_file_streams_with_options(&mut files, &mut output, &[None, None], &options, |_| {}).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let tracks = crate::list_tracks(&mut output).unwrap();
+        assert_eq!(tracks.iter().map(|x| (x.handler_type.as_str(), x.track_id)).collect::<Vec<_>>(), vec![("vide", 1), ("soun", 3)]);
+        let index = crate::RandomAccessIndex::from_reader(&mut output).unwrap();
+        // Samples up to 4 seconds after the priming sample
+        assert_eq!(index.sample_count(1), 189);
+        for i in [0, 100, 188] {
+            let sample = index.sample(1, i).unwrap();
+            assert_eq!(&output.get_ref()[sample.offset as usize..][..sample.size as usize], &soundtrack.sample_data(0, i as u32)[..]);
+        }
+        let desc = crate::desc_reader::read_file_desc(&mut output).unwrap();
+        assert_eq!(desc.moov_tracks[1].file_edit, Some((1024, 4.0)));
+    }
+}
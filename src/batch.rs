@@ -0,0 +1,60 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::Result;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use crate::{ MergeOptions, MergeReport, join_files_with_options, diagnostics::diag };
+
+/// Merges run at the same time by default. Merging is IO-bound, so more parallel merges
+/// reading from the same card or disk mostly cause seeking.
+pub const DEFAULT_CONCURRENT_MERGES: usize = 2;
+
+/// Independent session to be merged by `merge_groups`
+#[derive(Debug, Clone)]
+pub struct MergeGroup<P: AsRef<Path>> {
+    pub files: Vec<P>,
+    pub output: P,
+}
+
+/// Merge multiple independent sessions concurrently, at most `options.max_concurrent_merges` at a time.
+/// The largest groups are started first. `progress_cb` receives the progress of all groups, weighted by their size.
+/// Returns the result of each group, in the order of `groups`.
+pub fn merge_groups<P: AsRef<Path> + Sync, F: Fn(f64) + Sync>(groups: &[MergeGroup<P>], options: &MergeOptions, progress_cb: F) -> Vec<Result<MergeReport>> {
+    let sizes = groups.iter().map(|g| {
+        g.files.iter().map(|x| std::fs::metadata(x).map(|m| m.len()).unwrap_or(0)).sum::<u64>().max(1)
+    }).collect::<Vec<_>>();
+    let total_size = sizes.iter().sum::<u64>().max(1) as f64;
+
+    let mut queue = (0..groups.len()).collect::<Vec<_>>();
+    queue.sort_by_key(|&i| std::cmp::Reverse(sizes[i]));
+
+    let next = AtomicUsize::new(0);
+    let progress = Mutex::new(vec![0.0; groups.len()]);
+    let results = Mutex::new((0..groups.len()).map(|_| None).collect::<Vec<Option<Result<MergeReport>>>>());
+    let threads = options.max_concurrent_merges.unwrap_or(DEFAULT_CONCURRENT_MERGES).clamp(1, groups.len().max(1));
+
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                while let Some(&index) = queue.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    let group = &groups[index];
+                    let report_progress = |p: f64| {
+                        let mut progress = progress.lock().unwrap();
+                        progress[index] = p;
+                        progress_cb(progress.iter().zip(&sizes).map(|(p, size)| p * *size as f64).sum::<f64>() / total_size);
+                    };
+                    let result = join_files_with_options(&group.files, &group.output, options, report_progress);
+                    if let Err(e) = &result {
+                        diag!(Error, "Failed to merge group {index}: {e:?}");
+                        report_progress(1.0);
+                    }
+                    results.lock().unwrap()[index] = Some(result);
+                }
+            });
+        }
+    });
+
+    results.into_inner().unwrap().into_iter().map(|x| x.unwrap_or_else(|| Err(std::io::Error::other("Group wasn't merged")))).collect()
+}
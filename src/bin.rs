@@ -3,47 +3,310 @@
 
 use std::io::Write;
 use std::path::*;
-use mp4_merge::{join_files, update_file_times};
+use mp4_merge::{export_gpx, export_kml, find_dji_chapters, find_gopro_chapters, join_files_dry_run, join_files_with_options, merge_insv, read_playlist, repair_from_lrv, update_file_times_with_source, write_reference_movie, FileTimeSource, GpxFormat, GpxTrack, MergeOptions, TemplateSelection, TrackFilter};
+
+const USAGE: &str = "Usage: mp4_merge [merge] IN_FILE1.mp4 IN_FILE2.mp4 ... [-o|--out OUTPUT.mp4] [OPTIONS]
+
+Options:
+  --chapters                 Merge all chapters of the GoPro or DJI recording of each input file
+  --playlist FILE            Merge the files listed in a playlist (one path per line, or M3U)
+  --tee PATH                 Write a copy of the output to another path in the same pass
+  --copy-threads COUNT       Read the sample data of COUNT input files in parallel, e.g. on NVMe drives
+  --no-kernel-copy           Copy the sample data through memory instead of with copy_file_range (Linux)
+  --dry-run                  Print the predicted output size, duration and problems without merging
+  --reference                Write a preview which references the samples of the input files
+  --media-path DIR           Folder to search for the media of QuickTime reference movies
+  --replace-audio FILE       Replace the audio with the first audio track of an MP4/M4A file
+  --gpx FILE                 Add a GPX log as a telemetry track
+  --gpx-format camm|gpmf     Format of the GPX telemetry track (default camm)
+  --export-gpx FILE          Write the GoPro GPS track of the inputs as GPX, or KML for a .kml file
+  --title, --comment, --artist TEXT
+                             Movie metadata of the output
+  --metadata KEY=VALUE       Custom movie metadata of the output
+  --template N|auto          Input used as the template of the output
+  --drop-track N|HANDLER     Leave out the track at this index or with this handler type, e.g. soun
+  --drop-codec CODEC         Leave out the tracks with this codec, e.g. fdsc
+  --gap-threshold SECONDS    Shortest pause between files kept as a gap (default 1)
+  --no-gaps                  Concatenate the files without gaps, with single-entry edit lists
+  --faststart                Write the moov before the mdat, for streaming over HTTP
+  --keep-stco                Keep 32-bit stco chunk offsets when the merged file stays below 4 GiB
+  --sidx                     Write a segment index (sidx) with a reference to each input, for byte-range seeking
+  --chapter-markers          Add a track marking where each input starts
+  --strict                   Report every parameter set difference field by field
+  --repair-truncated         Rebuild the moov of the last file when the recording was cut off
+  --repair-lrv FILE          Repair an interrupted GoPro recording using its LRV proxy
+  -h, --help                 Print this help";
 
 fn main() {
     let _time = std::time::Instant::now();
 
     let mut files = Vec::new();
     let mut output_file = None;
+    let mut reference = false;
+    let mut dry_run = false;
+    let mut repair_lrv = None;
+    let mut gpx = None;
+    let mut gpx_format = GpxFormat::Camm;
+    let mut find_chapters = false;
+    let mut export_gps = None;
+    let mut options = MergeOptions::default();
 
-    let mut args = std::env::args().skip(1);
+    let mut args = std::env::args().skip(1).peekable();
+    // `merge` is the default subcommand, so dropping files onto the executable merges them too
+    args.next_if(|x| x == "merge");
     while let Some(arg) = args.next() {
-        if arg == "--out" {
+        if arg == "--help" || arg == "-h" {
+            println!("{USAGE}");
+            return;
+        }
+        if arg == "--out" || arg == "-o" {
             if let Some(out) = args.next() {
                 output_file = Some(Path::new(&out).to_owned())
             }
             continue;
         }
-        let p = Path::new(&arg);
-        if !p.exists() {
-            eprintln!("File doesn't exist {:?}", p);
+        if arg == "--chapters" {
+            find_chapters = true;
+            continue;
+        }
+        if arg == "--tee" {
+            if let Some(path) = args.next() {
+                options = options.tee_output(path);
+            }
+            continue;
+        }
+        if arg == "--media-path" {
+            if let Some(path) = args.next() {
+                options = options.external_media_path(path);
+            }
+            continue;
+        }
+        if arg == "--dry-run" {
+            dry_run = true;
             continue;
         }
-        println!("Merging file {:?}", p);
-        files.push(p.to_owned());
-        if output_file.is_none() {
-            output_file = Some(p.with_file_name(format!("{}_joined.mp4", p.file_name().unwrap().to_str().unwrap())));
+        if arg == "--reference" {
+            reference = true;
+            continue;
+        }
+        if arg == "--replace-audio" {
+            if let Some(path) = args.next() {
+                options = options.replace_audio(path);
+            }
+            continue;
+        }
+        if arg == "--gpx" {
+            gpx = args.next().map(PathBuf::from);
+            continue;
+        }
+        if arg == "--export-gpx" {
+            export_gps = args.next().map(PathBuf::from);
+            continue;
+        }
+        if arg == "--gpx-format" {
+            match args.next().as_deref() {
+                Some("camm") => gpx_format = GpxFormat::Camm,
+                Some("gpmf") => gpx_format = GpxFormat::Gpmf,
+                format => eprintln!("Unknown GPX track format {format:?}, expected camm or gpmf")
+            }
+            continue;
         }
+        if arg == "--title" || arg == "--comment" || arg == "--artist" {
+            if let Some(value) = args.next() {
+                options = match arg.as_str() {
+                    "--title" => options.title(value),
+                    "--comment" => options.comment(value),
+                    _ => options.artist(value),
+                };
+            }
+            continue;
+        }
+        if arg == "--metadata" {
+            match args.next().as_deref().and_then(|x| x.split_once('=')) {
+                Some((key, value)) => options = options.metadata(key, value),
+                None => eprintln!("Expected --metadata KEY=VALUE")
+            }
+            continue;
+        }
+        if arg == "--template" {
+            match args.next().as_deref() {
+                Some("auto") => options = options.template(TemplateSelection::Auto),
+                Some(index) if index.parse::<usize>().is_ok() => options = options.template(TemplateSelection::File(index.parse().unwrap())),
+                template => eprintln!("Unknown template {template:?}, expected auto or the index of an input file")
+            }
+            continue;
+        }
+        if arg == "--drop-track" || arg == "--drop-codec" {
+            match args.next() {
+                Some(codec) if arg == "--drop-codec" => options = options.drop_track(TrackFilter::Codec(codec)),
+                Some(index) if index.parse::<usize>().is_ok() => options = options.drop_track(TrackFilter::Index(index.parse().unwrap())),
+                Some(handler) => options = options.drop_track(TrackFilter::Handler(handler)),
+                None => eprintln!("Expected {arg} followed by a track")
+            }
+            continue;
+        }
+        if arg == "--gap-threshold" {
+            match args.next().and_then(|x| x.parse::<f64>().ok()).and_then(|x| std::time::Duration::try_from_secs_f64(x).ok()) {
+                Some(threshold) => options = options.gap_threshold(threshold),
+                None => eprintln!("Expected --gap-threshold SECONDS")
+            }
+            continue;
+        }
+        if arg == "--copy-threads" {
+            match args.next().and_then(|x| x.parse::<usize>().ok()) {
+                Some(threads) => options = options.copy_threads(threads),
+                None => eprintln!("Expected --copy-threads COUNT")
+            }
+            continue;
+        }
+        if arg == "--no-kernel-copy" {
+            options = options.disable_kernel_copy(true);
+            continue;
+        }
+        if arg == "--no-gaps" {
+            options = options.disable_gaps(true);
+            continue;
+        }
+        if arg == "--faststart" {
+            options = options.faststart(true);
+            continue;
+        }
+        if arg == "--keep-stco" {
+            options = options.keep_stco(true);
+            continue;
+        }
+        if arg == "--sidx" {
+            options = options.sidx(true);
+            continue;
+        }
+        if arg == "--chapter-markers" {
+            options = options.chapter_markers(true);
+            continue;
+        }
+        if arg == "--strict" {
+            options = options.strict_parameter_sets(true);
+            continue;
+        }
+        if arg == "--repair-truncated" {
+            options = options.repair_truncated_last(true);
+            continue;
+        }
+        if arg == "--repair-lrv" {
+            repair_lrv = args.next().map(PathBuf::from);
+            continue;
+        }
+        let inputs = if arg == "--playlist" {
+            let Some(playlist) = args.next() else { continue; };
+            match read_playlist(&playlist) {
+                Ok(files) => files,
+                Err(e) => { eprintln!("Failed to read playlist {playlist:?}: {e}"); continue; }
+            }
+        } else {
+            vec![Path::new(&arg).to_owned()]
+        };
+        for p in inputs {
+            if !p.exists() {
+                eprintln!("File doesn't exist {:?}", p);
+                continue;
+            }
+            println!("Merging file {:?}", p);
+            if output_file.is_none() {
+                // Insta360 Studio only opens .insv files
+                let extension = if is_insv(&p) { "insv" } else { "mp4" };
+                output_file = Some(p.with_file_name(format!("{}_joined.{extension}", p.file_name().unwrap().to_str().unwrap())));
+            }
+            files.push(p);
+        }
+    }
+    if find_chapters {
+        let mut chapters: Vec<PathBuf> = Vec::new();
+        for file in &files {
+            match find_gopro_chapters(file).or_else(|_| find_dji_chapters(file)) {
+                Ok(found) => chapters.extend(found.into_iter().filter(|x| !chapters.contains(x)).collect::<Vec<_>>()),
+                Err(e) => { eprintln!("{e}"); chapters.push(file.clone()); }
+            }
+        }
+        for file in chapters.iter().filter(|x| !files.contains(x)) {
+            println!("Merging chapter {:?}", file);
+        }
+        files = chapters;
     }
-    if files.is_empty() { eprintln!("No input files!"); return; }
+    if files.is_empty() { eprintln!("No input files!\n\n{USAGE}"); return; }
     if output_file.is_none() { eprintln!("Output file not specified!"); return; }
 
     let final_output_file = output_file.as_ref().unwrap();
+    if let Some(gpx) = gpx {
+        options = options.gpx_track(GpxTrack::new(gpx, gpx_format));
+    }
 
     println!("Output file {:?}", final_output_file);
 
-    join_files(&files, final_output_file, |progress| {
+    if let Some(path) = export_gps {
+        let kml = path.extension().is_some_and(|x| x.eq_ignore_ascii_case("kml"));
+        let result = std::fs::File::create(&path).and_then(|file| {
+            let writer = std::io::BufWriter::new(file);
+            if kml { export_kml(&files, writer) } else { export_gpx(&files, writer) }
+        });
+        match result {
+            Ok(()) => println!("GPS track written to {:?}", path),
+            Err(e) => eprintln!("Failed to export the GPS track: {e}")
+        }
+    }
+
+    if let Some(lrv) = repair_lrv {
+        // The first input lost its moov, the second one is another chapter with the right sample descriptions
+        let report = repair_from_lrv(&files[0], &lrv, files.get(1), final_output_file).unwrap();
+        for (track, (found, expected)) in report.track_samples.iter().enumerate() {
+            println!("Track {track}: recovered {found} of {expected} samples");
+        }
+        println!("Done in {:.3}s", _time.elapsed().as_millis() as f64 / 1000.0);
+        return;
+    }
+
+    if reference {
+        // Only the moov is written, the samples stay in the input files
+        write_reference_movie(&files, final_output_file, &options).unwrap();
+        println!("Done in {:.3}s", _time.elapsed().as_millis() as f64 / 1000.0);
+        return;
+    }
+
+    if dry_run {
+        let report = join_files_dry_run(&files, &options).unwrap();
+        for reason in report.compatibility.reasons() {
+            println!("Incompatible: {reason}");
+        }
+        match &report.error {
+            Some(e) => println!("The merge would fail: {e}"),
+            None => println!("Output size: {} bytes, duration: {:.3}s, moov: {} bytes", report.output_size, report.duration.as_secs_f64(), report.moov_size)
+        }
+        return;
+    }
+
+    let mut outputs = vec![final_output_file.clone()];
+    let progress = |progress| {
         print!("\rMerging... {:.2}%", progress * 100.0);
         std::io::stdout().flush().unwrap();
-    }).unwrap();
+    };
+    if files.iter().all(|x| is_insv(x)) {
+        // Both lens sequences of a dual-lens session are merged, each to its own output
+        outputs = merge_insv(&files, final_output_file, &options, progress).unwrap().into_iter().map(|x| x.0).collect();
+        for output in &outputs[1..] {
+            println!("\rOutput file {:?}", output);
+        }
+    } else {
+        join_files_with_options(&files, final_output_file, &options, progress).unwrap();
+    }
 
-    update_file_times(&files[0], final_output_file);
+    for output in outputs.iter().chain(&options.tee_outputs) {
+        if let Err(e) = update_file_times_with_source(&files[0], output, FileTimeSource::Embedded) {
+            eprintln!("Failed to update file times: {e:?}");
+        }
+    }
 
     println!("\rDone in {:.3}s                ", _time.elapsed().as_millis() as f64 / 1000.0);
     std::io::stdout().flush().unwrap();
 }
+
+fn is_insv(path: &Path) -> bool {
+    path.extension().is_some_and(|x| x.eq_ignore_ascii_case("insv"))
+}
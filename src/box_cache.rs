@@ -0,0 +1,147 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, Result, SeekFrom };
+use crate::{ fourcc, read_box, diagnostics::diag };
+
+/// In-memory copy of the top-level boxes of a file, without the mdat payload.
+/// Used to parse and rewrite the first file without reading its moov again.
+#[derive(Default, Debug, Clone)]
+pub struct BoxCache {
+    segments: Vec<(u64, Vec<u8>)>, // (offset in the file, data)
+}
+
+impl BoxCache {
+    pub fn read<R: Read + Seek>(reader: &mut R, max_read: u64) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut pos = 0;
+        reader.seek(SeekFrom::Start(0))?;
+        while pos < max_read {
+            let Ok((typ, offs, size, header_size)) = read_box(reader) else { break; };
+            if size == 0 || typ == 0 { break; }
+
+            let len = if typ == fourcc("mdat") { header_size as u64 } else { size };
+            reader.seek(SeekFrom::Start(offs))?;
+            let mut data = Vec::with_capacity(len as usize);
+            reader.take(len).read_to_end(&mut data)?;
+            let truncated = (data.len() as u64) < len;
+            segments.push((offs, data));
+            if truncated { break; }
+
+            pos = offs + size;
+            reader.seek(SeekFrom::Start(pos))?;
+        }
+        diag!(Debug, "Cached {} bytes of {} top-level boxes", segments.iter().map(|x| x.1.len()).sum::<usize>(), segments.len());
+        Ok(Self { segments })
+    }
+
+    /// Same boxes with moov moved before the first mdat, so the output can be played while it's downloaded
+    pub fn moov_first(&self) -> Self {
+        let is_typ = |data: &[u8], typ: &[u8]| data.get(4..8) == Some(typ);
+        let (Some(moov), Some(mdat)) = (self.segments.iter().position(|x| is_typ(&x.1, b"moov")), self.segments.iter().position(|x| is_typ(&x.1, b"mdat"))) else {
+            return self.clone();
+        };
+        if moov < mdat { return self.clone(); }
+
+        let mut order = self.segments.clone();
+        let moov = order.remove(moov);
+        order.insert(mdat, moov);
+        // Box sizes are kept, only the offsets change
+        let mut pos = 0;
+        for (offs, data) in &mut order {
+            let size = read_box(&mut std::io::Cursor::new(&data)).map(|x| x.2).unwrap_or(data.len() as u64);
+            *offs = pos;
+            pos += size;
+        }
+        diag!(Debug, "Moving moov before mdat");
+        Self { segments: order }
+    }
+
+    pub fn reader(&self) -> BoxCacheReader<'_> {
+        BoxCacheReader { cache: self, position: 0 }
+    }
+
+    fn len(&self) -> u64 {
+        self.segments.last().map(|(offs, data)| offs + data.len() as u64).unwrap_or(0)
+    }
+}
+
+/// Reader over the cached boxes. Reading from a range which isn't cached (the mdat payload) returns EOF.
+pub struct BoxCacheReader<'a> {
+    cache: &'a BoxCache,
+    position: u64,
+}
+
+impl Read for BoxCacheReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let segments = &self.cache.segments;
+        let index = segments.partition_point(|(offs, _)| *offs <= self.position);
+        let Some((offs, data)) = index.checked_sub(1).map(|i| &segments[i]) else { return Ok(0); };
+        let start = (self.position - offs) as usize;
+        if start >= data.len() { return Ok(0); }
+
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for BoxCacheReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(x)   => Some(x),
+            SeekFrom::Current(x) => self.position.checked_add_signed(x),
+            SeekFrom::End(x)     => self.cache.len().checked_add_signed(x),
+        };
+        self.position = new_pos.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid seek to a negative position"))?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_cache_skips_mdat_payload() {
+        let mut data = Vec::new();
+        data.extend(16u32.to_be_bytes()); data.extend(b"ftyp"); data.extend(b"isom\0\0\0\0");
+        data.extend(12u32.to_be_bytes()); data.extend(b"mdat"); data.extend([1, 2, 3, 4]);
+        data.extend(12u32.to_be_bytes()); data.extend(b"moov"); data.extend([5, 6, 7, 8]);
+
+        let cache = BoxCache::read(&mut Cursor::new(&data), u64::MAX).unwrap();
+        let mut reader = cache.reader();
+
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, data[..24]); // Stops at the mdat payload
+
+        reader.seek(SeekFrom::Start(28)).unwrap();
+        assert_eq!(read_box(&mut reader).unwrap(), (fourcc("moov"), 28, 12, 8));
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [5, 6, 7, 8]);
+        assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), data.len() as u64);
+    }
+
+    #[test]
+    fn test_faststart_merge() {
+        use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+        let file = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50));
+        let mut files = [file.cursor(), file.cursor()];
+        let mut output = Cursor::new(Vec::new());
+        let options = crate::MergeOptions::default().faststart(true);
+        crate::join_file_streams_with_options(&mut files, &mut output, &[None, None], &options, |_| {}).unwrap();
+
+        let structure = crate::inspect::read_structure(&mut output).unwrap();
+        let order = structure.boxes.iter().map(|x| crate::typ_to_str(x.header.typ)).collect::<Vec<_>>();
+        assert_eq!(order, ["ftyp", "moov", "mdat"]);
+        let index = crate::RandomAccessIndex::from_reader(&mut output).unwrap();
+        for (i, file_sample) in [(10, 10), (60, 10)] {
+            let sample = index.sample(0, i).unwrap();
+            assert_eq!(&output.get_ref()[sample.offset as usize..][..sample.size as usize], &file.sample_data(0, file_sample)[..]);
+        }
+    }
+}
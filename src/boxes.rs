@@ -0,0 +1,207 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+//! Low-level MP4 box parsing primitives
+
+use std::io::{ Read, Seek, Result, SeekFrom };
+use byteorder::{ BigEndian, ReadBytesExt };
+
+/// Box type from its four-character code, e.g. `fourcc("moov")`
+pub const fn fourcc(s: &str) -> u32 {
+    let s = s.as_bytes();
+    (s[3] as u32) | ((s[2] as u32) << 8) | ((s[1] as u32) << 16) | ((s[0] as u32) << 24)
+}
+
+/// Four-character code of a box type, or its hex value if it's not valid UTF-8
+pub fn typ_to_str(typ: u32) -> String {
+    match String::from_utf8(vec![(typ >> 24) as u8, (typ >> 16) as u8, (typ >> 8) as u8, typ as u8 ]) {
+        Ok(x) => x,
+        Err(_) => format!("{:08X}", typ)
+    }
+}
+
+/// Read the header of the box at the current position. Returns the type, offset, size and header size.
+/// The size isn't validated, see `BoxIter` for a safe way to walk the boxes
+pub fn read_box<R: Read + Seek>(reader: &mut R) -> Result<(u32, u64, u64, i64)> {
+    let pos = reader.stream_position()?;
+    let size = reader.read_u32::<BigEndian>()?;
+    let typ = reader.read_u32::<BigEndian>()?;
+    if size == 1 {
+        let largesize = reader.read_u64::<BigEndian>()?;
+        Ok((typ, pos, largesize, 16))
+    } else {
+        Ok((typ, pos, size as u64, 8))
+    }
+}
+
+/// Header of a single box
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoxHeader {
+    pub typ: u32,
+    /// Offset of the box in the stream
+    pub offset: u64,
+    /// Size of the box including the header
+    pub size: u64,
+    /// 8, or 16 with a 64-bit size
+    pub header_size: u64,
+}
+
+impl BoxHeader {
+    pub fn typ_str(&self) -> String { typ_to_str(self.typ) }
+    /// Offset of the content, after the header
+    pub fn content_offset(&self) -> u64 { self.offset + self.header_size }
+    pub fn content_size(&self) -> u64 { self.size - self.header_size }
+    /// Offset of the next box
+    pub fn end(&self) -> u64 { self.offset + self.size }
+}
+
+/// Iterator over the boxes in a range of the stream. Sizes are validated against the range, a box with size 0 extends to its end.
+/// After each item the reader is positioned at the content of the returned box. Iteration stops after the first error
+pub struct BoxIter<'a, R: Read + Seek> {
+    reader: &'a mut R,
+    next: u64,
+    end: u64,
+}
+
+impl<'a, R: Read + Seek> BoxIter<'a, R> {
+    /// Boxes between `start` and `end`
+    pub fn new(reader: &'a mut R, start: u64, end: u64) -> Self {
+        Self { reader, next: start, end }
+    }
+
+    /// Top-level boxes of the whole stream
+    pub fn top_level(reader: &'a mut R) -> Result<Self> {
+        let end = reader.seek(SeekFrom::End(0))?;
+        Ok(Self::new(reader, 0, end))
+    }
+
+    /// The underlying reader, e.g. to read the content of the current box
+    pub fn reader(&mut self) -> &mut R { self.reader }
+
+    /// Child boxes of `parent`. Full boxes (e.g. meta) have to skip their version and flags with `BoxIter::new` instead
+    pub fn children(&mut self, parent: &BoxHeader) -> BoxIter<'_, R> {
+        BoxIter::new(self.reader, parent.content_offset(), parent.end())
+    }
+
+    fn read_header(&mut self) -> Result<BoxHeader> {
+        self.reader.seek(SeekFrom::Start(self.next))?;
+        let (typ, offset, size, header_size) = read_box(self.reader)?;
+        let header_size = header_size as u64;
+        let size = if size == 0 { self.end - offset } else { size };
+        if size < header_size {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid size {size} of box {} at {offset}", typ_to_str(typ))));
+        }
+        if offset + size > self.end {
+            return Err(crate::Error::TruncatedFile { path: typ_to_str(typ), offset, reason: format!("Size {size} extends past the end at {}", self.end) }.into());
+        }
+        Ok(BoxHeader { typ, offset, size, header_size })
+    }
+}
+
+impl<R: Read + Seek> Iterator for BoxIter<'_, R> {
+    type Item = Result<BoxHeader>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next + 8 > self.end { return None; }
+        let header = self.read_header();
+        self.next = match &header {
+            Ok(x) => x.end(),
+            Err(_) => self.end
+        };
+        Some(header)
+    }
+}
+
+/// Find the first box at the given path, e.g. `["moov", "trak", "mdia"]`
+pub fn find_box<R: Read + Seek>(reader: &mut R, path: &[&str]) -> Result<Option<BoxHeader>> {
+    let mut iter = BoxIter::top_level(reader)?;
+    find_in(&mut iter, path)
+}
+
+fn find_in<R: Read + Seek>(iter: &mut BoxIter<'_, R>, path: &[&str]) -> Result<Option<BoxHeader>> {
+    let Some((first, rest)) = path.split_first() else { return Ok(None); };
+    while let Some(header) = iter.next() {
+        let header = header?;
+        if header.typ != fourcc(first) { continue; }
+        if rest.is_empty() { return Ok(Some(header)); }
+        if let Some(found) = find_in(&mut iter.children(&header), rest)? {
+            return Ok(Some(found));
+        }
+    }
+    Ok(None)
+}
+
+/// Bytes of the trak box at `index` in moov
+pub(crate) fn read_trak<R: Read + Seek>(reader: &mut R, index: usize) -> Result<Option<Vec<u8>>> {
+    let Some(moov) = find_box(reader, &["moov"])? else { return Ok(None); };
+    let mut top = BoxIter::new(reader, moov.offset, moov.end());
+    let mut children = top.children(&moov);
+    let mut traks = Vec::new();
+    for header in children.by_ref() {
+        let header = header?;
+        if header.typ == fourcc("trak") { traks.push(header); }
+    }
+    let Some(trak) = traks.get(index) else { return Ok(None); };
+    let reader = children.reader();
+    let mut data = vec![0u8; trak.size as usize];
+    reader.seek(SeekFrom::Start(trak.offset))?;
+    reader.read_exact(&mut data)?;
+    Ok(Some(data))
+}
+
+/// Track ID in the tkhd of a trak box
+pub(crate) fn track_id(trak: &[u8]) -> Option<u32> {
+    let tkhd = find_box(&mut std::io::Cursor::new(trak), &["trak", "tkhd"]).ok()??;
+    let pos = (tkhd.content_offset() + if trak[tkhd.content_offset() as usize] == 1 { 20 } else { 12 }) as usize;
+    Some(u32::from_be_bytes(trak.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+/// Write `track_id` to the tkhd of a trak box
+pub(crate) fn set_track_id(trak: &mut [u8], track_id: u32) {
+    let mut pos = 8;
+    while pos + 12 <= trak.len() {
+        let size = u32::from_be_bytes(trak[pos..pos + 4].try_into().unwrap()) as usize;
+        if size < 8 { return; }
+        if &trak[pos + 4..pos + 8] == b"tkhd" {
+            // After the version, flags and the creation and modification times
+            let id_pos = pos + 12 + if trak[pos + 8] == 1 { 16 } else { 8 };
+            if let Some(id) = trak.get_mut(id_pos..id_pos + 4) {
+                id.copy_from_slice(&track_id.to_be_bytes());
+            }
+            return;
+        }
+        pos += size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_box_iter() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&[0, 0, 0, 8]);
+        data.extend_from_slice(b"free");
+        data.extend_from_slice(&[0, 0, 0, 1]);
+        data.extend_from_slice(b"moov");
+        data.extend_from_slice(&28u64.to_be_bytes());
+        data.extend_from_slice(&[0, 0, 0, 12]);
+        data.extend_from_slice(b"trak");
+        data.extend_from_slice(b"data");
+        data.extend_from_slice(&[0, 0, 0, 99]);
+        data.extend_from_slice(b"mdat");
+        let mut reader = std::io::Cursor::new(data);
+
+        let boxes = BoxIter::top_level(&mut reader).unwrap().collect::<Vec<_>>();
+        assert_eq!(boxes.len(), 3);
+        assert_eq!(boxes[1].as_ref().unwrap(), &BoxHeader { typ: fourcc("moov"), offset: 8, size: 28, header_size: 16 });
+        // The mdat claims more data than there is
+        assert!(boxes[2].is_err());
+
+        let trak = find_box(&mut reader, &["moov", "trak"]).unwrap().unwrap();
+        assert_eq!((trak.typ_str().as_str(), trak.content_offset(), trak.content_size()), ("trak", 32, 4));
+        // Not found before the broken mdat
+        assert!(find_box(&mut reader, &["moov", "mvhd"]).is_err());
+    }
+}
@@ -0,0 +1,80 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Write, Seek, SeekFrom, Result };
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+
+/// Stops a running merge from another thread, e.g. from a cancel button. Pass a clone to `MergeOptions::cancellation`.
+/// The merge fails with `Error::Cancelled` at the next input file or output write, and `join_files_with_options`
+/// deletes the incomplete output unless `keep_incomplete_output` is set
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Fail with `Error::Cancelled` once the token is cancelled
+    pub(crate) fn check(token: Option<&Self>) -> Result<()> {
+        match token {
+            Some(token) if token.is_cancelled() => Err(crate::Error::Cancelled.into()),
+            _ => Ok(())
+        }
+    }
+}
+
+/// Output which fails every write after the token was cancelled
+pub(crate) struct Cancellable<W> {
+    inner: W,
+    token: Option<CancellationToken>,
+}
+
+impl<W> Cancellable<W> {
+    pub fn new(inner: W, token: Option<&CancellationToken>) -> Self {
+        Self { inner, token: token.cloned() }
+    }
+}
+
+impl<W: Write> Write for Cancellable<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        CancellationToken::check(self.token.as_ref())?;
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> Result<()> { self.inner.flush() }
+}
+impl<W: Read> Read for Cancellable<W> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> { self.inner.read(buf) }
+}
+impl<W: Seek> Seek for Cancellable<W> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> { self.inner.seek(pos) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+
+    #[test]
+    fn test_cancel_merge() {
+        let chapter = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 500)).track(SyntheticTrack::audio(48000, 940));
+        let token = CancellationToken::new();
+        let options = crate::MergeOptions::default().cancellation(token.clone());
+        let mut files = vec![chapter.cursor(), chapter.cursor()];
+        let e = crate::join_file_streams_with_options(&mut files, std::io::Cursor::new(Vec::new()), &[None, None], &options, |progress| {
+            if progress >= 0.1 { token.cancel(); }
+        }).unwrap_err();
+        assert!(matches!(crate::Error::from(e), crate::Error::Cancelled));
+
+        // Cancelled before the merge started
+        let e = crate::join_file_streams_sequential(&mut files, Vec::new(), &[None, None], &options.precompute_layout(true), |_| {}).unwrap_err();
+        assert!(matches!(crate::Error::from(e), crate::Error::Cancelled));
+    }
+}
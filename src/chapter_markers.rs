@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::time::SystemTime;
+use crate::desc_reader::{ self, Desc, TrackDesc, system_time_to_mp4_time };
+use crate::stsd::SampleEntry;
+use crate::diagnostics::diag;
+
+const MARKER_TIMESCALE: u32 = 1000;
+const MIME_TYPE: &str = "application/json";
+
+/// Wall-clock start of a file: from the GPS time, the creation time in mvhd or the filesystem creation time
+fn wall_clock_start(desc: &Desc, file_index: usize) -> Option<SystemTime> {
+    let info = desc.file_info(file_index);
+    info.gps_time_range.map(|x| x.0).or(info.embedded_creation_time).or(info.filesystem_creation_time)
+}
+
+/// UTC time formatted as RFC 3339 with milliseconds, e.g. "2024-05-31T10:00:00.000Z"
+pub(crate) fn format_rfc3339(time: SystemTime) -> String {
+    let millis = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
+    let (days, millis) = (millis.div_euclid(86_400_000), millis.rem_euclid(86_400_000));
+    let (year, month, day) = crate::gpmf::civil_from_days(days);
+    format!("{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z", millis / 3_600_000, millis / 60_000 % 60, millis / 1000 % 60, millis % 1000)
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// JSON payload of the marker of a file, e.g. `{"index":0,"file":"GX010042.MP4","start":"2024-05-31T10:00:00.000Z"}`
+fn marker_sample(index: usize, name: &str, start: Option<SystemTime>) -> Vec<u8> {
+    let start = start.map(|x| json_string(&format_rfc3339(x))).unwrap_or_else(|| "null".into());
+    format!("{{\"index\":{index},\"file\":{},\"start\":{start}}}", json_string(name)).into_bytes()
+}
+
+/// Add a timed metadata track (`mett`, application/json) with one sample per input, presented from the start of the input
+/// on the merged timeline until the next one. `names` are the names of the inputs, `input_order` maps the merge order to them
+pub(crate) fn add_chapter_markers(desc: &mut Desc, names: &[String], input_order: &[usize]) {
+    let starts = desc.file_timeline_starts();
+    let Some(&last_start) = starts.last() else { return; };
+    let last = starts.len() - 1;
+    let end = last_start + desc.file_info(last).duration - desc.file_trims.get(last).copied().unwrap_or(0.0);
+    let times = starts.iter().chain([&end]).map(|x| (x.max(0.0) * MARKER_TIMESCALE as f64).round() as u64).collect::<Vec<_>>();
+
+    let mut track = TrackDesc {
+        handler_type: "meta".into(),
+        track_id: desc.moov_tracks.iter().map(|x| x.track_id).max().unwrap_or(0) + 1,
+        language: "und".into(),
+        mdhd_timescale: MARKER_TIMESCALE,
+        stsc: vec![(1, starts.len() as u32, 1)],
+        stsz_count: starts.len() as u32,
+        ..Default::default()
+    };
+    for i in 0..starts.len() {
+        let input = input_order.get(i).copied().unwrap_or(i);
+        let name = names.get(input).cloned().unwrap_or_else(|| format!("File {input}"));
+        let data = marker_sample(input, &name, wall_clock_start(desc, i));
+        let duration = times[i + 1].saturating_sub(times[i]) as u32;
+        match track.stts.last_mut() {
+            Some(last) if last.1 == duration => last.0 += 1,
+            _ => track.stts.push((1, duration)),
+        }
+        track.stsz.push(data.len() as u32);
+        let offset = desc.append_synthesized(&data);
+        if i == 0 { track.stco.push(offset); }
+    }
+    diag!(Debug, "Added a chapter marker track with {} samples", starts.len());
+
+    track.mdhd_duration = track.stts.iter().map(|x| x.0 as u64 * x.1 as u64).sum();
+    track.tkhd_duration = desc_reader::convert_duration_ceil(track.mdhd_duration, MARKER_TIMESCALE, desc.moov_mvhd_timescale);
+    track.elst_segment_duration = track.tkhd_duration;
+
+    // TextMetaDataSampleEntry: reserved, data_reference_index, content_encoding and mime_format
+    let fields = [&[0, 0, 0, 0, 0, 0, 0, 1][..], b"\0", MIME_TYPE.as_bytes(), b"\0"].concat();
+    track.sample_entries = vec![SampleEntry { codec: "mett".into(), fields: fields.clone(), boxes: Vec::new() }];
+    let creation_time = desc.file_mvhd_creation_times.first().copied().flatten().map(system_time_to_mp4_time).unwrap_or(0) as u32;
+    let trak = crate::template::metadata_trak(&track, &crate::template::mp4_box(b"mett", &fields), "Chapter markers", creation_time);
+    desc.append_track(track, trak);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+    use std::time::{ Duration, SystemTime };
+
+    #[test]
+    fn test_chapter_markers() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_717_149_600); // 2024-05-31 10:00:00 UTC
+        let chapters = [2, 3].map(|seconds| SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 25 * seconds)));
+        let mut files = chapters.each_ref().map(|x| x.cursor());
+        let mut output = std::io::Cursor::new(Vec::new());
+        let options = crate::MergeOptions { file_names: vec!["GX010042.MP4".into(), "GX020042.MP4".into()], ..Default::default() }.chapter_markers(true);
+        // The second chapter starts after a 5-second pause
+        let times = [Some(start), Some(start + Duration::from_secs(7))];
+        crate::join_file_streams_with_options(&mut files, &mut output, &times, &options, |_| {}).unwrap();
+
+        let tracks = crate::list_tracks(&mut output).unwrap();
+        assert_eq!(tracks[1].codec, "mett");
+        let index = crate::RandomAccessIndex::from_reader(&mut output).unwrap();
+        let samples = (0..2).map(|i| index.sample(1, i).unwrap()).collect::<Vec<_>>();
+        assert_eq!(samples.iter().map(|x| (x.time, x.duration)).collect::<Vec<_>>(), vec![(0.0, 7.0), (7.0, 3.0)]);
+        let text = |i: usize| String::from_utf8(output.get_ref()[samples[i].offset as usize..][..samples[i].size as usize].to_vec()).unwrap();
+        assert_eq!(text(0), r#"{"index":0,"file":"GX010042.MP4","start":"2024-05-31T10:00:00.000Z"}"#);
+        assert_eq!(text(1), r#"{"index":1,"file":"GX020042.MP4","start":"2024-05-31T10:00:07.000Z"}"#);
+    }
+}
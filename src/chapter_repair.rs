@@ -0,0 +1,171 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, Result, SeekFrom, Cursor };
+use crate::desc_reader::{ self, Desc };
+use crate::lrv_repair::{ apply_chunks, mdat_payload, scan_chunks, template_chunks, update_durations, video_formats };
+use crate::boxes::{ fourcc, BoxIter };
+use crate::{ box_cache, writer, diagnostics::diag };
+
+/// Whether the file has a moov box
+pub(crate) fn has_moov<R: Read + Seek>(reader: &mut R) -> Result<bool> {
+    let found = BoxIter::top_level(reader)?.filter_map(|x| x.ok()).any(|x| x.typ == fourcc("moov"));
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(found)
+}
+
+/// Last chapter of a recording which stopped without writing its moov, e.g. when the camera lost power.
+/// It reads as a file with the samples found in its mdat and a moov rebuilt from the previous chapter:
+/// the chapters of a recording have the same tracks and chunk interleaving, so the video frames are found by parsing their NAL units
+/// in the chunk order of the previous chapter. The chunks of the other tracks are expected to have about the same size as there,
+/// they end where the next video frame starts. Their sample sizes are approximate
+pub(crate) struct RebuiltInput<R> {
+    input: R,
+    /// Boxes before the mdat payload
+    head: Vec<u8>,
+    /// Found samples in the mdat payload of the input
+    data: std::ops::Range<u64>,
+    /// Boxes after the mdat payload
+    tail: Vec<u8>,
+    position: u64,
+}
+
+impl<R: Read + Seek> RebuiltInput<R> {
+    pub(crate) fn new<T: Read + Seek>(mut input: R, template: &mut T) -> Result<Self> {
+        let template_desc = desc_reader::read_file_desc(template)?;
+        let num_tracks = template_desc.moov_tracks.iter().rposition(|t| !t.handler_type.is_empty()).map(|x| x + 1).unwrap_or(0);
+        let formats = video_formats(template, num_tracks)?;
+        let chunks = template_chunks(&template_desc, num_tracks);
+
+        let scan = scan_chunks(&mut input, &chunks, &formats, None::<(&mut T, u64)>)?;
+        let (data_start, _) = mdat_payload(&mut input)?;
+        if scan.size == 0 {
+            return Err(crate::Error::TruncatedFile { path: "mdat".into(), offset: data_start, reason: "No samples found to rebuild the moov".into() }.into());
+        }
+        let mut desc = Desc { mdat_position: vec![(Some(0), data_start, scan.size)], ..template_desc.clone() };
+        desc.moov_tracks.truncate(num_tracks);
+        for (track, (found, expected)) in apply_chunks(&mut desc, &scan.found, true).into_iter().enumerate() {
+            diag!(Info, "Rebuilt track {track} with {found} samples, the previous chapter has {expected}");
+        }
+        update_durations(&mut desc);
+        // The chapter starts when the previous one ends
+        if let Some(time) = template_desc.file_mvhd_creation_times.first().copied().flatten() {
+            let duration = template_desc.moov_mvhd_duration as f64 / template_desc.moov_mvhd_timescale.max(1) as f64;
+            desc.output_creation_time = Some(time + std::time::Duration::from_secs_f64(duration));
+        }
+
+        let tree = box_cache::BoxCache::read(template, u64::MAX)?;
+        template.seek(SeekFrom::Start(0))?;
+        let input_size = input.seek(SeekFrom::End(0))? as usize;
+        let mut layout = Cursor::new(Vec::new());
+        desc.skip_mdat_data = true;
+        writer::rewrite_from_desc(&mut tree.reader(), &mut [(&mut input, input_size)], &mut layout, &mut desc, 0, u64::MAX, &crate::copy::Synchronous)?;
+        crate::patch_chunk_offsets(&mut layout, &desc)?;
+        let mut head = layout.into_inner();
+        let tail = head.split_off(desc.mdat_final_position as usize);
+
+        Ok(Self { input, head, data: data_start..data_start + scan.size, tail, position: 0 })
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.head.len() as u64 + (self.data.end - self.data.start) + self.tail.len() as u64
+    }
+}
+
+impl<R: Read + Seek> Read for RebuiltInput<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let head_len = self.head.len() as u64;
+        let data_end = head_len + self.data.end - self.data.start;
+        let read = if self.position < head_len {
+            let len = buf.len().min((head_len - self.position) as usize);
+            buf[..len].copy_from_slice(&self.head[self.position as usize..][..len]);
+            len
+        } else if self.position < data_end {
+            let len = buf.len().min((data_end - self.position) as usize);
+            self.input.seek(SeekFrom::Start(self.data.start + self.position - head_len))?;
+            self.input.read(&mut buf[..len])?
+        } else {
+            let start = ((self.position - data_end) as usize).min(self.tail.len());
+            let len = buf.len().min(self.tail.len() - start);
+            buf[..len].copy_from_slice(&self.tail[start..][..len]);
+            len
+        };
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for RebuiltInput<R> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let len = self.head.len() as u64 + (self.data.end - self.data.start) + self.tail.len() as u64;
+        let position = match pos {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::End(x) => len.checked_add_signed(x),
+            SeekFrom::Current(x) => self.position.checked_add_signed(x),
+        };
+        self.position = position.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid seek to a negative position"))?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ mp4_box, SyntheticMp4, SyntheticTrack };
+
+    #[test]
+    fn test_rebuild_last_chapter() {
+        let template = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 30)).track(SyntheticTrack::audio(48000, 30));
+        let template_data = template.build();
+        let template_desc = desc_reader::read_file_desc(&mut Cursor::new(&template_data)).unwrap();
+
+        // H.264 frames with a keyframe every 10 frames, and audio chunks a bit larger than in the previous chapter
+        let frame = |i: u32| {
+            let mut frame = Vec::new();
+            for slice in 0..2u8 {
+                let mut nal = vec![if i.is_multiple_of(10) { 0x65 } else { 0x41 }, if slice == 0 { 0x88 } else { 0x08 }];
+                nal.resize(200 + i as usize * 3, i as u8);
+                frame.extend((nal.len() as u32).to_be_bytes());
+                frame.extend(nal);
+            }
+            frame
+        };
+        let mut samples = template_desc.moov_tracks[..2].iter().enumerate().flat_map(|(t, track)| track.sample_infos().into_iter().enumerate().map(move |(i, x)| (x.offset, t, i as u32, x.chunk))).collect::<Vec<_>>();
+        samples.sort_by_key(|x| x.0);
+        let mut payload = Vec::new();
+        let mut frames = Vec::new();
+        for (i, &(_, track, sample, chunk)) in samples.iter().enumerate() {
+            if track == 0 {
+                frames.push((payload.len(), frame(sample)));
+                payload.extend(frame(sample));
+            } else {
+                payload.extend(template.sample_data(1, sample));
+                if samples.get(i + 1).is_none_or(|x| (x.1, x.3) != (track, chunk)) { payload.extend([0xaa; 4]); }
+            }
+        }
+        // The camera lost power in the middle of the 26th frame
+        let cut = frames[25].0 + 100;
+        let damaged = [mp4_box(b"ftyp", b"mp41\0\0\0\0mp41"), 0u32.to_be_bytes().to_vec(), b"mdat".to_vec(), payload[..cut].to_vec()].concat();
+
+        let dir = std::env::temp_dir().join(format!("mp4_merge_chapter_repair_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths = [dir.join("GX010001.MP4"), dir.join("GX020001.MP4"), dir.join("out.mp4")];
+        std::fs::write(&paths[0], &template_data).unwrap();
+        std::fs::write(&paths[1], &damaged).unwrap();
+        // Without the repair, the samples of the last chapter aren't described
+        crate::join_files_with_options(&paths[..2], &paths[2], &crate::MergeOptions::default(), |_| {}).unwrap();
+        assert_eq!(crate::list_tracks(&mut std::fs::File::open(&paths[2]).unwrap()).unwrap()[0].sample_count, 30);
+        crate::join_files_with_options(&paths[..2], &paths[2], &crate::MergeOptions::default().repair_truncated_last(true), |_| {}).unwrap();
+        let output = std::fs::read(&paths[2]).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(crate::verify::check(&mut Cursor::new(&output)).unwrap().is_ok());
+        let index = crate::RandomAccessIndex::from_reader(&mut Cursor::new(&output)).unwrap();
+        assert_eq!(index.sample_count(0), 30 + 25);
+        for (i, (_, expected)) in frames[..25].iter().enumerate() {
+            let sample = index.sample(0, 30 + i).unwrap();
+            assert_eq!(&output[sample.offset as usize..][..sample.size as usize], &expected[..], "frame {i}");
+            assert_eq!(sample.is_sync, i % 10 == 0, "frame {i}");
+        }
+    }
+}
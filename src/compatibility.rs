@@ -0,0 +1,195 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, Result, SeekFrom };
+use std::path::Path;
+use byteorder::{ ReadBytesExt, BigEndian };
+use crate::{ fourcc, read_box, typ_to_str, stsd };
+
+/// Validations done by `check_compatibility`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompatibilityCheck {
+    /// Same codec and codec configuration in every track
+    Codecs,
+    /// Same media timescale in every track
+    Timescales,
+    /// Same number and types of tracks
+    TrackLayout,
+    /// Compatible ftyp brands
+    Brands,
+    /// No encrypted tracks
+    Encryption,
+    /// No fragmented files
+    Fragmentation,
+}
+
+/// Outcome of a single check
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    pub check: CompatibilityCheck,
+    pub passed: bool,
+    /// Human-readable reasons of the failure
+    pub reasons: Vec<String>,
+}
+
+/// Whether the files can be merged losslessly
+#[derive(Debug, Clone, Default)]
+pub struct CompatibilityReport {
+    pub checks: Vec<CheckResult>,
+}
+
+impl CompatibilityReport {
+    pub fn is_compatible(&self) -> bool {
+        self.checks.iter().all(|x| x.passed)
+    }
+
+    /// Reasons of all failed checks
+    pub fn reasons(&self) -> impl Iterator<Item = &str> {
+        self.checks.iter().flat_map(|x| x.reasons.iter().map(|x| x.as_str()))
+    }
+}
+
+/// Container-level information of a single file
+#[derive(Debug, Clone, Default)]
+pub struct FileProbe {
+    pub major_brand: String,
+    pub compatible_brands: Vec<String>,
+    pub tracks: Vec<TrackProbe>,
+    pub encrypted: bool,  // Protected sample entries or pssh
+    pub fragmented: bool, // mvex or moof
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TrackProbe {
+    pub handler_type: String,
+    pub timescale: u32,
+    pub sample_entries: Vec<stsd::SampleEntry>,
+}
+
+/// Read the container-level information of a file
+pub fn probe<R: Read + Seek>(reader: &mut R) -> Result<FileProbe> {
+    let mut info = FileProbe::default();
+    reader.seek(SeekFrom::Start(0))?;
+    probe_boxes(reader, u64::MAX, &mut info)?;
+    for (track, entries) in info.tracks.iter_mut().zip(stsd::read_sample_entries(reader)?) {
+        info.encrypted |= entries.iter().any(|e| e.codec == "encv" || e.codec == "enca" || e.child("sinf").is_some());
+        track.sample_entries = entries;
+    }
+    Ok(info)
+}
+
+fn probe_boxes<R: Read + Seek>(reader: &mut R, max_read: u64, info: &mut FileProbe) -> Result<()> {
+    let start_pos = reader.stream_position()?;
+    while reader.stream_position()? - start_pos < max_read {
+        let Ok((typ, offs, size, header_size)) = read_box(reader) else { break; };
+        if size == 0 || typ == 0 { break; }
+        let content_size = size - header_size as u64;
+
+        if typ == fourcc("trak") {
+            info.tracks.push(TrackProbe::default());
+        }
+        if typ == fourcc("moov") || typ == fourcc("trak") || typ == fourcc("mdia") {
+            probe_boxes(reader, content_size, info)?;
+        } else if typ == fourcc("ftyp") && content_size >= 8 {
+            info.major_brand = typ_to_str(reader.read_u32::<BigEndian>()?);
+            reader.seek(SeekFrom::Current(4))?; // Minor version
+            for _ in 0..(content_size - 8) / 4 {
+                info.compatible_brands.push(typ_to_str(reader.read_u32::<BigEndian>()?));
+            }
+        } else if typ == fourcc("mdhd") {
+            let v = reader.read_u8()?;
+            reader.seek(SeekFrom::Current(3 + if v == 1 { 16 } else { 8 }))?;
+            let timescale = reader.read_u32::<BigEndian>()?;
+            if let Some(track) = info.tracks.last_mut() { track.timescale = timescale; }
+        } else if typ == fourcc("hdlr") {
+            reader.seek(SeekFrom::Current(8))?;
+            let handler_type = typ_to_str(reader.read_u32::<BigEndian>()?);
+            if let Some(track) = info.tracks.last_mut() { track.handler_type = handler_type; }
+        } else if typ == fourcc("mvex") || typ == fourcc("moof") {
+            info.fragmented = true;
+        } else if typ == fourcc("pssh") {
+            info.encrypted = true;
+        }
+        reader.seek(SeekFrom::Start(offs + size))?;
+    }
+    Ok(())
+}
+
+/// Run all validations on the files, so the application can decide between merging and a fallback (e.g. transcoding) up front
+pub fn check_compatibility<P: AsRef<Path>>(files: &[P]) -> Result<CompatibilityReport> {
+    let mut probes = Vec::with_capacity(files.len());
+    for x in files {
+        probes.push(probe(&mut std::io::BufReader::with_capacity(16*1024, std::fs::File::open(x)?))?);
+    }
+    Ok(check_probes(&probes))
+}
+
+pub fn check_probes(probes: &[FileProbe]) -> CompatibilityReport {
+    let mut report = CompatibilityReport::default();
+    let mut add = |check, reasons: Vec<String>| report.checks.push(CheckResult { check, passed: reasons.is_empty(), reasons });
+    let Some(first) = probes.first() else { return report; };
+    let others = || probes.iter().enumerate().skip(1);
+
+    let mut layout = Vec::new();
+    for (i, p) in others() {
+        let handlers = |x: &FileProbe| x.tracks.iter().map(|t| t.handler_type.clone()).collect::<Vec<_>>();
+        if handlers(first) != handlers(p) {
+            layout.push(format!("File {i} has tracks {:?}, the first file has {:?}", handlers(p), handlers(first)));
+        }
+    }
+    add(CompatibilityCheck::TrackLayout, layout);
+
+    let mut codecs = Vec::new();
+    for (i, p) in others() {
+        let entries = |x: &FileProbe| x.tracks.iter().map(|t| t.sample_entries.clone()).collect::<Vec<_>>();
+        codecs.extend(stsd::check_compatibility(&entries(first), &entries(p)).into_iter().map(|x| format!("File {i}: {x}")));
+    }
+    add(CompatibilityCheck::Codecs, codecs);
+
+    let mut timescales = Vec::new();
+    for (i, p) in others() {
+        for (track, (a, b)) in first.tracks.iter().zip(&p.tracks).enumerate() {
+            if a.timescale != b.timescale {
+                timescales.push(format!("File {i}: track {track} timescale is {}, the first file has {}", b.timescale, a.timescale));
+            }
+        }
+    }
+    add(CompatibilityCheck::Timescales, timescales);
+
+    let mut brands = Vec::new();
+    for (i, p) in others() {
+        let lists = |x: &FileProbe, brand: &str| x.major_brand == brand || x.compatible_brands.iter().any(|b| b == brand);
+        if first.major_brand != p.major_brand && !lists(p, &first.major_brand) && !lists(first, &p.major_brand) {
+            brands.push(format!("File {i} has brand '{}', the first file has '{}'", p.major_brand, first.major_brand));
+        }
+    }
+    add(CompatibilityCheck::Brands, brands);
+
+    add(CompatibilityCheck::Encryption, probes.iter().enumerate().filter(|(_, p)| p.encrypted).map(|(i, _)| format!("File {i} is encrypted")).collect());
+    add(CompatibilityCheck::Fragmentation, probes.iter().enumerate().filter(|(_, p)| p.fragmented).map(|(i, _)| format!("File {i} is fragmented")).collect());
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(brand: &str, timescales: &[u32]) -> FileProbe {
+        FileProbe {
+            major_brand: brand.into(),
+            tracks: timescales.iter().map(|x| TrackProbe { handler_type: "vide".into(), timescale: *x, ..Default::default() }).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check_probes() {
+        assert!(check_probes(&[file("mp41", &[30000]), file("mp41", &[30000])]).is_compatible());
+
+        let report = check_probes(&[file("mp41", &[30000]), file("qt  ", &[60000]), FileProbe { fragmented: true, ..file("mp41", &[30000]) }]);
+        let failed = report.checks.iter().filter(|x| !x.passed).map(|x| x.check).collect::<Vec<_>>();
+        assert_eq!(failed, vec![CompatibilityCheck::Timescales, CompatibilityCheck::Brands, CompatibilityCheck::Fragmentation]);
+        assert_eq!(report.reasons().count(), 3);
+    }
+}
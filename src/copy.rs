@@ -0,0 +1,362 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Write, Seek, Result, SeekFrom };
+use std::sync::{ mpsc, Mutex };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::Duration;
+use crate::diagnostics::diag;
+
+/// Size of the blocks read from the source files when copying mdat data
+pub const DEFAULT_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Number of blocks in flight between the reader and the writer: one being read while the other is being written
+pub const DEFAULT_BUFFERS: usize = 2;
+
+/// How the mdat data is copied from the source files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CopySettings {
+    /// Block size in bytes, `DEFAULT_BLOCK_SIZE` if 0
+    pub block_size: usize,
+    /// Number of blocks in flight. With 2 or more, a reader thread fills the blocks while the calling thread writes them.
+    /// With 0 or 1 the data is copied synchronously
+    pub buffers: usize,
+    /// Fail when no data moves between the source files and the output for this long. The reads always run on the reader thread then.
+    /// A read or write blocked in the operating system can't be interrupted, the error is returned once it completes
+    pub stall_timeout: Option<Duration>,
+    /// Number of threads reading different source files at the same time, each `buffers` blocks ahead of the writer.
+    /// With 0 or 1 a single reader goes through the files one after another
+    pub reader_threads: usize,
+}
+
+impl CopySettings {
+    pub(crate) fn block_size(&self) -> usize {
+        if self.block_size > 0 { self.block_size } else { DEFAULT_BLOCK_SIZE }
+    }
+}
+
+/// Read `size` bytes at `offset` of `reader` in blocks, restoring the reader position afterwards
+fn read_range<R: Read + Seek, F: FnMut(&mut R, usize) -> Result<bool>>(reader: &mut R, offset: u64, size: u64, block_size: usize, mut read_block: F) -> Result<bool> {
+    let prev_pos = reader.stream_position()?;
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut remaining = size;
+    while remaining > 0 {
+        let len = remaining.min(block_size as u64) as usize;
+        if !read_block(reader, len)? { return Ok(false); }
+        remaining -= len as u64;
+    }
+    reader.seek(SeekFrom::Start(prev_pos))?;
+    Ok(true)
+}
+
+/// File index and offset of the byte at `position` of the concatenated ranges
+fn range_position(ranges: &[(usize, u64, u64)], mut position: u64) -> (usize, u64) {
+    for &(file_index, offset, size) in ranges {
+        if position < size { return (file_index, offset + position); }
+        position -= size;
+    }
+    ranges.last().map(|x| (x.0, x.1 + x.2)).unwrap_or_default()
+}
+
+fn stall_error(message: String) -> std::io::Error {
+    diag!(Error, "{message}");
+    std::io::Error::new(std::io::ErrorKind::TimedOut, message)
+}
+
+/// File index and the `(offset, size)` ranges read from it one after another
+type FileRun = (usize, Vec<(u64, u64)>);
+
+/// Consecutive ranges of the same file, or None when a file is read again after another one
+fn file_runs(ranges: &[(usize, u64, u64)]) -> Option<Vec<FileRun>> {
+    let mut runs: Vec<FileRun> = Vec::new();
+    for &(file_index, offset, size) in ranges {
+        if let Some(run) = runs.last_mut().filter(|x| x.0 == file_index) {
+            run.1.push((offset, size));
+        } else if runs.iter().any(|x| x.0 == file_index) {
+            return None;
+        } else {
+            runs.push((file_index, vec![(offset, size)]));
+        }
+    }
+    Some(runs)
+}
+
+/// How the mdat data is read from the source files. The reader threads of `CopySettings` need files which can be sent
+/// to other threads, so only `Threaded` uses them. `Synchronous` reads on the calling thread, e.g. for the stream API
+pub(crate) trait RangeCopier<R> {
+    fn copy_ranges<W: Write>(&self, files: &mut [(R, usize)], ranges: &[(usize, u64, u64)], output: &mut W, settings: &CopySettings) -> Result<u64>;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Synchronous;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Threaded;
+
+impl<R: Read + Seek> RangeCopier<R> for Synchronous {
+    fn copy_ranges<W: Write>(&self, files: &mut [(R, usize)], ranges: &[(usize, u64, u64)], output: &mut W, settings: &CopySettings) -> Result<u64> {
+        copy_ranges_synchronously(files, ranges, output, settings.block_size())
+    }
+}
+
+impl<R: Read + Seek + Send> RangeCopier<R> for Threaded {
+    fn copy_ranges<W: Write>(&self, files: &mut [(R, usize)], ranges: &[(usize, u64, u64)], output: &mut W, settings: &CopySettings) -> Result<u64> {
+        copy_ranges(files, ranges, output, settings)
+    }
+}
+
+/// Copy the ranges on the calling thread, one block at a time
+fn copy_ranges_synchronously<R: Read + Seek, W: Write>(files: &mut [(R, usize)], ranges: &[(usize, u64, u64)], output: &mut W, block_size: usize) -> Result<u64> {
+    let total = ranges.iter().map(|x| x.2).sum::<u64>();
+    let mut buf = vec![0u8; block_size.min(total as usize)];
+    for &(file_index, offset, size) in ranges {
+        read_range(&mut files[file_index].0, offset, size, block_size, |reader, len| {
+            reader.read_exact(&mut buf[..len])?;
+            output.write_all(&buf[..len])?;
+            Ok(true)
+        })?;
+    }
+    Ok(total)
+}
+
+/// Copy the `(file index, offset, size)` ranges of `files` to `output`, in order. Returns the number of bytes copied
+pub fn copy_ranges<R: Read + Seek + Send, W: Write>(files: &mut [(R, usize)], ranges: &[(usize, u64, u64)], output: &mut W, settings: &CopySettings) -> Result<u64> {
+    let block_size = settings.block_size();
+    let total = ranges.iter().map(|x| x.2).sum::<u64>();
+    let timeout = settings.stall_timeout;
+    if settings.reader_threads > 1 {
+        match file_runs(ranges) {
+            Some(runs) if runs.len() > 1 => return copy_runs_parallel(files, runs, ranges, output, settings),
+            Some(_) => { },
+            None => diag!(Debug, "The mdat ranges go back to a previous file, copying them with a single reader")
+        }
+    }
+    // The watchdog needs the reads on their own thread
+    let buffers = if timeout.is_some() { settings.buffers.max(2) } else { settings.buffers };
+
+    if buffers <= 1 || (total <= block_size as u64 && timeout.is_none()) {
+        return copy_ranges_synchronously(files, ranges, output, block_size);
+    }
+
+    // The reader thread fills the blocks and sends them for writing. Written blocks are sent back to be reused,
+    // so the reads continue across the source files while the previous blocks are being written
+    let (filled_tx, filled_rx) = mpsc::sync_channel::<Result<Vec<u8>>>(buffers);
+    let (empty_tx, empty_rx) = mpsc::channel::<Vec<u8>>();
+    for _ in 0..buffers {
+        empty_tx.send(Vec::with_capacity(block_size)).unwrap();
+    }
+
+    let written = AtomicU64::new(0);
+    std::thread::scope(|s| {
+        // Dropped when the writer returns, which stops the reader
+        let (filled_rx, empty_tx) = (filled_rx, empty_tx);
+        let written = &written;
+        s.spawn(move || {
+            let result = (|| {
+                for &(file_index, offset, size) in ranges {
+                    let completed = read_range(&mut files[file_index].0, offset, size, block_size, |reader, len| {
+                        let buf = match timeout {
+                            Some(timeout) => empty_rx.recv_timeout(timeout).map_err(|e| match e {
+                                mpsc::RecvTimeoutError::Timeout => Some(stall_error(format!("No data was written to the output for {:.1}s, after {} bytes of sample data", timeout.as_secs_f64(), written.load(Ordering::Relaxed)))),
+                                mpsc::RecvTimeoutError::Disconnected => None
+                            }),
+                            None => empty_rx.recv().map_err(|_| None)
+                        };
+                        let mut buf = match buf {
+                            Ok(x) => x,
+                            Err(Some(e)) => return Err(e),
+                            Err(None) => return Ok(false) // The writer failed
+                        };
+                        buf.resize(len, 0);
+                        reader.read_exact(&mut buf)?;
+                        Ok(filled_tx.send(Ok(buf)).is_ok())
+                    })?;
+                    if !completed { break; }
+                }
+                Ok(())
+            })();
+            if let Err(e) = result {
+                let _ = filled_tx.send(Err(e));
+            }
+        });
+
+        let mut remaining = total;
+        while remaining > 0 {
+            let buf = match timeout {
+                Some(timeout) => filled_rx.recv_timeout(timeout).map_err(|e| match e {
+                    mpsc::RecvTimeoutError::Timeout => {
+                        let (file_index, offset) = range_position(ranges, total - remaining);
+                        stall_error(format!("No data was read from file {file_index} at offset {offset} for {:.1}s", timeout.as_secs_f64()))
+                    },
+                    mpsc::RecvTimeoutError::Disconnected => std::io::Error::other("Reader thread stopped")
+                })??,
+                None => filled_rx.recv().map_err(|_| std::io::Error::other("Reader thread stopped"))??
+            };
+            output.write_all(&buf)?;
+            remaining -= buf.len() as u64;
+            written.store(total - remaining, Ordering::Relaxed);
+            let _ = empty_tx.send(buf);
+        }
+        Ok(total)
+    })
+}
+
+/// Copy the runs of ranges of different files with `reader_threads` threads, each reading a whole file ahead of the writer.
+/// The writer takes the blocks in the order of the runs. A stalled output isn't detected by the readers here, only stalled reads
+fn copy_runs_parallel<R: Read + Seek + Send, W: Write>(files: &mut [(R, usize)], runs: Vec<FileRun>, ranges: &[(usize, u64, u64)], output: &mut W, settings: &CopySettings) -> Result<u64> {
+    let block_size = settings.block_size();
+    let total = ranges.iter().map(|x| x.2).sum::<u64>();
+    let threads = settings.reader_threads.min(runs.len());
+    diag!(Debug, "Copying the mdat data of {} files with {threads} reader threads", runs.len());
+
+    let mut readers = files.iter_mut().map(|x| Some(&mut x.0)).collect::<Vec<_>>();
+    let mut receivers = Vec::with_capacity(runs.len());
+    let mut jobs = std::collections::VecDeque::with_capacity(runs.len());
+    for (file_index, file_ranges) in runs {
+        let (tx, rx) = mpsc::sync_channel::<Result<Vec<u8>>>(settings.buffers.max(1));
+        receivers.push((rx, file_ranges.iter().map(|x| x.1).sum::<u64>()));
+        let reader = readers[file_index].take().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid file index {file_index}")))?;
+        jobs.push_back((reader, file_ranges, tx));
+    }
+    let jobs = Mutex::new(jobs);
+
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                // The next file is taken once the previous one is read completely, so the files are read in the order they're written
+                while let Some((reader, file_ranges, tx)) = jobs.lock().unwrap().pop_front() {
+                    let result = file_ranges.iter().try_fold(true, |completed, &(offset, size)| {
+                        if !completed { return Ok(false); }
+                        read_range(reader, offset, size, block_size, |reader, len| {
+                            let mut buf = vec![0u8; len];
+                            reader.read_exact(&mut buf)?;
+                            Ok(tx.send(Ok(buf)).is_ok())
+                        })
+                    });
+                    match result {
+                        Ok(true) => { },
+                        Ok(false) => break, // The writer failed
+                        Err(e) => { let _ = tx.send(Err(e)); break; }
+                    }
+                }
+            });
+        }
+
+        // Dropped when the writer returns, which stops the readers
+        let receivers = receivers;
+        let mut position = 0;
+        for (rx, size) in &receivers {
+            let end = position + size;
+            while position < end {
+                let buf = match settings.stall_timeout {
+                    Some(timeout) => rx.recv_timeout(timeout).map_err(|e| match e {
+                        mpsc::RecvTimeoutError::Timeout => {
+                            let (file_index, offset) = range_position(ranges, position);
+                            stall_error(format!("No data was read from file {file_index} at offset {offset} for {:.1}s", timeout.as_secs_f64()))
+                        },
+                        mpsc::RecvTimeoutError::Disconnected => std::io::Error::other("Reader thread stopped")
+                    })??,
+                    None => rx.recv().map_err(|_| std::io::Error::other("Reader thread stopped"))??
+                };
+                output.write_all(&buf)?;
+                position += buf.len() as u64;
+            }
+        }
+        Ok(total)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_copy_ranges() {
+        let data = (0..10000u32).map(|x| x as u8).collect::<Vec<_>>();
+        let mut expected = data[100..9100].to_vec();
+        expected.extend_from_slice(&data[..500]);
+        for buffers in [1, 2, 4] {
+            for block_size in [1, 7, 4096, 0] {
+                let mut files = vec![(Cursor::new(&data), 0), (Cursor::new(&data), 0)];
+                let mut output = Vec::new();
+                let settings = CopySettings { block_size, buffers, ..Default::default() };
+                assert_eq!(copy_ranges(&mut files, &[(0, 100, 9000), (1, 0, 500)], &mut output, &settings).unwrap(), 9500);
+                assert_eq!(output, expected);
+            }
+        }
+        let mut files = vec![(Cursor::new(&data), 0)];
+        assert!(copy_ranges(&mut files, &[(0, 9000, 5000)], &mut Vec::new(), &CopySettings { block_size: 100, buffers: 2, ..Default::default() }).is_err());
+    }
+
+    #[test]
+    fn test_parallel_copy() {
+        let files_data = (0..4u8).map(|i| (0..5000u32).map(|x| (x as u8).wrapping_mul(i + 1)).collect::<Vec<_>>()).collect::<Vec<_>>();
+        let ranges = [(0, 0, 3000), (0, 3500, 1500), (1, 100, 4900), (2, 0, 10), (3, 1000, 2000)];
+        let expected = ranges.iter().flat_map(|&(file, offset, size)| files_data[file][offset as usize..][..size as usize].to_vec()).collect::<Vec<_>>();
+        for reader_threads in [2, 3, 8] {
+            for block_size in [7, 1000] {
+                let mut files = files_data.iter().map(|x| (Cursor::new(x), 0)).collect::<Vec<_>>();
+                let mut output = Vec::new();
+                let settings = CopySettings { block_size, buffers: 2, reader_threads, ..Default::default() };
+                assert_eq!(copy_ranges(&mut files, &ranges, &mut output, &settings).unwrap(), expected.len() as u64);
+                assert_eq!(output, expected);
+            }
+        }
+        // Going back to the first file falls back to a single reader
+        let mut files = files_data.iter().map(|x| (Cursor::new(x), 0)).collect::<Vec<_>>();
+        let mut output = Vec::new();
+        let settings = CopySettings { block_size: 100, buffers: 2, reader_threads: 4, ..Default::default() };
+        copy_ranges(&mut files, &[(0, 0, 100), (1, 0, 100), (0, 100, 100)], &mut output, &settings).unwrap();
+        assert_eq!(output, [&files_data[0][..100], &files_data[1][..100], &files_data[0][100..200]].concat());
+        // A read error of a later file
+        let mut files = files_data.iter().map(|x| (Cursor::new(x), 0)).collect::<Vec<_>>();
+        assert!(copy_ranges(&mut files, &[(0, 0, 100), (1, 4000, 2000)], &mut Vec::new(), &settings).is_err());
+    }
+
+    #[test]
+    fn test_stall_timeout() {
+        struct SlowReader(Cursor<Vec<u8>>);
+        impl Read for SlowReader {
+            fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+                if self.0.position() >= 300 { std::thread::sleep(Duration::from_millis(200)); }
+                self.0.read(buf)
+            }
+        }
+        impl Seek for SlowReader {
+            fn seek(&mut self, pos: SeekFrom) -> Result<u64> { self.0.seek(pos) }
+        }
+        let mut files = vec![(SlowReader(Cursor::new(vec![0; 1000])), 0), (SlowReader(Cursor::new(vec![0; 1000])), 0)];
+        let settings = CopySettings { block_size: 100, buffers: 1, stall_timeout: Some(Duration::from_millis(20)), ..Default::default() };
+        let e = copy_ranges(&mut files, &[(0, 0, 100), (1, 200, 500)], &mut Vec::new(), &settings).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+        assert!(e.to_string().contains("file 1 at offset 300"), "{e}");
+    }
+
+    #[test]
+    fn test_stream_readers_without_send() {
+        // Readers shared through an Rc can't go to the reader threads, so they're read on the calling thread
+        struct SharedReader(std::rc::Rc<std::cell::RefCell<Cursor<Vec<u8>>>>);
+        impl Read for SharedReader {
+            fn read(&mut self, buf: &mut [u8]) -> Result<usize> { self.0.borrow_mut().read(buf) }
+        }
+        impl Seek for SharedReader {
+            fn seek(&mut self, pos: SeekFrom) -> Result<u64> { self.0.borrow_mut().seek(pos) }
+        }
+        let file = crate::test_util::SyntheticMp4::new().track(crate::test_util::SyntheticTrack::video(25, 1, 50)).build();
+        let reader = || (SharedReader(std::rc::Rc::new(std::cell::RefCell::new(Cursor::new(file.clone())))), file.len());
+        let options = crate::MergeOptions::default();
+        let mut output = Cursor::new(Vec::new());
+        crate::join_file_streams_with_options(&mut [reader(), reader()], &mut output, &[None, None], &options, |_| {}).unwrap();
+        let merged = output.into_inner();
+        let mut expected = Cursor::new(Vec::new());
+        crate::join_file_streams_with_options(&mut [(Cursor::new(file.clone()), file.len()), (Cursor::new(file.clone()), file.len())], &mut expected, &[None, None], &options, |_| {}).unwrap();
+        assert_eq!(merged, expected.into_inner());
+
+        // The options of the reader threads aren't ignored
+        for options in [options.clone().copy_buffers(2), options.clone().copy_threads(2), options.stall_timeout(Duration::from_secs(1))] {
+            let e = crate::join_file_streams_with_options(&mut [reader(), reader()], Cursor::new(Vec::new()), &[None, None], &options, |_| {}).unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        }
+    }
+}
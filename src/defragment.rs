@@ -0,0 +1,379 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, Result, SeekFrom, Cursor };
+use byteorder::{ BigEndian, ReadBytesExt };
+use crate::desc_reader::{ self, TrackDesc };
+use crate::boxes::{ fourcc, BoxIter };
+use crate::stsd::next_box;
+use crate::{ box_cache, read_box, writer, diagnostics::diag };
+
+/// Top-level boxes of a fragmented file which only describe the fragments
+const FRAGMENT_BOXES: &[&str] = &["moof", "mdat", "mfra", "sidx", "ssix", "styp", "prft", "emsg"];
+
+/// Whether the file is fragmented, with its samples described by moof boxes instead of the moov
+pub(crate) fn is_fragmented<R: Read + Seek>(reader: &mut R) -> Result<bool> {
+    let found = BoxIter::top_level(reader)?.map_while(|x| x.ok()).any(|x| x.typ == fourcc("moof"));
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(found)
+}
+
+/// Sample defaults of a track from its trex, overridden by the tfhd of each fragment
+#[derive(Debug, Clone, Copy, Default)]
+struct SampleDefaults {
+    description_index: u32,
+    duration: u32,
+    size: u32,
+    flags: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FragmentSample {
+    offset: u64, // In the input file
+    size: u32,
+    duration: u32,
+    composition_offset: i32,
+    description_index: u32,
+    is_sync: bool,
+}
+
+/// Fragmented file (e.g. from a dashcam or a drone) which reads as a regular file: the samples of its moof/trun boxes are
+/// written to the sample tables of the moov, and the payloads of its mdat boxes are joined into a single mdat.
+/// The mvex and the fragment boxes are left out. A fragment cut off at the end of the file, e.g. by a power loss, is dropped
+pub(crate) struct DefragmentedInput<R> {
+    input: R,
+    /// Boxes before the mdat payload
+    head: Vec<u8>,
+    /// Start in the joined payload, offset in the input and size of the mdat payloads
+    data: Vec<(u64, u64, u64)>,
+    data_size: u64,
+    /// Boxes after the mdat payload
+    tail: Vec<u8>,
+    position: u64,
+}
+
+impl<R: Read + Seek> DefragmentedInput<R> {
+    pub(crate) fn new(mut input: R) -> Result<Self> {
+        let file_size = input.seek(SeekFrom::End(0))?;
+        let mut skeleton = Vec::new();
+        let mut trex = Vec::new();
+        let mut moofs = Vec::new();
+        let mut data = Vec::new();
+        let mut data_size = 0;
+        let mut pos = 0;
+        while pos + 8 <= file_size {
+            input.seek(SeekFrom::Start(pos))?;
+            let (typ, offs, size, header_size) = read_box(&mut input)?;
+            let size = if size == 0 { file_size - offs } else { size };
+            if size < header_size as u64 { break; }
+            let available = (offs + size).min(file_size) - offs;
+            let name = crate::typ_to_str(typ);
+            if typ == fourcc("mdat") {
+                let payload = available.saturating_sub(header_size as u64);
+                data.push((data_size, offs + header_size as u64, payload));
+                data_size += payload;
+            } else if available == size {
+                let mut content = vec![0; size as usize];
+                input.seek(SeekFrom::Start(offs))?;
+                input.read_exact(&mut content)?;
+                if typ == fourcc("moof") {
+                    moofs.push((offs, content));
+                } else if typ == fourcc("moov") {
+                    skeleton.extend(without_mvex(&content[header_size as usize..], &mut trex));
+                } else if !FRAGMENT_BOXES.contains(&name.as_str()) {
+                    skeleton.extend(content);
+                }
+            }
+            pos = offs + size;
+        }
+        // A single mdat with all the payloads, the samples are mapped to it below
+        skeleton.extend(1u32.to_be_bytes());
+        skeleton.extend(b"mdat");
+        skeleton.extend((16 + data_size).to_be_bytes());
+        let mut skeleton = Cursor::new(skeleton);
+
+        let mut desc = desc_reader::read_file_desc(&mut skeleton)?;
+        if desc.moov_tracks.iter().any(|x| x.stsz_count > 0) {
+            return Err(crate::Error::UnsupportedBox { path: "moov/trak/mdia/minf/stbl".into(), reason: "Samples both in the moov and in movie fragments".into() }.into());
+        }
+        let track_ids = desc.moov_tracks.iter().map(|x| x.track_id).collect::<Vec<_>>();
+        let mut samples = vec![Vec::new(); track_ids.len()];
+        let mut end_times = vec![0; track_ids.len()];
+        for (moof_offset, moof) in &moofs {
+            read_moof(*moof_offset, moof, &track_ids, &trex, &mut samples, &mut end_times)?;
+        }
+
+        let mapped = |offset: u64, size: u32| data.iter()
+            .find(|x| offset >= x.1 && offset + size as u64 <= x.1 + x.2)
+            .map(|x| x.0 + offset - x.1);
+        for (track_index, (track, samples)) in desc.moov_tracks.iter_mut().zip(&samples).enumerate() {
+            if samples.is_empty() { continue; }
+            let mut chunks: Vec<(u64, u32, u32)> = Vec::new();
+            let mut end = 0;
+            let mut count = 0;
+            for sample in samples {
+                let Some(offset) = mapped(sample.offset, sample.size) else { break; };
+                match chunks.last_mut() {
+                    Some(x) if offset == end && x.2 == sample.description_index => x.1 += 1,
+                    _ => chunks.push((offset, 1, sample.description_index))
+                }
+                end = offset + sample.size as u64;
+                count += 1;
+            }
+            if count < samples.len() {
+                diag!(Warn, "Dropping {} samples of track {track_index} past the end of the file", samples.len() - count);
+            }
+            let samples = &samples[..count];
+            set_tables(track, samples);
+            track.set_chunks(&chunks);
+            diag!(Debug, "Track {track_index} has {count} samples in {} fragments", moofs.len());
+        }
+        crate::lrv_repair::update_durations(&mut desc);
+
+        let tree = box_cache::BoxCache::read(&mut skeleton, u64::MAX)?;
+        let skeleton_size = skeleton.get_ref().len();
+        let mut layout = Cursor::new(Vec::new());
+        desc.skip_mdat_data = true;
+        writer::rewrite_from_desc(&mut tree.reader(), &mut [(&mut skeleton, skeleton_size)], &mut layout, &mut desc, 0, u64::MAX, &crate::copy::Synchronous)?;
+        crate::patch_chunk_offsets(&mut layout, &desc)?;
+        let mut head = layout.into_inner();
+        let tail = head.split_off(desc.mdat_final_position as usize);
+
+        Ok(Self { input, head, data, data_size, tail, position: 0 })
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.head.len() as u64 + self.data_size + self.tail.len() as u64
+    }
+}
+
+/// Children of a moov payload without the mvex, as a moov box. The trex defaults of its tracks are added to `trex`
+fn without_mvex(moov: &[u8], trex: &mut Vec<(u32, SampleDefaults)>) -> Vec<u8> {
+    let mut children = Vec::with_capacity(moov.len());
+    let mut pos = 0;
+    loop {
+        let start = pos;
+        let Some((typ, content)) = next_box(moov, &mut pos) else { break; };
+        if typ == "mvex" {
+            let mut mvex_pos = 0;
+            while let Some((typ, content)) = next_box(content, &mut mvex_pos) {
+                if typ != "trex" || content.len() < 24 { continue; }
+                let mut d = Cursor::new(&content[4..]);
+                let mut read = || d.read_u32::<BigEndian>().unwrap_or(0);
+                let track_id = read();
+                trex.push((track_id, SampleDefaults { description_index: read(), duration: read(), size: read(), flags: read() }));
+            }
+        } else {
+            children.extend_from_slice(&moov[start..pos]);
+        }
+    }
+    [&(8 + children.len() as u32).to_be_bytes()[..], b"moov", &children].concat()
+}
+
+/// Add the samples of the trafs of a moof at `moof_offset` to the tracks with the IDs `track_ids`. `end_times` is the decode time
+/// after the last sample of each track
+fn read_moof(moof_offset: u64, moof: &[u8], track_ids: &[u32], trex: &[(u32, SampleDefaults)], samples: &mut [Vec<FragmentSample>], end_times: &mut [u64]) -> Result<()> {
+    let invalid = |reason: &str| std::io::Error::from(crate::Error::UnsupportedBox { path: "moof/traf".into(), reason: reason.into() });
+    let mut pos = 8;
+    let mut data_end = moof_offset;
+    while let Some((typ, traf)) = next_box(moof, &mut pos) {
+        if typ != "traf" { continue; }
+        let mut traf_pos = 0;
+        let mut track = None;
+        let mut defaults = SampleDefaults::default();
+        let mut base_offset = data_end;
+        let mut decode_time = None;
+        while let Some((typ, content)) = next_box(traf, &mut traf_pos) {
+            let mut d = Cursor::new(content);
+            if typ == "senc" || typ == "saiz" || typ == "saio" {
+                return Err(invalid("Encrypted fragments can't be merged"));
+            }
+            if typ == "tfhd" {
+                let flags = d.read_u32::<BigEndian>()? & 0xffffff;
+                let track_id = d.read_u32::<BigEndian>()?;
+                track = track_ids.iter().position(|x| *x == track_id);
+                defaults = trex.iter().find(|x| x.0 == track_id).map(|x| x.1).unwrap_or_default();
+                base_offset = if flags & 0x1 != 0 { d.read_u64::<BigEndian>()? } else if flags & 0x20000 != 0 { moof_offset } else { data_end };
+                if flags & 0x2  != 0 { defaults.description_index = d.read_u32::<BigEndian>()?; }
+                if flags & 0x8  != 0 { defaults.duration          = d.read_u32::<BigEndian>()?; }
+                if flags & 0x10 != 0 { defaults.size              = d.read_u32::<BigEndian>()?; }
+                if flags & 0x20 != 0 { defaults.flags             = d.read_u32::<BigEndian>()?; }
+            }
+            if typ == "tfdt" {
+                let v = d.read_u8()?;
+                d.set_position(4);
+                decode_time = Some(if v == 1 { d.read_u64::<BigEndian>()? } else { d.read_u32::<BigEndian>()? as u64 });
+            }
+            if typ == "trun" {
+                let Some(track) = track else { return Err(invalid("trun of an unknown track")); };
+                let flags = d.read_u32::<BigEndian>()? & 0xffffff;
+                let count = d.read_u32::<BigEndian>()?;
+                let mut offset = if flags & 0x1 != 0 { base_offset.checked_add_signed(d.read_i32::<BigEndian>()? as i64).ok_or_else(|| invalid("Invalid data offset"))? } else { data_end.max(base_offset) };
+                let first_flags = if flags & 0x4 != 0 { Some(d.read_u32::<BigEndian>()?) } else { None };
+                let samples = &mut samples[track];
+                // Samples start at the decode time of the fragment, a gap extends the previous sample
+                if let Some(time) = decode_time.take().filter(|x| *x > end_times[track]) {
+                    if let Some(last) = samples.last_mut() { last.duration += (time - end_times[track]) as u32; }
+                    end_times[track] = time;
+                }
+                for i in 0..count {
+                    let duration = if flags & 0x100 != 0 { d.read_u32::<BigEndian>()? } else { defaults.duration };
+                    let size     = if flags & 0x200 != 0 { d.read_u32::<BigEndian>()? } else { defaults.size };
+                    let sample_flags = if flags & 0x400 != 0 { d.read_u32::<BigEndian>()? } else { first_flags.filter(|_| i == 0).unwrap_or(defaults.flags) };
+                    // Signed in version 1, and in practice in version 0 too
+                    let composition_offset = if flags & 0x800 != 0 { d.read_i32::<BigEndian>()? } else { 0 };
+                    samples.push(FragmentSample { offset, size, duration, composition_offset, description_index: defaults.description_index.max(1), is_sync: sample_flags & 0x10000 == 0 });
+                    offset += size as u64;
+                    end_times[track] += duration as u64;
+                }
+                data_end = offset;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Count and value of each run of equal values, as in stts and ctts
+fn run_lengths<T: PartialEq>(values: impl Iterator<Item = T>) -> Vec<(u32, T)> {
+    let mut table: Vec<(u32, T)> = Vec::new();
+    for value in values {
+        match table.last_mut() {
+            Some(x) if x.1 == value => x.0 += 1,
+            _ => table.push((1, value))
+        }
+    }
+    table
+}
+
+/// Replace the sample tables of `track` with `samples`, except the chunks
+fn set_tables(track: &mut TrackDesc, samples: &[FragmentSample]) {
+    track.stts = run_lengths(samples.iter().map(|x| x.duration));
+    track.ctts = if samples.iter().any(|x| x.composition_offset != 0) { run_lengths(samples.iter().map(|x| x.composition_offset)) } else { Vec::new() };
+    let sizes = samples.iter().map(|x| x.size).collect::<Vec<_>>();
+    if sizes.iter().all(|x| *x == sizes[0]) {
+        track.stsz_sample_size = sizes[0];
+        track.stsz.clear();
+    } else {
+        track.stsz_sample_size = 0;
+        track.stsz = sizes;
+    }
+    track.stsz_count = samples.len() as u32;
+    // An existing stss has to list every sync sample, otherwise it's only needed when some samples aren't
+    track.stss = if samples.iter().all(|x| x.is_sync) && !track.file_has_stss.first().copied().unwrap_or(false) {
+        Vec::new()
+    } else {
+        samples.iter().enumerate().filter(|x| x.1.is_sync).map(|x| x.0 as u32 + 1).collect()
+    };
+    track.stps.clear();
+    track.sdtp.clear();
+    track.cslg = None;
+    track.mdhd_duration = samples.iter().map(|x| x.duration as u64).sum();
+    if let Some(range) = track.file_sample_ranges.first_mut() { *range = 0..samples.len() as u32; }
+}
+
+impl<R: Read + Seek> Read for DefragmentedInput<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let head_len = self.head.len() as u64;
+        let data_end = head_len + self.data_size;
+        let read = if self.position < head_len {
+            let len = buf.len().min((head_len - self.position) as usize);
+            buf[..len].copy_from_slice(&self.head[self.position as usize..][..len]);
+            len
+        } else if self.position < data_end {
+            let position = self.position - head_len;
+            let Some(&(start, offset, size)) = self.data.iter().find(|x| position < x.0 + x.2) else { return Ok(0); };
+            let len = buf.len().min((start + size - position) as usize);
+            self.input.seek(SeekFrom::Start(offset + position - start))?;
+            self.input.read(&mut buf[..len])?
+        } else {
+            let start = ((self.position - data_end) as usize).min(self.tail.len());
+            let len = buf.len().min(self.tail.len() - start);
+            buf[..len].copy_from_slice(&self.tail[start..][..len]);
+            len
+        };
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for DefragmentedInput<R> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::End(x) => self.len().checked_add_signed(x),
+            SeekFrom::Current(x) => self.position.checked_add_signed(x),
+        };
+        self.position = position.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid seek to a negative position"))?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+
+    #[test]
+    fn test_fragmented_input() {
+        let video = SyntheticTrack { composition_offsets: vec![1, 3, 0, 0], ..SyntheticTrack::video(25, 1, 50) };
+        let file = SyntheticMp4::new().track(video).track(SyntheticTrack::audio(48000, 50));
+        let fragmented = file.build_fragmented(10);
+        let defragmented = |data: Vec<u8>| {
+            let mut input = DefragmentedInput::new(Cursor::new(data)).unwrap();
+            let mut output = Vec::new();
+            input.read_to_end(&mut output).unwrap();
+            assert_eq!(output.len() as u64, input.len());
+            output
+        };
+        assert!(is_fragmented(&mut Cursor::new(&fragmented)).unwrap());
+        assert!(!is_fragmented(&mut Cursor::new(file.build())).unwrap());
+
+        let output = defragmented(fragmented.clone());
+        assert!(!is_fragmented(&mut Cursor::new(&output)).unwrap());
+        assert!(crate::verify::check(&mut Cursor::new(&output)).unwrap().is_ok());
+        let desc = desc_reader::read_file_desc(&mut Cursor::new(&output)).unwrap();
+        let expected = desc_reader::read_file_desc(&mut Cursor::new(file.build())).unwrap();
+        for track in 0..2 {
+            let (tables, expected) = (&desc.moov_tracks[track], &expected.moov_tracks[track]);
+            assert_eq!((&tables.stts, &tables.ctts, tables.stsz_count, &tables.stss), (&expected.stts, &expected.ctts, expected.stsz_count, &expected.stss));
+            assert_eq!(tables.mdhd_duration, expected.mdhd_duration);
+        }
+        let index = crate::RandomAccessIndex::from_reader(&mut Cursor::new(&output)).unwrap();
+        for (track, sample) in [(0, 0), (0, 37), (1, 49)] {
+            let location = index.sample(track, sample).unwrap();
+            assert_eq!(&output[location.offset as usize..][..location.size as usize], &file.sample_data(track, sample as u32)[..]);
+        }
+
+        // The last fragment is cut off
+        let truncated = defragmented(fragmented[..fragmented.len() - 500].to_vec());
+        let desc = desc_reader::read_file_desc(&mut Cursor::new(&truncated)).unwrap();
+        assert!(desc.moov_tracks[0].stsz_count < 50 && desc.moov_tracks[0].stsz_count >= 40);
+
+        let dir = std::env::temp_dir().join(format!("mp4_merge_fragmented_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let inputs = [dir.join("a.mp4"), dir.join("b.mp4")];
+        for input in &inputs {
+            std::fs::write(input, &fragmented).unwrap();
+        }
+        let output = dir.join("out.mp4");
+        crate::join_files(&inputs, &output, |_| {}).unwrap();
+        let merged = std::fs::read(&output).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(crate::verify::check(&mut Cursor::new(&merged)).unwrap().is_ok());
+        let index = crate::RandomAccessIndex::from_reader(&mut Cursor::new(&merged)).unwrap();
+        assert_eq!(index.sample_count(0), 100);
+        let location = index.sample(0, 60).unwrap();
+        assert_eq!(&merged[location.offset as usize..][..location.size as usize], &file.sample_data(0, 10)[..]);
+    }
+
+    #[test]
+    fn test_fragmented_stream_input() {
+        let file = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50)).track(SyntheticTrack::audio(48000, 50));
+        let fragmented = file.build_fragmented(30);
+        let size = fragmented.len();
+        let mut files = [file.cursor(), (Cursor::new(fragmented), size)];
+        let err = crate::join_file_streams(&mut files, Cursor::new(Vec::new()), |_| {}).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+        assert!(matches!(crate::Error::from_io(&err), Some(crate::Error::UnsupportedBox { path, .. }) if path == "moof"), "{err}");
+    }
+}
@@ -3,21 +3,24 @@
 
 use std::io::{ Read, Seek, Result, SeekFrom };
 use byteorder::{ ReadBytesExt, BigEndian };
-use crate::{ fourcc, read_box, typ_to_str };
+use crate::{ fourcc, read_box, typ_to_str, diagnostics::diag };
 
 #[derive(Default, Clone, Debug)]
 pub struct TrackDesc {
     pub tkhd_duration: u64,
     pub elst_segment_duration: u64,
     pub mdhd_timescale: u32,
+    pub file_mdhd_timescale: u32, // mdhd timescale of the file being read, its samples are rescaled to mdhd_timescale
     pub mdhd_duration: u64,
     pub stts: Vec<(u32, u32)>,
     pub stsz: Vec<u32>,
     pub stco: Vec<u64>,
     pub stss: Vec<u32>,
+    pub stps: Vec<u32>, // Partial sync samples, e.g. the recovery points of open-GOP H.264
     pub sdtp: Vec<u8>,
     pub sample_offset: u32,
     pub chunk_offset: u32,
+    pub stsc_offset: u32, // Number of stsc entries before the file being read
     pub stsz_sample_size: u32,
     pub stsz_count: u32,
     pub stsc: Vec<(u32, u32, u32)>, // first_chunk, samples_per_chunk, sample_description_index
@@ -25,9 +28,277 @@ pub struct TrackDesc {
     pub skip: bool,
     pub elst_entries: Vec<EditListEntry>, // Edit list entries including gaps
     pub handler_type: String, // Track handler type (e.g., "vide", "soun", "meta", etc.)
+    pub file_sample_ranges: Vec<std::ops::Range<u32>>, // Range of sample indexes (0-based) coming from each file
+    pub sample_entries: Vec<crate::stsd::SampleEntry>, // Sample entries from stsd of the first file
+    pub stss_present: bool, // Whether the file currently being read has stss
+    pub file_has_stss: Vec<bool>, // Whether each file has stss. Every sample is a sync sample in files without it
+    pub track_id: u32, // track_ID from tkhd of the first file
+    pub language: String, // ISO-639-2/T language code from mdhd of the first file
+    pub dropped: bool, // Not written to the output, e.g. GoPro fdsc tracks which describe a single chapter
+    pub has_edts: bool, // Whether the trak of the first file has an edts box
+    pub has_stps: bool, // Whether the stbl of the first file has an stps box
+    pub ctts: Vec<(u32, i32)>, // Sample count and composition offset. Empty when no file has ctts
+    pub has_ctts: bool, // Whether the stbl of the first file has a ctts box
+    pub cslg: Option<[i64; 5]>, // cslg of the file being read
+    pub file_cslg: Vec<Option<[i64; 5]>>, // Composition shift, least and greatest delta, composition start and end of each file
+    pub file_edit: Option<(i64, f64)>, // First media entry of the edit list of the file being read: media time and duration in seconds
+    pub file_encoder_delay: Vec<(u64, u64)>, // Priming and remainder samples (in media timescale) of each file, trimmed by the edit list
+    pub rounding_error: f64, // Largest timing error introduced by timescale conversions, in seconds
 }
 
-#[derive(Clone, Debug)]
+/// Location and timing of a single sample of the merged track
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SampleInfo {
+    pub offset: u64,       // Offset relative to the start of the merged mdat payload
+    pub size: u32,
+    pub decode_time: u64,  // In media timescale
+    pub duration: u32,     // In media timescale
+    pub composition_offset: i32, // Presentation time minus decode time, in media timescale
+    pub chunk: u32,        // 0-based chunk index
+    pub description_index: u32,
+    pub is_sync: bool,
+}
+
+impl TrackDesc {
+    /// Expand the sample tables to per-sample information
+    pub fn sample_infos(&self) -> Vec<SampleInfo> {
+        let mut ret = Vec::with_capacity(self.stsz_count as usize);
+        let mut deltas = self.sample_deltas();
+        let mut composition_offsets = self.composition_offsets();
+        let mut stss = self.stss.iter().peekable();
+        let mut stsc_index = 0;
+        let mut decode_time = 0u64;
+        for (chunk, chunk_offset) in self.stco.iter().enumerate() {
+            let chunk_number = chunk as u32 + 1;
+            while stsc_index + 1 < self.stsc.len() && self.stsc[stsc_index + 1].0 <= chunk_number {
+                stsc_index += 1;
+            }
+            let Some(&(_, samples_per_chunk, description_index)) = self.stsc.get(stsc_index) else { break; };
+            let mut offset = *chunk_offset;
+            for _ in 0..samples_per_chunk {
+                let index = ret.len();
+                if index >= self.stsz_count as usize { break; }
+                let size = if self.stsz_sample_size > 0 { self.stsz_sample_size } else { self.stsz.get(index).copied().unwrap_or(0) };
+                let duration = deltas.next().unwrap_or(0);
+                let composition_offset = composition_offsets.next().unwrap_or(0);
+                while stss.next_if(|x| (**x as usize) < index + 1).is_some() { }
+                let is_sync = self.stss.is_empty() || stss.peek() == Some(&&(index as u32 + 1));
+                ret.push(SampleInfo { offset, size, decode_time, duration, composition_offset, chunk: chunk as u32, description_index, is_sync });
+                offset += size as u64;
+                decode_time += duration as u64;
+            }
+        }
+        ret
+    }
+
+    /// Add all samples of files without stss to the sync samples, if other files have stss.
+    /// Without that, the samples of such files (common with AV1) would be treated as non-sync in the merged track.
+    pub fn fill_missing_sync_samples(&mut self) {
+        if self.skip || !self.file_has_stss.iter().any(|x| *x) || self.file_has_stss.iter().all(|x| *x) { return; }
+        for (has_stss, range) in self.file_has_stss.iter().zip(&self.file_sample_ranges) {
+            if !has_stss {
+                self.stss.extend(range.start + 1..=range.end);
+            }
+        }
+        self.stss.sort_unstable();
+    }
+
+    /// Cut the timing tables and the sample sizes to the first `count` samples. The chunks are set with `set_chunks`
+    pub fn truncate_samples(&mut self, count: u32) {
+        let mut remaining = count;
+        self.stts.retain_mut(|x| {
+            x.0 = x.0.min(remaining);
+            remaining -= x.0;
+            x.0 > 0
+        });
+        let mut remaining = count;
+        self.ctts.retain_mut(|x| {
+            x.0 = x.0.min(remaining);
+            remaining -= x.0;
+            x.0 > 0
+        });
+        self.stss.retain(|x| *x <= count);
+        self.stps.retain(|x| *x <= count);
+        self.sdtp.truncate(count as usize);
+        self.stsz.truncate(count as usize);
+        self.stsz_count = self.stsz_count.min(count);
+        self.mdhd_duration = self.stts.iter().map(|x| x.0 as u64 * x.1 as u64).sum();
+    }
+
+    /// Replace the chunk offsets and the sample-to-chunk table with `chunks`: offset, number of samples and sample description index
+    pub fn set_chunks(&mut self, chunks: &[(u64, u32, u32)]) {
+        self.stco = chunks.iter().map(|x| x.0).collect();
+        self.stsc.clear();
+        for (i, &(_, samples, description_index)) in chunks.iter().enumerate() {
+            if self.stsc.last().is_none_or(|x| (x.1, x.2) != (samples, description_index)) {
+                self.stsc.push((i as u32 + 1, samples, description_index));
+            }
+        }
+    }
+
+    /// Extend the last sample so the track lasts `duration` (in media timescale), if its samples end earlier.
+    /// Sparse timed metadata (e.g. MDPM or GPMF) often ends before the file does, and the samples of the next file would start too early.
+    pub fn extend_last_sample(&mut self, duration: u64) {
+        let total = self.stts.iter().map(|(count, delta)| *count as u64 * *delta as u64).sum::<u64>();
+        if total >= duration || self.stsz_count == self.sample_offset { return; }
+        let Some(last) = self.stts.last_mut() else { return; };
+        let delta = last.1 + (duration - total) as u32;
+        diag!(Debug, "Extending the last sample from {} to {delta}", last.1);
+        if last.0 > 1 {
+            last.0 -= 1;
+            self.stts.push((1, delta));
+        } else {
+            last.1 = delta;
+        }
+    }
+
+    /// Validate the stsc entries of the file just read, which describe the chunks from `chunk_offset`, and repair what's possible.
+    /// The entries must start at the first chunk of the file, increase strictly and stay within its chunks, otherwise the merged
+    /// stsc is rejected by demuxers. Returns whether the entries had to be changed.
+    pub fn normalize_stsc(&mut self, file_index: usize) -> bool {
+        let (first_chunk, last_chunk) = (self.chunk_offset + 1, self.stco.len() as u32);
+        let original = self.stsc[(self.stsc_offset as usize).min(self.stsc.len())..].to_vec();
+        let mut entries = original.clone();
+        if last_chunk < first_chunk {
+            entries.clear();
+        }
+        entries.retain(|x| x.0 <= last_chunk);
+        for x in &mut entries {
+            x.0 = x.0.max(first_chunk);
+        }
+        // Later entries describe the same chunks again when the order is broken, the last one wins
+        entries.reverse();
+        entries.sort_by_key(|x| x.0);
+        entries.dedup_by_key(|x| x.0);
+        if let Some(x) = entries.first_mut() { x.0 = first_chunk; }
+
+        let changed = entries != original;
+        if changed {
+            diag!(Warn, "Repaired the stsc of track {} in file {file_index}: {original:?} -> {entries:?}", self.track_id);
+        }
+        let mut samples = 0u64;
+        for (i, x) in entries.iter().enumerate() {
+            let next = entries.get(i + 1).map(|x| x.0).unwrap_or(last_chunk + 1);
+            samples += (next - x.0) as u64 * x.1 as u64;
+        }
+        let expected = (self.stsz_count - self.sample_offset.min(self.stsz_count)) as u64;
+        if samples != expected {
+            diag!(Warn, "The stsc of track {} in file {file_index} describes {samples} samples, but the file has {expected}", self.track_id);
+        }
+        self.stsc.truncate(self.stsc_offset as usize);
+        self.stsc.extend(entries);
+        changed
+    }
+
+    /// Whether the merged track has stss but the first file, whose boxes are copied to the output, doesn't
+    pub fn needs_new_stss(&self) -> bool {
+        !self.stss.is_empty() && !self.file_has_stss.first().copied().unwrap_or(true)
+    }
+
+    /// Decode time (in media timescale) of the first sample of each file
+    pub fn file_decode_starts(&self) -> Vec<u64> {
+        let mut starts = Vec::with_capacity(self.file_sample_ranges.len());
+        let mut deltas = self.sample_deltas();
+        let (mut time, mut sample) = (0u64, 0u32);
+        for range in &self.file_sample_ranges {
+            time += deltas.by_ref().take(range.start.saturating_sub(sample) as usize).map(|x| x as u64).sum::<u64>();
+            sample = sample.max(range.start);
+            starts.push(time);
+        }
+        starts
+    }
+
+    /// cslg of the merged track, derived from the cslg of every file. None when a file has no cslg
+    pub fn merged_cslg(&self) -> Option<[i64; 5]> {
+        if self.file_cslg.len() != self.file_sample_ranges.len() { return None; }
+        let mut ret: Option<[i64; 5]> = None;
+        for (cslg, start) in self.file_cslg.iter().zip(self.file_decode_starts()) {
+            let [shift, least, greatest, composition_start, composition_end] = (*cslg)?;
+            ret = Some(match ret {
+                None => [shift, least, greatest, composition_start, composition_end + start as i64],
+                Some(x) => [x[0].max(shift), x[1].min(least), x[2].max(greatest), x[3], x[4].max(composition_end + start as i64)]
+            });
+        }
+        ret
+    }
+
+    /// Record the timing error (in seconds) of a value rounded to a timescale, or accumulated from rounded values
+    pub fn note_rounding_error(&mut self, error: f64) {
+        self.rounding_error = self.rounding_error.max(error.abs());
+    }
+
+    /// Whether the merged track has partial sync samples but the first file has no stps
+    pub fn needs_new_stps(&self) -> bool {
+        !self.stps.is_empty() && !self.has_stps
+    }
+
+    /// Update the track durations after the edit list has changed
+    pub fn update_edit_list_durations(&mut self, movie_timescale: u32) {
+        // Update total elst_segment_duration to include gaps
+        self.elst_segment_duration = self.elst_entries.iter()
+            .map(|entry| entry.segment_duration)
+            .sum();
+            
+        // Fix: Convert tkhd_duration from movie timescale to media timescale
+        // tkhd_duration must be in the track's media timescale (mdhd), but elst_segment_duration is in movie (mvhd) timescale
+        if movie_timescale > 0 && self.mdhd_timescale > 0 {
+            let total_duration_seconds = self.elst_segment_duration as f64 / movie_timescale as f64;
+            self.tkhd_duration = (total_duration_seconds * self.mdhd_timescale as f64).round() as u64;
+        } else {
+            // Fallback to direct assignment if timescales are not available
+            self.tkhd_duration = self.elst_segment_duration;
+        }
+    }
+
+    /// Whether an edts box has to be added to the output, because the first file has none but the edit list has entries
+    pub fn needs_new_edts(&self) -> bool {
+        !self.has_edts && !self.elst_entries.is_empty()
+    }
+
+    /// Iterator over sample durations (stts deltas) in sample order
+    pub fn sample_deltas(&self) -> impl Iterator<Item = u32> + '_ {
+        self.stts.iter().flat_map(|(count, delta)| std::iter::repeat_n(*delta, *count as usize))
+    }
+
+    /// Composition offset of each sample, as in ctts
+    pub fn composition_offsets(&self) -> impl Iterator<Item = i32> + '_ {
+        self.ctts.iter().flat_map(|(count, offset)| std::iter::repeat_n(*offset, *count as usize))
+    }
+
+    /// Pad the composition offsets to `count` samples. Samples of files without ctts (e.g. without B-frames)
+    /// are presented at their decode time, so they get an offset of 0
+    pub fn pad_ctts(&mut self, count: u32) {
+        let covered = self.ctts.iter().map(|x| x.0).sum::<u32>();
+        if covered >= count { return; }
+        match self.ctts.last_mut() {
+            Some(last) if last.1 == 0 => last.0 += count - covered,
+            _ => self.ctts.push((count - covered, 0)),
+        }
+    }
+
+    /// Whether a new ctts box has to be written, because the first file has none
+    pub fn needs_new_ctts(&self) -> bool {
+        !self.ctts.is_empty() && !self.has_ctts
+    }
+
+    /// Time (relative to the start of the file, in media timescale) of the first sync sample
+    /// of the given file at or after `min_time`. Every sample is a sync sample if there's no stss.
+    pub fn next_sync_sample_time(&self, file_index: usize, min_time: u64) -> Option<u64> {
+        let range = self.file_sample_ranges.get(file_index)?;
+        let mut time = 0u64;
+        for (i, delta) in self.sample_deltas().skip(range.start as usize).take(range.len()).enumerate() {
+            let sample_number = range.start + i as u32 + 1; // stss is 1-based
+            if time >= min_time && (self.stss.is_empty() || self.stss.binary_search(&sample_number).is_ok()) {
+                return Some(time);
+            }
+            time += delta as u64;
+        }
+        None
+    }
+}
+
+/// A single entry of the elst box of a track
+#[derive(Clone, Debug, PartialEq)]
 pub struct EditListEntry {
     pub segment_duration: u64, // Duration in movie timescale
     pub media_time: i64,       // Media time (-1 for gaps)
@@ -51,27 +322,205 @@ pub struct Desc {
     pub moov_mvhd_timescale: u32,
     pub moov_mvhd_duration: u64,
     pub moov_tracks: Vec<TrackDesc>,
+    pub track_map: Vec<usize>, // Index in moov_tracks of each trak of the file being read, in file order. Empty when they're in the same order
     pub mdat_offset: u64,
     pub mdat_final_position: u64,
-    pub file_creation_times: Vec<Option<std::time::SystemTime>>, // Creation time of each file
+    pub file_creation_times: Vec<Option<std::time::SystemTime>>, // Filesystem creation time of each file
+    pub file_gps_times: Vec<Option<(std::time::SystemTime, std::time::SystemTime)>>, // Wall-clock start and end of each file from GPMF GPSU
+    pub file_mvhd_creation_times: Vec<Option<std::time::SystemTime>>, // Creation time stored in mvhd (or the first tkhd when mvhd has none) of each file
+    pub file_has_insta360: Vec<bool>, // Whether each file ends with the Insta360 metadata trailer
+    pub file_cameras: Vec<crate::gopro::CameraInfo>, // Camera model, serial and firmware of each file
     pub file_durations: Vec<f64>, // Duration of each file in seconds (legacy, from first track)
     pub track_file_durations: Vec<Vec<f64>>, // track_file_durations[track_index][file_index] = duration in seconds
+    pub gap_overrides: Option<Vec<f64>>, // Caller-supplied gaps between files in seconds
+    pub file_duration_overrides: Option<Vec<f64>>, // Caller-supplied duration of each file in seconds
+    pub gap_model: Option<std::sync::Arc<dyn GapModel>>, // Caller-supplied gap logic
+    pub edit_list_editor: Option<std::sync::Arc<dyn EditListEditor>>, // Caller-supplied edit list changes
+    pub trim_overlaps: bool, // Trim the start of files which overlap with the previous file
+    pub gap_threshold: Option<f64>, // Derived gaps up to this long (in seconds) are ignored, DEFAULT_GAP_THRESHOLD without it
+    pub disable_gaps: bool, // Plain concatenation: no gaps and no edit list entries
+    pub file_trims: Vec<f64>, // Time trimmed from the start of each file in seconds
+    pub file_gaps: Vec<f64>, // Gaps between consecutive files in seconds, as decided by compute_gaps
+    pub output_creation_time: Option<std::time::SystemTime>, // Caller-supplied creation time written to mvhd/tkhd/mdhd
+    pub output_modification_time: Option<std::time::SystemTime>, // Caller-supplied modification time written to mvhd/tkhd/mdhd
+    pub copy: crate::copy::CopySettings, // Block size, number of buffers and reader threads of the mdat copy
+    pub repair_chunk_offsets: bool, // Re-derive the chunk offsets which point outside of the mdat of their file
+    pub skip_mdat_data: bool, // Only write the mdat header, the data is copied by the caller
+    pub synthesized_data: Vec<u8>, // Data of samples created by the merge, referenced by the mdat_position entries without a file
+    pub start_timecode: Option<(crate::timecode::TimecodeFormat, i64)>, // Format and start frame of the timecode track of the first file
+    pub interpolated_telemetry: Vec<std::ops::Range<f64>>, // Ranges of the merged timeline filled with interpolated telemetry, in seconds
+    pub data_references: Vec<String>, // URLs of the source files when writing a reference movie, whose chunk offsets point into them
+    pub table_progress: Option<crate::progress_stream::ProgressReporter>, // Receives the entries written to the sample tables
+    pub renumbered_track_ids: Vec<(u32, u32)>, // Original and new IDs of the tracks renumbered after dropping tracks
+    pub appended_traks: Vec<(usize, Vec<u8>)>, // Track index and template trak of the tracks created by the merge, e.g. from a GPX file, written after the tracks of the first file
+    pub movie_metadata: Option<crate::metadata::MovieMetadata>, // Caller-supplied title, comment, artist and custom values written to moov/udta
+    pub in_trak: bool, // Set while the children of a trak are written, to tell the udta and meta of the movie from the ones of the tracks
+    pub written_metadata_boxes: Vec<u32>, // Boxes of the current moov rewritten with movie_metadata, the missing ones are added at its end
+    pub stco_32bit: bool, // Write the chunk offsets as 32-bit stco instead of co64, because the merged file stays below 4 GiB
+    pub sidx: bool, // Write a segment index before the mdat, with a reference to the data of each file
+    pub cenc_tracks: Vec<(usize, usize)>, // File and track index of the tracks with sample auxiliary information of encrypted samples (saiz, saio, senc)
+}
+
+/// Everything known about a single input file, passed to the gap model
+#[derive(Debug, Clone, Default)]
+pub struct FileInfo {
+    pub index: usize,
+    pub duration: f64, // Duration in seconds
+    pub embedded_creation_time: Option<std::time::SystemTime>, // From mvhd
+    pub filesystem_creation_time: Option<std::time::SystemTime>,
+    pub gps_time_range: Option<(std::time::SystemTime, std::time::SystemTime)>, // Wall-clock start and end from GPMF GPSU
+}
+
+/// Decision of the gap model for a pair of consecutive files
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GapDecision {
+    /// Use the built-in heuristics
+    Default,
+    /// Files are contiguous
+    NoGap,
+    /// Insert a pause of the given length between the files
+    Gap(std::time::Duration),
+    /// The next file starts before the previous one ended
+    Overlap(std::time::Duration),
+}
+
+/// Custom gap logic, invoked for every pair of consecutive files
+pub trait GapModel: Send + Sync {
+    fn gap(&self, prev: &FileInfo, next: &FileInfo) -> GapDecision;
+}
+impl<F: Fn(&FileInfo, &FileInfo) -> GapDecision + Send + Sync> GapModel for F {
+    fn gap(&self, prev: &FileInfo, next: &FileInfo) -> GapDecision { self(prev, next) }
+}
+impl std::fmt::Debug for dyn GapModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { f.write_str("GapModel") }
+}
+
+/// Track whose edit list is passed to the edit list editor
+#[derive(Debug, Clone, Default)]
+pub struct EditListTrack {
+    pub index: usize,
+    pub track_id: u32,
+    pub handler_type: String,
+    pub movie_timescale: u32, // Timescale of segment_duration
+    pub media_timescale: u32, // Timescale of media_time
+}
+
+/// Replaces or adjusts the edit list of each track before writing, e.g. to force the offsets required by an editor.
+/// The entries computed from the gaps are passed in, an empty list means the default single entry is written
+pub trait EditListEditor: Send + Sync {
+    fn edit(&self, track: &EditListTrack, entries: &mut Vec<EditListEntry>);
+}
+impl<F: Fn(&EditListTrack, &mut Vec<EditListEntry>) + Send + Sync> EditListEditor for F {
+    fn edit(&self, track: &EditListTrack, entries: &mut Vec<EditListEntry>) { self(track, entries) }
+}
+impl std::fmt::Debug for dyn EditListEditor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { f.write_str("EditListEditor") }
+}
+
+impl Desc {
+    /// Number of entries written to the sample tables and patched in the chunk offset tables of the merged moov
+    fn table_entry_counts(&self) -> (u64, u64) {
+        let tracks = self.moov_tracks.iter().filter(|x| !x.dropped);
+        tracks.fold((0, 0), |(tables, patching), x| {
+            let entries = x.stts.len() + x.stsz.len() + x.stss.len() + x.stps.len() + x.stco.len() + x.sdtp.len() + x.stsc.len();
+            (tables + entries as u64, patching + x.stco.len() as u64)
+        })
+    }
+
+    /// Report the progress of writing the sample tables to `reporter`
+    pub(crate) fn set_table_progress(&mut self, reporter: Option<crate::progress_stream::ProgressReporter>) {
+        if let Some(reporter) = &reporter {
+            let (tables, patching) = self.table_entry_counts();
+            reporter.set_total_entries(tables, patching);
+        }
+        self.table_progress = reporter;
+    }
+
+    /// Update the movie header duration to include gaps
+    fn update_movie_duration(&mut self) {
+        if let Some(first_track) = self.moov_tracks.first() {
+            if !first_track.skip && !first_track.elst_entries.is_empty() {
+                self.moov_mvhd_duration = first_track.elst_segment_duration;
+            }
+        }
+    }
+
+    /// Add a track created by the merge after the tracks of the first file. `trak` is written with the tables and durations of `track`
+    pub fn append_track(&mut self, track: TrackDesc, trak: Vec<u8>) {
+        let index = self.moov_tracks.iter().rposition(|t| !t.handler_type.is_empty()).map(|x| x + 1).unwrap_or(0);
+        self.appended_traks.push((index, trak));
+        if index < self.moov_tracks.len() {
+            self.moov_tracks[index] = track;
+        } else {
+            self.moov_tracks.push(track);
+        }
+    }
+
+    /// Append the data of a sample created by the merge to the end of the mdat. Returns its offset in the merged mdat data
+    pub(crate) fn append_synthesized(&mut self, data: &[u8]) -> u64 {
+        let offset = self.mdat_position.iter().map(|x| x.2).sum::<u64>();
+        let start = self.synthesized_data.len() as u64;
+        self.synthesized_data.extend_from_slice(data);
+        match self.mdat_position.last_mut() {
+            Some((None, position, size)) if *position + *size == start => *size += data.len() as u64,
+            _ => self.mdat_position.push((None, start, data.len() as u64)),
+        }
+        offset
+    }
+
+    /// Start of each file on the merged timeline in seconds, after the gaps and trims
+    pub fn file_timeline_starts(&self) -> Vec<f64> {
+        let mut time = 0.0;
+        (0..self.file_durations.len()).map(|i| {
+            let start = time;
+            time += self.file_info(i).duration - self.file_trims.get(i).copied().unwrap_or(0.0) + self.file_gaps.get(i).copied().unwrap_or(0.0).max(0.0);
+            start
+        }).collect()
+    }
+
+    pub fn file_info(&self, file_index: usize) -> FileInfo {
+        FileInfo {
+            index: file_index,
+            duration: self.file_duration_overrides.as_ref().and_then(|x| x.get(file_index).copied())
+                .unwrap_or_else(|| self.file_durations.get(file_index).copied().unwrap_or(0.0)),
+            embedded_creation_time: self.file_mvhd_creation_times.get(file_index).copied().flatten(),
+            filesystem_creation_time: self.file_creation_times.get(file_index).copied().flatten(),
+            gps_time_range: self.file_gps_times.get(file_index).copied().flatten(),
+        }
+    }
+
+    /// Duration of file `file_index` in seconds, from its part of the first track. The mdhd duration of the track accumulates
+    /// over all files read so far, so it's only the duration of the first file
+    pub fn first_track_file_duration(&self, file_index: usize) -> Option<f64> {
+        self.track_file_durations.first().and_then(|x| x.get(file_index)).copied()
+    }
 }
 
 pub fn read_desc<R: Read + Seek>(d: &mut R, desc: &mut Desc, track: usize, max_read: u64, file_index: usize) -> Result<()> {
     let mut tl_track = track;
+    let mut trak_position = track;
     let start_offs = d.stream_position()?;
     desc.mvhd_timescale_per_file.push(0);
     while let Ok((typ, offs, size, header_size)) = read_box(d) {
         if size == 0 || typ == 0 { continue; }
         if crate::has_children(typ, true) {
+            if typ == fourcc("trak") {
+                tl_track = desc.track_map.get(trak_position).copied().unwrap_or(trak_position);
+                if tl_track >= desc.moov_tracks.len() {
+                    return Err(crate::Error::TooManyTracks { max: desc.moov_tracks.len() }.into());
+                }
+            }
+            if typ == fourcc("edts") && file_index == 0 {
+                if let Some(track_desc) = desc.moov_tracks.get_mut(tl_track) { track_desc.has_edts = true; }
+            }
             read_desc(d, desc, tl_track, size - header_size as u64, file_index)?;
 
             if typ == fourcc("trak") {
-                tl_track += 1;
+                trak_position += 1;
+                tl_track = trak_position;
             }
         } else {
-            log::debug!("Reading {}, offset: {}, size: {size}, header_size: {header_size}", typ_to_str(typ), offs);
+            diag!(Debug, "Reading {}, offset: {}, size: {size}, header_size: {header_size}", typ_to_str(typ), offs);
             let org_pos = d.stream_position()?;
             // if typ == fourcc("mdat") {
             //     desc.mdat_position.push((None, org_pos, size - header_size as u64));
@@ -80,8 +529,12 @@ pub fn read_desc<R: Read + Seek>(d: &mut R, desc: &mut Desc, track: usize, max_r
             if typ == fourcc("mvhd") || typ == fourcc("tkhd") || typ == fourcc("mdhd") {
                 let (v, _flags) = (d.read_u8()?, d.read_u24::<BigEndian>()?);
                 if typ == fourcc("mvhd") {
-                    let timescale = if v == 1 { d.seek(SeekFrom::Current(8+8))?; d.read_u32::<BigEndian>()? }
-                                    else      { d.seek(SeekFrom::Current(4+4))?; d.read_u32::<BigEndian>()? };
+                    let creation_time = if v == 1 { d.read_u64::<BigEndian>()? } else { d.read_u32::<BigEndian>()? as u64 };
+                    if let Some(x) = desc.file_mvhd_creation_times.get_mut(file_index) {
+                        *x = mp4_time_to_system_time(creation_time);
+                    }
+                    let timescale = if v == 1 { d.seek(SeekFrom::Current(8))?; d.read_u32::<BigEndian>()? }
+                                    else      { d.seek(SeekFrom::Current(4))?; d.read_u32::<BigEndian>()? };
                     let duration = if v == 1 { d.read_u64::<BigEndian>()? }
                                    else      { d.read_u32::<BigEndian>()? as u64 };
                     if desc.moov_mvhd_timescale == 0 {
@@ -92,19 +545,32 @@ pub fn read_desc<R: Read + Seek>(d: &mut R, desc: &mut Desc, track: usize, max_r
                 }
                 if let Some(track_desc) = desc.moov_tracks.get_mut(tl_track) {
                     if typ == fourcc("tkhd") {
-                        let duration = if v == 1 { d.seek(SeekFrom::Current(8+8+4+4))?; d.read_u64::<BigEndian>()? }
-                                       else      { d.seek(SeekFrom::Current(4+4+4+4))?; d.read_u32::<BigEndian>()? as u64 };
-                        track_desc.tkhd_duration += ((duration as f64 / *desc.mvhd_timescale_per_file.get(file_index).ok_or(std::io::Error::other("Invalid index"))? as f64) * desc.moov_mvhd_timescale as f64).ceil() as u64;
+                        let creation_time = if v == 1 { d.read_u64::<BigEndian>()? } else { d.read_u32::<BigEndian>()? as u64 };
+                        if let Some(x @ None) = desc.file_mvhd_creation_times.get_mut(file_index) {
+                            *x = mp4_time_to_system_time(creation_time);
+                        }
+                        d.seek(SeekFrom::Current(if v == 1 { 8 } else { 4 }))?;
+                        let track_id = d.read_u32::<BigEndian>()?;
+                        if file_index == 0 {
+                            track_desc.track_id = track_id;
+                        }
+                        let duration = if v == 1 { d.seek(SeekFrom::Current(4))?; d.read_u64::<BigEndian>()? }
+                                       else      { d.seek(SeekFrom::Current(4))?; d.read_u32::<BigEndian>()? as u64 };
+                        let file_timescale = *desc.mvhd_timescale_per_file.get(file_index).ok_or(std::io::Error::other("Invalid index"))?;
+                        track_desc.tkhd_duration += convert_duration_ceil(duration, file_timescale, desc.moov_mvhd_timescale);
                     }
                     if typ == fourcc("mdhd") {
                         let timescale = if v == 1 { d.seek(SeekFrom::Current(8+8))?; d.read_u32::<BigEndian>()? }
                                         else      { d.seek(SeekFrom::Current(4+4))?; d.read_u32::<BigEndian>()? };
                         let duration = if v == 1 { d.read_u64::<BigEndian>()? }
                                        else      { d.read_u32::<BigEndian>()? as u64 };
+                        track_desc.file_mdhd_timescale = timescale;
                         if track_desc.mdhd_timescale == 0 {
                             track_desc.mdhd_timescale = timescale;
+                            track_desc.language = decode_language(d.read_u16::<BigEndian>()?);
                         }
-                        let add_duration = ((duration as f64 / timescale as f64) * track_desc.mdhd_timescale as f64).ceil() as u64;
+                        // Rounded like the sample durations, which are rescaled after the file is read
+                        let add_duration = crate::rescale::rescale(duration, timescale, track_desc.mdhd_timescale);
                         track_desc.mdhd_duration += add_duration;
                         
                         // Store per-track, per-file duration in seconds
@@ -115,17 +581,44 @@ pub fn read_desc<R: Read + Seek>(d: &mut R, desc: &mut Desc, track: usize, max_r
                         if file_index < desc.track_file_durations[tl_track].len() {
                             let duration_seconds = duration as f64 / timescale as f64;
                             desc.track_file_durations[tl_track][file_index] = duration_seconds;
-                            log::debug!("Track {} file {} duration: {:.2}s", tl_track, file_index, duration_seconds);
+                            diag!(Debug, "Track {} file {} duration: {:.2}s", tl_track, file_index, duration_seconds);
                         }
                     }
                 }
             }
-            if typ == fourcc("elst") || typ == fourcc("stts") || typ == fourcc("stsz") || typ == fourcc("stss") ||
-               typ == fourcc("stco") || typ == fourcc("co64") || typ == fourcc("sdtp") || typ == fourcc("stsc") {
+            if (typ == fourcc("saiz") || typ == fourcc("saio") || typ == fourcc("senc")) && !desc.cenc_tracks.contains(&(file_index, tl_track)) {
+                desc.cenc_tracks.push((file_index, tl_track));
+            }
+            if typ == fourcc("elst") || typ == fourcc("stts") || typ == fourcc("stsz") || typ == fourcc("stss") || typ == fourcc("stps") || typ == fourcc("cslg") ||
+               typ == fourcc("ctts") || typ == fourcc("stco") || typ == fourcc("co64") || typ == fourcc("sdtp") || typ == fourcc("stsc") {
+                let movie_timescale = desc.mvhd_timescale_per_file.get(file_index).copied().unwrap_or(0).max(1);
                 let track_desc = desc.moov_tracks.get_mut(tl_track).unwrap();
                 if !(track_desc.skip && file_index > 0) {
                     let (v, _flags) = (d.read_u8()?, d.read_u24::<BigEndian>()?);
 
+                    if typ == fourcc("stss") {
+                        track_desc.stss_present = true;
+                    }
+                    if typ == fourcc("cslg") {
+                        let mut values = [0i64; 5];
+                        for x in &mut values {
+                            *x = if v == 1 { d.read_i64::<BigEndian>()? } else { d.read_i32::<BigEndian>()? as i64 };
+                        }
+                        track_desc.cslg = Some(values);
+                    }
+                    if typ == fourcc("stps") && file_index == 0 {
+                        track_desc.has_stps = true;
+                    }
+                    if typ == fourcc("ctts") {
+                        // Offsets are signed in version 1, and in practice in version 0 too
+                        if file_index == 0 { track_desc.has_ctts = true; }
+                        let sample_offset = track_desc.sample_offset;
+                        track_desc.pad_ctts(sample_offset);
+                        let count = d.read_u32::<BigEndian>()?;
+                        for _ in 0..count {
+                            track_desc.ctts.push((d.read_u32::<BigEndian>()?, d.read_i32::<BigEndian>()?));
+                        }
+                    }
                     if typ == fourcc("elst") {
                         let entry_count = d.read_u32::<BigEndian>()?;
                         for _ in 0..entry_count {
@@ -134,27 +627,44 @@ pub fn read_desc<R: Read + Seek>(d: &mut R, desc: &mut Desc, track: usize, max_r
                             d.seek(SeekFrom::Current(4))?; // Skip Media rate
                             if media_time != -1 {
                                 track_desc.elst_segment_duration += segment_duration;
+                                if track_desc.file_edit.is_none() {
+                                    track_desc.file_edit = Some((media_time, segment_duration as f64 / movie_timescale as f64));
+                                }
                             }
                         }
                     }
                     if typ == fourcc("stsz") {
-                        track_desc.stsz_sample_size = d.read_u32::<BigEndian>()?;
+                        let sample_size = d.read_u32::<BigEndian>()?;
                         let count = d.read_u32::<BigEndian>()?;
-                        if track_desc.stsz_sample_size == 0 {
-                            for _ in 0..count { track_desc.stsz.push(d.read_u32::<BigEndian>()?); }
+                        if track_desc.stsz_count == 0 {
+                            track_desc.stsz_sample_size = sample_size;
                         }
+                        if sample_size == 0 || sample_size != track_desc.stsz_sample_size {
+                            if track_desc.stsz_sample_size > 0 {
+                                // Constant size in the previous files, but not in this one. Expand the previous sizes
+                                track_desc.stsz = vec![track_desc.stsz_sample_size; track_desc.stsz_count as usize];
+                                track_desc.stsz_sample_size = 0;
+                            }
+                            if sample_size == 0 {
+                                for _ in 0..count { track_desc.stsz.push(d.read_u32::<BigEndian>()?); }
+                            } else {
+                                track_desc.stsz.resize(track_desc.stsz.len() + count as usize, sample_size);
+                            }
+                        }
+                        // Otherwise the sample size is the same constant as in the previous files (e.g. PCM audio), keep the table compact
                         track_desc.stsz_count += count;
                     }
                     if typ == fourcc("sdtp") {
                         let count = size - header_size as u64 - 4;
                         for _ in 0..count { track_desc.sdtp.push(d.read_u8()?); }
                     }
-                    if typ == fourcc("stss") || typ == fourcc("stco") || typ == fourcc("co64") || typ == fourcc("stts") || typ == fourcc("stsc") {
+                    if typ == fourcc("stss") || typ == fourcc("stps") || typ == fourcc("stco") || typ == fourcc("co64") || typ == fourcc("stts") || typ == fourcc("stsc") {
                         let count = d.read_u32::<BigEndian>()?;
                         let current_file_mdat_position = desc.mdat_position.last().unwrap().1;
                         let mdat_offset = desc.mdat_offset as i64 - current_file_mdat_position as i64;
                         for _ in 0..count {
                             if typ == fourcc("stss") { track_desc.stss.push(d.read_u32::<BigEndian>()? + track_desc.sample_offset); }
+                            if typ == fourcc("stps") { track_desc.stps.push(d.read_u32::<BigEndian>()? + track_desc.sample_offset); }
                             if typ == fourcc("stco") { track_desc.stco.push((d.read_u32::<BigEndian>()? as i64 + mdat_offset) as u64); }
                             if typ == fourcc("co64") { track_desc.stco.push((d.read_u64::<BigEndian>()? as i64 + mdat_offset) as u64); }
                             if typ == fourcc("stts") { track_desc.stts.push((d.read_u32::<BigEndian>()?, d.read_u32::<BigEndian>()?)); }
@@ -167,11 +677,6 @@ pub fn read_desc<R: Read + Seek>(d: &mut R, desc: &mut Desc, track: usize, max_r
                     }
                 }
             }
-            if typ == fourcc("tmcd") {
-                // Timecode shouldn't be merged
-                let track_desc = desc.moov_tracks.get_mut(tl_track).unwrap();
-                track_desc.skip = true;
-            }
             if typ == fourcc("hdlr") {
                 // Read handler type to identify track type (video, audio, metadata, etc.)
                 let track_desc = desc.moov_tracks.get_mut(tl_track).unwrap();
@@ -179,13 +684,13 @@ pub fn read_desc<R: Read + Seek>(d: &mut R, desc: &mut Desc, track: usize, max_r
                 d.seek(SeekFrom::Current(4))?; // Skip pre_defined
                 let handler_type = d.read_u32::<BigEndian>()?;
                 track_desc.handler_type = typ_to_str(handler_type);
-                log::debug!("Track {} handler type: {}", tl_track, track_desc.handler_type);
+                diag!(Debug, "Track {} handler type: {}", tl_track, track_desc.handler_type);
                 
                 // Check if this is a GPMF metadata track
                 if track_desc.handler_type == "meta" {
                     // This could be a GPMF metadata track - we'll handle it like other metadata tracks
                     // but the GPMF module will process the actual GPS data during merging
-                    log::debug!("Found metadata track {} - could contain GPMF data", tl_track);
+                    diag!(Debug, "Found metadata track {} - could contain GPMF data", tl_track);
                 }
             }
             d.seek(SeekFrom::Start(org_pos + size - header_size as u64))?;
@@ -197,38 +702,103 @@ pub fn read_desc<R: Read + Seek>(d: &mut R, desc: &mut Desc, track: usize, max_r
     Ok(())
 }
 
-pub fn compute_gaps_and_edit_lists(desc: &mut Desc) -> Result<()> {
-    log::debug!("Computing gaps and edit lists for {} files", desc.file_creation_times.len());
-    
+/// Read the description of a single file. Chunk offsets are relative to the start of its (first) mdat payload.
+pub fn read_file_desc<R: Read + Seek>(reader: &mut R) -> Result<Desc> {
+    let mut desc = Desc::default();
+    desc.moov_tracks.resize(10, Default::default());
+    desc.file_creation_times.resize(1, None);
+    desc.file_mvhd_creation_times.resize(1, None);
+    desc.file_durations.resize(1, 0.0);
+    desc.track_file_durations.resize(10, vec![0.0; 1]);
+
+    reader.seek(SeekFrom::Start(0))?;
+    while let Ok((typ, offs, size, header_size)) = read_box(reader) {
+        if size == 0 || typ == 0 { break; }
+        if typ == fourcc("mdat") {
+            desc.mdat_position.push((Some(0), offs + header_size as u64, size - header_size as u64));
+            desc.mdat_final_position = offs + header_size as u64;
+            break;
+        }
+        reader.seek(SeekFrom::Start(offs + size))?;
+    }
+    if desc.mdat_position.is_empty() {
+        return Err(crate::Error::TruncatedFile { path: "mdat".into(), offset: 0, reason: "mdat not found".into() }.into());
+    }
+
+    reader.seek(SeekFrom::Start(0))?;
+    read_desc(reader, &mut desc, 0, u64::MAX, 0)?;
+    for t in &mut desc.moov_tracks {
+        t.file_sample_ranges.push(0..t.stsz_count);
+        t.file_has_stss.push(std::mem::take(&mut t.stss_present));
+        t.file_cslg.push(t.cslg.take());
+        if !t.ctts.is_empty() { t.pad_ctts(t.stsz_count); }
+    }
+    Ok(desc)
+}
+
+/// Gaps between consecutive files in seconds, negative when the files overlap.
+/// Returns None if there's nothing to derive the gaps from.
+pub fn compute_gaps(desc: &Desc) -> Option<Vec<f64>> {
+    if desc.disable_gaps { return None; }
+    if let Some(gap_overrides) = &desc.gap_overrides {
+        diag!(Debug, "Using caller-supplied gaps: {:?}", gap_overrides);
+        return Some(gap_overrides.clone());
+    }
     // Check if we have enough timestamps to compute gaps
-    let has_timestamps = desc.file_creation_times.iter().any(|t| t.is_some());
-    
-    if !has_timestamps {
-        log::debug!("No timestamps available, skipping gap computation");
+    let has_timestamps = desc.file_creation_times.iter().chain(&desc.file_mvhd_creation_times).any(|t| t.is_some()) || desc.file_gps_times.iter().any(|t| t.is_some());
+
+    if !has_timestamps && desc.file_duration_overrides.is_none() && desc.gap_model.is_none() {
+        diag!(Debug, "No timestamps available, skipping gap computation");
+        return None;
+    }
+
+    Some((1..desc.file_creation_times.len()).map(|file_index| compute_gap_duration(desc, file_index - 1, file_index)).collect())
+}
+
+pub fn compute_gaps_and_edit_lists(desc: &mut Desc) -> Result<()> {
+    if desc.disable_gaps {
+        diag!(Debug, "Gaps are disabled, the files are concatenated");
         return Ok(());
     }
-    
-    // First, compute all gaps 
-    let mut gaps = Vec::new();
-    for file_index in 1..desc.file_creation_times.len() {
-        let gap_duration = compute_gap_duration(desc, file_index - 1, file_index);
-        gaps.push(gap_duration);
+    diag!(Debug, "Computing gaps and edit lists for {} files", desc.file_creation_times.len());
+
+    let has_encoder_delay = desc.moov_tracks.iter().any(|t| !t.skip && t.file_encoder_delay.iter().any(|x| *x != (0, 0)));
+    // Tracks missing from the first file start with an empty edit
+    let late_tracks = (0..desc.moov_tracks.len()).filter(|i| crate::missing_tracks::starts_late(desc, *i)).collect::<Vec<_>>();
+    let has_late_tracks = !late_tracks.is_empty();
+    let Some(gaps) = compute_gaps(desc).or_else(|| (has_encoder_delay || has_late_tracks).then(|| vec![0.0; desc.file_creation_times.len().saturating_sub(1)])) else { return Ok(()); };
+    desc.file_gaps = gaps.clone();
+
+    // Negative gaps mean that the files overlap
+    desc.file_trims = vec![0.0; desc.file_creation_times.len()];
+    for (i, gap) in gaps.iter().enumerate() {
+        if *gap < 0.0 {
+            if desc.trim_overlaps {
+                desc.file_trims[i + 1] = snap_trim_to_keyframe(desc, i + 1, -gap);
+                diag!(Debug, "Files {} and {} overlap by {:.3}s, trimming {:.3}s", i, i + 1, -gap, desc.file_trims[i + 1]);
+            } else {
+                diag!(Warn, "Files {} and {} overlap by {:.3}s, duplicated frames will be kept", i, i + 1, -gap);
+            }
+        }
     }
-    
+    let has_trims = desc.file_trims.iter().any(|&trim| trim > 0.0);
+
     // Check if there are any meaningful gaps
     let has_gaps = gaps.iter().any(|&gap| gap > 0.0);
-    
-    if !has_gaps {
-        log::debug!("No gaps detected, using default edit list behavior");
+
+    if !has_gaps && !has_trims && !has_encoder_delay && !has_late_tracks && desc.file_duration_overrides.is_none() {
+        diag!(Debug, "No gaps detected, using default edit list behavior");
         return Ok(());
     }
     
+    let presented_file_durations = (0..desc.file_creation_times.len()).map(|i| desc.file_info(i).duration - desc.file_trims[i]).collect::<Vec<_>>();
+
     // For each track, create edit list entries including gaps
     for track_index in 0..desc.moov_tracks.len() {
         let track = &mut desc.moov_tracks[track_index];
         
         // Add debug logging for track handler types to aid identification
-        log::debug!("Processing track {} with handler type: '{}' (skip: {})", 
+        diag!(Debug, "Processing track {} with handler type: '{}' (skip: {})", 
                    track_index, track.handler_type, track.skip);
         
         if track.skip {
@@ -237,11 +807,14 @@ pub fn compute_gaps_and_edit_lists(desc: &mut Desc) -> Result<()> {
         
         track.elst_entries.clear();
         let mut cumulative_media_time = 0i64;
+        // Exact end of the presentation and start of the media of the current file, in seconds
+        let (mut exact_end, mut exact_media_time) = (0.0, 0.0);
+        let movie_timescale = desc.moov_mvhd_timescale.max(1) as f64;
         
         for file_index in 0..desc.file_creation_times.len() {
             // Add gap before this file (except for the first file)
             if file_index > 0 {
-                let gap_duration = gaps[file_index - 1];
+                let gap_duration = gaps.get(file_index - 1).copied().unwrap_or(0.0);
                 if gap_duration > 0.0 {
                     let gap_duration_timescale = (gap_duration * desc.moov_mvhd_timescale as f64).round() as u64;
                     track.elst_entries.push(EditListEntry {
@@ -249,7 +822,10 @@ pub fn compute_gaps_and_edit_lists(desc: &mut Desc) -> Result<()> {
                         media_time: -1, // -1 indicates a gap/pause
                         media_rate: 0x00010000,
                     });
-                    log::debug!("Added gap of {:.2}s between files {} and {}", gap_duration, file_index - 1, file_index);
+                    exact_end += gap_duration;
+                    let end = track.elst_entries.iter().map(|x| x.segment_duration).sum::<u64>();
+                    track.note_rounding_error(end as f64 / movie_timescale - exact_end);
+                    diag!(Debug, "Added gap of {:.2}s between files {} and {}", gap_duration, file_index - 1, file_index);
                 }
             }
             
@@ -262,13 +838,35 @@ pub fn compute_gaps_and_edit_lists(desc: &mut Desc) -> Result<()> {
                 desc.file_durations.get(file_index).copied().unwrap_or(0.0)
             };
             
+            if track_file_duration <= 0.0 && late_tracks.contains(&track_index) && track.elst_entries.iter().all(|x| x.media_time == -1) {
+                // The track doesn't exist yet, present nothing for the length of the file
+                let duration = presented_file_durations.get(file_index).copied().unwrap_or(0.0).max(0.0);
+                let duration_timescale = (duration * desc.moov_mvhd_timescale as f64).round() as u64;
+                match track.elst_entries.last_mut() {
+                    Some(last) => last.segment_duration += duration_timescale,
+                    None => track.elst_entries.push(EditListEntry { segment_duration: duration_timescale, media_time: -1, media_rate: 0x00010000 }),
+                }
+                exact_end += duration;
+                diag!(Debug, "Track {track_index} starts after file {file_index}, added an empty edit of {duration:.2}s");
+            }
             if track_file_duration > 0.0 {
-                let file_duration_timescale = (track_file_duration * desc.moov_mvhd_timescale as f64).round() as u64;
+                // Skip the encoder priming and remainder samples, so the audio of consecutive files is gapless
+                let timescale = track.mdhd_timescale.max(1) as f64;
+                let (priming, remainder) = track.file_encoder_delay.get(file_index).map(|x| (x.0 as f64 / timescale, x.1 as f64 / timescale)).unwrap_or_default();
+                let trim = desc.file_trims.get(file_index).copied().unwrap_or(0.0).min(track_file_duration);
+                let presented_duration = desc.file_duration_overrides.as_ref().and_then(|x| x.get(file_index).copied()).unwrap_or(track_file_duration - priming - remainder) - trim;
+                let file_duration_timescale = (presented_duration.max(0.0) * desc.moov_mvhd_timescale as f64).round() as u64;
+                let media_time = cumulative_media_time + ((trim + priming) * timescale).round() as i64;
                 track.elst_entries.push(EditListEntry {
                     segment_duration: file_duration_timescale,
-                    media_time: cumulative_media_time,
+                    media_time,
                     media_rate: 0x00010000,
                 });
+                exact_end += presented_duration.max(0.0);
+                let end = track.elst_entries.iter().map(|x| x.segment_duration).sum::<u64>();
+                track.note_rounding_error(end as f64 / movie_timescale - exact_end);
+                track.note_rounding_error(media_time as f64 / timescale - (exact_media_time + trim + priming));
+                exact_media_time += track_file_duration;
                 
                 // Convert file duration to media timescale for next media_time
                 if track.mdhd_timescale > 0 {
@@ -277,52 +875,186 @@ pub fn compute_gaps_and_edit_lists(desc: &mut Desc) -> Result<()> {
             }
         }
         
-        // Update total elst_segment_duration to include gaps
-        track.elst_segment_duration = track.elst_entries.iter()
-            .map(|entry| entry.segment_duration)
-            .sum();
-            
-        // Fix: Convert tkhd_duration from movie timescale to media timescale
-        // tkhd_duration must be in the track's media timescale (mdhd), but elst_segment_duration is in movie (mvhd) timescale
-        if desc.moov_mvhd_timescale > 0 && track.mdhd_timescale > 0 {
-            let total_duration_seconds = track.elst_segment_duration as f64 / desc.moov_mvhd_timescale as f64;
-            track.tkhd_duration = (total_duration_seconds * track.mdhd_timescale as f64).round() as u64;
-        } else {
-            // Fallback to direct assignment if timescales are not available
-            track.tkhd_duration = track.elst_segment_duration;
-        }
+        track.update_edit_list_durations(desc.moov_mvhd_timescale);
     }
     
-    // Update the movie header duration to include gaps
-    if let Some(first_track) = desc.moov_tracks.first() {
-        if !first_track.skip && !first_track.elst_entries.is_empty() {
-            desc.moov_mvhd_duration = first_track.elst_segment_duration;
-        }
-    }
+    desc.update_movie_duration();
     
     Ok(())
 }
 
+/// Let the caller's edit list editor replace or adjust the edit list of every track
+pub fn apply_edit_list_editor(desc: &mut Desc) {
+    let Some(editor) = desc.edit_list_editor.clone() else { return; };
+    let movie_timescale = desc.moov_mvhd_timescale;
+    for (index, track) in desc.moov_tracks.iter_mut().enumerate() {
+        if track.handler_type.is_empty() || track.skip || track.dropped { continue; }
+        let info = EditListTrack {
+            index,
+            track_id: track.track_id,
+            handler_type: track.handler_type.clone(),
+            movie_timescale,
+            media_timescale: track.mdhd_timescale,
+        };
+        let mut entries = track.elst_entries.clone();
+        editor.edit(&info, &mut entries);
+        if entries == track.elst_entries { continue; }
+        if entries.is_empty() {
+            diag!(Warn, "Edit list editor cleared the edit list of track {index}, keeping the computed one");
+            continue;
+        }
+        diag!(Debug, "Edit list of track {index} replaced with {} entries", entries.len());
+        track.elst_entries = entries;
+        track.update_edit_list_durations(movie_timescale);
+    }
+    desc.update_movie_duration();
+}
+
+/// Decode the packed ISO-639-2/T language code of mdhd (three 5-bit characters offset by 0x60)
+pub fn decode_language(code: u16) -> String {
+    [10, 5, 0].iter().map(|shift| (((code >> shift) & 0x1F) as u8 + 0x60) as char).collect()
+}
+
+/// Convert MP4 time (seconds since 1904-01-01 UTC) to SystemTime. Zero means the time is not set.
+pub fn mp4_time_to_system_time(time: u64) -> Option<std::time::SystemTime> {
+    const MP4_EPOCH_OFFSET: u64 = 2082844800; // Seconds between 1904-01-01 and 1970-01-01
+    if time <= MP4_EPOCH_OFFSET { return None; }
+    std::time::SystemTime::UNIX_EPOCH.checked_add(std::time::Duration::from_secs(time - MP4_EPOCH_OFFSET))
+}
+
+/// Values from the movie header and the media header of the first track
+#[derive(Debug, Clone, Default)]
+pub struct HeaderInfo {
+    pub creation_time: Option<std::time::SystemTime>, // From mvhd
+    pub movie_duration: f64,                          // From mvhd, in seconds
+    pub first_track_duration: Option<f64>,            // From mdhd of the first track, in seconds
+}
+
+/// Read mvhd and the first mdhd without parsing the sample tables
+pub fn read_headers<R: Read + Seek>(reader: &mut R) -> Result<HeaderInfo> {
+    let mut info = HeaderInfo::default();
+    let mut in_first_trak = false;
+    let mut end = u64::MAX;
+    reader.seek(SeekFrom::Start(0))?;
+    while reader.stream_position()? < end {
+        let Ok((typ, offs, size, header_size)) = read_box(reader) else { break; };
+        if size < header_size as u64 { break; }
+        if typ == fourcc("moov") || (typ == fourcc("trak") && !in_first_trak && info.first_track_duration.is_none()) || (typ == fourcc("mdia") && in_first_trak) {
+            // Descend into the box
+            in_first_trak |= typ == fourcc("trak");
+            end = end.min(offs + size);
+            continue;
+        }
+        if typ == fourcc("mvhd") || (typ == fourcc("mdhd") && in_first_trak) {
+            let v = reader.read_u8()?;
+            reader.seek(SeekFrom::Current(3))?;
+            let creation_time = if v == 1 { reader.read_u64::<BigEndian>()? } else { reader.read_u32::<BigEndian>()? as u64 };
+            reader.seek(SeekFrom::Current(if v == 1 { 8 } else { 4 }))?; // Modification time
+            let timescale = reader.read_u32::<BigEndian>()?.max(1) as f64;
+            let duration = if v == 1 { reader.read_u64::<BigEndian>()? } else { reader.read_u32::<BigEndian>()? as u64 } as f64 / timescale;
+            if typ == fourcc("mvhd") {
+                info.creation_time = mp4_time_to_system_time(creation_time);
+                info.movie_duration = duration;
+            } else {
+                info.first_track_duration = Some(duration);
+                break;
+            }
+        }
+        reader.seek(SeekFrom::Start(offs + size))?;
+    }
+    Ok(info)
+}
+
+/// Read the creation time stored in moov/mvhd
+pub fn read_mvhd_creation_time<R: Read + Seek>(reader: &mut R) -> Result<Option<std::time::SystemTime>> {
+    Ok(read_headers(reader)?.creation_time)
+}
+
+/// Convert a duration to another timescale, rounding up. Exact in integers, the float ratio rounds exact durations up by a tick
+pub(crate) fn convert_duration_ceil(duration: u64, from_timescale: u32, to_timescale: u32) -> u64 {
+    (duration as u128 * to_timescale as u128).div_ceil(from_timescale.max(1) as u128) as u64
+}
+
+/// Convert SystemTime to MP4 time (seconds since 1904-01-01 UTC)
+pub fn system_time_to_mp4_time(time: std::time::SystemTime) -> u64 {
+    const MP4_EPOCH_OFFSET: u64 = 2082844800;
+    match time.duration_since(std::time::SystemTime::UNIX_EPOCH) {
+        Ok(x) => x.as_secs() + MP4_EPOCH_OFFSET,
+        Err(e) => MP4_EPOCH_OFFSET.saturating_sub(e.duration().as_secs())
+    }
+}
+
+/// Extend the trim at the start of a file so that the first video frame presented is a keyframe.
+/// Returns the trim in seconds.
+fn snap_trim_to_keyframe(desc: &Desc, file_index: usize, trim: f64) -> f64 {
+    let video_track = desc.moov_tracks.iter().enumerate().find(|(_, t)| t.handler_type == "vide" && !t.skip && t.mdhd_timescale > 0);
+    if let Some((track_index, track)) = video_track {
+        let min_time = (trim * track.mdhd_timescale as f64).ceil() as u64;
+        if let Some(time) = track.next_sync_sample_time(file_index, min_time) {
+            let snapped = time as f64 / track.mdhd_timescale as f64;
+            let file_duration = desc.track_file_durations.get(track_index).and_then(|x| x.get(file_index)).copied().unwrap_or(f64::MAX);
+            if snapped < file_duration {
+                return snapped;
+            }
+        }
+    }
+    trim
+}
+
+/// Derived gaps up to this long are ignored by default, creation times have a one second resolution
+const DEFAULT_GAP_THRESHOLD: f64 = 1.0;
+
+/// Returns the gap between the files in seconds. Negative values mean that the files overlap.
 fn compute_gap_duration(desc: &Desc, prev_file_index: usize, current_file_index: usize) -> f64 {
-    // Try to compute gap based on file creation times
-    if let (Some(prev_time), Some(current_time)) = (
-        desc.file_creation_times[prev_file_index],
-        desc.file_creation_times[current_file_index]
+    if let Some(gap_model) = &desc.gap_model {
+        match gap_model.gap(&desc.file_info(prev_file_index), &desc.file_info(current_file_index)) {
+            GapDecision::Gap(gap) => return gap.as_secs_f64(),
+            GapDecision::NoGap => return 0.0,
+            GapDecision::Overlap(overlap) => return -overlap.as_secs_f64(),
+            GapDecision::Default => { }
+        }
+    }
+
+    let threshold = desc.gap_threshold.unwrap_or(DEFAULT_GAP_THRESHOLD);
+
+    // GPS UTC time from GPMF is the most reliable source, use it when both files have it
+    if let (Some(Some((_, prev_end))), Some(Some((current_start, _)))) = (
+        desc.file_gps_times.get(prev_file_index),
+        desc.file_gps_times.get(current_file_index)
     ) {
+        let net_gap = match current_start.duration_since(*prev_end) {
+            Ok(gap) => gap.as_secs_f64(),
+            Err(e) => -e.duration().as_secs_f64()
+        };
+        diag!(Debug, "Net gap from GPSU between files {} and {}: {:.2}s", prev_file_index, current_file_index, net_gap);
+
+        // GPS time is precise enough to detect overlaps. Creation times below have a one second resolution, so they can't be used for that.
+        return if !(0.0..=threshold).contains(&net_gap) { net_gap } else { 0.0 };
+    }
+
+    // Try to compute gap based on the creation times stored in the files. The filesystem times are only used when one of them
+    // has none, as they're often changed when the files are copied off the SD card
+    let embedded = |i: usize| desc.file_mvhd_creation_times.get(i).copied().flatten();
+    let creation_times = match (embedded(prev_file_index), embedded(current_file_index)) {
+        (Some(prev), Some(current)) => (Some(prev), Some(current)),
+        _ => (desc.file_creation_times[prev_file_index], desc.file_creation_times[current_file_index])
+    };
+    if let (Some(prev_time), Some(current_time)) = creation_times {
         if let Ok(gap) = current_time.duration_since(prev_time) {
-            let prev_duration = desc.file_durations[prev_file_index];
+            let prev_duration = desc.file_duration_overrides.as_ref().and_then(|x| x.get(prev_file_index).copied())
+                .unwrap_or(desc.file_durations[prev_file_index]);
             let gap_seconds = gap.as_secs_f64();
             
-            log::debug!("File {} ended at {:.2}s after creation", prev_file_index, prev_duration);
-            log::debug!("File {} created {:.2}s after file {}", current_file_index, gap_seconds, prev_file_index);
+            diag!(Debug, "File {} ended at {:.2}s after creation", prev_file_index, prev_duration);
+            diag!(Debug, "File {} created {:.2}s after file {}", current_file_index, gap_seconds, prev_file_index);
             
             // The actual gap is the time difference minus the duration of the previous file
             let net_gap = gap_seconds - prev_duration;
             
-            log::debug!("Net gap: {:.2}s", net_gap);
+            diag!(Debug, "Net gap: {:.2}s", net_gap);
             
-            // Only consider it a gap if it's more than 1 second to avoid false positives
-            if net_gap > 1.0 {
+            // Only consider it a gap if it's longer than the threshold to avoid false positives
+            if net_gap > threshold {
                 return net_gap;
             }
         }
@@ -371,6 +1103,338 @@ mod tests {
         assert_eq!(fixed_track.tkhd_duration, 384000);
     }
     
+    #[test]
+    fn test_gps_times_take_precedence_over_creation_times() {
+        let mut desc = Desc {
+            moov_mvhd_timescale: 1000,
+            // Creation times suggest a 10s net gap
+            file_creation_times: vec![
+                Some(SystemTime::UNIX_EPOCH),
+                Some(SystemTime::UNIX_EPOCH + Duration::from_secs(12))
+            ],
+            // GPSU says the second file started 4s after the first one ended
+            file_gps_times: vec![
+                Some((SystemTime::UNIX_EPOCH + Duration::from_secs(100), SystemTime::UNIX_EPOCH + Duration::from_secs(102))),
+                Some((SystemTime::UNIX_EPOCH + Duration::from_secs(106), SystemTime::UNIX_EPOCH + Duration::from_secs(109))),
+            ],
+            file_durations: vec![2.0, 3.0],
+            ..Default::default()
+        };
+        desc.moov_tracks.push(TrackDesc { mdhd_timescale: 1000, ..Default::default() });
+
+        compute_gaps_and_edit_lists(&mut desc).unwrap();
+
+        let track = &desc.moov_tracks[0];
+        assert_eq!(track.elst_entries.len(), 3);
+        assert_eq!(track.elst_entries[1].segment_duration, 4000);
+        assert_eq!(track.elst_entries[1].media_time, -1);
+    }
+
+    #[test]
+    fn test_gap_threshold() {
+        let mut desc = Desc {
+            file_creation_times: vec![Some(SystemTime::UNIX_EPOCH), Some(SystemTime::UNIX_EPOCH + Duration::from_secs(5))],
+            file_durations: vec![2.0, 3.0],
+            ..Default::default()
+        };
+        assert_eq!(compute_gaps(&desc), Some(vec![3.0]));
+        desc.gap_threshold = Some(5.0);
+        assert_eq!(compute_gaps(&desc), Some(vec![0.0]));
+        desc.gap_threshold = Some(0.0);
+        desc.file_creation_times[1] = Some(SystemTime::UNIX_EPOCH + Duration::from_millis(2500));
+        assert_eq!(compute_gaps(&desc), Some(vec![0.5]));
+    }
+
+    #[test]
+    fn test_gaps_prefer_embedded_creation_times() {
+        // The files were copied off the card one second apart, they were recorded 10 seconds apart
+        let copied = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut desc = Desc {
+            file_creation_times: vec![Some(copied), Some(copied + Duration::from_secs(1)), Some(copied + Duration::from_secs(30))],
+            file_mvhd_creation_times: vec![Some(SystemTime::UNIX_EPOCH), Some(SystemTime::UNIX_EPOCH + Duration::from_secs(10)), None],
+            file_durations: vec![2.0, 3.0, 4.0],
+            ..Default::default()
+        };
+        // The last file has no creation time in its mvhd, so the filesystem times are used for its gap
+        assert_eq!(compute_gaps(&desc), Some(vec![8.0, 26.0]));
+        desc.file_mvhd_creation_times = vec![None; 3];
+        assert_eq!(compute_gaps(&desc), Some(vec![0.0, 26.0]));
+    }
+
+    #[test]
+    fn test_disable_gaps() {
+        let chapter = |start: u64| crate::test_util::SyntheticMp4::new().creation_time(SystemTime::UNIX_EPOCH + Duration::from_secs(start)).track(crate::test_util::SyntheticTrack::video(25, 1, 50)).track(crate::test_util::SyntheticTrack::audio(48000, 94));
+        let (first, second) = (chapter(1_000_000_000), chapter(1_000_000_010));
+        let merge = |options: &crate::MergeOptions| {
+            let mut output = std::io::Cursor::new(Vec::new());
+            let report = crate::join_file_streams_with_options(&mut [first.cursor(), second.cursor()], &mut output, &[None, None], options, |_| {}).unwrap();
+            let structure = crate::inspect::read_structure(&mut output).unwrap();
+            let elst = structure.find(&["moov", "trak", "edts", "elst"]).unwrap().header;
+            (report.gaps, u32::from_be_bytes(output.get_ref()[elst.content_offset() as usize + 4..][..4].try_into().unwrap()))
+        };
+        assert_eq!(merge(&crate::MergeOptions::default()), (vec![8.0], 3));
+        assert_eq!(merge(&crate::MergeOptions::default().disable_gaps(true)), (vec![], 1));
+    }
+
+    #[test]
+    fn test_explicit_gaps_and_durations_override_timestamps() {
+        let mut desc = Desc {
+            moov_mvhd_timescale: 1000,
+            file_creation_times: vec![
+                Some(SystemTime::UNIX_EPOCH),
+                Some(SystemTime::UNIX_EPOCH + Duration::from_secs(20))
+            ],
+            file_durations: vec![2.0, 3.0],
+            gap_overrides: Some(vec![0.5]),
+            file_duration_overrides: Some(vec![1.5, 3.0]),
+            ..Default::default()
+        };
+        desc.moov_tracks.push(TrackDesc { mdhd_timescale: 1000, ..Default::default() });
+
+        compute_gaps_and_edit_lists(&mut desc).unwrap();
+
+        let track = &desc.moov_tracks[0];
+        assert_eq!(track.elst_entries.len(), 3);
+        assert_eq!(track.elst_entries[0].segment_duration, 1500);
+        assert_eq!(track.elst_entries[1].segment_duration, 500);
+        assert_eq!(track.elst_entries[1].media_time, -1);
+        // Media time of the second file still starts after the full first file
+        assert_eq!(track.elst_entries[2].media_time, 2000);
+        assert_eq!(track.elst_segment_duration, 5000);
+    }
+
+    #[test]
+    fn test_gap_model_hook() {
+        let mut desc = Desc {
+            moov_mvhd_timescale: 1000,
+            file_creation_times: vec![None, None, None],
+            file_durations: vec![1.0, 1.0, 1.0],
+            gap_model: Some(std::sync::Arc::new(|prev: &FileInfo, next: &FileInfo| {
+                assert_eq!(prev.index + 1, next.index);
+                if next.index == 1 { GapDecision::Gap(Duration::from_secs(2)) } else { GapDecision::Default }
+            })),
+            ..Default::default()
+        };
+        desc.moov_tracks.push(TrackDesc { mdhd_timescale: 1000, ..Default::default() });
+
+        compute_gaps_and_edit_lists(&mut desc).unwrap();
+
+        let track = &desc.moov_tracks[0];
+        // media, gap, media, media (no timestamps for the default heuristic between files 1 and 2)
+        assert_eq!(track.elst_entries.len(), 4);
+        assert_eq!(track.elst_entries[1].segment_duration, 2000);
+        assert_eq!(track.elst_segment_duration, 5000);
+    }
+
+    #[test]
+    fn test_first_track_file_duration() {
+        let desc = Desc {
+            // Three files of 2, 3 and 4 seconds
+            moov_tracks: vec![TrackDesc { mdhd_timescale: 1000, mdhd_duration: 9000, ..Default::default() }],
+            track_file_durations: vec![vec![2.0, 3.0, 4.0]],
+            ..Default::default()
+        };
+        assert_eq!(desc.first_track_file_duration(0), Some(2.0));
+        assert_eq!(desc.first_track_file_duration(2), Some(4.0));
+        assert_eq!(desc.first_track_file_duration(3), None);
+    }
+
+    #[test]
+    fn test_edit_list_editor() {
+        let mut desc = Desc {
+            moov_mvhd_timescale: 1000,
+            file_creation_times: vec![None, None],
+            file_durations: vec![1.0, 1.0],
+            gap_overrides: Some(vec![2.0]),
+            edit_list_editor: Some(std::sync::Arc::new(|track: &EditListTrack, entries: &mut Vec<EditListEntry>| {
+                assert_eq!((track.handler_type.as_str(), track.media_timescale), ("vide", 30000));
+                entries.retain(|x| x.media_time >= 0);
+                entries[0].media_time = 1001;
+            })),
+            ..Default::default()
+        };
+        desc.moov_tracks.push(TrackDesc { handler_type: "vide".into(), mdhd_timescale: 30000, ..Default::default() });
+
+        compute_gaps_and_edit_lists(&mut desc).unwrap();
+        apply_edit_list_editor(&mut desc);
+
+        let track = &desc.moov_tracks[0];
+        assert_eq!(track.elst_entries.iter().map(|x| (x.segment_duration, x.media_time)).collect::<Vec<_>>(), vec![(1000, 1001), (1000, 30000)]);
+        assert_eq!(track.elst_segment_duration, 2000);
+        assert_eq!(desc.moov_mvhd_duration, 2000);
+
+        // Through the merge options, a single input isn't copied as is then
+        let file = crate::test_util::SyntheticMp4::new().track(crate::test_util::SyntheticTrack::video(25, 1, 50));
+        let options = crate::MergeOptions::default().edit_list_editor(|track: &EditListTrack, entries: &mut Vec<EditListEntry>| {
+            *entries = vec![EditListEntry { segment_duration: track.movie_timescale as u64, media_time: 5, ..Default::default() }];
+        });
+        for num_files in [2, 1] {
+            let mut output = std::io::Cursor::new(Vec::new());
+            crate::join_file_streams_with_options(&mut vec![file.cursor(); num_files], &mut output, &vec![None; num_files], &options, |_| {}).unwrap();
+            let merged = output.into_inner();
+            let structure = crate::inspect::read_structure(&mut std::io::Cursor::new(&merged)).unwrap();
+            let elst = structure.find(&["moov", "trak", "edts", "elst"]).unwrap().header;
+            // The media time of the first entry follows the version, flags, entry count and segment duration
+            let content = &merged[elst.content_offset() as usize..];
+            let media_time = if content[0] == 1 { i64::from_be_bytes(content[16..24].try_into().unwrap()) } else { i32::from_be_bytes(content[12..16].try_into().unwrap()) as i64 };
+            assert_eq!(media_time, 5);
+        }
+    }
+
+    #[test]
+    fn test_overlap_trimmed_to_keyframe() {
+        let mut desc = Desc {
+            moov_mvhd_timescale: 1000,
+            file_creation_times: vec![None, None],
+            file_gps_times: vec![
+                Some((SystemTime::UNIX_EPOCH, SystemTime::UNIX_EPOCH + Duration::from_secs(2))),
+                Some((SystemTime::UNIX_EPOCH + Duration::from_millis(1700), SystemTime::UNIX_EPOCH + Duration::from_millis(3700))),
+            ],
+            file_durations: vec![2.0, 2.0],
+            track_file_durations: vec![vec![2.0, 2.0], vec![2.0, 2.0]],
+            trim_overlaps: true,
+            ..Default::default()
+        };
+        // 10 fps video, keyframe every 5 frames
+        desc.moov_tracks.push(TrackDesc {
+            mdhd_timescale: 1000,
+            handler_type: "vide".into(),
+            stts: vec![(40, 100)],
+            stss: vec![1, 6, 11, 16, 21, 26, 31, 36],
+            file_sample_ranges: vec![0..20, 20..40],
+            ..Default::default()
+        });
+        desc.moov_tracks.push(TrackDesc { mdhd_timescale: 48000, handler_type: "soun".into(), ..Default::default() });
+
+        compute_gaps_and_edit_lists(&mut desc).unwrap();
+
+        // 0.3s overlap is extended to the keyframe at 0.5s
+        assert_eq!(desc.file_trims, vec![0.0, 0.5]);
+        let video = &desc.moov_tracks[0];
+        assert_eq!(video.elst_entries.len(), 2);
+        assert_eq!(video.elst_entries[1].media_time, 2500);
+        assert_eq!(video.elst_entries[1].segment_duration, 1500);
+        let audio = &desc.moov_tracks[1];
+        assert_eq!(audio.elst_entries[1].media_time, 2 * 48000 + 24000);
+        assert_eq!(audio.elst_segment_duration, 3500);
+    }
+
+    #[test]
+    fn test_sample_infos() {
+        let track = TrackDesc {
+            stts: vec![(3, 10), (2, 20)],
+            stsz: vec![1, 2, 3, 4, 5],
+            stsz_count: 5,
+            stss: vec![1, 4],
+            stsc: vec![(1, 2, 1), (2, 3, 2)],
+            stco: vec![100, 200],
+            ..Default::default()
+        };
+        let samples = track.sample_infos();
+        assert_eq!(samples.len(), 5);
+        assert_eq!(samples.iter().map(|x| x.offset).collect::<Vec<_>>(), vec![100, 101, 200, 203, 207]);
+        assert_eq!(samples.iter().map(|x| x.decode_time).collect::<Vec<_>>(), vec![0, 10, 20, 30, 50]);
+        assert_eq!(samples.iter().map(|x| x.is_sync).collect::<Vec<_>>(), vec![true, false, false, true, false]);
+        assert_eq!(samples.iter().map(|x| x.description_index).collect::<Vec<_>>(), vec![1, 1, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_normalize_stsc() {
+        // Second file with 4 chunks (5 to 8) of 3 samples, whose stsc goes back and past its last chunk
+        let mut track = TrackDesc {
+            stco: vec![0; 8],
+            stsc: vec![(1, 2, 1), (4, 3, 1), (6, 3, 1), (5, 3, 1), (12, 1, 1)],
+            chunk_offset: 4,
+            stsc_offset: 2,
+            sample_offset: 8,
+            stsz_count: 20,
+            ..Default::default()
+        };
+        assert!(track.normalize_stsc(1));
+        assert_eq!(track.stsc, vec![(1, 2, 1), (4, 3, 1), (5, 3, 1), (6, 3, 1)]);
+        assert!(!track.normalize_stsc(1));
+    }
+
+    #[test]
+    fn test_extend_last_sample() {
+        let mut track = TrackDesc { stts: vec![(3, 1000)], stsz_count: 3, ..Default::default() };
+        track.extend_last_sample(3500);
+        assert_eq!(track.stts, vec![(2, 1000), (1, 1500)]);
+        track.extend_last_sample(3000);
+        assert_eq!(track.stts, vec![(2, 1000), (1, 1500)]);
+
+        // No samples from the current file
+        track.sample_offset = 3;
+        track.extend_last_sample(5000);
+        assert_eq!(track.stts, vec![(2, 1000), (1, 1500)]);
+    }
+
+    #[test]
+    fn test_convert_duration_ceil() {
+        // (2031.0 / 1000.0 * 1000.0).ceil() is 2032
+        assert_eq!(convert_duration_ceil(2031, 1000, 1000), 2031);
+        assert_eq!(convert_duration_ceil(85, 1000, 24000), 2040);
+        assert_eq!(convert_duration_ceil(1, 3, 2), 1);
+    }
+
+    #[test]
+    fn test_mp4_time_conversion() {
+        assert_eq!(mp4_time_to_system_time(0), None);
+        assert_eq!(mp4_time_to_system_time(2082844800 + 60), Some(SystemTime::UNIX_EPOCH + Duration::from_secs(60)));
+        assert_eq!(system_time_to_mp4_time(SystemTime::UNIX_EPOCH + Duration::from_secs(60)), 2082844800 + 60);
+    }
+
+    #[test]
+    fn test_constant_sample_sizes_stay_compact() {
+        fn stbl_with_stsz(sample_size: u32, sizes: &[u32]) -> Vec<u8> {
+            let mut stsz = vec![0u8; 4];
+            stsz.extend(sample_size.to_be_bytes());
+            stsz.extend((sizes.len().max(3) as u32).to_be_bytes());
+            if sample_size == 0 { for x in sizes { stsz.extend(x.to_be_bytes()); } }
+            let mut data = Vec::new();
+            for typ in ["trak", "mdia", "minf", "stbl", "stsz"] {
+                data.extend(b"\0\0\0\0");
+                data.extend(typ.as_bytes());
+            }
+            data.extend(stsz);
+            let len = data.len();
+            for i in 0..5 { data[i * 8..i * 8 + 4].copy_from_slice(&((len - i * 8) as u32).to_be_bytes()); }
+            data
+        }
+        let mut desc = Desc::default();
+        desc.moov_tracks.resize(1, Default::default());
+
+        read_desc(&mut std::io::Cursor::new(stbl_with_stsz(4, &[])), &mut desc, 0, u64::MAX, 0).unwrap();
+        read_desc(&mut std::io::Cursor::new(stbl_with_stsz(4, &[])), &mut desc, 0, u64::MAX, 1).unwrap();
+        assert_eq!((desc.moov_tracks[0].stsz_sample_size, desc.moov_tracks[0].stsz_count), (4, 6));
+        assert!(desc.moov_tracks[0].stsz.is_empty());
+
+        read_desc(&mut std::io::Cursor::new(stbl_with_stsz(0, &[1, 2, 3])), &mut desc, 0, u64::MAX, 2).unwrap();
+        assert_eq!(desc.moov_tracks[0].stsz_sample_size, 0);
+        assert_eq!(desc.moov_tracks[0].stsz, vec![4, 4, 4, 4, 4, 4, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_read_headers() {
+        let mut mvhd = vec![0u8; 4];
+        mvhd.extend((2082844800u32 + 60).to_be_bytes());
+        mvhd.extend(0u32.to_be_bytes());
+        mvhd.extend(1000u32.to_be_bytes());
+        mvhd.extend(2500u32.to_be_bytes());
+        let mut data = 8u32.to_be_bytes().to_vec();
+        data.extend(b"free");
+        data.extend((mvhd.len() as u32 + 16).to_be_bytes());
+        data.extend(b"moov");
+        data.extend((mvhd.len() as u32 + 8).to_be_bytes());
+        data.extend(b"mvhd");
+        data.extend(mvhd);
+
+        let info = read_headers(&mut std::io::Cursor::new(data)).unwrap();
+        assert_eq!(info.creation_time, Some(SystemTime::UNIX_EPOCH + Duration::from_secs(60)));
+        assert_eq!(info.movie_duration, 2.5);
+        assert_eq!(info.first_track_duration, None);
+    }
+
     #[test]
     fn test_tkhd_duration_conversion_edge_cases() {
         let mut desc = Desc {
@@ -661,4 +1725,19 @@ mod tests {
         // 8s * 1000 GPMF timescale = 8000 units
         assert_eq!(gpmf_track.tkhd_duration, 8000);
     }
+
+    #[test]
+    fn test_merged_cslg() {
+        let mut track = TrackDesc {
+            stts: vec![(10, 1000), (5, 2000)],
+            file_sample_ranges: vec![0..10, 10..15],
+            file_cslg: vec![Some([2000, -1000, 2000, 2000, 12000]), Some([1000, 0, 3000, 1000, 11000])],
+            ..Default::default()
+        };
+        assert_eq!(track.file_decode_starts(), vec![0, 10000]);
+        assert_eq!(track.merged_cslg(), Some([2000, -1000, 3000, 2000, 21000]));
+
+        track.file_cslg[1] = None;
+        assert_eq!(track.merged_cslg(), None);
+    }
 }
@@ -0,0 +1,114 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::cell::RefCell;
+use std::sync::Arc;
+
+/// Stage of the merge a diagnostic message comes from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Phase {
+    /// Reading the input files and building the merged description
+    #[default]
+    Scan,
+    /// Writing the merged boxes and copying the mdat data
+    Write,
+    /// Merging camera metadata (Insta360 trailer, GPMF)
+    Metadata,
+}
+
+/// A single message which is also sent to the `log` crate
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub level: log::Level,
+    pub phase: Phase,
+    /// Input file the message relates to, in the merge order
+    pub file_index: Option<usize>,
+    pub message: String,
+}
+
+/// Receives diagnostic messages, for host applications which can't route the `log` crate output (FFI, WASM)
+pub trait DiagnosticsSink: Send + Sync {
+    fn diagnostic(&self, diagnostic: &Diagnostic);
+}
+impl<F: Fn(&Diagnostic) + Send + Sync> DiagnosticsSink for F {
+    fn diagnostic(&self, diagnostic: &Diagnostic) { self(diagnostic) }
+}
+impl std::fmt::Debug for dyn DiagnosticsSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { f.write_str("DiagnosticsSink") }
+}
+
+struct Context {
+    sink: Arc<dyn DiagnosticsSink>,
+    phase: Phase,
+    file_index: Option<usize>,
+}
+
+thread_local! {
+    // Sink of the merge running on this thread
+    static CONTEXT: RefCell<Option<Context>> = const { RefCell::new(None) };
+}
+
+/// Restores the previous sink when the merge finishes
+pub(crate) struct SinkGuard {
+    previous: Option<Context>,
+}
+impl Drop for SinkGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CONTEXT.with(|c| *c.borrow_mut() = previous);
+    }
+}
+
+/// Send the messages emitted on this thread to `sink` until the returned guard is dropped
+pub(crate) fn scope(sink: Option<&Arc<dyn DiagnosticsSink>>) -> SinkGuard {
+    let context = sink.map(|sink| Context { sink: sink.clone(), phase: Phase::default(), file_index: None });
+    SinkGuard { previous: CONTEXT.with(|c| std::mem::replace(&mut *c.borrow_mut(), context)) }
+}
+
+pub(crate) fn set_phase(phase: Phase, file_index: Option<usize>) {
+    CONTEXT.with(|c| if let Some(context) = &mut *c.borrow_mut() {
+        context.phase = phase;
+        context.file_index = file_index;
+    });
+}
+
+pub(crate) fn emit(level: log::Level, args: std::fmt::Arguments) {
+    CONTEXT.with(|c| if let Some(context) = &*c.borrow() {
+        context.sink.diagnostic(&Diagnostic { level, phase: context.phase, file_index: context.file_index, message: args.to_string() });
+    });
+}
+
+/// Log a message and send it to the diagnostics sink of the current merge, e.g. `diag!(Warn, "File {i} is broken")`
+macro_rules! diag {
+    ($level:ident, $($arg:tt)+) => {
+        match format_args!($($arg)+) {
+            args => {
+                log::log!(log::Level::$level, "{}", args);
+                $crate::diagnostics::emit(log::Level::$level, args);
+            }
+        }
+    };
+}
+pub(crate) use diag;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_scope() {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let sink: Arc<dyn DiagnosticsSink> = Arc::new({
+            let messages = messages.clone();
+            move |d: &Diagnostic| messages.lock().unwrap().push((d.level, d.phase, d.file_index, d.message.clone()))
+        });
+        {
+            let _guard = scope(Some(&sink));
+            set_phase(Phase::Scan, Some(1));
+            diag!(Warn, "File {} is broken", 1);
+        }
+        diag!(Warn, "Not captured");
+        assert_eq!(*messages.lock().unwrap(), vec![(log::Level::Warn, Phase::Scan, Some(1), "File 1 is broken".to_string())]);
+    }
+}
@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::Result;
+use std::path::{ Path, PathBuf };
+use crate::diagnostics::diag;
+
+/// Chapter of a recording split by the camera, parsed from its file name
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ChapterName {
+    /// Identifies the recording: the naming scheme, the file number and the extension
+    recording: String,
+    /// 1-based chapter
+    chapter: u32,
+}
+
+impl ChapterName {
+    /// Parse `GOPR0123.MP4` (chapter 1), `GP010123.MP4` (chapter 2) or `GX010123.MP4` (chapter 1)
+    fn parse_gopro(path: &Path) -> Option<Self> {
+        let stem = path.file_stem()?.to_str()?.to_ascii_uppercase();
+        let extension = path.extension()?.to_str()?.to_ascii_uppercase();
+        if stem.len() != 8 || !stem.is_ascii() || !stem[4..].bytes().all(|x| x.is_ascii_digit()) { return None; }
+        let number = &stem[4..];
+        if &stem[..4] == "GOPR" {
+            return Some(Self { recording: format!("GP{number}.{extension}"), chapter: 1 });
+        }
+        let chapter = stem[2..4].parse::<u32>().ok()?;
+        match &stem[..2] {
+            "GP" => Some(Self { recording: format!("GP{number}.{extension}"), chapter: chapter + 1 }),
+            prefix if prefix.starts_with('G') && prefix.as_bytes()[1].is_ascii_uppercase() && chapter > 0 => Some(Self { recording: format!("{prefix}{number}.{extension}"), chapter }),
+            _ => None
+        }
+    }
+
+    /// Parse `DJI_0001_001.MP4` (chapter 1), `DJI_0001_002.MP4` (chapter 2), ... The part between `DJI_` and the chapter can contain
+    /// more fields, e.g. the date in `DJI_20230801123456_0001_001.MP4`
+    fn parse_dji(path: &Path) -> Option<Self> {
+        let stem = path.file_stem()?.to_str()?.to_ascii_uppercase();
+        let extension = path.extension()?.to_str()?.to_ascii_uppercase();
+        let (recording, chapter) = stem.strip_prefix("DJI_")?.rsplit_once('_')?;
+        if recording.is_empty() || chapter.len() < 3 || !chapter.bytes().all(|x| x.is_ascii_digit()) { return None; }
+        let chapter = chapter.parse::<u32>().ok().filter(|x| *x > 0)?;
+        Some(Self { recording: format!("DJI_{recording}.{extension}"), chapter })
+    }
+}
+
+/// Find all chapters of the GoPro recording of `file` in its folder, in chapter order. Both the `GOPR0123.MP4`, `GP010123.MP4`, ...
+/// naming of older cameras and the `GX010123.MP4`, `GX020123.MP4`, ... naming of HERO6 and later (`GH` for H.264) are recognized.
+/// Missing chapters are reported as warnings
+pub fn find_gopro_chapters<P: AsRef<Path>>(file: P) -> Result<Vec<PathBuf>> {
+    find_chapters(file.as_ref(), "GoPro", ChapterName::parse_gopro)
+}
+
+/// Find all chapters of the DJI recording of `file` in its folder, in chapter order: `DJI_0001_001.MP4`, `DJI_0001_002.MP4`, ...
+/// Missing chapters are reported as warnings
+pub fn find_dji_chapters<P: AsRef<Path>>(file: P) -> Result<Vec<PathBuf>> {
+    find_chapters(file.as_ref(), "DJI", ChapterName::parse_dji)
+}
+
+fn find_chapters(file: &Path, camera: &str, parse: fn(&Path) -> Option<ChapterName>) -> Result<Vec<PathBuf>> {
+    let name = parse(file).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} isn't named like a {camera} chapter", file.display())))?;
+    let dir = match file.parent() {
+        Some(x) if !x.as_os_str().is_empty() => x,
+        _ => Path::new(".")
+    };
+    let mut chapters = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if let Some(other) = parse(&path).filter(|x| x.recording == name.recording) {
+            chapters.push((other.chapter, path));
+        }
+    }
+    chapters.sort();
+    let max_chapter = chapters.last().map(|x| x.0).unwrap_or(0);
+    for missing in (1..=max_chapter).filter(|x| !chapters.iter().any(|c| c.0 == *x)) {
+        diag!(Warn, "{camera} chapter {missing} of {} is missing", file.display());
+    }
+    Ok(chapters.into_iter().map(|x| x.1).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_gopro_chapters() {
+        let dir = std::env::temp_dir().join(format!("mp4_merge_discovery_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["GP020123.MP4", "GOPR0123.MP4", "GP010123.MP4", "GP010123.LRV", "GOPR0124.MP4", "GX020125.MP4", "GX010125.MP4", "GH010125.MP4", "GX030125.MP4", "notes.txt"] {
+            std::fs::write(dir.join(name), []).unwrap();
+        }
+        let names = |file: &str| find_gopro_chapters(dir.join(file)).unwrap().iter().map(|x| x.file_name().unwrap().to_str().unwrap().to_owned()).collect::<Vec<_>>();
+        assert_eq!(names("GP010123.MP4"), ["GOPR0123.MP4", "GP010123.MP4", "GP020123.MP4"]);
+        assert_eq!(names("GX010125.MP4"), ["GX010125.MP4", "GX020125.MP4", "GX030125.MP4"]);
+        assert_eq!(names("GH010125.MP4"), ["GH010125.MP4"]);
+        assert!(find_gopro_chapters(dir.join("notes.txt")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_find_dji_chapters() {
+        let dir = std::env::temp_dir().join(format!("mp4_merge_discovery_dji_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["DJI_0001_010.MP4", "DJI_0001_002.MP4", "DJI_0001_001.MP4", "DJI_0001_001.SRT", "DJI_0002_001.MP4", "DJI_0001.MP4", "DJI_20230801123456_0003_001.MP4", "DJI_20230801123456_0003_002.MP4"] {
+            std::fs::write(dir.join(name), []).unwrap();
+        }
+        let names = |file: &str| find_dji_chapters(dir.join(file)).unwrap().iter().map(|x| x.file_name().unwrap().to_str().unwrap().to_owned()).collect::<Vec<_>>();
+        assert_eq!(names("DJI_0001_002.MP4"), ["DJI_0001_001.MP4", "DJI_0001_002.MP4", "DJI_0001_010.MP4"]);
+        assert_eq!(names("DJI_20230801123456_0003_001.MP4"), ["DJI_20230801123456_0003_001.MP4", "DJI_20230801123456_0003_002.MP4"]);
+        assert!(find_dji_chapters(dir.join("DJI_0001.MP4")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Cursor, Result };
+use std::path::Path;
+use std::time::Duration;
+use crate::{ check_compatibility, external, fourcc, CompatibilityReport, MergeOptions, MergeReport, OutputFormat };
+use crate::inspect::read_structure;
+
+/// Predicted outcome of a merge, from `join_files_dry_run`
+#[derive(Debug, Clone, Default)]
+pub struct DryRunReport {
+    /// Size of the merged file in bytes
+    pub output_size: u64,
+    /// Duration of the merged movie
+    pub duration: Duration,
+    /// Size of the merged moov in bytes
+    pub moov_size: u64,
+    /// Size of the rewritten stbl of each track of the output, in bytes
+    pub table_sizes: Vec<u64>,
+    /// Result of `check_compatibility` for the files
+    pub compatibility: CompatibilityReport,
+    /// Why the merge would fail, e.g. incompatible tracks. The sizes and the report are empty then
+    pub error: Option<String>,
+    /// Summary of the merge, as returned by `join_files_with_options`
+    pub report: MergeReport,
+}
+
+/// Scan the files like `join_files_with_options` and lay out the merged moov in memory, without writing anything.
+/// Returns the size of the merged file, its duration and the size of the sample tables, for showing an estimate before merging.
+/// Only a single MP4 output is predicted
+pub fn join_files_dry_run<P: AsRef<Path>>(files: &[P], options: &MergeOptions) -> Result<DryRunReport> {
+    if options.max_output_size.is_some() || options.output_format != OutputFormat::Mp4 {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "The dry run predicts a single MP4 output"));
+    }
+    let compatibility = check_compatibility(files)?;
+    let mut open_files = Vec::with_capacity(files.len());
+    let mut file_metadata = Vec::with_capacity(files.len());
+    for x in files {
+        let (f, size) = external::InputFile::open(x.as_ref(), &options.external_media_paths)?;
+        open_files.push((f, size as usize));
+        file_metadata.push(crate::filesystem_creation_time(&std::fs::metadata(x)?));
+    }
+
+    let _diagnostics = crate::diagnostics::scope(options.diagnostics.as_ref());
+    let mut scan = match crate::scan_files(&mut open_files, &file_metadata, options, &|_| {}) {
+        Ok(scan) => scan,
+        Err(e) => return Ok(DryRunReport { compatibility, error: Some(crate::Error::from(e).to_string()), ..Default::default() })
+    };
+    let (layout, trailer) = crate::sequential_layout(&mut open_files, &scan.first_boxes, &mut scan.desc, scan.insta360_max_read, scan.gpmf_detected)?;
+    let desc = &scan.desc;
+    let data_size = desc.mdat_position.iter().filter(|x| x.0.is_none_or(|i| i < files.len())).map(|x| x.2).sum::<u64>();
+
+    // The layout has the mdat header without its data, so the boxes before and after it are read separately
+    let (before_data, after_data) = layout.split_at(desc.mdat_final_position as usize);
+    let mut moov = None;
+    for part in [before_data, after_data] {
+        let structure = read_structure(&mut Cursor::new(part))?;
+        moov = moov.or_else(|| structure.boxes.into_iter().find(|x| x.header.typ == fourcc("moov")));
+    }
+    let table_sizes = moov.iter().flat_map(|x| &x.children).filter(|x| x.header.typ == fourcc("trak")).map(|trak| {
+        ["mdia", "minf", "stbl"].iter().try_fold(trak, |node, typ| node.children.iter().find(|x| x.header.typ == fourcc(typ))).map_or(0, |x| x.header.size)
+    }).collect();
+
+    Ok(DryRunReport {
+        output_size: layout.len() as u64 + data_size + trailer.len() as u64,
+        duration: Duration::from_secs_f64(desc.moov_mvhd_duration as f64 / desc.moov_mvhd_timescale.max(1) as f64),
+        moov_size: moov.as_ref().map_or(0, |x| x.header.size),
+        table_sizes,
+        compatibility,
+        error: None,
+        report: MergeReport::from_desc(desc, &scan.input_order),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+
+    #[test]
+    fn test_dry_run() {
+        let file = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50)).track(SyntheticTrack::audio(48000, 50));
+        // Without the audio track of the first file
+        let video_only = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50));
+        let dir = std::env::temp_dir().join(format!("mp4_merge_dry_run_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let inputs = [dir.join("a.mp4"), dir.join("b.mp4"), dir.join("c.mp4")];
+        std::fs::write(&inputs[0], file.build()).unwrap();
+        std::fs::write(&inputs[1], file.build()).unwrap();
+        std::fs::write(&inputs[2], video_only.build()).unwrap();
+        let output = dir.join("out.mp4");
+
+        let options = MergeOptions::default().faststart(true);
+        let report = join_files_dry_run(&inputs[..2], &options).unwrap();
+        crate::join_files_with_options(&inputs[..2], &output, &options, |_| {}).unwrap();
+        let merged = std::fs::read(&output).unwrap();
+        let failed = join_files_dry_run(&[&inputs[0], &inputs[2]], &MergeOptions::default()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(report.error.is_none() && report.compatibility.is_compatible());
+        assert_eq!(report.output_size, merged.len() as u64);
+        assert_eq!(report.duration, Duration::from_secs(4));
+        let structure = read_structure(&mut Cursor::new(&merged)).unwrap();
+        assert_eq!(report.moov_size, structure.find(&["moov"]).unwrap().header.size);
+        assert_eq!(report.table_sizes.len(), 2);
+        assert_eq!(report.table_sizes[0], structure.find(&["moov", "trak", "mdia", "minf", "stbl"]).unwrap().header.size);
+        assert_eq!(report.report.tracks[0].sample_count, 100);
+
+        assert!(!failed.compatibility.is_compatible());
+        assert!(failed.error.as_ref().is_some_and(|x| x.contains("missing")), "{:?}", failed.error);
+        assert_eq!(failed.output_size, 0);
+    }
+}
@@ -0,0 +1,98 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, Result, SeekFrom };
+use byteorder::{ ReadBytesExt, BigEndian };
+use crate::desc_reader::Desc;
+use crate::{ fourcc, read_box, diagnostics::diag };
+
+/// Priming and remainder samples from the iTunSMPB tag of moov/udta/meta/ilst
+pub(crate) fn read_itunsmpb<R: Read + Seek>(reader: &mut R) -> Result<Option<(u64, u64)>> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut text = None;
+    find_itunsmpb(reader, u64::MAX, 0, &mut text)?;
+    Ok(text.as_deref().and_then(parse_itunsmpb))
+}
+
+fn find_itunsmpb<R: Read + Seek>(reader: &mut R, max_read: u64, depth: usize, text: &mut Option<String>) -> Result<()> {
+    const PATH: [&str; 5] = ["moov", "udta", "meta", "ilst", "----"];
+    let start_pos = reader.stream_position()?;
+    while text.is_none() && reader.stream_position()? - start_pos < max_read {
+        let Ok((typ, _offs, size, header_size)) = read_box(reader) else { break; };
+        if size < header_size as u64 || typ == 0 { break; }
+        let org_pos = reader.stream_position()?;
+        let content_size = size - header_size as u64;
+
+        if depth < PATH.len() && typ == fourcc(PATH[depth]) {
+            if typ == fourcc("meta") && reader.read_u32::<BigEndian>()? != 0 {
+                // QuickTime meta has no version and flags
+                reader.seek(SeekFrom::Start(org_pos))?;
+            }
+            if typ == fourcc("----") {
+                *text = read_freeform_value(reader, content_size, "iTunSMPB")?;
+            } else {
+                let children_pos = reader.stream_position()?;
+                find_itunsmpb(reader, content_size - (children_pos - org_pos), depth + 1, text)?;
+            }
+        }
+        reader.seek(SeekFrom::Start(org_pos + content_size))?;
+    }
+    Ok(())
+}
+
+/// Value of a freeform (----) ilst item, if its name matches
+fn read_freeform_value<R: Read + Seek>(reader: &mut R, max_read: u64, name: &str) -> Result<Option<String>> {
+    let start_pos = reader.stream_position()?;
+    let (mut item_name, mut value) = (None, None);
+    while reader.stream_position()? - start_pos < max_read {
+        let Ok((typ, _offs, size, header_size)) = read_box(reader) else { break; };
+        if size < header_size as u64 + 4 || size > 1024 { break; }
+        let mut buf = vec![0u8; (size - header_size as u64) as usize];
+        reader.read_exact(&mut buf)?;
+        // name has version and flags, data has the type and the locale
+        if typ == fourcc("name") { item_name = Some(String::from_utf8_lossy(&buf[4..]).into_owned()); }
+        if typ == fourcc("data") && buf.len() >= 8 { value = Some(String::from_utf8_lossy(&buf[8..]).into_owned()); }
+    }
+    Ok(value.filter(|_| item_name.as_deref() == Some(name)))
+}
+
+/// Parse " 00000000 00000840 000001CA 00000000003F31F6 ..." to the priming and remainder sample counts
+fn parse_itunsmpb(text: &str) -> Option<(u64, u64)> {
+    let mut fields = text.split_whitespace().skip(1).map(|x| u64::from_str_radix(x, 16).ok());
+    let (priming, remainder) = (fields.next()??, fields.next()??);
+    (priming > 0 || remainder > 0).then_some((priming, remainder))
+}
+
+/// Store the encoder delay of the audio tracks of the file just read, from the first media entry of its edit list or the iTunSMPB tag.
+/// The remainder is only taken from the edit list when it also skips priming samples, otherwise it's just the rounding of the movie timescale
+pub(crate) fn store_file_delay(desc: &mut Desc, file_index: usize, itunsmpb: Option<(u64, u64)>) {
+    for (track_index, track) in desc.moov_tracks.iter_mut().enumerate() {
+        let edit = track.file_edit.take();
+        if track.handler_type != "soun" || track.skip { continue; }
+        let file_duration = desc.track_file_durations.get(track_index).and_then(|x| x.get(file_index)).copied().unwrap_or(0.0);
+        let duration = (file_duration * track.mdhd_timescale as f64).round() as u64;
+        let delay = match edit {
+            Some((media_time, segment_duration)) if media_time > 0 => {
+                let presented = (segment_duration * track.mdhd_timescale as f64).round() as u64;
+                (media_time as u64, duration.saturating_sub(media_time as u64 + presented))
+            }
+            _ => itunsmpb.unwrap_or_default()
+        };
+        if delay != (0, 0) {
+            diag!(Debug, "Track {} of file {file_index} has {} priming and {} remainder samples", track.track_id, delay.0, delay.1);
+        }
+        track.file_encoder_delay.push(delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_itunsmpb() {
+        assert_eq!(parse_itunsmpb(" 00000000 00000840 000001CA 00000000003F31F6 00000000 00000000 00000000"), Some((2112, 458)));
+        assert_eq!(parse_itunsmpb(" 00000000 00000000 00000000 0000000000000000"), None);
+        assert_eq!(parse_itunsmpb("garbage"), None);
+    }
+}
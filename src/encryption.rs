@@ -0,0 +1,70 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::Result;
+use crate::desc_reader::Desc;
+use crate::stsd::{ next_box, SampleEntry };
+use crate::track_filter::TrackFilter;
+use crate::Error;
+
+/// Protection scheme of an encrypted sample entry (`encv`, `enca` or one with a sinf), e.g. "cenc" or "cbcs" from its schm box.
+/// Outer None when the entry isn't encrypted, inner None when the sinf has no schm
+fn protection_scheme(entry: &SampleEntry) -> Option<Option<String>> {
+    let sinf = entry.child("sinf");
+    if sinf.is_none() && entry.codec != "encv" && entry.codec != "enca" { return None; }
+    Some(sinf.and_then(|sinf| {
+        let mut pos = 0;
+        std::iter::from_fn(|| next_box(sinf, &mut pos))
+            .find(|(typ, _)| typ == "schm")
+            .and_then(|(_, schm)| schm.get(4..8))
+            .map(|x| String::from_utf8_lossy(x).into_owned())
+    }))
+}
+
+/// Fail with `Error::EncryptedContentUnsupported` when a merged track of file `file_index` is encrypted: its sample entries are protected,
+/// or its sample table has auxiliary information (saiz, saio, senc). The initialization vectors of the samples aren't merged,
+/// so the output wouldn't decrypt. `entries` are the sample entries in the order of the merged tracks; dropped tracks are ignored
+pub(crate) fn check_unencrypted(desc: &Desc, entries: &[Vec<SampleEntry>], filters: &[TrackFilter], file_index: usize) -> Result<()> {
+    let merged = |track: usize| !desc.moov_tracks.get(track).is_some_and(|t| t.dropped) && !filters.iter().any(|f| f.matches(desc, track));
+    let protected = entries.iter().enumerate()
+        .filter(|(track, _)| merged(*track))
+        .find_map(|(track, entries)| entries.iter().find_map(protection_scheme).map(|scheme| (track, scheme)));
+    let aux_info = || desc.cenc_tracks.iter().find(|(file, track)| *file == file_index && merged(*track)).map(|(_, track)| (*track, None));
+    match protected.or_else(aux_info) {
+        Some((track_index, scheme)) => Err(Error::EncryptedContentUnsupported { file_index, track_index, scheme }.into()),
+        None => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{ mp4_box, SyntheticMp4, SyntheticTrack };
+
+    #[test]
+    fn test_encrypted_content() {
+        let file = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50)).track(SyntheticTrack::audio(48000, 50));
+        let mut encrypted = file.clone();
+        encrypted.tracks[0].codec = *b"encv";
+        let mut files = [file.cursor(), encrypted.cursor()];
+        let err = crate::join_file_streams(&mut files, std::io::Cursor::new(Vec::new()), |_| {}).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+        assert!(matches!(crate::Error::from_io(&err), Some(crate::Error::EncryptedContentUnsupported { file_index: 1, track_index: 0, scheme: None })), "{err}");
+
+        // Sample auxiliary information in the stbl of an otherwise plain track
+        let mut data = file.build();
+        let structure = crate::inspect::read_structure(&mut std::io::Cursor::new(&data)).unwrap();
+        let stbl = structure.find(&["moov", "trak", "mdia", "minf", "stbl"]).unwrap().header;
+        let saiz = mp4_box(b"saiz", &[0; 9]);
+        let end = (stbl.offset + stbl.size) as usize;
+        data.splice(end..end, saiz.iter().copied());
+        for parent in ["moov", "moov/trak", "moov/trak/mdia", "moov/trak/mdia/minf", "moov/trak/mdia/minf/stbl"] {
+            let header = structure.find(&parent.split('/').collect::<Vec<_>>()).unwrap().header;
+            let size = u32::from_be_bytes(data[header.offset as usize..][..4].try_into().unwrap()) + saiz.len() as u32;
+            data[header.offset as usize..][..4].copy_from_slice(&size.to_be_bytes());
+        }
+        let size = data.len();
+        let mut files = [(std::io::Cursor::new(data), size), file.cursor()];
+        let err = crate::join_file_streams(&mut files, std::io::Cursor::new(Vec::new()), |_| {}).unwrap_err();
+        assert!(matches!(crate::Error::from_io(&err), Some(crate::Error::EncryptedContentUnsupported { file_index: 0, track_index: 0, .. })), "{err}");
+    }
+}
@@ -0,0 +1,125 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::fmt;
+
+/// Cause of a failed merge. The functions of this crate return `std::io::Error`, which carries this error
+/// for the failures it describes. Convert it back with `Error::from(io_error)` (or borrow it with `Error::from_io`)
+/// to show a meaningful message, other errors become `Error::Io`
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing failed, or a failure not described by the other variants
+    Io(std::io::Error),
+    /// A box which the merge can't handle, at `path` (e.g. `moov/trak/mdia/minf/dinf/dref`)
+    UnsupportedBox { path: String, reason: String },
+    /// The tracks of a file can't be merged with the first file
+    IncompatibleTracks { file_index: usize, reason: String },
+    /// A box extends past the end of its parent or the file, or a required box is missing. `path` is the box type, e.g. `moov`
+    TruncatedFile { path: String, offset: u64, reason: String },
+    /// A file has more tracks than the merge supports
+    TooManyTracks { max: usize },
+    /// The merge was stopped with a `CancellationToken`
+    Cancelled,
+    /// The filesystem of the output `path` has less free space than the estimated size of the merged files written to it
+    InsufficientSpace { path: std::path::PathBuf, required: u64, available: u64 },
+    /// A track is encrypted (e.g. CENC with `scheme` "cenc" or "cbcs"). Its sample auxiliary information isn't merged, so the output wouldn't decrypt
+    EncryptedContentUnsupported { file_index: usize, track_index: usize, scheme: Option<String> },
+}
+
+impl Error {
+    /// The error carried by an `std::io::Error` returned from this crate, if it's one of the specific failures
+    pub fn from_io(error: &std::io::Error) -> Option<&Self> {
+        error.get_ref()?.downcast_ref()
+    }
+
+    /// Box path where the failure occurred, if known
+    pub fn box_path(&self) -> Option<&str> {
+        match self {
+            Self::UnsupportedBox { path, .. } | Self::TruncatedFile { path, .. } => Some(path),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{e}"),
+            Self::UnsupportedBox { path, reason } => write!(f, "Unsupported {path}: {reason}"),
+            Self::IncompatibleTracks { file_index, reason } => write!(f, "File {file_index} can't be merged with the first file: {reason}"),
+            Self::TruncatedFile { path, offset, reason } => write!(f, "Truncated file, {path} at {offset}: {reason}"),
+            Self::TooManyTracks { max } => write!(f, "More than {max} tracks in a file"),
+            Self::Cancelled => write!(f, "The merge was cancelled"),
+            Self::InsufficientSpace { path, required, available } => write!(f, "Not enough space for {}: the merged output needs about {required} bytes, {available} are available", path.display()),
+            Self::EncryptedContentUnsupported { file_index, track_index, scheme } => match scheme {
+                Some(scheme) => write!(f, "Track {track_index} of file {file_index} is encrypted with {scheme}, encrypted content can't be merged"),
+                None => write!(f, "Track {track_index} of file {file_index} is encrypted, encrypted content can't be merged"),
+            },
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<Error> for std::io::Error {
+    fn from(error: Error) -> Self {
+        let kind = match &error {
+            Error::UnsupportedBox { .. } | Error::TooManyTracks { .. } | Error::EncryptedContentUnsupported { .. } => std::io::ErrorKind::Unsupported,
+            Error::IncompatibleTracks { .. } | Error::TruncatedFile { .. } => std::io::ErrorKind::InvalidData,
+            Error::InsufficientSpace { .. } => std::io::ErrorKind::StorageFull,
+            // Not Interrupted, which write_all retries
+            Error::Io(_) | Error::Cancelled => std::io::ErrorKind::Other,
+        };
+        match error {
+            Error::Io(e) => e,
+            error => std::io::Error::new(kind, error),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        match error.get_ref().is_some_and(|x| x.is::<Self>()) {
+            true => *error.into_inner().unwrap().downcast::<Self>().unwrap(),
+            false => Self::Io(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+
+    #[test]
+    fn test_structured_errors() {
+        let merge = |chapters: &[&SyntheticMp4]| {
+            let mut files = chapters.iter().map(|x| x.cursor()).collect::<Vec<_>>();
+            crate::join_file_streams(&mut files, std::io::Cursor::new(Vec::new()), |_| {}).map(|_| ())
+        };
+        let mut many = SyntheticMp4::new();
+        for _ in 0..11 { many = many.track(SyntheticTrack::metadata(*b"mett", 2)); }
+        let e = merge(&[&many, &many]).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::Unsupported);
+        assert!(matches!(Error::from_io(&e), Some(Error::TooManyTracks { max: 10 })));
+
+        // Box sizes past the end of the file
+        let file = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 10)).build();
+        let mut files = vec![(std::io::Cursor::new(file[..file.len() - 10].to_vec()), file.len() - 10)];
+        let e = crate::join_file_streams(&mut files, std::io::Cursor::new(Vec::new()), |_| {}).unwrap_err();
+        match Error::from(e) {
+            Error::TruncatedFile { path, .. } => assert_eq!(path, "moov"),
+            e => panic!("Unexpected error {e:?}"),
+        }
+
+        let e = std::io::Error::other("Reader thread stopped");
+        assert!(matches!(Error::from(e), Error::Io(_)));
+    }
+}
@@ -0,0 +1,417 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::fs::File;
+use std::io::{ Read, Seek, Result, SeekFrom };
+use std::path::{ Path, PathBuf };
+use byteorder::{ ReadBytesExt, BigEndian };
+use crate::boxes::{ BoxIter, BoxHeader };
+use crate::{ fourcc, diagnostics::diag };
+
+/// Data reference (dref) entry
+#[derive(Debug, Clone, PartialEq)]
+struct DataReference {
+    /// The samples are stored in the file itself
+    self_contained: bool,
+    /// URL, URN or the file name of an alias
+    location: String,
+    /// Offset of the version and flags of the entry
+    flags_offset: u64,
+}
+
+/// Data references and chunk offset table of a track
+#[derive(Debug, Default)]
+struct TrackData {
+    references: Vec<DataReference>,
+    /// data_reference_index of each sample description
+    description_references: Vec<u16>,
+    /// Offset of the first entry, whether it's co64, and the number of entries
+    chunk_offsets: Option<(u64, bool, u32)>,
+}
+
+impl TrackData {
+    /// The dref entry of the samples, when they are in another file
+    fn external_reference(&self, track_index: usize) -> Result<Option<&DataReference>> {
+        let mut indices = self.description_references.clone();
+        indices.sort_unstable();
+        indices.dedup();
+        let references = indices.iter().filter_map(|x| self.references.get((*x as usize).checked_sub(1)?)).collect::<Vec<_>>();
+        if references.iter().all(|x| x.self_contained) {
+            return Ok(None);
+        }
+        if references.len() > 1 {
+            return Err(crate::Error::UnsupportedBox { path: "moov/trak/mdia/minf/dinf/dref".into(), reason: format!("Track {track_index} has sample descriptions with different data references") }.into());
+        }
+        Ok(references.first().copied())
+    }
+}
+
+fn read_tracks<R: Read + Seek>(reader: &mut R) -> Result<Vec<TrackData>> {
+    let mut tracks = Vec::new();
+    let mut top = BoxIter::top_level(reader)?;
+    while let Some(header) = top.next() {
+        let header = header?;
+        if header.typ != fourcc("moov") { continue; }
+        let mut moov = top.children(&header);
+        while let Some(header) = moov.next() {
+            let header = header?;
+            if header.typ != fourcc("trak") { continue; }
+            let mut track = TrackData::default();
+            read_track(&mut moov.children(&header), &mut track)?;
+            tracks.push(track);
+        }
+        break;
+    }
+    Ok(tracks)
+}
+
+fn read_track<R: Read + Seek>(iter: &mut BoxIter<'_, R>, track: &mut TrackData) -> Result<()> {
+    const CONTAINERS: [u32; 4] = [fourcc("mdia"), fourcc("minf"), fourcc("dinf"), fourcc("stbl")];
+    while let Some(header) = iter.next() {
+        let header = header?;
+        if CONTAINERS.contains(&header.typ) {
+            read_track(&mut iter.children(&header), track)?;
+        } else if header.typ == fourcc("dref") {
+            track.references = parse_dref(&read_content(iter.reader(), &header)?, header.content_offset());
+        } else if header.typ == fourcc("stsd") {
+            track.description_references = parse_stsd_references(&read_content(iter.reader(), &header)?);
+        } else if header.typ == fourcc("stco") || header.typ == fourcc("co64") {
+            let reader = iter.reader();
+            reader.seek(SeekFrom::Start(header.content_offset() + 4))?;
+            let count = reader.read_u32::<BigEndian>()?;
+            track.chunk_offsets = Some((header.content_offset() + 8, header.typ == fourcc("co64"), count));
+        }
+    }
+    Ok(())
+}
+
+fn read_content<R: Read + Seek>(reader: &mut R, header: &BoxHeader) -> Result<Vec<u8>> {
+    if header.content_size() > 1024 * 1024 {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} box is too large", header.typ_str())));
+    }
+    let mut data = vec![0u8; header.content_size() as usize];
+    reader.seek(SeekFrom::Start(header.content_offset()))?;
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Entries of a dref box. `content_offset` is the offset of `data` in the file
+fn parse_dref(data: &[u8], content_offset: u64) -> Vec<DataReference> {
+    let mut entries = Vec::new();
+    let mut pos = 8; // Version, flags and entry_count
+    while pos + 12 <= data.len() {
+        let size = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        if size < 12 || pos + size > data.len() { break; }
+        let flags = u32::from_be_bytes([0, data[pos + 9], data[pos + 10], data[pos + 11]]);
+        let payload = &data[pos + 12..pos + size];
+        let strings = payload.split(|x| *x == 0).map(|x| String::from_utf8_lossy(x).into_owned()).collect::<Vec<_>>();
+        let location = match &data[pos + 4..pos + 8] {
+            b"url " => strings.first().cloned().unwrap_or_default(),
+            // Name and location
+            b"urn " => strings.iter().rev().find(|x| !x.is_empty()).cloned().unwrap_or_default(),
+            // Classic Mac OS alias record, the file name is a Pascal string at byte 50
+            b"alis" if payload.len() > 51 => String::from_utf8_lossy(&payload[51..(51 + payload[50] as usize).min(payload.len())]).into_owned(),
+            typ => String::from_utf8_lossy(typ).into_owned()
+        };
+        entries.push(DataReference { self_contained: flags & 1 != 0, location, flags_offset: content_offset + pos as u64 + 8 });
+        pos += size;
+    }
+    entries
+}
+
+/// data_reference_index of each sample description in stsd
+fn parse_stsd_references(data: &[u8]) -> Vec<u16> {
+    let mut indices = Vec::new();
+    let mut pos = 8; // Version, flags and entry_count
+    while pos + 16 <= data.len() {
+        let size = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        if size < 16 || pos + size > data.len() { break; }
+        indices.push(u16::from_be_bytes([data[pos + 14], data[pos + 15]]));
+        pos += size;
+    }
+    indices
+}
+
+/// Fail with an explicit error when the samples of a track are stored in another file, the merge would copy the wrong data
+pub(crate) fn check_self_contained<R: Read + Seek>(reader: &mut R, file_index: usize) -> Result<()> {
+    for (track_index, track) in read_tracks(reader)?.iter().enumerate() {
+        if let Some(reference) = track.external_reference(track_index)? {
+            return Err(crate::Error::UnsupportedBox { path: "moov/trak/mdia/minf/dinf/dref".into(), reason: format!(
+                "Track {track_index} of file {file_index} stores its samples in another file ({}), which is only resolved by `join_files_with_options`",
+                reference.location
+            ) }.into());
+        }
+    }
+    Ok(())
+}
+
+/// Find the file of a data reference: the path of a file URL, then its file name next to the input and in `search_paths`
+fn resolve_location(location: &str, input_dir: &Path, search_paths: &[PathBuf]) -> Option<PathBuf> {
+    if let Some(url_path) = location.strip_prefix("file://") {
+        let url_path = percent_decode(url_path.strip_prefix("localhost").unwrap_or(url_path));
+        // file:///C:/... on Windows
+        let url_path = if url_path.as_bytes().get(2) == Some(&b':') { &url_path[1..] } else { &url_path[..] };
+        if Path::new(url_path).is_file() {
+            return Some(PathBuf::from(url_path));
+        }
+    } else if !location.contains("://") && input_dir.join(location).is_file() {
+        return Some(input_dir.join(location));
+    }
+    // Mac OS alias paths are separated with colons
+    let name = percent_decode(location.rsplit(['/', '\\', ':']).next()?);
+    if name.is_empty() { return None; }
+    std::iter::once(input_dir).chain(search_paths.iter().map(|x| x.as_path())).map(|x| x.join(&name)).find(|x| x.is_file())
+}
+
+fn percent_decode(text: &str) -> String {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut iter = text.bytes();
+    while let Some(byte) = iter.next() {
+        if byte == b'%' {
+            let hex = [iter.next().unwrap_or(0), iter.next().unwrap_or(0)];
+            if let Some(x) = std::str::from_utf8(&hex).ok().and_then(|x| u8::from_str_radix(x, 16).ok()) {
+                bytes.push(x);
+                continue;
+            }
+            bytes.extend_from_slice(&hex);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+enum Source {
+    /// Offset in the input file
+    Input(u64),
+    External(usize),
+    Bytes(Vec<u8>),
+}
+
+struct Segment {
+    start: u64,
+    len: u64,
+    source: Source,
+}
+
+/// Input whose externally referenced media is spliced into its mdat. The dref entries are marked as self-contained
+/// and the chunk offsets point at the spliced data, so the file reads as a self-contained movie
+pub(crate) struct ResolvedInput {
+    input: File,
+    externals: Vec<File>,
+    segments: Vec<Segment>,
+    /// Replaced bytes, by offset in the resolved file
+    patches: Vec<(u64, Vec<u8>)>,
+    position: u64,
+    len: u64,
+}
+
+impl ResolvedInput {
+    fn open(mut input: File, path: &Path, search_paths: &[PathBuf]) -> Result<Option<Self>> {
+        let input_len = input.metadata()?.len();
+        let mut reader = std::io::BufReader::with_capacity(16*1024, &mut input);
+        let tracks = read_tracks(&mut reader)?;
+        let mut externals: Vec<(String, PathBuf, u64)> = Vec::new();
+        let mut track_externals = Vec::with_capacity(tracks.len());
+        for (track_index, track) in tracks.iter().enumerate() {
+            let Some(reference) = track.external_reference(track_index)? else { track_externals.push(None); continue; };
+            let flags_offset = reference.flags_offset;
+            let index = match externals.iter().position(|x| x.0 == reference.location) {
+                Some(x) => x,
+                None => {
+                    let input_dir = path.parent().unwrap_or(Path::new("."));
+                    let resolved = resolve_location(&reference.location, input_dir, search_paths).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!(
+                        "Media {} referenced by track {track_index} of {} wasn't found, add its folder to the external media paths", reference.location, path.display()
+                    )))?;
+                    diag!(Debug, "Track {track_index} of {} references {}, resolved to {}", path.display(), reference.location, resolved.display());
+                    let len = std::fs::metadata(&resolved)?.len();
+                    externals.push((reference.location.clone(), resolved, len));
+                    externals.len() - 1
+                }
+            };
+            track_externals.push(Some((index, flags_offset)));
+        }
+        if externals.is_empty() {
+            return Ok(None);
+        }
+
+        // The media is inserted at the end of the first mdat, or in a new mdat at the end of the file
+        let mut patches = Vec::new();
+        let mdat = BoxIter::top_level(&mut reader)?.filter_map(|x| x.ok()).find(|x| x.typ == fourcc("mdat"));
+        let header = if mdat.is_some() { Vec::new() } else { [&1u32.to_be_bytes()[..], b"mdat", &[0; 8]].concat() };
+        let insert_at = mdat.map(|x| x.end()).unwrap_or(input_len);
+        let inserted = header.len() as u64 + externals.iter().map(|x| x.2).sum::<u64>();
+        if let Some(mdat) = mdat {
+            reader.seek(SeekFrom::Start(mdat.offset))?;
+            match reader.read_u32::<BigEndian>()? {
+                0 => { }, // Extends to the end of the file
+                1 => patches.push((mdat.offset + 8, (mdat.size + inserted).to_be_bytes().to_vec())),
+                size => {
+                    let size = u32::try_from(size as u64 + inserted).map_err(|_| std::io::Error::new(std::io::ErrorKind::Unsupported, "The referenced media doesn't fit in the 32-bit mdat of the file"))?;
+                    patches.push((mdat.offset, size.to_be_bytes().to_vec()));
+                }
+            }
+        }
+        let mut bases = Vec::with_capacity(externals.len());
+        let mut base = insert_at + header.len() as u64;
+        for x in &externals {
+            bases.push(base);
+            base += x.2;
+        }
+
+        for (track_index, (track, external)) in tracks.iter().zip(&track_externals).enumerate() {
+            if let Some((_, flags_offset)) = external {
+                patches.push((*flags_offset, vec![0, 0, 0, 1]));
+            }
+            let Some((offset, co64, count)) = track.chunk_offsets else { continue; };
+            reader.seek(SeekFrom::Start(offset))?;
+            let mut table = Vec::with_capacity(count as usize * if co64 { 8 } else { 4 });
+            for _ in 0..count {
+                let chunk_offset = if co64 { reader.read_u64::<BigEndian>()? } else { reader.read_u32::<BigEndian>()? as u64 };
+                let chunk_offset = match external {
+                    Some((x, _)) => bases[*x] + chunk_offset,
+                    None if chunk_offset >= insert_at => chunk_offset + inserted,
+                    None => chunk_offset
+                };
+                if co64 {
+                    table.extend_from_slice(&chunk_offset.to_be_bytes());
+                } else {
+                    let chunk_offset = u32::try_from(chunk_offset).map_err(|_| std::io::Error::new(std::io::ErrorKind::Unsupported, format!("Track {track_index} of {} can't address the referenced media with 32-bit chunk offsets", path.display())))?;
+                    table.extend_from_slice(&chunk_offset.to_be_bytes());
+                }
+            }
+            patches.push((offset, table));
+        }
+        drop(reader);
+        for patch in &mut patches {
+            if patch.0 >= insert_at { patch.0 += inserted; }
+        }
+
+        let mut segments = vec![Segment { start: 0, len: insert_at, source: Source::Input(0) }];
+        if !header.is_empty() {
+            let mut header = header;
+            header[8..].copy_from_slice(&inserted.to_be_bytes());
+            segments.push(Segment { start: insert_at, len: 16, source: Source::Bytes(header) });
+        }
+        for (i, x) in externals.iter().enumerate() {
+            segments.push(Segment { start: bases[i], len: x.2, source: Source::External(i) });
+        }
+        segments.push(Segment { start: insert_at + inserted, len: input_len - insert_at, source: Source::Input(insert_at) });
+
+        Ok(Some(Self {
+            input,
+            externals: externals.iter().map(|x| File::open(&x.1)).collect::<Result<Vec<_>>>()?,
+            segments,
+            patches,
+            position: 0,
+            len: input_len + inserted,
+        }))
+    }
+}
+
+impl Read for ResolvedInput {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let position = self.position;
+        let Some(segment) = self.segments.iter().find(|x| position < x.start + x.len) else { return Ok(0); };
+        let offset = position - segment.start;
+        let len = buf.len().min((segment.len - offset) as usize);
+        let read = match &segment.source {
+            Source::Input(start) => { self.input.seek(SeekFrom::Start(start + offset))?; self.input.read(&mut buf[..len])? },
+            Source::External(i) => { self.externals[*i].seek(SeekFrom::Start(offset))?; self.externals[*i].read(&mut buf[..len])? },
+            Source::Bytes(x) => { buf[..len].copy_from_slice(&x[offset as usize..offset as usize + len]); len }
+        };
+        for (patch_offset, bytes) in &self.patches {
+            let start = (*patch_offset).max(position);
+            let end = (patch_offset + bytes.len() as u64).min(position + read as u64);
+            if start < end {
+                buf[(start - position) as usize..(end - position) as usize].copy_from_slice(&bytes[(start - patch_offset) as usize..(end - patch_offset) as usize]);
+            }
+        }
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for ResolvedInput {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::End(x) => self.len.checked_add_signed(x),
+            SeekFrom::Current(x) => self.position.checked_add_signed(x),
+        };
+        self.position = position.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid seek to a negative position"))?;
+        Ok(self.position)
+    }
+}
+
+/// Input file opened by path
+pub(crate) enum InputFile {
+    File(File),
+    Resolved(Box<ResolvedInput>),
+    Rebuilt(Box<crate::chapter_repair::RebuiltInput<InputFile>>),
+    Defragmented(Box<crate::defragment::DefragmentedInput<File>>),
+}
+
+impl InputFile {
+    /// Open an input, resolving the media it references in other files and reading a fragmented file as a regular one.
+    /// Returns the file and its size
+    pub(crate) fn open(path: &Path, search_paths: &[PathBuf]) -> Result<(Self, u64)> {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        if crate::defragment::is_fragmented(&mut std::io::BufReader::with_capacity(16*1024, &mut file))? {
+            diag!(Debug, "{} is fragmented, reading its fragments", path.display());
+            file.seek(SeekFrom::Start(0))?;
+            let defragmented = crate::defragment::DefragmentedInput::new(file)?;
+            let len = defragmented.len();
+            return Ok((Self::Defragmented(Box::new(defragmented)), len));
+        }
+        let resolved = ResolvedInput::open(file.try_clone()?, path, search_paths)?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(match resolved {
+            Some(x) => { let len = x.len; (Self::Resolved(Box::new(x)), len) },
+            None => (Self::File(file), len)
+        })
+    }
+}
+
+impl Read for InputFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            Self::File(x) => x.read(buf),
+            Self::Resolved(x) => x.read(buf),
+            Self::Rebuilt(x) => x.read(buf),
+            Self::Defragmented(x) => x.read(buf),
+        }
+    }
+}
+
+impl Seek for InputFile {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        match self {
+            Self::File(x) => x.seek(pos),
+            Self::Resolved(x) => x.seek(pos),
+            Self::Rebuilt(x) => x.seek(pos),
+            Self::Defragmented(x) => x.seek(pos),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dref() {
+        let mut data = vec![0, 0, 0, 0, 0, 0, 0, 2];
+        data.extend_from_slice(&[0, 0, 0, 12]);
+        data.extend_from_slice(b"url \0\0\0\x01");
+        data.extend_from_slice(&[0, 0, 0, 36]);
+        data.extend_from_slice(b"url \0\0\0\0file:///Media/A%20B.mov\0");
+        let entries = parse_dref(&data, 100);
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].self_contained);
+        assert_eq!((entries[1].self_contained, entries[1].location.as_str(), entries[1].flags_offset), (false, "file:///Media/A%20B.mov", 128));
+        assert_eq!(percent_decode("A%20B.mov"), "A B.mov");
+        assert_eq!(resolve_location("file:///nonexistent/A%20B.mov", Path::new("/nonexistent"), &[]), None);
+    }
+}
@@ -0,0 +1,46 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, Result, SeekFrom };
+use crate::{ desc_reader, stsd };
+
+/// A single video sample with everything needed to decode it
+#[derive(Debug, Clone, Default)]
+pub struct VideoSample {
+    /// Sample entry type, e.g. "avc1", "hvc1" or "av01"
+    pub codec: String,
+    /// Type and payload of the decoder configuration box from stsd, e.g. ("avcC", ...)
+    pub config: Option<(String, Vec<u8>)>,
+    /// Sample data, as stored in mdat
+    pub data: Vec<u8>,
+    /// Decode time of the sample in seconds
+    pub time: f64,
+}
+
+const CONFIG_BOXES: &[&str] = &["avcC", "hvcC", "av1C", "vpcC"];
+
+/// Extract the first keyframe of the first video track, e.g. to generate a thumbnail of the merged output.
+/// Returns None if the file has no video track.
+pub fn extract_first_video_sample<R: Read + Seek>(reader: &mut R) -> Result<Option<VideoSample>> {
+    let desc = desc_reader::read_file_desc(reader)?;
+    let entries = stsd::read_sample_entries(reader)?;
+    let mdat_start = desc.mdat_final_position;
+
+    let Some(track_index) = desc.moov_tracks.iter().position(|t| t.handler_type == "vide" && !t.skip && t.stsz_count > 0) else { return Ok(None); };
+    let track = &desc.moov_tracks[track_index];
+    let Some(sample) = track.sample_infos().into_iter().find(|x| x.is_sync) else { return Ok(None); };
+
+    let entry = entries.get(track_index).and_then(|x| x.get(sample.description_index.max(1) as usize - 1));
+    let config = entry.and_then(|e| e.boxes.iter().find(|(typ, _)| CONFIG_BOXES.contains(&typ.as_str())).cloned());
+
+    let mut data = vec![0u8; sample.size as usize];
+    reader.seek(SeekFrom::Start(mdat_start.wrapping_add(sample.offset)))?;
+    reader.read_exact(&mut data)?;
+
+    Ok(Some(VideoSample {
+        codec: entry.map(|e| e.codec.clone()).unwrap_or_default(),
+        config,
+        data,
+        time: sample.decode_time as f64 / track.mdhd_timescale.max(1) as f64,
+    }))
+}
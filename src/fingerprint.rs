@@ -0,0 +1,67 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::hash::Hasher;
+use std::io::{ Read, Seek, Result, SeekFrom };
+
+/// Bytes hashed at the start and at the end of each input
+const HASHED_SIZE: u64 = 64 * 1024;
+
+/// Size and a hash of the start and the end of an input, to detect files which are modified during the merge,
+/// e.g. still being recorded or copied. Cameras write the moov last, so a changing file also changes its tail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Fingerprint {
+    size: u64,
+    hash: u64,
+}
+
+impl Fingerprint {
+    pub(crate) fn read<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        let size = reader.seek(SeekFrom::End(0))?;
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        let mut buf = Vec::with_capacity(HASHED_SIZE as usize);
+        for start in [0, size.saturating_sub(HASHED_SIZE)] {
+            buf.clear();
+            reader.seek(SeekFrom::Start(start))?;
+            reader.take(HASHED_SIZE).read_to_end(&mut buf)?;
+            hasher.write(&buf);
+        }
+        reader.seek(SeekFrom::Start(0))?;
+        Ok(Self { size, hash: hasher.finish() })
+    }
+}
+
+/// Fail if any input changed since its fingerprint was taken. `input_order` maps the files to their index in the input list
+pub(crate) fn verify_unchanged<R: Read + Seek>(files: &mut [(R, usize)], fingerprints: &[Fingerprint], input_order: &[usize]) -> Result<()> {
+    for (i, (file, fingerprint)) in files.iter_mut().zip(fingerprints).enumerate() {
+        let current = Fingerprint::read(&mut file.0)?;
+        if current != *fingerprint {
+            let input_index = input_order.get(i).copied().unwrap_or(i);
+            let change = if current.size != fingerprint.size { format!("its size changed from {} to {} bytes", fingerprint.size, current.size) } else { "its content changed".to_string() };
+            return Err(std::io::Error::other(format!("Input file {input_index} was modified during the merge, {change}. Wait until it's completely written")));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_unchanged() {
+        let mut files = vec![(std::io::Cursor::new(vec![1u8; 200_000]), 200_000), (std::io::Cursor::new(vec![2u8; 100]), 100)];
+        let fingerprints = files.iter_mut().map(|x| Fingerprint::read(&mut x.0)).collect::<Result<Vec<_>>>().unwrap();
+        assert!(verify_unchanged(&mut files, &fingerprints, &[0, 1]).is_ok());
+
+        // Data changed in the middle isn't hashed
+        files[0].0.get_mut()[100_000] = 0;
+        assert!(verify_unchanged(&mut files, &fingerprints, &[0, 1]).is_ok());
+
+        files[0].0.get_mut()[199_999] = 0;
+        assert!(verify_unchanged(&mut files, &fingerprints, &[1, 0]).unwrap_err().to_string().contains("Input file 1 was modified"));
+        files[0].0.get_mut()[199_999] = 1;
+        files[1].0.get_mut().push(3);
+        assert!(verify_unchanged(&mut files, &fingerprints, &[0, 1]).unwrap_err().to_string().contains("from 100 to 101 bytes"));
+    }
+}
@@ -0,0 +1,249 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::*;
+use crate::{ fourcc, read_box, diagnostics::diag };
+
+/// Recording identification stored by GoPro cameras in moov/udta
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GoProUdta {
+    pub media_uid: Option<Vec<u8>>,  // MUID - unique per file
+    pub capture_id: Option<Vec<u8>>, // CPID - shared by all chapters of one recording
+    pub chapter: Option<u32>,        // CPIN - 1-based chapter (part) number
+    pub camera: CameraInfo,
+}
+
+/// Camera which recorded the file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CameraInfo {
+    /// Camera model, e.g. "HERO10 Black"
+    pub model: Option<String>,
+    /// Camera serial number, or its hash on GoPro cameras
+    pub serial: Option<String>,
+    pub firmware: Option<String>,
+    /// Lens serial number
+    pub lens: Option<String>,
+}
+
+impl CameraInfo {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+// QuickTime metadata boxes (©mod, ©swr) written by other vendors
+const QT_MODEL: u32    = u32::from_be_bytes([0xA9, b'm', b'o', b'd']);
+const QT_SOFTWARE: u32 = u32::from_be_bytes([0xA9, b's', b'w', b'r']);
+
+/// Printable text of a string value, or the hex representation of binary data
+fn value_to_string(data: &[u8]) -> Option<String> {
+    let data = &data[..data.iter().rposition(|x| *x != 0).map(|x| x + 1).unwrap_or(0)];
+    if data.is_empty() { return None; }
+    if data.iter().all(|x| x.is_ascii_graphic() || *x == b' ') {
+        Some(String::from_utf8_lossy(data).trim().to_string())
+    } else {
+        Some(data.iter().map(|x| format!("{x:02X}")).collect())
+    }
+}
+
+/// Text of a QuickTime ©xxx box: 16-bit length, 16-bit language, string
+fn qt_string(data: &[u8]) -> Option<String> {
+    let len = u16::from_be_bytes([*data.first()?, *data.get(1)?]) as usize;
+    value_to_string(data.get(4..4 + len)?)
+}
+
+impl GoProUdta {
+    fn set_value(&mut self, key: u32, struct_size: u8, data: &[u8]) {
+        if key == fourcc("FIRM") {
+            self.camera.firmware = value_to_string(data);
+        } else if key == fourcc("LENS") {
+            self.camera.lens = value_to_string(data);
+        } else if key == fourcc("CAME") {
+            self.camera.serial = value_to_string(data);
+        } else if key == fourcc("MINF") {
+            self.camera.model = value_to_string(data);
+        } else if key == QT_MODEL {
+            self.camera.model = self.camera.model.take().or_else(|| qt_string(data));
+        } else if key == QT_SOFTWARE {
+            self.camera.firmware = self.camera.firmware.take().or_else(|| qt_string(data));
+        } else if key == fourcc("MUID") {
+            self.media_uid = Some(data.to_vec());
+        } else if key == fourcc("CPID") {
+            self.capture_id = Some(data.to_vec());
+        } else if key == fourcc("CPIN") {
+            self.chapter = match (struct_size, data) {
+                (1, [a, ..])          => Some(*a as u32),
+                (2, [a, b, ..])       => Some(u16::from_be_bytes([*a, *b]) as u32),
+                (4, [a, b, c, d, ..]) => Some(u32::from_be_bytes([*a, *b, *c, *d])),
+                _ => None
+            };
+        }
+    }
+}
+
+/// Read the GoPro udta metadata of a single file.
+/// Both the older layout (every value in its own box) and the newer one (a GPMF payload inside udta) are supported.
+pub fn read_udta_info<R: Read + Seek>(reader: &mut R) -> Result<GoProUdta> {
+    let mut info = GoProUdta::default();
+    reader.seek(SeekFrom::Start(0))?;
+    read_udta_boxes(reader, u64::MAX, false, &mut info)?;
+    Ok(info)
+}
+
+fn read_udta_boxes<R: Read + Seek>(reader: &mut R, max_read: u64, in_udta: bool, info: &mut GoProUdta) -> Result<()> {
+    let start_pos = reader.stream_position()?;
+    while reader.stream_position()? - start_pos < max_read {
+        let Ok((typ, _offs, size, header_size)) = read_box(reader) else { break; };
+        if size == 0 || typ == 0 { break; }
+        let org_pos = reader.stream_position()?;
+        let content_size = size - header_size as u64;
+
+        if !in_udta && (typ == fourcc("moov") || typ == fourcc("udta")) {
+            read_udta_boxes(reader, content_size, typ == fourcc("udta"), info)?;
+        } else if in_udta && typ == fourcc("GPMF") {
+            let mut buf = vec![0u8; content_size as usize];
+            reader.read_exact(&mut buf)?;
+            crate::gpmf::walk_klv(&buf, &mut |header, data| info.set_value(header.key, header.struct_size, data));
+        } else if in_udta && content_size <= 1024 {
+            let mut buf = vec![0u8; content_size as usize];
+            reader.read_exact(&mut buf)?;
+            info.set_value(typ, 4, &buf);
+        }
+        reader.seek(SeekFrom::Start(org_pos + content_size))?;
+    }
+    Ok(())
+}
+
+/// Merge order established from the chapter numbers
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChapterOrder {
+    pub order: Vec<usize>,  // order[i] = index of the input file that should be merged at position i
+    pub missing: Vec<u32>,  // Chapter numbers not present in the input list
+}
+
+/// Establish the merge order from the GoPro chapter numbers.
+/// Returns None if any file lacks the chapter number, the files come from different recordings or a chapter is duplicated.
+pub fn chapter_order(info: &[GoProUdta]) -> Option<ChapterOrder> {
+    let chapters = info.iter().map(|x| x.chapter).collect::<Option<Vec<u32>>>()?;
+    if info.iter().any(|x| x.capture_id != info[0].capture_id) {
+        diag!(Warn, "Input files come from different GoPro recordings");
+        return None;
+    }
+
+    let mut order = (0..info.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| chapters[i]);
+    if order.windows(2).any(|w| chapters[w[0]] == chapters[w[1]]) {
+        diag!(Warn, "Duplicated GoPro chapter numbers in the input files");
+        return None;
+    }
+
+    let max_chapter = chapters.iter().copied().max().unwrap_or(0);
+    let missing = (1..=max_chapter).filter(|x| !chapters.contains(x)).collect();
+
+    Some(ChapterOrder { order, missing })
+}
+
+/// Reorder `items` in place so that `items[i]` becomes the original `items[order[i]]`
+pub fn apply_order<T>(items: &mut [T], order: &[usize]) {
+    for i in 0..order.len() {
+        let mut j = order[i];
+        while j < i { j = order[j]; }
+        items.swap(i, j);
+    }
+}
+
+/// Read the udta info from every file
+pub fn read_all_udta_info<R: Read + Seek>(files: &mut [(R, usize)]) -> Result<Vec<GoProUdta>> {
+    let mut info = Vec::with_capacity(files.len());
+    for (file, _size) in files.iter_mut() {
+        let mut reader = std::io::BufReader::with_capacity(16*1024, &mut *file);
+        info.push(read_udta_info(&mut reader).unwrap_or_else(|e| {
+            diag!(Warn, "Failed to read udta: {e:?}");
+            GoProUdta::default()
+        }));
+        drop(reader);
+        file.seek(SeekFrom::Start(0))?;
+    }
+    Ok(info)
+}
+
+/// Reorder the files and their udta `info` by chapter number.
+/// Returns the applied order, or None if the files don't contain the chapter information.
+pub fn sort_by_chapters<R: Read + Seek>(files: &mut [(R, usize)], info: &mut [GoProUdta]) -> Result<Option<Vec<usize>>> {
+    let Some(chapters) = chapter_order(info) else { return Ok(None); };
+    for missing in &chapters.missing {
+        diag!(Warn, "GoPro chapter {missing} is missing from the input files");
+    }
+    if chapters.order.iter().enumerate().any(|(i, x)| i != *x) {
+        diag!(Warn, "Input files are not in chapter order, reordering to {:?}", chapters.order);
+        apply_order(files, &chapters.order);
+        apply_order(info, &chapters.order);
+    }
+    Ok(Some(chapters.order))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chapter(capture_id: u8, chapter: u32) -> GoProUdta {
+        GoProUdta { capture_id: Some(vec![capture_id; 4]), chapter: Some(chapter), ..Default::default() }
+    }
+
+    #[test]
+    fn test_chapter_order_sorts_and_reports_missing() {
+        let info = vec![chapter(1, 3), chapter(1, 1), chapter(1, 4)];
+        let order = chapter_order(&info).unwrap();
+        assert_eq!(order.order, vec![1, 0, 2]);
+        assert_eq!(order.missing, vec![2]);
+
+        let mut items = vec!["c3", "c1", "c4"];
+        apply_order(&mut items, &order.order);
+        assert_eq!(items, vec!["c1", "c3", "c4"]);
+    }
+
+    #[test]
+    fn test_chapter_order_rejects_mixed_recordings() {
+        assert!(chapter_order(&[chapter(1, 1), chapter(2, 2)]).is_none());
+        assert!(chapter_order(&[chapter(1, 1), chapter(1, 1)]).is_none());
+        assert!(chapter_order(&[chapter(1, 1), GoProUdta::default()]).is_none());
+    }
+
+    #[test]
+    fn test_apply_order_cycles() {
+        let order = vec![2, 0, 3, 1];
+        let mut items = vec![10, 11, 12, 13];
+        apply_order(&mut items, &order);
+        assert_eq!(items, vec![12, 10, 13, 11]);
+    }
+
+    #[test]
+    fn test_read_udta_info_gpmf_payload() {
+        let mut klv = fourcc("CPIN").to_be_bytes().to_vec();
+        klv.extend([b'L', 4, 0, 1]);
+        klv.extend(2u32.to_be_bytes());
+        let mut gpmf = ((klv.len() + 8) as u32).to_be_bytes().to_vec();
+        gpmf.extend(fourcc("GPMF").to_be_bytes());
+        gpmf.extend(klv);
+        let mut udta = ((gpmf.len() + 8) as u32).to_be_bytes().to_vec();
+        udta.extend(fourcc("udta").to_be_bytes());
+        udta.extend(gpmf);
+        let mut moov = ((udta.len() + 8) as u32).to_be_bytes().to_vec();
+        moov.extend(fourcc("moov").to_be_bytes());
+        moov.extend(udta);
+
+        let info = read_udta_info(&mut Cursor::new(moov)).unwrap();
+        assert_eq!(info.chapter, Some(2));
+    }
+
+    #[test]
+    fn test_camera_info_values() {
+        let mut info = GoProUdta::default();
+        info.set_value(fourcc("FIRM"), 4, b"H21.01.01.62.00\0");
+        info.set_value(fourcc("CAME"), 4, &[0xAB, 0x01, 0x00, 0xFF]);
+        info.set_value(QT_MODEL, 4, b"\0\x05\x15\xC7Pixel");
+        assert_eq!(info.camera.firmware.as_deref(), Some("H21.01.01.62.00"));
+        assert_eq!(info.camera.serial.as_deref(), Some("AB0100FF"));
+        assert_eq!(info.camera.model.as_deref(), Some("Pixel"));
+    }
+}
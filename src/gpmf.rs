@@ -2,17 +2,22 @@
 // Copyright © 2022 Adrian <adrian.eddy at gmail>
 
 use std::io::*;
+use std::path::Path;
+use std::time::{ Duration, SystemTime };
 use byteorder::{BigEndian, ReadBytesExt};
-use crate::{fourcc, read_box, typ_to_str};
+use crate::{fourcc, read_box, typ_to_str, diagnostics::diag};
 
 /// GoPro GPMF (General Purpose Metadata Format) handler type identifier
 pub const GPMF_HANDLER_TYPE: &str = "meta";
 
 /// GPMF GPS data stream identifier - used to detect GPS data in GPMF payloads
 const GPMF_GPS_STREAM_ID: u32 = fourcc("GPS5"); // GPS5 = GPS data (lat, lon, alt, speed2d, speed3d)
+const GPMF_GPS9_STREAM_ID: u32 = fourcc("GPS9"); // GPS9 = GPS5 followed by days, seconds, DOP and fix (HERO11 and later)
 const GPMF_GPS_TIME_ID: u32 = fourcc("GPSU"); // GPSU = GPS timestamp (UTC)
+const GPMF_GPS_FIX_ID: u32 = fourcc("GPSF"); // GPSF = GPS fix (0 - no lock, 2 - 2D lock, 3 - 3D lock)
 const GPMF_GYRO_ID: u32 = fourcc("GYRO"); // GYRO = gyroscope data
 const GPMF_ACCL_ID: u32 = fourcc("ACCL"); // ACCL = accelerometer data
+const GPMF_SCALE_ID: u32 = fourcc("SCAL"); // SCAL = divisor of the values of the stream, one for all or one per channel
 
 /// Represents a GPMF GPS sample with timestamp and location data
 #[derive(Debug, Clone)]
@@ -25,8 +30,28 @@ pub struct GpmfGpsSample {
     pub speed_3d: f64,              // 3D speed in m/s
 }
 
+/// A gyroscope (rad/s) or accelerometer (m/s²) sample, with the axes in the order stored by the camera
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GpmfImuSample {
+    pub timestamp_us: u64,           // Timestamp in microseconds
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+/// GPS, gyroscope and accelerometer samples of the GPMF track of a file, timestamped from the start of the track
+#[derive(Debug, Clone, Default)]
+pub struct GpmfTelemetry {
+    pub gps: Vec<GpmfGpsSample>,
+    /// GPSU of each payload with a GPS lock, with the timestamp of the payload
+    pub gps_utc: Vec<(u64, SystemTime)>,
+    pub gyro: Vec<GpmfImuSample>,
+    pub accl: Vec<GpmfImuSample>,
+}
+
 /// Represents a GPMF track containing GPS samples from a single file
 #[derive(Debug, Clone)]
+#[allow(dead_code)]
 pub struct GpmfTrackData {
     pub samples: Vec<GpmfGpsSample>,
     pub duration_seconds: f64,
@@ -132,7 +157,7 @@ impl GpmfProcessor {
                 
                 if handler_type_str == GPMF_HANDLER_TYPE {
                     is_metadata_track = true;
-                    log::debug!("Found metadata track with handler type: {}", handler_type_str);
+                    diag!(Debug, "Found metadata track with handler type: {}", handler_type_str);
                 }
                 reader.seek(SeekFrom::Current(size as i64 - header_size - 12))?; // Skip rest of hdlr
             } else if typ == fourcc("stsd") && is_metadata_track {
@@ -172,7 +197,7 @@ impl GpmfProcessor {
             
             // Look for 'gpmd' (GoPro Metadata) sample description
             if entry_typ == fourcc("gpmd") {
-                log::debug!("Found GPMF sample description entry");
+                diag!(Debug, "Found GPMF sample description entry");
                 return Ok(true);
             }
             
@@ -192,10 +217,11 @@ impl GpmfProcessor {
         // Extract GPS samples from GPMF metadata track
         let gps_samples = self.extract_gps_samples_from_mdat(reader)?;
         
+        let sample_rate = if file_duration > 0.0 && !gps_samples.is_empty() { gps_samples.len() as f64 / file_duration } else { 1.0 }; // Default 1Hz for GPS
         let track_data = GpmfTrackData {
             samples: gps_samples,
             duration_seconds: file_duration,
-            sample_rate: 1.0, // Default 1Hz for GPS
+            sample_rate,
         };
         
         self.tracks.push(track_data);
@@ -206,16 +232,8 @@ impl GpmfProcessor {
 
     /// Extract GPS samples from GPMF data in mdat box
     fn extract_gps_samples_from_mdat<R: Read + Seek>(&self, reader: &mut R) -> Result<Vec<GpmfGpsSample>> {
-        let mut samples = Vec::new();
-        
-        // For now, return empty samples - this will be enhanced to parse actual GPMF
-        // The full implementation would:
-        // 1. Find metadata track samples in mdat
-        // 2. Parse GPMF format to extract GPS5 and GPSU streams
-        // 3. Convert GPS data to GpmfGpsSample format
-        
-        log::debug!("GPMF GPS extraction placeholder - would extract {} samples", samples.len());
-        
+        let samples = read_gpmf_telemetry(reader)?.gps;
+        diag!(Debug, "Extracted {} GPMF GPS samples", samples.len());
         Ok(samples)
     }
 
@@ -245,6 +263,7 @@ impl GpmfProcessor {
     }
 
     /// Create GPMF metadata payload from merged GPS samples
+    #[allow(dead_code)]
     pub fn create_merged_gpmf_payload(&self, _merged_samples: &[GpmfGpsSample]) -> Result<Vec<u8>> {
         // For now, return empty payload - this would be extended to create actual GPMF format
         let payload = Vec::new();
@@ -256,6 +275,7 @@ impl GpmfProcessor {
     }
 
     /// Write merged GPMF metadata to output file
+    #[allow(dead_code)]
     pub fn write_merged_metadata<W: Write + Seek>(
         &self,
         _output: &mut W,
@@ -268,6 +288,488 @@ impl GpmfProcessor {
     }
 }
 
+/// Location of a single GPMF payload (one sample of the `gpmd` track) inside a file
+#[derive(Debug, Clone, Copy)]
+pub struct GpmfSampleRef {
+    pub offset: u64,    // Absolute file offset of the payload
+    pub size: u32,      // Payload size in bytes
+    pub time: u64,      // Decode time in the track's media timescale
+}
+
+/// Sample table of the GPMF metadata track of a single file
+#[derive(Debug, Clone, Default)]
+pub struct GpmfSampleTable {
+    pub timescale: u32,
+    pub duration: u64,  // Track duration in the media timescale
+    pub samples: Vec<GpmfSampleRef>,
+}
+
+/// Raw tables collected while walking a single `trak` box
+#[derive(Default)]
+struct RawTrackTables {
+    handler_type: u32,
+    sample_entry: u32,
+    timescale: u32,
+    duration: u64,
+    stts: Vec<(u32, u32)>,
+    stsz: Vec<u32>,
+    stsz_sample_size: u32,
+    stsz_count: u32,
+    stsc: Vec<(u32, u32)>, // first_chunk, samples_per_chunk
+    stco: Vec<u64>,
+}
+
+impl RawTrackTables {
+    fn into_sample_table(self) -> GpmfSampleTable {
+        let mut samples = Vec::with_capacity(self.stsz_count as usize);
+        let mut sample_index = 0usize;
+        let mut stts_iter = self.stts.iter().flat_map(|(count, delta)| std::iter::repeat_n(*delta, *count as usize));
+        let mut time = 0u64;
+        for (chunk_index, chunk_offset) in self.stco.iter().enumerate() {
+            let chunk_number = chunk_index as u32 + 1;
+            let samples_per_chunk = self.stsc.iter().rev().find(|x| x.0 <= chunk_number).map(|x| x.1).unwrap_or(0);
+            let mut offset = *chunk_offset;
+            for _ in 0..samples_per_chunk {
+                if sample_index >= self.stsz_count as usize { break; }
+                let size = if self.stsz_sample_size > 0 { self.stsz_sample_size } else { self.stsz[sample_index] };
+                samples.push(GpmfSampleRef { offset, size, time });
+                offset += size as u64;
+                time += stts_iter.next().unwrap_or(0) as u64;
+                sample_index += 1;
+            }
+        }
+        GpmfSampleTable { timescale: self.timescale, duration: self.duration, samples }
+    }
+}
+
+/// Read the sample table of the first GPMF (`gpmd`) track in the file
+pub fn read_gpmf_sample_table<R: Read + Seek>(reader: &mut R) -> Result<Option<GpmfSampleTable>> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut current = RawTrackTables::default();
+    let mut found = None;
+    walk_track_tables(reader, u64::MAX, &mut current, &mut found)?;
+    Ok(found)
+}
+
+fn walk_track_tables<R: Read + Seek>(reader: &mut R, max_read: u64, current: &mut RawTrackTables, found: &mut Option<GpmfSampleTable>) -> Result<()> {
+    let start_pos = reader.stream_position()?;
+    while found.is_none() && reader.stream_position()? - start_pos < max_read {
+        let Ok((typ, _offs, size, header_size)) = read_box(reader) else { break; };
+        if size == 0 || typ == 0 { break; }
+        let org_pos = reader.stream_position()?;
+        let content_size = size - header_size as u64;
+
+        if typ == fourcc("trak") {
+            *current = RawTrackTables::default();
+            walk_track_tables(reader, content_size, current, found)?;
+            if found.is_none() && typ_to_str(current.handler_type) == GPMF_HANDLER_TYPE && current.sample_entry == fourcc("gpmd") {
+                *found = Some(std::mem::take(current).into_sample_table());
+            }
+        } else if typ != fourcc("stsd") && crate::has_children(typ, true) {
+            walk_track_tables(reader, content_size, current, found)?;
+        } else {
+            let (v, _flags) = (reader.read_u8()?, reader.read_u24::<BigEndian>()?);
+            if typ == fourcc("hdlr") {
+                reader.seek(SeekFrom::Current(4))?; // Skip pre_defined
+                current.handler_type = reader.read_u32::<BigEndian>()?;
+            } else if typ == fourcc("stsd") {
+                if reader.read_u32::<BigEndian>()? > 0 {
+                    current.sample_entry = read_box(reader)?.0;
+                }
+            } else if typ == fourcc("mdhd") {
+                if v == 1 { reader.seek(SeekFrom::Current(8+8))?; } else { reader.seek(SeekFrom::Current(4+4))?; }
+                current.timescale = reader.read_u32::<BigEndian>()?;
+                current.duration = if v == 1 { reader.read_u64::<BigEndian>()? } else { reader.read_u32::<BigEndian>()? as u64 };
+            } else if typ == fourcc("stts") {
+                let count = reader.read_u32::<BigEndian>()?;
+                for _ in 0..count { current.stts.push((reader.read_u32::<BigEndian>()?, reader.read_u32::<BigEndian>()?)); }
+            } else if typ == fourcc("stsz") {
+                current.stsz_sample_size = reader.read_u32::<BigEndian>()?;
+                current.stsz_count = reader.read_u32::<BigEndian>()?;
+                if current.stsz_sample_size == 0 {
+                    for _ in 0..current.stsz_count { current.stsz.push(reader.read_u32::<BigEndian>()?); }
+                }
+            } else if typ == fourcc("stsc") {
+                let count = reader.read_u32::<BigEndian>()?;
+                for _ in 0..count {
+                    current.stsc.push((reader.read_u32::<BigEndian>()?, reader.read_u32::<BigEndian>()?));
+                    reader.seek(SeekFrom::Current(4))?; // Skip sample_description_index
+                }
+            } else if typ == fourcc("stco") || typ == fourcc("co64") {
+                let count = reader.read_u32::<BigEndian>()?;
+                for _ in 0..count {
+                    current.stco.push(if typ == fourcc("co64") { reader.read_u64::<BigEndian>()? } else { reader.read_u32::<BigEndian>()? as u64 });
+                }
+            }
+        }
+        reader.seek(SeekFrom::Start(org_pos + content_size))?;
+    }
+    Ok(())
+}
+
+/// Header of a single GPMF KLV (key, length, value) entry
+#[derive(Debug, Clone, Copy)]
+pub struct KlvHeader {
+    pub key: u32,
+    pub typ: u8,          // Value type, 0 for nested entries
+    pub struct_size: u8,  // Size of a single sample in bytes
+    pub repeat: u16,      // Number of samples
+}
+
+impl KlvHeader {
+    pub fn data_size(&self) -> usize { self.struct_size as usize * self.repeat as usize }
+
+    /// Header at the start of `data`, None at the end of the entries
+    fn parse(data: &[u8]) -> Option<Self> {
+        let header = data.get(..8)?;
+        let header = KlvHeader {
+            key: u32::from_be_bytes([header[0], header[1], header[2], header[3]]),
+            typ: header[4],
+            struct_size: header[5],
+            repeat: u16::from_be_bytes([header[6], header[7]]),
+        };
+        (header.key != 0).then_some(header) // Padding
+    }
+
+    /// Append the entry with `data` as its value, padded to 4 bytes. The repeat count is taken from the size of the data
+    fn write(mut self, data: &[u8], output: &mut Vec<u8>) {
+        if self.struct_size > 0 {
+            self.repeat = (data.len() / self.struct_size as usize) as u16;
+        }
+        output.extend_from_slice(&self.key.to_be_bytes());
+        output.extend_from_slice(&[self.typ, self.struct_size]);
+        output.extend_from_slice(&self.repeat.to_be_bytes());
+        output.extend_from_slice(data);
+        output.resize(output.len() + (4 - data.len() % 4) % 4, 0);
+    }
+}
+
+/// A GPMF KLV entry with its nested entries
+#[derive(Debug, Clone)]
+pub(crate) struct Klv {
+    pub header: KlvHeader,
+    pub data: Vec<u8>,      // Value of leaf entries
+    pub children: Vec<Klv>, // Entries of nested entries (DEVC, STRM)
+}
+
+/// Walk all leaf KLV entries in a GPMF payload, recursing into nested entries (DEVC, STRM)
+pub fn walk_klv<F: FnMut(&KlvHeader, &[u8])>(data: &[u8], f: &mut F) {
+    for (header, value) in klv_entries(data) {
+        if header.typ == 0 {
+            walk_klv(value, f);
+        } else {
+            f(&header, value);
+        }
+    }
+}
+
+/// Same as `walk_klv`, but the values can be modified in place
+pub fn walk_klv_mut<F: FnMut(&KlvHeader, &mut [u8])>(data: &mut [u8], f: &mut F) {
+    let mut pos = 0;
+    while let Some(header) = data.get(pos..).and_then(KlvHeader::parse) {
+        let start = pos + 8;
+        let end = start + header.data_size();
+        if end > data.len() { break; }
+        if header.typ == 0 {
+            walk_klv_mut(&mut data[start..end], f);
+        } else {
+            f(&header, &mut data[start..end]);
+        }
+        pos = start + ((header.data_size() + 3) & !3);
+    }
+}
+
+/// Entries directly inside a GPMF payload or nested entry, without descending into the nested ones
+fn klv_entries(data: &[u8]) -> Vec<(KlvHeader, &[u8])> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while let Some(header) = data.get(pos..).and_then(KlvHeader::parse) {
+        let start = pos + 8;
+        let Some(value) = data.get(start..start + header.data_size()) else { break; };
+        entries.push((header, value));
+        pos = start + ((header.data_size() + 3) & !3);
+    }
+    entries
+}
+
+/// Parse a GPMF payload into a tree of entries, which can be modified and written back with `write_klv`
+pub(crate) fn parse_klv(data: &[u8]) -> Vec<Klv> {
+    klv_entries(data).into_iter().map(|(header, value)| match header.typ {
+        0 => Klv { header, data: Vec::new(), children: parse_klv(value) },
+        _ => Klv { header, data: value.to_vec(), children: Vec::new() }
+    }).collect()
+}
+
+/// Serialize the entries of `parse_klv`, with the repeat counts updated to the size of the values
+pub(crate) fn write_klv(klvs: &[Klv], output: &mut Vec<u8>) {
+    for klv in klvs {
+        if klv.header.typ == 0 {
+            let mut nested = Vec::new();
+            write_klv(&klv.children, &mut nested);
+            klv.header.write(&nested, output);
+        } else {
+            klv.header.write(&klv.data, output);
+        }
+    }
+}
+
+/// A single KLV entry of `key` with `data` as its value, made of samples of `struct_size` bytes. A `typ` of 0 nests the entries in `data`
+pub(crate) fn klv(key: &[u8; 4], typ: u8, struct_size: u8, data: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::new();
+    KlvHeader { key: u32::from_be_bytes(*key), typ, struct_size, repeat: 0 }.write(data, &mut ret);
+    ret
+}
+
+/// Call `f` with the entries of every stream (STRM) of a payload, in order
+fn for_each_stream<F: FnMut(&[(KlvHeader, &[u8])])>(data: &[u8], f: &mut F) {
+    let entries = klv_entries(data);
+    if entries.iter().any(|x| x.0.typ == 0) {
+        for (_, value) in entries.iter().filter(|x| x.0.typ == 0) {
+            for_each_stream(value, f);
+        }
+    } else {
+        f(&entries);
+    }
+}
+
+/// Numeric values of a KLV entry, None for strings and complex types
+fn klv_values(header: &KlvHeader, data: &[u8]) -> Option<Vec<f64>> {
+    let size = match header.typ {
+        b'b' | b'B' => 1,
+        b's' | b'S' => 2,
+        b'l' | b'L' | b'f' => 4,
+        b'd' | b'j' | b'J' => 8,
+        _ => return None
+    };
+    Some(data.chunks_exact(size).map(|x| match header.typ {
+        b'b' => x[0] as i8 as f64,
+        b'B' => x[0] as f64,
+        b's' => i16::from_be_bytes([x[0], x[1]]) as f64,
+        b'S' => u16::from_be_bytes([x[0], x[1]]) as f64,
+        b'l' => i32::from_be_bytes(x.try_into().unwrap()) as f64,
+        b'L' => u32::from_be_bytes(x.try_into().unwrap()) as f64,
+        b'f' => f32::from_be_bytes(x.try_into().unwrap()) as f64,
+        b'd' => f64::from_be_bytes(x.try_into().unwrap()),
+        b'j' => i64::from_be_bytes(x.try_into().unwrap()) as f64,
+        _ => u64::from_be_bytes(x.try_into().unwrap()) as f64,
+    }).collect())
+}
+
+/// Parse the GPS5/GPS9, GPSU, GYRO and ACCL streams of a single GPMF payload, which starts at `start_us` and lasts `duration_us`.
+/// The samples of each stream are spread evenly over the payload
+pub fn parse_gpmf_payload(payload: &[u8], start_us: u64, duration_us: u64, telemetry: &mut GpmfTelemetry) {
+    if let Some(time) = payload_gps_time(payload) {
+        telemetry.gps_utc.push((start_us, time));
+    }
+    for_each_stream(payload, &mut |entries| {
+        let scale = entries.iter().find(|x| x.0.key == GPMF_SCALE_ID).and_then(|x| klv_values(&x.0, x.1)).unwrap_or_default();
+        for (header, data) in entries {
+            if ![GPMF_GPS_STREAM_ID, GPMF_GPS9_STREAM_ID, GPMF_GYRO_ID, GPMF_ACCL_ID].contains(&header.key) { continue; }
+            let Some(values) = klv_values(header, data) else { continue; };
+            let repeat = header.repeat as usize;
+            if repeat == 0 { continue; }
+            let channels = values.len() / repeat;
+            for (i, sample) in values.chunks_exact(channels.max(1)).enumerate() {
+                let value = |c: usize| sample.get(c).copied().unwrap_or_default() / scale.get(c).or(scale.first()).copied().filter(|x| *x != 0.0).unwrap_or(1.0);
+                let timestamp_us = start_us + duration_us * i as u64 / repeat as u64;
+                if header.key == GPMF_GYRO_ID || header.key == GPMF_ACCL_ID {
+                    let sample = GpmfImuSample { timestamp_us, x: value(0), y: value(1), z: value(2) };
+                    if header.key == GPMF_GYRO_ID { telemetry.gyro.push(sample); } else { telemetry.accl.push(sample); }
+                } else {
+                    telemetry.gps.push(GpmfGpsSample { timestamp_us, latitude: value(0), longitude: value(1), altitude: value(2), speed_2d: value(3), speed_3d: value(4) });
+                }
+            }
+        }
+    });
+}
+
+/// Read the GPS, gyroscope and accelerometer samples of the first GPMF (`gpmd`) track of a file, e.g. of a merged output.
+/// Empty when the file has no GPMF track
+pub fn read_gpmf_telemetry<R: Read + Seek>(reader: &mut R) -> Result<GpmfTelemetry> {
+    let mut telemetry = GpmfTelemetry::default();
+    let Some(table) = read_gpmf_sample_table(reader)? else { return Ok(telemetry); };
+    let timescale = table.timescale.max(1) as u64;
+    let mut buf = Vec::new();
+    for (i, sample) in table.samples.iter().enumerate() {
+        let end = table.samples.get(i + 1).map_or(table.duration.max(sample.time), |x| x.time);
+        buf.resize(sample.size as usize, 0);
+        reader.seek(SeekFrom::Start(sample.offset))?;
+        reader.read_exact(&mut buf)?;
+        let start_us = sample.time * 1_000_000 / timescale;
+        parse_gpmf_payload(&buf, start_us, end * 1_000_000 / timescale - start_us, &mut telemetry);
+    }
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(telemetry)
+}
+
+/// GPS points further apart than this, in seconds, are written to separate track segments
+const GPS_SEGMENT_GAP: f64 = 2.0;
+
+/// GPS points of the files in merge order with their UTC time, split into continuous segments.
+/// A file starts at its first GPSU time, or the creation time in mvhd, or where the previous file ends. Points without a GPS lock are left out
+fn merged_gps_segments<P: AsRef<Path>>(files: &[P]) -> Result<Vec<Vec<(SystemTime, GpmfGpsSample)>>> {
+    let mut segments: Vec<Vec<(SystemTime, GpmfGpsSample)>> = Vec::new();
+    let mut previous_end = None;
+    for path in files {
+        let mut reader = BufReader::with_capacity(16*1024, std::fs::File::open(path)?);
+        let headers = crate::desc_reader::read_headers(&mut reader)?;
+        let telemetry = read_gpmf_telemetry(&mut reader)?;
+        let gps_start = telemetry.gps_utc.first().and_then(|(timestamp, utc)| utc.checked_sub(Duration::from_micros(*timestamp)));
+        let start = gps_start.or(headers.creation_time).or(previous_end).unwrap_or_else(|| {
+            diag!(Warn, "{} has no GPS time or creation time, its GPS points start at 1970", path.as_ref().display());
+            SystemTime::UNIX_EPOCH
+        });
+        previous_end = start.checked_add(Duration::from_secs_f64(headers.movie_duration.max(0.0)));
+
+        for sample in telemetry.gps.into_iter().filter(|x| x.latitude != 0.0 || x.longitude != 0.0) {
+            let time = start + Duration::from_micros(sample.timestamp_us);
+            let continuous = segments.last().and_then(|x| x.last()).and_then(|x| time.duration_since(x.0).ok()).is_some_and(|x| x.as_secs_f64() <= GPS_SEGMENT_GAP);
+            if !continuous { segments.push(Vec::new()); }
+            segments.last_mut().unwrap().push((time, sample));
+        }
+    }
+    Ok(segments)
+}
+
+/// Write the GPS track of the GPMF tracks of `files`, in merge order, as GPX. Each continuous part is a track segment,
+/// so the pauses between the files (or a lost GPS signal) aren't drawn as a straight line
+pub fn export_gpx<P: AsRef<Path>, W: Write>(files: &[P], mut writer: W) -> Result<()> {
+    let segments = merged_gps_segments(files)?;
+    writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(writer, r#"<gpx version="1.1" creator="mp4-merge" xmlns="http://www.topografix.com/GPX/1/1">"#)?;
+    writeln!(writer, "  <trk>")?;
+    for segment in &segments {
+        writeln!(writer, "    <trkseg>")?;
+        for (time, x) in segment {
+            writeln!(writer, r#"      <trkpt lat="{:.7}" lon="{:.7}"><ele>{:.3}</ele><time>{}</time></trkpt>"#, x.latitude, x.longitude, x.altitude, crate::chapter_markers::format_rfc3339(*time))?;
+        }
+        writeln!(writer, "    </trkseg>")?;
+    }
+    writeln!(writer, "  </trk>")?;
+    writeln!(writer, "</gpx>")?;
+    Ok(())
+}
+
+/// Same as `export_gpx`, as KML with a line for each continuous part
+pub fn export_kml<P: AsRef<Path>, W: Write>(files: &[P], mut writer: W) -> Result<()> {
+    let segments = merged_gps_segments(files)?;
+    writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(writer, r#"<kml xmlns="http://www.opengis.net/kml/2.2">"#)?;
+    writeln!(writer, "  <Document>")?;
+    for segment in &segments {
+        let (Some(first), Some(last)) = (segment.first(), segment.last()) else { continue; };
+        writeln!(writer, "    <Placemark>")?;
+        writeln!(writer, "      <TimeSpan><begin>{}</begin><end>{}</end></TimeSpan>", crate::chapter_markers::format_rfc3339(first.0), crate::chapter_markers::format_rfc3339(last.0))?;
+        writeln!(writer, "      <LineString>")?;
+        writeln!(writer, "        <altitudeMode>absolute</altitudeMode>")?;
+        write!(writer, "        <coordinates>")?;
+        for (_, x) in segment {
+            write!(writer, "{:.7},{:.7},{:.3} ", x.longitude, x.latitude, x.altitude)?;
+        }
+        writeln!(writer, "</coordinates>")?;
+        writeln!(writer, "      </LineString>")?;
+        writeln!(writer, "    </Placemark>")?;
+    }
+    writeln!(writer, "  </Document>")?;
+    writeln!(writer, "</kml>")?;
+    Ok(())
+}
+
+/// Parse a GPSU value ("yymmddhhmmss.sss" in UTC) to SystemTime
+pub fn parse_gpsu(data: &[u8]) -> Option<SystemTime> {
+    let s = std::str::from_utf8(data.get(..16)?).ok()?;
+    let num = |range: std::ops::Range<usize>| s.get(range)?.parse::<u32>().ok();
+    let (year, month, day) = (2000 + num(0..2)? as i64, num(2..4)?, num(4..6)?);
+    let (hour, minute) = (num(6..8)?, num(8..10)?);
+    let seconds = s.get(10..16)?.parse::<f64>().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || !(0.0..61.0).contains(&seconds) {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    let secs = days as f64 * 86400.0 + hour as f64 * 3600.0 + minute as f64 * 60.0 + seconds;
+    SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs_f64(secs.max(0.0)))
+}
+
+/// Format a SystemTime as a GPSU value ("yymmddhhmmss.sss" in UTC)
+pub fn format_gpsu(time: SystemTime) -> Vec<u8> {
+    let millis = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
+    let (days, millis) = (millis.div_euclid(86_400_000), millis.rem_euclid(86_400_000));
+    let (year, month, day) = civil_from_days(days);
+    format!("{:02}{month:02}{day:02}{:02}{:02}{:02}.{:03}", year.rem_euclid(100), millis / 3_600_000, millis / 60_000 % 60, millis / 1000 % 60, millis % 1000).into_bytes()
+}
+
+/// Number of days since 1970-01-01 for a proleptic Gregorian date
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * ((month as i64 + 9) % 12) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Proleptic Gregorian date of a number of days since 1970-01-01, the inverse of `days_from_civil`
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + if month <= 2 { 1 } else { 0 }, month, day)
+}
+
+/// Get the GPS UTC time of a single GPMF payload, if the payload has a GPS lock
+fn payload_gps_time(payload: &[u8]) -> Option<SystemTime> {
+    let mut gps_time = None;
+    let mut has_fix = true;
+    walk_klv(payload, &mut |header, data| {
+        if header.key == GPMF_GPS_TIME_ID && gps_time.is_none() {
+            gps_time = parse_gpsu(data);
+        }
+        if header.key == GPMF_GPS_FIX_ID && data.len() >= 4 {
+            has_fix = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) > 0;
+        }
+    });
+    gps_time.filter(|_| has_fix)
+}
+
+/// Derive the wall-clock start and end of a file from the GPSU timestamps in its GPMF track.
+/// Returns None if the file has no GPMF track or no payload with a GPS lock.
+pub fn read_gpsu_range<R: Read + Seek>(reader: &mut R) -> Result<Option<(SystemTime, SystemTime)>> {
+    let Some(table) = read_gpmf_sample_table(reader)? else { return Ok(None); };
+    if table.timescale == 0 { return Ok(None); }
+
+    let mut read_time = |sample: &GpmfSampleRef| -> Result<Option<SystemTime>> {
+        let mut buf = vec![0u8; sample.size as usize];
+        reader.seek(SeekFrom::Start(sample.offset))?;
+        reader.read_exact(&mut buf)?;
+        // Move the GPS time back to the start of the track
+        let sample_time = Duration::from_secs_f64(sample.time as f64 / table.timescale as f64);
+        Ok(payload_gps_time(&buf).and_then(|t| t.checked_sub(sample_time)))
+    };
+
+    let mut start = None;
+    for sample in &table.samples {
+        if let Some(t) = read_time(sample)? { start = Some(t); break; }
+    }
+    let mut end = None;
+    for sample in table.samples.iter().rev() {
+        if let Some(t) = read_time(sample)? { end = Some(t); break; }
+    }
+    let track_duration = Duration::from_secs_f64(table.duration as f64 / table.timescale as f64);
+
+    match (start, end.and_then(|t| t.checked_add(track_duration))) {
+        (Some(start), Some(end)) => {
+            diag!(Debug, "GPSU range: {:?} - {:?}", start, end);
+            Ok(Some((start, end)))
+        },
+        _ => Ok(None)
+    }
+}
+
 /// Check if any of the input files contain GPMF metadata
 pub fn detect_gpmf_files<R: Read + Seek>(files: &mut [(R, usize)]) -> Result<Vec<bool>> {
     let mut gpmf_flags = Vec::with_capacity(files.len());
@@ -277,7 +779,7 @@ pub fn detect_gpmf_files<R: Read + Seek>(files: &mut [(R, usize)]) -> Result<Vec
         gpmf_flags.push(has_gpmf);
         
         if has_gpmf {
-            log::debug!("Detected GPMF metadata in file");
+            diag!(Debug, "Detected GPMF metadata in file");
         }
     }
     
@@ -293,10 +795,12 @@ pub fn merge_gpmf_metadata<R: Read + Seek, W: Write + Seek>(
 ) -> Result<()> {
     let mut processor = GpmfProcessor::new();
     
-    // Extract GPMF data from each file
+    // Extract GPMF data from each file. Only used for the log, so unreadable payloads don't stop the merge
     for (file_index, (file, _size)) in files.iter_mut().enumerate() {
         let file_duration = file_durations.get(file_index).copied().unwrap_or(0.0);
-        processor.extract_gpmf_from_file(file, file_duration)?;
+        if let Err(e) = processor.extract_gpmf_from_file(file, file_duration) {
+            diag!(Warn, "Failed to read the GPMF data of file {file_index}: {e}");
+        }
     }
     
     // Merge all tracks into a continuous GPS track
@@ -304,7 +808,7 @@ pub fn merge_gpmf_metadata<R: Read + Seek, W: Write + Seek>(
     
     // The actual GPMF sample data merging is handled by the existing MP4 infrastructure
     // Here we just log what would be done with the merged GPS data
-    log::debug!("GPMF merge complete: {} total GPS samples across {:.2}s", 
+    diag!(Debug, "GPMF merge complete: {} total GPS samples across {:.2}s", 
                merged_samples.len(), processor.total_duration);
     
     // In a full implementation, we would:
@@ -313,7 +817,7 @@ pub fn merge_gpmf_metadata<R: Read + Seek, W: Write + Seek>(
     // 3. Repack the adjusted GPS data into GPMF format
     // 4. Update the merged metadata track with the new GPMF data
     
-    log::debug!("Successfully processed GPMF metadata from {} files", files.len());
+    diag!(Debug, "Successfully processed GPMF metadata from {} files", files.len());
     
     Ok(())
 }
@@ -359,7 +863,7 @@ mod tests {
     fn test_gpmf_detection_with_empty_file() {
         let mut empty_cursor = Cursor::new(Vec::new());
         let result = GpmfProcessor::detect_gpmf_in_file(&mut empty_cursor).unwrap();
-        assert_eq!(result, false);
+        assert!(!result);
     }
 
     #[test]
@@ -427,6 +931,100 @@ mod tests {
         assert_eq!(merged_samples[2].latitude, 37.7751);
     }
 
+    #[test]
+    fn test_parse_gpsu() {
+        let time = parse_gpsu(b"230615123456.250").unwrap();
+        let secs = time.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
+        assert!((secs - 1686832496.25).abs() < 0.001);
+
+        assert!(parse_gpsu(b"231315123456.250").is_none()); // Invalid month
+        assert!(parse_gpsu(b"2306").is_none());
+        assert_eq!(format_gpsu(time), b"230615123456.250");
+    }
+
+    #[test]
+    fn test_parse_gpmf_payload() {
+        let gps = [[377749000i32, -1224194000, 100000, 5000, 5100], [377750000, -1224195000, 101000, 5100, 5200]];
+        let mut gps_strm = klv(b"GPSF", b'L', 4, &3u32.to_be_bytes());
+        gps_strm.extend(klv(b"GPSU", b'U', 16, b"230615123456.250"));
+        gps_strm.extend(klv(b"SCAL", b'l', 4, &[10000000i32, 10000000, 1000, 1000, 1000].iter().flat_map(|x| x.to_be_bytes()).collect::<Vec<_>>()));
+        gps_strm.extend(klv(b"GPS5", b'l', 20, &gps.iter().flatten().flat_map(|x| x.to_be_bytes()).collect::<Vec<_>>()));
+        let mut gyro_strm = klv(b"STNM", b'c', 4, b"Gyro");
+        gyro_strm.extend(klv(b"SCAL", b's', 2, &100i16.to_be_bytes()));
+        gyro_strm.extend(klv(b"GYRO", b's', 6, &[1i16, 2, 3, 4, 5, 6, 7, 8, 9, -10, -11, -12].iter().flat_map(|x| x.to_be_bytes()).collect::<Vec<_>>()));
+        let mut devc = klv(b"DVID", b'L', 4, &1u32.to_be_bytes());
+        devc.extend(klv(b"STRM", 0, 1, &gps_strm));
+        devc.extend(klv(b"STRM", 0, 1, &gyro_strm));
+        let payload = klv(b"DEVC", 0, 1, &devc);
+
+        let mut telemetry = GpmfTelemetry::default();
+        parse_gpmf_payload(&payload, 2_000_000, 1_000_000, &mut telemetry);
+        assert_eq!(telemetry.gps.len(), 2);
+        assert_eq!(telemetry.gps[1].timestamp_us, 2_500_000);
+        assert!((telemetry.gps[1].latitude - 37.775).abs() < 1e-9 && (telemetry.gps[1].longitude + 122.4195).abs() < 1e-9);
+        assert_eq!((telemetry.gps[1].altitude, telemetry.gps[1].speed_2d, telemetry.gps[1].speed_3d), (101.0, 5.1, 5.2));
+        assert_eq!(telemetry.gps_utc, [(2_000_000, parse_gpsu(b"230615123456.250").unwrap())]);
+        assert!(telemetry.accl.is_empty());
+        assert_eq!(telemetry.gyro.iter().map(|x| x.timestamp_us).collect::<Vec<_>>(), [2_000_000, 2_250_000, 2_500_000, 2_750_000]);
+        assert_eq!(telemetry.gyro[3], GpmfImuSample { timestamp_us: 2_750_000, x: -0.1, y: -0.11, z: -0.12 });
+    }
+
+    #[test]
+    fn test_export_gpx() {
+        // Two chapters with a GPS point per second, 8 seconds apart
+        let payload = |gpsu: &str, latitude: i32| {
+            let mut strm = klv(b"GPSF", b'L', 4, &3u32.to_be_bytes());
+            strm.extend(klv(b"GPSU", b'U', 16, gpsu.as_bytes()));
+            strm.extend(klv(b"SCAL", b'l', 4, &10000000i32.to_be_bytes()));
+            strm.extend(klv(b"GPS5", b'l', 20, &[latitude, 100000000, 0, 0, 0].iter().flat_map(|x| x.to_be_bytes()).collect::<Vec<_>>()));
+            let strm = klv(b"STRM", 0, 1, &strm);
+            klv(b"DEVC", 0, 1, &strm)
+        };
+        let chapter = |payloads: [Vec<u8>; 2]| {
+            let mut gpmd = crate::test_util::SyntheticTrack::metadata(*b"gpmd", 2);
+            gpmd.sample_size = payloads[0].len() as u32;
+            let mut data = crate::test_util::SyntheticMp4::new().track(crate::test_util::SyntheticTrack::video(25, 1, 50)).track(gpmd).build();
+            let table = read_gpmf_sample_table(&mut Cursor::new(&data)).unwrap().unwrap();
+            for (sample, payload) in table.samples.iter().zip(payloads) {
+                data[sample.offset as usize..][..payload.len()].copy_from_slice(&payload);
+            }
+            data
+        };
+        let dir = std::env::temp_dir().join(format!("mp4_merge_export_gpx_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = [dir.join("GX010001.MP4"), dir.join("GX020001.MP4")];
+        std::fs::write(&files[0], chapter([payload("230615120000.000", 450000000), payload("230615120001.000", 450000100)])).unwrap();
+        std::fs::write(&files[1], chapter([payload("230615120010.000", 450000900), payload("230615120011.000", 450001000)])).unwrap();
+
+        let mut gpx = Vec::new();
+        export_gpx(&files, &mut gpx).unwrap();
+        let mut kml = Vec::new();
+        export_kml(&files, &mut kml).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let gpx = String::from_utf8(gpx).unwrap();
+        assert_eq!(gpx.matches("<trkseg>").count(), 2);
+        assert_eq!(gpx.matches("<trkpt").count(), 4);
+        assert!(gpx.contains(r#"<trkpt lat="45.0000000" lon="10.0000000"><ele>0.000</ele><time>2023-06-15T12:00:00.000Z</time></trkpt>"#));
+        assert!(gpx.contains("<time>2023-06-15T12:00:11.000Z</time>"));
+        let kml = String::from_utf8(kml).unwrap();
+        assert_eq!(kml.matches("<LineString>").count(), 2);
+        assert!(kml.contains("<coordinates>10.0000000,45.0000900,0.000 10.0000000,45.0001000,0.000 </coordinates>"));
+    }
+
+    #[test]
+    fn test_payload_gps_time_requires_fix() {
+        let payload = |fix: u32| {
+            let mut strm = klv(b"GPSF", b'L', 4, &fix.to_be_bytes());
+            strm.extend(klv(b"GPSU", b'U', 16, b"230615123456.250"));
+            let strm = klv(b"STRM", 0, 1, &strm);
+            klv(b"DEVC", 0, 1, &strm)
+        };
+
+        assert!(payload_gps_time(&payload(3)).is_some());
+        assert!(payload_gps_time(&payload(0)).is_none());
+    }
+
     #[test]
     fn test_detect_gpmf_files_integration() {
         let empty_data = vec![];
@@ -434,7 +1032,7 @@ mod tests {
         
         let result = detect_gpmf_files(&mut files).unwrap();
         assert_eq!(result.len(), 1);
-        assert_eq!(result[0], false); // Empty file should not have GPMF
+        assert!(!result[0]); // Empty file should not have GPMF
     }
 
     #[test]
@@ -0,0 +1,301 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::Result;
+use std::path::PathBuf;
+use std::time::{ Duration, SystemTime };
+use crate::desc_reader::{ self, Desc, TrackDesc, EditListEntry, system_time_to_mp4_time };
+use crate::stsd::SampleEntry;
+use crate::diagnostics::diag;
+use crate::gpmf::klv;
+
+/// Media timescale of the track created from a GPX file, in milliseconds
+const GPX_TIMESCALE: u32 = 1000;
+
+/// Format of the telemetry track created from a GPX file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GpxFormat {
+    /// Camera Motion Metadata (camm) GPS samples of type 5: latitude, longitude and altitude
+    #[default]
+    Camm,
+    /// GoPro Metadata Format (gpmd) with a GPS5 stream, read by the tools which expect GoPro telemetry
+    Gpmf,
+}
+
+/// GPX log added to the merged output as a timed-metadata track, e.g. recorded by a phone next to a camera without GPS
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GpxTrack {
+    pub path: PathBuf,
+    pub format: GpxFormat,
+    /// Seconds the clock of the camera is ahead of UTC, subtracted from the creation times of the files.
+    /// The GPS times of GoPro files are UTC and aren't adjusted
+    pub camera_clock_offset: f64,
+}
+
+impl GpxTrack {
+    pub fn new<P: Into<PathBuf>>(path: P, format: GpxFormat) -> Self {
+        Self { path: path.into(), format, camera_clock_offset: 0.0 }
+    }
+
+    /// Set the difference between the camera clock and UTC in seconds
+    pub fn camera_clock_offset(mut self, seconds: f64) -> Self {
+        self.camera_clock_offset = seconds;
+        self
+    }
+}
+
+/// A track point of a GPX file
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct GpxPoint {
+    time: SystemTime,
+    latitude: f64,
+    longitude: f64,
+    elevation: f64,
+}
+
+/// Read the timestamped track points of a GPX file. Points without a time can't be aligned and are skipped
+fn parse_gpx(data: &str) -> Vec<GpxPoint> {
+    let mut points = Vec::new();
+    let mut rest = data;
+    while let Some(start) = rest.find("<trkpt") {
+        rest = &rest[start + 6..];
+        let Some(tag_end) = rest.find('>') else { break; };
+        let tag = &rest[..tag_end];
+        let content = if tag.ends_with('/') { "" } else { rest.find("</trkpt>").map(|end| &rest[tag_end..end]).unwrap_or("") };
+        let point = (|| Some(GpxPoint {
+            latitude: attribute(tag, "lat")?.parse().ok()?,
+            longitude: attribute(tag, "lon")?.parse().ok()?,
+            elevation: element(content, "ele").and_then(|x| x.parse().ok()).unwrap_or(0.0),
+            time: parse_time(element(content, "time")?)?,
+        }))();
+        points.extend(point);
+    }
+    points
+}
+
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = tag;
+    loop {
+        let pos = rest.find(name)?;
+        let preceded_by_space = rest[..pos].ends_with(char::is_whitespace);
+        rest = rest[pos + name.len()..].trim_start();
+        if let (true, Some(value)) = (preceded_by_space, rest.strip_prefix('=')) {
+            let value = value.trim_start();
+            let quote = value.chars().next().filter(|x| *x == '"' || *x == '\'')?;
+            return value[1..].split(quote).next();
+        }
+    }
+}
+
+fn element<'a>(content: &'a str, name: &str) -> Option<&'a str> {
+    let start = content.find(&format!("<{name}>"))? + name.len() + 2;
+    let end = content[start..].find("</")? + start;
+    Some(content[start..end].trim())
+}
+
+/// Parse an ISO 8601 date and time, e.g. `2024-05-01T10:20:30.5Z` or `2024-05-01T12:20:30+02:00`
+fn parse_time(text: &str) -> Option<SystemTime> {
+    let (date, time) = text.split_once('T')?;
+    let mut date = date.split('-').map(|x| x.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()?? as u32, date.next()?? as u32);
+    let zone_start = time.find(['Z', 'z', '+', '-']).unwrap_or(time.len());
+    let zone = &time[zone_start..];
+    let mut time = time[..zone_start].split(':');
+    let (hours, minutes, seconds) = (time.next()?.parse::<f64>().ok()?, time.next()?.parse::<f64>().ok()?, time.next().unwrap_or("0").parse::<f64>().ok()?);
+    let zone_offset = match zone.chars().next() {
+        Some(sign @ ('+' | '-')) => {
+            let (h, m) = zone[1..].split_once(':').unwrap_or((&zone[1..zone.len().min(3)], zone.get(3..).unwrap_or("0")));
+            let offset = h.parse::<f64>().ok()? * 3600.0 + m.parse::<f64>().unwrap_or(0.0) * 60.0;
+            if sign == '-' { -offset } else { offset }
+        },
+        _ => 0.0
+    };
+    let secs = crate::gpmf::days_from_civil(year, month, day) as f64 * 86400.0 + hours * 3600.0 + minutes * 60.0 + seconds - zone_offset;
+    (secs >= 0.0).then(|| SystemTime::UNIX_EPOCH + Duration::from_secs_f64(secs))
+}
+
+fn seconds(time: SystemTime) -> f64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).map(|x| x.as_secs_f64()).unwrap_or(0.0)
+}
+
+/// Time of the merged timeline for each point, using the wall-clock start of each file (GPS time, then the creation time
+/// from mvhd, then the filesystem) and the gaps between them. Points outside of the files and their gaps are dropped
+fn align_points(desc: &Desc, points: &[GpxPoint], camera_clock_offset: f64) -> Result<Vec<(f64, GpxPoint)>> {
+    let starts = desc.file_timeline_starts();
+    let mut files = Vec::with_capacity(starts.len());
+    for (i, timeline_start) in starts.iter().enumerate() {
+        let info = desc.file_info(i);
+        let trim = desc.file_trims.get(i).copied().unwrap_or(0.0);
+        let wall_start = match info.gps_time_range {
+            Some((start, _)) => seconds(start),
+            None => match info.embedded_creation_time.or(info.filesystem_creation_time) {
+                Some(time) => seconds(time) - camera_clock_offset,
+                None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("File {i} has no creation time to align the GPX track with")))
+            }
+        };
+        files.push((wall_start + trim, *timeline_start, *timeline_start + info.duration - trim));
+    }
+    let end = files.last().map(|x| x.2).unwrap_or(0.0);
+    Ok(points.iter().filter_map(|point| {
+        let time = seconds(point.time);
+        let i = files.iter().rposition(|x| x.0 <= time)?;
+        let movie_time = files[i].1 + time - files[i].0;
+        // The gap after the file lasts until the next file starts
+        let limit = files.get(i + 1).map(|x| x.1).unwrap_or(end);
+        (movie_time < limit).then_some((movie_time, *point))
+    }).collect())
+}
+
+/// CAMM sample of type 5: latitude, longitude and altitude in little-endian doubles
+fn camm_sample(point: &GpxPoint) -> Vec<u8> {
+    let mut data = vec![0, 0, 5, 0];
+    for x in [point.latitude, point.longitude, point.elevation] {
+        data.extend_from_slice(&x.to_le_bytes());
+    }
+    data
+}
+
+/// GPMF payload with a single GPS5 sample, the speeds derived from the distance to the next point
+fn gpmf_sample(point: &GpxPoint, next: Option<&GpxPoint>) -> Vec<u8> {
+    let (speed_2d, speed_3d) = match next {
+        Some(next) => {
+            let elapsed = (seconds(next.time) - seconds(point.time)).max(0.001);
+            let (lat1, lat2) = (point.latitude.to_radians(), next.latitude.to_radians());
+            let a = ((lat2 - lat1) / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * ((next.longitude - point.longitude).to_radians() / 2.0).sin().powi(2);
+            let distance = 2.0 * 6_371_000.0 * a.sqrt().asin();
+            let climb = next.elevation - point.elevation;
+            (distance / elapsed, (distance * distance + climb * climb).sqrt() / elapsed)
+        },
+        None => (0.0, 0.0)
+    };
+    let scale = [10_000_000i32, 10_000_000, 1000, 1000, 100];
+    let values = [point.latitude, point.longitude, point.elevation, speed_2d, speed_3d];
+    let gps5 = values.iter().zip(scale).flat_map(|(x, s)| ((x * s as f64).round() as i32).to_be_bytes()).collect::<Vec<_>>();
+    let name = b"GPS (Lat., Long., Alt., 2D speed, 3D speed)";
+    let stream = [
+        klv(b"STNM", b'c', 1, name),
+        klv(b"GPSU", b'U', 16, &crate::gpmf::format_gpsu(point.time)),
+        klv(b"GPSF", b'L', 4, &3u32.to_be_bytes()),
+        klv(b"SCAL", b'l', 4, &scale.iter().flat_map(|x| x.to_be_bytes()).collect::<Vec<_>>()),
+        klv(b"GPS5", b'l', 20, &gps5),
+    ].concat();
+    let device = [
+        klv(b"DVID", b'L', 4, &1u32.to_be_bytes()),
+        klv(b"DVNM", b'c', 1, b"GPX"),
+        klv(b"STRM", 0, 1, &stream),
+    ].concat();
+    klv(b"DEVC", 0, 1, &device)
+}
+
+/// Convert the GPX file to a telemetry track appended to the merged tracks. Its samples are synthesized at the end of the mdat
+pub(crate) fn add_gpx_track(desc: &mut Desc, gpx: &GpxTrack) -> Result<()> {
+    let points = parse_gpx(&std::fs::read_to_string(&gpx.path)?);
+    let mut aligned = align_points(desc, &points, gpx.camera_clock_offset)?;
+    aligned.sort_by(|a, b| a.0.total_cmp(&b.0));
+    aligned.dedup_by_key(|x| (x.0 * GPX_TIMESCALE as f64).round() as u64);
+    diag!(Debug, "{} of the {} points of {} are within the merged timeline", aligned.len(), points.len(), gpx.path.display());
+    if aligned.is_empty() {
+        diag!(Warn, "No point of {} is within the merged timeline, check the clock of the camera", gpx.path.display());
+        return Ok(());
+    }
+
+    let codec = match gpx.format { GpxFormat::Camm => "camm", GpxFormat::Gpmf => "gpmd" };
+    let times = aligned.iter().map(|x| (x.0 * GPX_TIMESCALE as f64).round() as u64).collect::<Vec<_>>();
+    let mut track = TrackDesc {
+        handler_type: "meta".into(),
+        track_id: desc.moov_tracks.iter().map(|x| x.track_id).max().unwrap_or(0) + 1,
+        language: "und".into(),
+        mdhd_timescale: GPX_TIMESCALE,
+        stsc: vec![(1, aligned.len() as u32, 1)],
+        stsz_count: aligned.len() as u32,
+        sample_entries: vec![SampleEntry { codec: codec.into(), fields: vec![0, 0, 0, 0, 0, 0, 0, 1], boxes: Vec::new() }],
+        ..Default::default()
+    };
+    for (i, (_, point)) in aligned.iter().enumerate() {
+        let data = match gpx.format {
+            GpxFormat::Camm => camm_sample(point),
+            GpxFormat::Gpmf => gpmf_sample(point, aligned.get(i + 1).map(|x| &x.1)),
+        };
+        let duration = match times.get(i + 1) {
+            Some(next) => next - times[i],
+            None => track.stts.last().map(|x| x.1 as u64).unwrap_or(GPX_TIMESCALE as u64),
+        } as u32;
+        match track.stts.last_mut() {
+            Some(last) if last.1 == duration => last.0 += 1,
+            _ => track.stts.push((1, duration)),
+        }
+        track.stsz.push(data.len() as u32);
+        let offset = desc.append_synthesized(&data);
+        if i == 0 { track.stco.push(offset); }
+    }
+
+    // The track starts with the first point, after an empty edit
+    track.mdhd_duration = track.stts.iter().map(|x| x.0 as u64 * x.1 as u64).sum();
+    let movie_timescale = desc.moov_mvhd_timescale;
+    let empty = desc_reader::convert_duration_ceil(times[0], GPX_TIMESCALE, movie_timescale);
+    let media = desc_reader::convert_duration_ceil(track.mdhd_duration, GPX_TIMESCALE, movie_timescale);
+    if empty > 0 {
+        track.elst_entries = vec![EditListEntry { segment_duration: empty, media_time: -1, ..Default::default() }, EditListEntry { segment_duration: media, ..Default::default() }];
+    }
+    track.tkhd_duration = empty + media;
+    track.elst_segment_duration = track.tkhd_duration;
+
+    let creation_time = desc.file_mvhd_creation_times.first().copied().flatten().map(system_time_to_mp4_time).unwrap_or(0) as u32;
+    let entry = crate::template::mp4_box(codec.as_bytes().try_into().unwrap_or(b"camm"), &[&[0; 6][..], &1u16.to_be_bytes()].concat());
+    let trak = crate::template::metadata_trak(&track, &entry, "GPX", creation_time);
+    desc.append_track(track, trak);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+
+    #[test]
+    fn test_gpx_track() {
+        let points = parse_gpx(r#"<?xml version="1.0"?><gpx version="1.1"><trk><trkseg>
+            <trkpt lat="46.5" lon="6.25"><ele>372.5</ele><time>2024-05-01T09:59:59Z</time></trkpt>
+            <trkpt lat="46.5001" lon='6.2501'><ele>373</ele><time>2024-05-01T12:00:01.5+02:00</time></trkpt>
+            <trkpt lon="6.2502" lat="46.5002"><time>2024-05-01T10:00:03Z</time></trkpt>
+            <trkpt lat="46.5003" lon="6.2503"/>
+            <trkpt lat="46.5004" lon="6.2504"><time>2024-05-01T10:00:09Z</time></trkpt>
+        </trkseg></trk></gpx>"#);
+        assert_eq!(points.len(), 4);
+        assert_eq!((points[1].latitude, points[1].longitude, points[1].elevation), (46.5001, 6.2501, 373.0));
+        let start = parse_time("2024-05-01T10:00:00Z").unwrap();
+        assert_eq!(points[1].time, start + Duration::from_millis(1500));
+
+        let path = std::env::temp_dir().join(format!("mp4_merge_gpx_{}.gpx", std::process::id()));
+        std::fs::write(&path, r#"<gpx><trk><trkseg>
+            <trkpt lat="46.5" lon="6.25"><time>2024-05-01T10:00:01Z</time></trkpt>
+            <trkpt lat="46.5001" lon="6.2501"><time>2024-05-01T10:00:03Z</time></trkpt>
+            <trkpt lat="46.5002" lon="6.2502"><time>2024-05-01T10:00:05Z</time></trkpt>
+            <trkpt lat="46.5003" lon="6.2503"><time>2024-05-01T10:00:30Z</time></trkpt>
+        </trkseg></trk></gpx>"#).unwrap();
+
+        // Two 2-second files, recorded 4 seconds apart
+        let times = [start, start + Duration::from_secs(4)];
+        let mut files = times.map(|time| SyntheticMp4 { creation_time: Some(time), ..SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50)) }.cursor());
+        for format in [GpxFormat::Camm, GpxFormat::Gpmf] {
+            let options = crate::MergeOptions::default().gpx_track(GpxTrack::new(&path, format));
+            let mut output = std::io::Cursor::new(Vec::new());
+            crate::join_file_streams_with_options(&mut files, &mut output, &times.map(Some), &options, |_| {}).unwrap();
+
+            let tracks = crate::list_tracks(&mut output).unwrap();
+            assert_eq!(tracks.len(), 2);
+            assert_eq!(tracks[1].telemetry, Some(match format { GpxFormat::Camm => crate::TelemetryFormat::Camm, GpxFormat::Gpmf => crate::TelemetryFormat::Gpmf }));
+            let index = crate::RandomAccessIndex::from_reader(&mut output).unwrap();
+            // The last point is after the end of the second file
+            assert_eq!(index.sample_count(1), 3);
+            let second = index.sample(1, 1).unwrap();
+            assert_eq!((second.time, second.duration), (2.0, 2.0));
+            let data = &output.get_ref()[second.offset as usize..][..second.size as usize];
+            match format {
+                GpxFormat::Camm => assert_eq!(f64::from_le_bytes(data[4..12].try_into().unwrap()), 46.5001),
+                GpxFormat::Gpmf => assert!(data.windows(4).any(|x| x == b"GPS5")),
+            }
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, Result, SeekFrom };
+use std::path::Path;
+use std::time::SystemTime;
+use crate::{ fourcc, read_box, compatibility, desc_reader, gopro };
+
+/// Files smaller than this didn't hit the FAT32 file size limit, so the recording didn't continue in another file
+pub const MIN_SPLIT_SIZE: u64 = 3584 * 1024 * 1024;
+
+/// Maximum difference between the end of a file and the start of its continuation, in seconds.
+/// mvhd creation times have 1-second resolution
+const CONTIGUOUS_TOLERANCE: f64 = 2.0;
+
+/// Identifiers of a single file used to find its continuation
+#[derive(Debug, Clone, Default)]
+struct FileSignature {
+    start: Option<SystemTime>,
+    duration: f64,
+    size: u64,
+    layout: Vec<(String, String, u32)>, // Handler type, codec and timescale of each track
+    camera: gopro::CameraInfo,
+    uuids: Vec<[u8; 16]>, // Extended types of the uuid boxes at the top level, in moov and in udta
+    capture_id: Option<Vec<u8>>, // GoPro CPID
+}
+
+fn read_uuids<R: Read + Seek>(reader: &mut R, max_read: u64, uuids: &mut Vec<[u8; 16]>) -> Result<()> {
+    let start_pos = reader.stream_position()?;
+    while reader.stream_position()? - start_pos < max_read {
+        let Ok((typ, offs, size, header_size)) = read_box(reader) else { break; };
+        if size == 0 || typ == 0 { break; }
+        if typ == fourcc("moov") || typ == fourcc("udta") {
+            read_uuids(reader, size - header_size as u64, uuids)?;
+        } else if typ == fourcc("uuid") {
+            let mut usertype = [0u8; 16];
+            reader.read_exact(&mut usertype)?;
+            uuids.push(usertype);
+        }
+        reader.seek(SeekFrom::Start(offs + size))?;
+    }
+    Ok(())
+}
+
+fn read_signature(path: &Path) -> Result<FileSignature> {
+    let file = std::fs::File::open(path)?;
+    let metadata = file.metadata()?;
+    let mut reader = std::io::BufReader::with_capacity(16*1024, file);
+
+    let headers = desc_reader::read_headers(&mut reader)?;
+    let probe = compatibility::probe(&mut reader)?;
+    let udta = gopro::read_udta_info(&mut reader)?;
+    let mut uuids = Vec::new();
+    reader.seek(SeekFrom::Start(0))?;
+    read_uuids(&mut reader, u64::MAX, &mut uuids)?;
+    uuids.sort_unstable();
+
+    Ok(FileSignature {
+        start: headers.creation_time.or_else(|| crate::filesystem_creation_time(&metadata)),
+        duration: headers.movie_duration,
+        size: metadata.len(),
+        layout: probe.tracks.iter().map(|t| (t.handler_type.clone(), t.sample_entries.first().map(|x| x.codec.clone()).unwrap_or_default(), t.timescale)).collect(),
+        camera: udta.camera,
+        uuids,
+        capture_id: udta.capture_id,
+    })
+}
+
+/// Whether `next` continues the recording of `prev`
+fn continues(prev: &FileSignature, next: &FileSignature) -> bool {
+    if let (Some(a), Some(b)) = (&prev.capture_id, &next.capture_id) {
+        return a == b;
+    }
+    if prev.size < MIN_SPLIT_SIZE || prev.layout != next.layout || prev.camera != next.camera || prev.uuids != next.uuids {
+        return false;
+    }
+    let (Some(prev_start), Some(next_start)) = (prev.start, next.start) else { return false; };
+    let offset = match next_start.duration_since(prev_start) {
+        Ok(x) => x.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64()
+    };
+    (offset - prev.duration).abs() <= CONTIGUOUS_TOLERANCE
+}
+
+fn group_signatures(signatures: &[FileSignature]) -> Vec<Vec<usize>> {
+    let mut order = (0..signatures.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| (signatures[i].start.is_none(), signatures[i].start, i));
+
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for i in order {
+        match groups.last_mut() {
+            Some(group) if continues(&signatures[*group.last().unwrap()], &signatures[i]) => group.push(i),
+            _ => groups.push(vec![i])
+        }
+    }
+    groups
+}
+
+/// Group the files of a whole card into recordings split by the camera at the 4 GB file size limit.
+/// Files are matched by their embedded identifiers (camera model and serial, uuid boxes, GoPro capture id),
+/// identical track layout and contiguous creation times. Each group is in merge order, groups are ordered by their start time.
+pub fn group_split_files<P: AsRef<Path> + Clone>(files: &[P]) -> Result<Vec<Vec<P>>> {
+    let signatures = files.iter().map(|x| read_signature(x.as_ref())).collect::<Result<Vec<_>>>()?;
+    Ok(group_signatures(&signatures).into_iter().map(|group| group.into_iter().map(|i| files[i].clone()).collect()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn file(start: u64, duration: f64, size: u64) -> FileSignature {
+        FileSignature {
+            start: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(start)),
+            duration,
+            size,
+            layout: vec![("vide".into(), "avc1".into(), 30000)],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_group_signatures() {
+        let big = MIN_SPLIT_SIZE + 1;
+        let files = vec![
+            file(1000, 600.0, big),   // Continued by file 2
+            file(5000, 10.0, 1000),   // Separate recording
+            file(1601, 300.0, 1000),  // Continuation of file 0, ends the recording
+            file(1901, 10.0, 1000),   // Contiguous, but file 2 didn't hit the size limit
+        ];
+        assert_eq!(group_signatures(&files), vec![vec![0, 2], vec![3], vec![1]]);
+
+        let mut other_camera = file(1601, 300.0, 1000);
+        other_camera.camera.serial = Some("123".into());
+        assert_eq!(group_signatures(&[file(1000, 600.0, big), other_camera]), vec![vec![0], vec![1]]);
+    }
+}
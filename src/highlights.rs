@@ -0,0 +1,70 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, Result };
+use crate::desc_reader::Desc;
+use crate::{ fourcc, gpmf, telemetry, diagnostics::diag };
+
+/// GPMF entries holding highlight times, in milliseconds from the start of the file
+const HIGHLIGHT_TIME_IDS: [u32; 2] = [fourcc("MANL"), fourcc("HMMT")];
+
+/// Shift the highlight times in the HLMT timed metadata track of each file by the start of that file on the merged timeline.
+/// The payloads of the later files are rewritten to the synthesized data, the samples keep their sizes and chunks
+pub(crate) fn merge_highlight_tracks<R: Read + Seek>(files: &mut [(R, usize)], desc: &mut Desc) -> Result<()> {
+    let starts = desc.file_timeline_starts();
+    for track_index in 0..desc.moov_tracks.len() {
+        let track = &desc.moov_tracks[track_index];
+        if track.skip || track.dropped || track.sample_entries.first().is_none_or(|x| x.codec != "HLMT") { continue; }
+        let samples = track.sample_infos();
+        let mut shifted = 0;
+        for (file_index, range) in track.file_sample_ranges.clone().into_iter().enumerate().skip(1) {
+            let trim = desc.file_trims.get(file_index).copied().unwrap_or(0.0);
+            let offset = ((starts.get(file_index).copied().unwrap_or(0.0) - trim) * 1000.0).round() as i64;
+            if offset == 0 { continue; }
+            let mut prev_chunk = None;
+            for sample in samples.get(range.start as usize..range.end as usize).unwrap_or_default() {
+                let mut data = telemetry::read_sample(files, desc, sample)?;
+                shifted += shift_highlight_times(&mut data, offset);
+                let position = desc.append_synthesized(&data);
+                if prev_chunk != Some(sample.chunk) {
+                    desc.moov_tracks[track_index].stco[sample.chunk as usize] = position;
+                    prev_chunk = Some(sample.chunk);
+                }
+            }
+        }
+        diag!(Debug, "Shifted {shifted} highlight times of track {track_index}");
+    }
+    Ok(())
+}
+
+/// Add `offset` milliseconds to the highlight times of a GPMF payload. Returns the number of changed values
+fn shift_highlight_times(payload: &mut [u8], offset: i64) -> usize {
+    let mut count = 0;
+    gpmf::walk_klv_mut(payload, &mut |header, data| {
+        if !HIGHLIGHT_TIME_IDS.contains(&header.key) || !matches!(header.typ, b'L' | b'l') || header.struct_size != 4 { return; }
+        for x in data.chunks_exact_mut(4) {
+            let time = u32::from_be_bytes([x[0], x[1], x[2], x[3]]) as i64 + offset;
+            x.copy_from_slice(&(time.clamp(0, u32::MAX as i64) as u32).to_be_bytes());
+            count += 1;
+        }
+    });
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shift_highlight_times() {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(b"HLMT\0\x01\0\x1c");
+        payload.extend_from_slice(b"MANL\x4c\x04\0\x02");
+        payload.extend_from_slice(&1500u32.to_be_bytes());
+        payload.extend_from_slice(&9000u32.to_be_bytes());
+        payload.extend_from_slice(b"TYPE\x63\x01\0\x04GoPr");
+        assert_eq!(shift_highlight_times(&mut payload, 60_000), 2);
+        assert_eq!(payload[16..24], [61_500u32.to_be_bytes(), 69_000u32.to_be_bytes()].concat());
+        assert_eq!(&payload[24..], b"TYPE\x63\x01\0\x04GoPr");
+    }
+}
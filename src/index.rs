@@ -0,0 +1,140 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, Result };
+use crate::desc_reader::{ self, Desc, SampleInfo };
+
+/// Location of a single sample in the file
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SampleLocation {
+    /// 0-based sample index in the track
+    pub sample: u32,
+    /// 0-based chunk index in the track
+    pub chunk: u32,
+    /// Absolute offset of the sample data in the file
+    pub offset: u64,
+    pub size: u32,
+    /// Decode time of the sample in seconds (without the edit list applied)
+    pub time: f64,
+    /// Duration of the sample in seconds
+    pub duration: f64,
+    pub is_sync: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+struct TrackIndex {
+    handler_type: String,
+    timescale: u32,
+    samples: Vec<SampleInfo>,
+}
+
+/// Maps track time to the sample, chunk and byte offset containing it.
+/// Can be built for any input file or for the merged output.
+#[derive(Debug, Clone, Default)]
+pub struct RandomAccessIndex {
+    tracks: Vec<TrackIndex>,
+    mdat_start: u64,
+}
+
+impl RandomAccessIndex {
+    /// Build the index of a file by parsing its sample tables
+    pub fn from_reader<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        let desc = desc_reader::read_file_desc(reader)?;
+        Ok(Self::from_desc(&desc))
+    }
+
+    /// Build the index from a description. Chunk offsets are relative to `desc.mdat_final_position`.
+    pub(crate) fn from_desc(desc: &Desc) -> Self {
+        let num_tracks = desc.moov_tracks.iter().rposition(|t| !t.handler_type.is_empty()).map(|x| x + 1).unwrap_or(0);
+        Self {
+            tracks: desc.moov_tracks[..num_tracks].iter().map(|t| TrackIndex {
+                handler_type: t.handler_type.clone(),
+                timescale: t.mdhd_timescale.max(1),
+                samples: t.sample_infos(),
+            }).collect(),
+            mdat_start: desc.mdat_final_position,
+        }
+    }
+
+    pub fn track_count(&self) -> usize { self.tracks.len() }
+
+    /// Handler type of the track, e.g. "vide" or "soun"
+    pub fn handler_type(&self, track: usize) -> Option<&str> {
+        self.tracks.get(track).map(|t| t.handler_type.as_str())
+    }
+
+    /// Index of the first track with the given handler type
+    pub fn find_track(&self, handler_type: &str) -> Option<usize> {
+        self.tracks.iter().position(|t| t.handler_type == handler_type)
+    }
+
+    pub fn sample_count(&self, track: usize) -> usize {
+        self.tracks.get(track).map(|t| t.samples.len()).unwrap_or(0)
+    }
+
+    /// Location of the given sample
+    pub fn sample(&self, track: usize, sample: usize) -> Option<SampleLocation> {
+        let t = self.tracks.get(track)?;
+        let info = t.samples.get(sample)?;
+        Some(SampleLocation {
+            sample: sample as u32,
+            chunk: info.chunk,
+            offset: self.mdat_start.wrapping_add(info.offset),
+            size: info.size,
+            time: info.decode_time as f64 / t.timescale as f64,
+            duration: info.duration as f64 / t.timescale as f64,
+            is_sync: info.is_sync,
+        })
+    }
+
+    /// The sample which is presented at `time` seconds (the last sample starting at or before `time`)
+    pub fn locate(&self, track: usize, time: f64) -> Option<SampleLocation> {
+        let index = self.sample_at(track, time)?;
+        self.sample(track, index)
+    }
+
+    /// The last sync sample at or before `time` seconds, i.e. where decoding has to start to present `time`
+    pub fn locate_sync(&self, track: usize, time: f64) -> Option<SampleLocation> {
+        let index = self.sample_at(track, time)?;
+        let samples = &self.tracks.get(track)?.samples;
+        let sync = samples[..=index].iter().rposition(|x| x.is_sync)?;
+        self.sample(track, sync)
+    }
+
+    fn sample_at(&self, track: usize, time: f64) -> Option<usize> {
+        let t = self.tracks.get(track)?;
+        let time = (time.max(0.0) * t.timescale as f64 + 1e-6).floor() as u64;
+        t.samples.partition_point(|x| x.decode_time <= time).checked_sub(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::desc_reader::TrackDesc;
+
+    #[test]
+    fn test_locate() {
+        let mut desc = Desc { mdat_final_position: 100, ..Default::default() };
+        desc.moov_tracks.push(TrackDesc {
+            mdhd_timescale: 10,
+            handler_type: "vide".into(),
+            stts: vec![(20, 1)],
+            stsz_sample_size: 5,
+            stsz_count: 20,
+            stss: vec![1, 11],
+            stsc: vec![(1, 2, 1)],
+            stco: (0..10).map(|x| x * 1000).collect(),
+            ..Default::default()
+        });
+        let index = RandomAccessIndex::from_desc(&desc);
+
+        let sample = index.locate(0, 1.55).unwrap();
+        assert_eq!((sample.sample, sample.chunk, sample.offset, sample.is_sync), (15, 7, 100 + 7000 + 5, false));
+
+        let sync = index.locate_sync(0, 1.55).unwrap();
+        assert_eq!((sync.sample, sync.offset), (10, 100 + 5000));
+        assert_eq!(index.find_track("vide"), Some(0));
+        assert!(index.locate(1, 0.0).is_none());
+    }
+}
@@ -0,0 +1,127 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+//! Box structure of a file, as seen by the merger
+
+use std::io::{ Read, Seek, SeekFrom, Result };
+use crate::boxes::{ fourcc, BoxHeader, BoxIter };
+
+/// Boxes of a file and their nesting
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Mp4Structure {
+    pub boxes: Vec<BoxNode>,
+    /// The invalid box which stopped the walk, e.g. in a truncated file. The boxes before it are kept
+    pub error: Option<String>,
+}
+
+/// A box and its children. Only container boxes are descended into, not sample entries or data boxes
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoxNode {
+    pub header: BoxHeader,
+    pub children: Vec<BoxNode>,
+}
+
+impl Mp4Structure {
+    /// Find the first box at the given path, e.g. `["moov", "trak", "mdia"]`
+    pub fn find(&self, path: &[&str]) -> Option<&BoxNode> {
+        let (first, rest) = path.split_first()?;
+        let mut node = self.boxes.iter().find(|x| x.header.typ == fourcc(first))?;
+        for typ in rest {
+            node = node.children.iter().find(|x| x.header.typ == fourcc(typ))?;
+        }
+        Some(node)
+    }
+}
+
+impl std::fmt::Display for Mp4Structure {
+    /// One box per line, indented by nesting: type, offset and size
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn write_nodes(f: &mut std::fmt::Formatter<'_>, nodes: &[BoxNode], depth: usize) -> std::fmt::Result {
+            for node in nodes {
+                writeln!(f, "{:indent$}{} @ {}, {} bytes", "", node.header.typ_str(), node.header.offset, node.header.size, indent = depth * 2)?;
+                write_nodes(f, &node.children, depth + 1)?;
+            }
+            Ok(())
+        }
+        write_nodes(f, &self.boxes, 0)?;
+        if let Some(error) = &self.error {
+            writeln!(f, "Error: {error}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Read the box tree of a file, built on the same box walk the merger uses. The Insta360 metadata trailer isn't walked
+pub fn read_structure<R: Read + Seek>(reader: &mut R) -> Result<Mp4Structure> {
+    let mut structure = Mp4Structure::default();
+    let end = match crate::insta360::trailer_start(reader)? {
+        Some(x) => x,
+        None => reader.seek(SeekFrom::End(0))?
+    };
+    let mut iter = BoxIter::new(reader, 0, end);
+    structure.boxes = read_nodes(&mut iter, &mut structure.error)?;
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(structure)
+}
+
+fn read_nodes<R: Read + Seek>(iter: &mut BoxIter<'_, R>, error: &mut Option<String>) -> Result<Vec<BoxNode>> {
+    let mut nodes = Vec::new();
+    while let Some(header) = iter.next() {
+        let header = match header {
+            Ok(x) => x,
+            Err(e) => { *error = Some(e.to_string()); break; }
+        };
+        let children = match children_offset(iter.reader(), &header)? {
+            Some(offset) if offset <= header.end() => read_nodes(&mut BoxIter::new(iter.reader(), offset, header.end()), error)?,
+            _ => Vec::new()
+        };
+        nodes.push(BoxNode { header, children });
+        if error.is_some() { break; }
+    }
+    Ok(nodes)
+}
+
+/// Offset of the first child of a container box, None for other boxes
+fn children_offset<R: Read + Seek>(reader: &mut R, header: &BoxHeader) -> Result<Option<u64>> {
+    const CONTAINERS: [&str; 16] = ["moov", "trak", "edts", "mdia", "minf", "dinf", "stbl", "udta", "tref", "mvex", "moof", "traf", "mfra", "ilst", "sinf", "schi"];
+    let typ = header.typ;
+    if CONTAINERS.iter().any(|x| fourcc(x) == typ) {
+        return Ok(Some(header.content_offset()));
+    }
+    if typ == fourcc("stsd") || typ == fourcc("dref") {
+        // Version, flags and entry count
+        return Ok(Some(header.content_offset() + 8));
+    }
+    if typ == fourcc("meta") && header.content_size() >= 4 {
+        // A full box in MP4, but not in QuickTime files where the hdlr follows the header directly
+        let mut version_flags = [0u8; 4];
+        reader.seek(SeekFrom::Start(header.content_offset()))?;
+        reader.read_exact(&mut version_flags)?;
+        return Ok(Some(header.content_offset() + if version_flags == [0; 4] { 4 } else { 0 }));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+
+    #[test]
+    fn test_read_structure() {
+        let file = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 10)).track(SyntheticTrack::audio(48000, 20)).build();
+        let structure = read_structure(&mut std::io::Cursor::new(&file)).unwrap();
+        assert_eq!(structure.boxes.iter().map(|x| x.header.typ_str()).collect::<Vec<_>>(), ["ftyp", "mdat", "moov"]);
+        assert_eq!(structure.boxes.last().unwrap().header.end(), file.len() as u64);
+        let moov = structure.find(&["moov"]).unwrap();
+        assert_eq!(moov.children.iter().filter(|x| x.header.typ == fourcc("trak")).count(), 2);
+        let stsd = structure.find(&["moov", "trak", "mdia", "minf", "stbl", "stsd"]).unwrap();
+        assert_eq!(stsd.children.iter().map(|x| x.header.typ_str()).collect::<Vec<_>>(), ["avc1"]);
+        assert!(structure.to_string().contains("\n  mvhd @ "));
+
+        // Truncated file: the boxes before the broken one are kept
+        let structure = read_structure(&mut std::io::Cursor::new(&file[..file.len() - 10])).unwrap();
+        assert_eq!(structure.boxes.len(), 2);
+        assert!(structure.error.is_some());
+    }
+}
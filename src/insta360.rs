@@ -1,11 +1,25 @@
 use std::{collections::BTreeMap, io::*};
 use byteorder::{ LittleEndian, ReadBytesExt, WriteBytesExt };
-use crate::writer::get_first;
+use crate::diagnostics::diag;
 
 pub const HEADER_SIZE: usize = 32 + 4 + 4 + 32; // padding(32), size(4), version(4), magic(32)
 pub const MAGIC: &[u8] = b"8db42d694ccc418790edff439fe026bf";
 
-pub fn get_insta360_offsets<R: Read + Seek>(files: &mut [(R, usize)]) -> Result<Vec<BTreeMap<u64, (u32, u8, u8, i64)>>> {
+/// Offset in the file -> (data version, record id, record format, record size)
+pub type RecordOffsets = BTreeMap<u64, (u32, u8, u8, i64)>;
+
+/// Offset of the metadata trailer which Insta360 cameras append after the boxes, None when the file doesn't end with one
+pub(crate) fn trailer_start<R: Read + Seek>(stream: &mut R) -> Result<Option<u64>> {
+    let size = stream.seek(SeekFrom::End(0))?;
+    if size < 40 { return Ok(None); }
+    stream.seek(SeekFrom::End(-40))?;
+    let mut buf = [0u8; 40];
+    stream.read_exact(&mut buf)?;
+    if &buf[8..] != MAGIC { return Ok(None); }
+    Ok(Some(size.saturating_sub((&buf[..]).read_u32::<LittleEndian>()? as u64)))
+}
+
+pub fn get_insta360_offsets<R: Read + Seek>(files: &mut [(R, usize)]) -> Result<Vec<RecordOffsets>> {
     let mut ret = Vec::new();
     for (ref mut stream, size) in files {
         let mut stream = std::io::BufReader::with_capacity(16*1024, stream);
@@ -62,56 +76,191 @@ pub fn get_insta360_offsets<R: Read + Seek>(files: &mut [(R, usize)]) -> Result<
                 }
             }
         }
+        diag!(Debug, "Found {} Insta360 metadata records", offsets.len());
         ret.push(offsets);
     }
     Ok(ret)
 }
 
-pub fn merge_metadata<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize)], offsets: &[BTreeMap<u64, (u32, u8, u8, i64)>], mut f_out: W) -> Result<()> {
+/// Format of the Offsets record (id 0) which newer firmware writes last in the trailer, None when the records are only chained
+fn offsets_record_format<R: Read + Seek>(stream: &mut R) -> Result<Option<u8>> {
+    stream.seek(SeekFrom::End(-(HEADER_SIZE as i64 + 4+1+1)))?;
+    let format = stream.read_u8()?;
+    let id     = stream.read_u8()?;
+    Ok((id == 0).then_some(format))
+}
+
+/// Merge the trailers of all files. The records are laid out like in the first file which has metadata, files without it
+/// (e.g. a chapter saved by an app which stripped the trailer) are skipped. The binary records (gyro, exposure...) are
+/// timestamped by the camera clock, so the data of the other files keeps its place and the missing chapters leave a hole.
+/// When the template has an Offsets record, a new one pointing to the merged records is written.
+pub fn merge_metadata<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize)], offsets: &[RecordOffsets], mut f_out: W) -> Result<()> {
     assert_eq!(files.len(), offsets.len());
 
     let mut total_size = 0;
     let mut data_version = 3;
 
-    for (offset, (ver, id, format, size)) in offsets.first().unwrap() {
+    let Some(template) = offsets.iter().position(|x| !x.is_empty()) else {
+        diag!(Warn, "None of the files has Insta360 metadata records");
+        return Ok(());
+    };
+    if template > 0 {
+        diag!(Warn, "The first file has no Insta360 metadata, the record layout of file {template} is used");
+    }
+
+    let offsets_format = offsets_record_format(&mut files[template].0)?;
+    // Id, format, size and offset from the start of the trailer of each merged record
+    let mut merged_records = Vec::new();
+
+    for (offset, (ver, id, format, size)) in &offsets[template] {
         data_version = *ver;
-        let first_stream = get_first(files);
-        first_stream.seek(SeekFrom::Start(*offset))?;
-        std::io::copy(&mut first_stream.take(*size as u64), &mut f_out)?;
+        let template_stream = &mut files[template].0;
+        template_stream.seek(SeekFrom::Start(*offset + *size as u64))?;
 
-        let format2 = first_stream.read_u8()?;
-        let id2     = first_stream.read_u8()?;
-        let mut size2 = first_stream.read_u32::<LittleEndian>()? as i64;
+        let format2 = template_stream.read_u8()?;
+        let id2     = template_stream.read_u8()?;
+        let size2   = template_stream.read_u32::<LittleEndian>()? as i64;
 
         if *id != id2 || *format != format2 || *size != size2 {
+            diag!(Error, "Insta360 record {id} doesn't match its header (format {format2}, size {size2})");
             return Err(Error::new(ErrorKind::InvalidData, "Invalid metadata"));
         }
+        diag!(Debug, "Merging Insta360 record {id2}, format {format2}");
 
-        if id2 != 0 && id2 != 1 && id2 != 2 && id2 != 5 { // If not Offsets, Metadata, Thumbnail, ThumbnailExt
-            // Merge binary data
-            for (file_i, map) in offsets.iter().enumerate() {
-                if file_i == 0 { continue; }
-                for (offset, (_ver, id, _format, size)) in map {
-                    if id2 == *id {
-                        let stream_i = files.get_mut(file_i).map(|x| &mut x.0).unwrap();
-                        stream_i.seek(SeekFrom::Start(*offset))?;
-                        std::io::copy(&mut stream_i.take(*size as u64), &mut f_out)?;
-                        size2 += *size as i64;
-                    }
+        let mut merged_size = 0;
+        for (file_i, map) in offsets.iter().enumerate() {
+            // Only the binary data is merged, Offsets, Metadata, Thumbnail and ThumbnailExt come from the template
+            if file_i != template && matches!(id2, 0 | 1 | 2 | 5) { continue; }
+            for (offset, (_ver, id, _format, size)) in map {
+                if id2 == *id {
+                    let stream_i = &mut files[file_i].0;
+                    stream_i.seek(SeekFrom::Start(*offset))?;
+                    std::io::copy(&mut stream_i.take(*size as u64), &mut f_out)?;
+                    merged_size += *size;
                 }
             }
         }
         f_out.write_u8(format2)?;
         f_out.write_u8(id2)?;
-        f_out.write_u32::<LittleEndian>(size2 as u32)?;
-        total_size += size2 + 1+1+4;
+        f_out.write_u32::<LittleEndian>(merged_size as u32)?;
+        merged_records.push((id2, format2, merged_size as u32, total_size as u32));
+        total_size += merged_size + 1+1+4;
+    }
+
+    if let Some(format) = offsets_format {
+        for (id, format, size, offset) in &merged_records {
+            f_out.write_u8(*id)?;
+            f_out.write_u8(*format)?;
+            f_out.write_u32::<LittleEndian>(*size)?;
+            f_out.write_u32::<LittleEndian>(*offset)?;
+        }
+        let size = merged_records.len() as i64 * (1+1+4+4);
+        f_out.write_u8(format)?;
+        f_out.write_u8(0)?;
+        f_out.write_u32::<LittleEndian>(size as u32)?;
+        total_size += size + 1+1+4;
     }
 
     f_out.write_u128::<LittleEndian>(0)?; // padding
     f_out.write_u128::<LittleEndian>(0)?; // padding
     f_out.write_u32::<LittleEndian>(total_size as u32 + 72)?;
     f_out.write_u32::<LittleEndian>(data_version)?; // version
-    f_out.write(MAGIC)?;
+    f_out.write_all(MAGIC)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_trailer(data: &[u8], records: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut ret = data.to_vec();
+        let start = ret.len();
+        for (id, record) in records.iter().rev() {
+            ret.extend_from_slice(record);
+            ret.extend_from_slice(&[0, *id]);
+            ret.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        }
+        let extra_size = (ret.len() - start + HEADER_SIZE) as u32;
+        ret.extend_from_slice(&[0; 32]);
+        ret.extend_from_slice(&extra_size.to_le_bytes());
+        ret.extend_from_slice(&3u32.to_le_bytes());
+        ret.extend_from_slice(MAGIC);
+        ret
+    }
+
+    /// Trailer of newer firmware, with an Offsets record pointing to the other records
+    fn with_offsets_trailer(data: &[u8], records: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut trailer = Vec::new();
+        let mut entries = Vec::new();
+        for (id, record) in records {
+            entries.extend([*id, 0]);
+            entries.extend((record.len() as u32).to_le_bytes());
+            entries.extend((trailer.len() as u32).to_le_bytes());
+            trailer.extend_from_slice(record);
+            trailer.extend([0, *id]);
+            trailer.extend((record.len() as u32).to_le_bytes());
+        }
+        trailer.extend_from_slice(&entries);
+        trailer.extend([0, 0]);
+        trailer.extend((entries.len() as u32).to_le_bytes());
+        let extra_size = (trailer.len() + HEADER_SIZE) as u32;
+        [data, &trailer, &[0; 32], &extra_size.to_le_bytes(), &3u32.to_le_bytes(), MAGIC].concat()
+    }
+
+    /// Records of a trailer, read like a reader of the Insta360 format does
+    fn records(data: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let offsets = get_insta360_offsets(&mut [(Cursor::new(data), data.len())]).unwrap().remove(0);
+        offsets.iter().map(|(offset, (_, id, _, size))| (*id, data[*offset as usize..][..*size as usize].to_vec())).collect()
+    }
+
+    #[test]
+    fn test_merge_offsets_record() {
+        let inputs = [with_offsets_trailer(&[0; 40], &[(1, b"meta1"), (3, b"gyro1"), (4, b"exp1")]), with_offsets_trailer(&[0; 60], &[(1, b"meta2"), (3, b"gyro22"), (4, b"exp2")])];
+        assert_eq!(records(&inputs[0]), [(1, b"meta1".to_vec()), (3, b"gyro1".to_vec()), (4, b"exp1".to_vec())]);
+        let mut files = inputs.iter().map(|x| (Cursor::new(x), x.len())).collect::<Vec<_>>();
+        let offsets = get_insta360_offsets(&mut files).unwrap();
+
+        let mut output = Cursor::new(vec![0u8; 30]);
+        output.seek(SeekFrom::End(0)).unwrap();
+        merge_metadata(&mut files, &offsets, &mut output).unwrap();
+        let output = output.into_inner();
+        assert_eq!(output, with_offsets_trailer(&[0; 30], &[(1, b"meta1"), (3, b"gyro1gyro22"), (4, b"exp1exp2")]));
+        assert_eq!(records(&output), [(1, b"meta1".to_vec()), (3, b"gyro1gyro22".to_vec()), (4, b"exp1exp2".to_vec())]);
+    }
+
+    #[test]
+    fn test_merge_dual_track_insv() {
+        // Single-file mode of newer cameras: both lenses as video tracks of one file
+        let file = crate::test_util::SyntheticMp4::new().track(crate::test_util::SyntheticTrack::video(30000, 1001, 20)).track(crate::test_util::SyntheticTrack::video(30000, 1001, 20)).track(crate::test_util::SyntheticTrack::audio(48000, 20));
+        let inputs = [with_trailer(&file.build(), &[(1, b"meta1"), (3, b"gyro1")]), with_trailer(&file.build(), &[(1, b"meta2"), (3, b"gyro2")])];
+        let mut files = inputs.iter().map(|x| (Cursor::new(x.clone()), x.len())).collect::<Vec<_>>();
+        let mut output = Cursor::new(Vec::new());
+        crate::join_file_streams_with_options(&mut files, &mut output, &[None, None], &crate::MergeOptions::default(), |_| {}).unwrap();
+        let output = output.into_inner();
+
+        let index = crate::RandomAccessIndex::from_reader(&mut Cursor::new(&output)).unwrap();
+        for track in 0..2 {
+            assert_eq!(index.sample_count(track), 40);
+            let sample = index.sample(track, 21).unwrap();
+            assert_eq!(&output[sample.offset as usize..][..sample.size as usize], &file.sample_data(track, 1)[..]);
+        }
+        assert_eq!(crate::verify::check(&mut Cursor::new(&output)).unwrap().issues, []);
+        // Records in file order, with_trailer writes them reversed
+        assert_eq!(records(&output), [(3, b"gyro1gyro2".to_vec()), (1, b"meta1".to_vec())]);
+    }
+
+    #[test]
+    fn test_merge_with_missing_trailer() {
+        let inputs = [vec![0u8; 100], with_trailer(&[0; 50], &[(1, b"meta2"), (3, b"gyro2")]), with_trailer(&[0; 70], &[(1, b"meta3"), (3, b"gyro3")])];
+        let mut files = inputs.iter().map(|x| (Cursor::new(x), x.len())).collect::<Vec<_>>();
+        let offsets = get_insta360_offsets(&mut files).unwrap();
+        assert!(offsets[0].is_empty());
+
+        let mut output = Cursor::new(Vec::new());
+        merge_metadata(&mut files, &offsets, &mut output).unwrap();
+        // Metadata of the first file which has it, the gyro data of all files
+        assert_eq!(output.into_inner(), with_trailer(&[], &[(1, b"meta2"), (3, b"gyro2gyro3")]));
+    }
+}
@@ -0,0 +1,167 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::fs::File;
+use std::io::{ Write, Result };
+use crate::external::InputFile;
+use crate::cancel::CancellationToken;
+use crate::progress_stream::{ self, ProgressPhase, ProgressReporter };
+use crate::{ copy, diagnostics::{ self, diag }, MergeOptions, MergeReport, OutputFormat };
+
+/// Largest part of an mdat range copied by one call, so the progress and the cancellation are checked in between
+const CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Whether `join_files_with_options` copies the mdat data file to file in the kernel: a single MP4 output and inputs which are plain files.
+/// The stall watchdog and the parallel readers need the reads in user space, so they turn it off
+pub(crate) fn applies(files: &[(InputFile, usize)], options: &MergeOptions) -> bool {
+    cfg!(target_os = "linux") && !options.disable_kernel_copy && options.max_output_size.is_none() && options.tee_outputs.is_empty()
+        && options.stall_timeout.is_none() && options.copy_threads.is_none()
+        && options.output_format == OutputFormat::Mp4 && !options.allows_passthrough(files.len())
+        && files.iter().all(|x| matches!(x.0, InputFile::File(_)))
+}
+
+/// Merge the files like `join_file_streams_sequential`, copying the mdat data with `copy_file_range`. The data doesn't pass through
+/// user space, and filesystems like Btrfs and XFS share the extents instead of copying them. When the kernel can't copy between
+/// the files, e.g. on another filesystem, the data is read and written as usual
+pub(crate) fn join_files<F: Fn(f64)>(files: &mut [(InputFile, usize)], mut output: File, file_metadata: &[Option<std::time::SystemTime>], options: &MergeOptions, progress_cb: F) -> Result<MergeReport> {
+    let _diagnostics = diagnostics::scope(options.diagnostics.as_ref());
+    let _events = progress_stream::event_scope(options.progress_events.as_ref());
+    let cancellation = options.cancellation.as_ref();
+    CancellationToken::check(cancellation)?;
+    let mut scan = crate::scan_files(files, file_metadata, options, &progress_cb)?;
+    let total_size = scan.total_size;
+
+    let reporter = ProgressReporter::new(options.progress_listener.as_ref(), total_size as u64);
+    scan.desc.set_table_progress(reporter.clone());
+    let progress = |total: u64| {
+        let fraction = (0.1 + ((total as f64 / total_size as f64) * 0.9)).min(0.9999);
+        progress_cb(fraction);
+        if let Some(reporter) = &reporter { reporter.bytes(total, fraction); }
+    };
+    let (layout, trailer) = crate::sequential_layout(files, &scan.first_boxes, &mut scan.desc, scan.insta360_max_read, scan.gpmf_detected)?;
+
+    diagnostics::set_phase(diagnostics::Phase::Write, None);
+    let desc = &scan.desc;
+    let (before_data, after_data) = layout.split_at(desc.mdat_final_position as usize);
+    output.write_all(before_data)?;
+    let mut written = before_data.len() as u64;
+    let data_size = desc.mdat_position.iter().filter(|x| x.0.is_none_or(|i| i < files.len())).map(|x| x.2).sum::<u64>();
+    let mut kernel_copy = true;
+    for &(file_index, offset, size) in &desc.mdat_position {
+        match file_index {
+            Some(file_index) if file_index < files.len() => {
+                let mut done = 0;
+                while done < size {
+                    CancellationToken::check(cancellation)?;
+                    let len = (size - done).min(CHUNK_SIZE);
+                    let copied = match &files[file_index].0 {
+                        InputFile::File(input) if kernel_copy => copy_file_range(input, offset + done, len, &output)?,
+                        _ => None
+                    };
+                    let copied = match copied {
+                        Some(0) => return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, format!("File {file_index} ends before its mdat data"))),
+                        Some(copied) => copied,
+                        None => {
+                            if kernel_copy { diag!(Debug, "copy_file_range isn't supported between these files, copying the data through memory"); }
+                            kernel_copy = false;
+                            copy::copy_ranges(std::slice::from_mut(&mut files[file_index]), &[(0, offset + done, len)], &mut output, &desc.copy)?
+                        }
+                    };
+                    done += copied;
+                    written += copied;
+                    progress(written);
+                    progress_stream::emit_event(ProgressPhase::Copying, Some(file_index), written - before_data.len() as u64, data_size);
+                }
+            },
+            Some(_) => { },
+            None => {
+                let data = desc.synthesized_data.get(offset as usize..(offset + size) as usize)
+                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Synthesized sample data out of range"))?;
+                output.write_all(data)?;
+                written += size;
+                progress_stream::emit_event(ProgressPhase::Copying, None, written - before_data.len() as u64, data_size);
+            }
+        }
+    }
+    output.write_all(after_data)?;
+    output.write_all(&trailer)?;
+    output.flush()?;
+    scan.verify_inputs(files)?;
+
+    progress_cb(1.0);
+
+    Ok(MergeReport::from_desc(&scan.desc, &scan.input_order))
+}
+
+/// Copy up to `len` bytes at `offset` of `input` to the current position of `output`. Returns the number of bytes copied,
+/// or None when the kernel can't copy between the two files
+#[cfg(target_os = "linux")]
+fn copy_file_range(input: &File, offset: u64, len: u64, output: &File) -> Result<Option<u64>> {
+    use std::os::fd::AsRawFd;
+    let mut offset_in = offset as libc::loff_t;
+    // SAFETY: both descriptors are open for the lifetime of the borrows, and the output offset is the file position
+    let copied = unsafe { libc::copy_file_range(input.as_raw_fd(), &mut offset_in, output.as_raw_fd(), std::ptr::null_mut(), len as usize, 0) };
+    if copied >= 0 {
+        return Ok(Some(copied as u64));
+    }
+    let e = std::io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EXDEV | libc::ENOSYS | libc::EOPNOTSUPP | libc::EINVAL | libc::EPERM) => Ok(None),
+        _ => Err(e)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_file_range(_input: &File, _offset: u64, _len: u64, _output: &File) -> Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+
+    #[test]
+    fn test_kernel_copy() {
+        let file = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50)).track(SyntheticTrack::audio(48000, 50));
+        let dir = std::env::temp_dir().join(format!("mp4_merge_kernel_copy_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let inputs = [dir.join("a.mp4"), dir.join("b.mp4")];
+        for input in &inputs {
+            std::fs::write(input, file.build()).unwrap();
+        }
+        let mut outputs = Vec::new();
+        for disable_kernel_copy in [false, true] {
+            let output = dir.join(format!("out_{disable_kernel_copy}.mp4"));
+            let options = crate::MergeOptions { disable_kernel_copy, ..Default::default() };
+            crate::join_files_with_options(&inputs, &output, &options, |_| {}).unwrap();
+            outputs.push(std::fs::read(&output).unwrap());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(outputs[0], outputs[1]);
+        assert!(crate::verify::check(&mut std::io::Cursor::new(&outputs[0])).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_stall_timeout_uses_reader_threads() {
+        let file = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50));
+        let dir = std::env::temp_dir().join(format!("mp4_merge_kernel_copy_stall_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let inputs = [dir.join("a.mp4"), dir.join("b.mp4")];
+        for input in &inputs {
+            std::fs::write(input, file.build()).unwrap();
+        }
+        let files = inputs.iter().map(|x| InputFile::open(x, &[]).map(|(f, size)| (f, size as usize))).collect::<std::io::Result<Vec<_>>>().unwrap();
+        let watched = crate::MergeOptions { stall_timeout: Some(std::time::Duration::from_secs(10)), ..Default::default() };
+        let parallel = crate::MergeOptions { copy_threads: Some(2), ..Default::default() };
+        let output = dir.join("out.mp4");
+        crate::join_files_with_options(&inputs, &output, &watched, |_| {}).unwrap();
+        let merged = std::fs::read(&output).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(applies(&files, &crate::MergeOptions::default()), cfg!(target_os = "linux"));
+        assert!(!applies(&files, &watched) && !applies(&files, &parallel));
+        assert!(crate::verify::check(&mut std::io::Cursor::new(&merged)).unwrap().is_ok());
+    }
+}
@@ -3,15 +3,105 @@
 
 use std::io::{ Read, Seek, Write, Result };
 use std::path::*;
-use byteorder::{ BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt };
 use std::time::Instant;
 
+pub mod boxes;
+pub mod inspect;
+pub mod verify;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 mod desc_reader;
 mod progress_stream;
+mod box_cache;
 mod writer;
 mod insta360;
 mod gpmf;
+mod gopro;
+mod options;
+mod report;
+mod split;
+mod stsd;
+mod extract;
+mod index;
+mod compatibility;
+mod batch;
+mod passthrough;
+mod multi_lens;
+mod grouping;
+mod track_info;
+mod diagnostics;
+mod copy;
+mod kernel_copy;
+mod preallocate;
+mod dry_run;
+mod sidx;
+mod encryption;
+mod mkv;
+mod telemetry;
+mod timecode;
+mod repair;
+mod playlist;
+mod rescale;
+mod encoder_delay;
+mod highlights;
+mod reference;
+mod external;
+mod fingerprint;
+mod vendor;
+mod lrv_repair;
+mod gpx;
+mod audio_replacement;
+mod param_sets;
+mod metadata;
+mod missing_tracks;
+mod template;
+mod chapter_markers;
+mod tee;
+mod error;
+mod cancel;
+mod track_filter;
+mod track_match;
+mod chapter_repair;
+mod defragment;
+mod discovery;
+#[cfg(feature = "tokio")]
+mod async_merge;
 use progress_stream::*;
+use boxes::{ fourcc, typ_to_str };
+use diagnostics::diag;
+use copy::RangeCopier;
+pub use boxes::read_box;
+pub use options::{ MergeOptions, OutputFormat };
+pub use progress_stream::{ ProgressInfo, ProgressListener, WriteStage, ProgressEvent, ProgressEventListener, ProgressPhase };
+pub use diagnostics::{ Diagnostic, DiagnosticsSink, Phase };
+pub use report::{ MergeReport, FileReport, PartReport, TrackReport, BoundaryReport };
+pub use desc_reader::{ FileInfo, GapDecision, GapModel, EditListEntry, EditListTrack, EditListEditor };
+pub use extract::{ extract_first_video_sample, VideoSample };
+pub use index::{ RandomAccessIndex, SampleLocation };
+pub use compatibility::{ check_compatibility, CompatibilityReport, CompatibilityCheck, CheckResult };
+pub use stsd::SampleEntry;
+pub use timecode::TimecodeFormat;
+pub use gopro::CameraInfo;
+pub use grouping::group_split_files;
+pub use track_info::{ list_tracks, TrackInfo, TelemetryFormat };
+pub use multi_lens::{ back_lens_path, merge_dual_lens, merge_insta360_pro, merge_insv, merge_lens_groups, DualLensReport };
+pub use batch::{ merge_groups, MergeGroup };
+pub use reference::write_reference_movie;
+pub use playlist::{ read_playlist, join_from_playlist, join_from_playlist_with_options };
+pub use vendor::{ read_vendor_boxes, VendorBox, CANON_UUID };
+pub use lrv_repair::{ repair_from_lrv, repair_streams_from_lrv, LrvRepairReport };
+pub use gpx::{ GpxTrack, GpxFormat };
+pub use gpmf::{ export_gpx, export_kml, read_gpmf_telemetry, GpmfTelemetry, GpmfGpsSample, GpmfImuSample };
+pub use param_sets::{ compare_parameter_sets, ParameterSetDiff };
+pub use template::TemplateSelection;
+pub use dry_run::{ join_files_dry_run, DryRunReport };
+pub use tee::TeeWriter;
+pub use error::Error;
+pub use cancel::CancellationToken;
+pub use track_filter::TrackFilter;
+pub use discovery::{ find_dji_chapters, find_gopro_chapters };
+#[cfg(feature = "tokio")]
+pub use async_merge::join_file_streams_async;
 
 // We need to:
 // - Merge mdat boxes
@@ -20,60 +110,156 @@ use progress_stream::*;
 // - Sum         moov/trak/mdia/mdhd/duration
 // - Sum         moov/trak/edts/elst/segment duration
 // - Merge lists moov/trak/mdia/minf/stbl/stts
+// - Merge lists moov/trak/mdia/minf/stbl/ctts
 // - Merge lists moov/trak/mdia/minf/stbl/stsz
 // - Merge lists moov/trak/mdia/minf/stbl/stss
 // - Merge lists moov/trak/mdia/minf/stbl/stco and co64
-// - Rewrite stco to co64
+// - Rewrite stco to co64, unless `keep_stco` is set and the merged file stays below 4 GiB
+
+/// Room for the tables added to the moov by the merge, when checking whether the merged file fits in 32-bit chunk offsets
+const STCO_SIZE_MARGIN: u64 = 64 * 1024 * 1024;
 
-const fn fourcc(s: &str) -> u32 {
-    let s = s.as_bytes();
-    (s[3] as u32) | ((s[2] as u32) << 8) | ((s[1] as u32) << 16) | ((s[0] as u32) << 24)
-}
 const fn has_children(typ: u32, is_read: bool) -> bool {
     typ == fourcc("moov") || typ == fourcc("trak") || typ == fourcc("edts") ||
     typ == fourcc("mdia") || typ == fourcc("minf") || typ == fourcc("stbl") ||
     (typ == fourcc("stsd") && is_read)
 }
-fn typ_to_str(typ: u32) -> String {
-    match String::from_utf8(vec![(typ >> 24) as u8, (typ >> 16) as u8, (typ >> 8) as u8, typ as u8 ]) {
-        Ok(x) => x,
-        Err(_) => format!("{:08X}", typ)
-    }
-}
 
-pub fn read_box<R: Read + Seek>(reader: &mut R) -> Result<(u32, u64, u64, i64)> {
-    let pos = reader.stream_position()?;
-    let size = reader.read_u32::<BigEndian>()?;
-    let typ = reader.read_u32::<BigEndian>()?;
-    if size == 1 {
-        let largesize = reader.read_u64::<BigEndian>()?;
-        Ok((typ, pos, largesize, 16))
-    } else {
-        Ok((typ, pos, size as u64, 8))
-    }
+pub fn join_files<P: AsRef<Path>, F: Fn(f64)>(files: &[P], output_file: &P, progress_cb: F) -> Result<()> {
+    join_files_with_options(files, output_file, &MergeOptions::default(), progress_cb).map(|_| ())
 }
 
-pub fn join_files<P: AsRef<Path>, F: Fn(f64)>(files: &[P], output_file: &P, progress_cb: F) -> Result<()> {
+pub fn join_files_with_options<P: AsRef<Path>, F: Fn(f64)>(files: &[P], output_file: &P, options: &MergeOptions, progress_cb: F) -> Result<MergeReport> {
     let mut open_files = Vec::with_capacity(files.len());
     let mut file_metadata = Vec::with_capacity(files.len());
+    let named_options;
+    let options = if options.chapter_markers && options.file_names.is_empty() {
+        let file_names = files.iter().map(|x| x.as_ref().file_name().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default()).collect();
+        named_options = MergeOptions { file_names, ..options.clone() };
+        &named_options
+    } else {
+        options
+    };
     
     for x in files {
-        let f = std::fs::File::open(x)?;
-        let metadata = f.metadata()?;
-        let size = metadata.len() as usize;
-        
-        // Extract creation time from file metadata
-        let creation_time = filetime_creation::FileTime::from_creation_time(&metadata)
-            .and_then(|ft| {
-                // Convert FileTime to SystemTime
-                std::time::SystemTime::UNIX_EPOCH.checked_add(std::time::Duration::from_secs(ft.seconds() as u64))
-            });
-        
-        open_files.push((f, size));
-        file_metadata.push(creation_time);
+        // Media referenced from other files is read as if it was in the mdat of the input
+        let (f, size) = external::InputFile::open(x.as_ref(), &options.external_media_paths)?;
+        open_files.push((f, size as usize));
+        file_metadata.push(filesystem_creation_time(&std::fs::metadata(x)?));
+    }
+    if options.repair_truncated_last && open_files.len() > 1 && !chapter_repair::has_moov(&mut open_files.last_mut().unwrap().0)? {
+        diag!(Warn, "The last input has no moov, rebuilding it from the previous input");
+        let (last, _) = open_files.pop().unwrap();
+        let rebuilt = chapter_repair::RebuiltInput::new(last, &mut open_files.last_mut().unwrap().0)?;
+        let size = rebuilt.len() as usize;
+        open_files.push((external::InputFile::Rebuilt(Box::new(rebuilt)), size));
     }
     
-    join_file_streams_with_metadata(&mut open_files, std::fs::File::create(output_file)?, &file_metadata, progress_cb)
+    let output_file = output_file.as_ref();
+    let mut outputs = Vec::new();
+    let estimated_size = preallocate::estimate_output_size(&mut open_files)?;
+    // The space of split outputs isn't reserved
+    let reserved_size = if options.disable_preallocation || options.max_output_size.is_some() { 0 } else { estimated_size };
+    if !options.disable_space_check {
+        // Every output gets the whole merged file, the split parts add up to it
+        let paths = std::iter::once(output_file).chain(options.tee_outputs.iter().map(PathBuf::as_path)).collect::<Vec<_>>();
+        preallocate::check_free_space(&paths, estimated_size)?;
+    }
+    let create_output = |path: &PathBuf| -> Result<std::fs::File> {
+        let file = std::fs::File::create(path)?;
+        if reserved_size > 0 { preallocate::reserve(&file, reserved_size)?; }
+        Ok(file)
+    };
+    let result = if kernel_copy::applies(&open_files, options) {
+        outputs.push(output_file.to_path_buf());
+        create_output(&outputs[0]).and_then(|f| kernel_copy::join_files(&mut open_files, f, &file_metadata, options, progress_cb))
+    } else if options.max_output_size.is_some() {
+        join_file_streams_split_with_copier(&mut open_files, |i| {
+            outputs.push(split::part_path(output_file, i));
+            std::fs::File::create(&outputs[i])
+        }, &file_metadata, options, &copy::Threaded, progress_cb)
+    } else {
+        outputs.push(output_file.to_path_buf());
+        outputs.extend(options.tee_outputs.iter().cloned());
+        // The merge is written to every output in the same pass
+        outputs.iter().map(create_output).collect::<Result<Vec<_>>>()
+            .and_then(|f| join_file_streams_with_copier(&mut open_files, TeeWriter::new(f), &file_metadata, options, &copy::Threaded, progress_cb))
+    };
+    let report = match result {
+        Ok(report) => report,
+        Err(e) => {
+            // Don't leave incomplete files behind, they look valid at first glance
+            if options.keep_incomplete_output && reserved_size > 0 {
+                for output in outputs.iter().filter(|x| x.exists()) {
+                    preallocate::release_unused(output, reserved_size);
+                }
+            } else if !options.keep_incomplete_output {
+                for output in outputs.iter().filter(|x| x.exists()) {
+                    diag!(Debug, "Removing incomplete output {}", output.display());
+                    if let Err(e) = std::fs::remove_file(output) {
+                        diag!(Warn, "Failed to remove incomplete output {}: {e:?}", output.display());
+                    }
+                }
+            }
+            return Err(e);
+        }
+    };
+    if reserved_size > 0 {
+        for output in &outputs {
+            preallocate::release_unused(output, reserved_size);
+        }
+    }
+
+    if options.creation_time.is_some() || options.modification_time.is_some() {
+        for output in outputs {
+            set_file_times(output, options.creation_time, options.modification_time)?;
+        }
+    }
+
+    Ok(report)
+}
+
+pub(crate) fn filesystem_creation_time(metadata: &std::fs::Metadata) -> Option<std::time::SystemTime> {
+    filetime_creation::FileTime::from_creation_time(metadata)
+        .and_then(|ft| {
+            // Convert FileTime to SystemTime
+            std::time::SystemTime::UNIX_EPOCH.checked_add(std::time::Duration::from_secs(ft.seconds() as u64))
+        })
+}
+
+/// Estimate the duration of the merged output by reading only the mvhd and mdhd headers of each file,
+/// e.g. to show the merged length before merging. The gaps are derived from the creation times, GPS time isn't read.
+pub fn estimate_duration<P: AsRef<Path>>(files: &[P]) -> Result<std::time::Duration> {
+    estimate_duration_with_options(files, &MergeOptions::default())
+}
+
+pub fn estimate_duration_with_options<P: AsRef<Path>>(files: &[P], options: &MergeOptions) -> Result<std::time::Duration> {
+    options.validate(files.len())?;
+
+    let mut desc = desc_reader::Desc::default();
+    for x in files {
+        let f = std::fs::File::open(x)?;
+        desc.file_creation_times.push(filesystem_creation_time(&f.metadata()?));
+        let info = desc_reader::read_headers(&mut std::io::BufReader::with_capacity(16*1024, f))?;
+        desc.file_mvhd_creation_times.push(info.creation_time);
+        desc.file_durations.push(info.first_track_duration.unwrap_or(info.movie_duration));
+    }
+    desc.file_gps_times.resize(files.len(), None);
+    desc.gap_model = options.gap_model.clone();
+    desc.gap_threshold = options.gap_threshold.map(|x| x.as_secs_f64());
+    desc.disable_gaps = options.disable_gaps;
+    desc.edit_list_editor = options.edit_list_editor.clone();
+    desc.gap_overrides = options.explicit_gaps.as_ref().map(|x| x.iter().map(|d| d.as_secs_f64()).collect());
+    desc.file_duration_overrides = options.file_durations.as_ref().map(|x| x.iter().map(|d| d.as_secs_f64()).collect());
+
+    let mut total = (0..files.len()).map(|i| desc.file_info(i).duration).sum::<f64>();
+    for gap in desc_reader::compute_gaps(&desc).unwrap_or_default() {
+        // Overlaps only shorten the output when they are trimmed
+        if gap > 0.0 || options.trim_overlaps {
+            total += gap;
+        }
+    }
+    Ok(std::time::Duration::from_secs_f64(total.max(0.0)))
 }
 
 pub fn join_file_streams<F: Fn(f64), I: Read + Seek, O: Read + Write + Seek>(files: &mut [(I, usize)], output_file: O, progress_cb: F) -> Result<()> {
@@ -82,28 +268,249 @@ pub fn join_file_streams<F: Fn(f64), I: Read + Seek, O: Read + Write + Seek>(fil
     join_file_streams_with_metadata(files, output_file, &empty_metadata, progress_cb)
 }
 
+/// Note: GoPro files which contain chapter numbers in udta are reordered in place to the chapter order.
 pub fn join_file_streams_with_metadata<F: Fn(f64), I: Read + Seek, O: Read + Write + Seek>(files: &mut [(I, usize)], output_file: O, file_metadata: &[Option<std::time::SystemTime>], progress_cb: F) -> Result<()> {
+    join_file_streams_with_options(files, output_file, file_metadata, &MergeOptions::default(), progress_cb).map(|_| ())
+}
+
+/// Note: GoPro files which contain chapter numbers in udta are reordered in place to the chapter order.
+pub fn join_file_streams_with_options<F: Fn(f64), I: Read + Seek, O: Read + Write + Seek>(files: &mut [(I, usize)], output_file: O, file_metadata: &[Option<std::time::SystemTime>], options: &MergeOptions, progress_cb: F) -> Result<MergeReport> {
+    options.validate_stream()?;
+    join_file_streams_with_copier(files, output_file, file_metadata, options, &copy::Synchronous, progress_cb)
+}
+
+/// `join_file_streams_with_options` reading the mdat data with `copier`. The reader threads of `copy::Threaded` need inputs
+/// which can be sent to other threads, so the public stream functions read on the calling thread
+pub(crate) fn join_file_streams_with_copier<F: Fn(f64), I: Read + Seek, O: Read + Write + Seek, T: RangeCopier<I>>(files: &mut [(I, usize)], output_file: O, file_metadata: &[Option<std::time::SystemTime>], options: &MergeOptions, copier: &T, progress_cb: F) -> Result<MergeReport> {
+    if options.max_output_size.is_some() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "max_output_size requires multiple outputs, use join_file_streams_split"));
+    }
+    let _diagnostics = diagnostics::scope(options.diagnostics.as_ref());
+    let _events = progress_stream::event_scope(options.progress_events.as_ref());
+    let output_file = cancel::Cancellable::new(output_file, options.cancellation.as_ref());
+    if options.allows_passthrough(files.len()) {
+        return copy_single_file(&mut files[0].0, files[0].1, output_file, options, progress_cb);
+    }
+    let mut scan = scan_files(files, file_metadata, options, &progress_cb)?;
+    let total_size = scan.total_size;
+
+    let reporter = ProgressReporter::new(options.progress_listener.as_ref(), total_size as u64);
+    scan.desc.set_table_progress(reporter.clone());
+    let progress = |total: usize| {
+        let fraction = (0.1 + ((total as f64 / total_size as f64) * 0.9)).min(0.9999);
+        progress_cb(fraction);
+        if let Some(reporter) = &reporter { reporter.bytes(total as u64, fraction); }
+    };
+    if options.output_format == OutputFormat::Matroska {
+        write_mkv(files, output_file, &scan.desc, progress)?;
+    } else if options.precompute_layout {
+        write_merged_sequential(files, output_file, &scan.first_boxes, &mut scan.desc, scan.insta360_max_read, scan.gpmf_detected, copier, progress)?;
+    } else {
+        write_merged(files, output_file, &scan.first_boxes, &mut scan.desc, scan.insta360_max_read, scan.gpmf_detected, copier, progress)?;
+    }
+    scan.verify_inputs(files)?;
+
+    progress_cb(1.0);
+
+    Ok(MergeReport::from_desc(&scan.desc, &scan.input_order))
+}
+
+/// Merge into an output which doesn't need to be seekable, e.g. a pipe or a network stream.
+/// The final layout is computed before writing, so the output is written front to back in a single pass.
+/// Note: GoPro files which contain chapter numbers in udta are reordered in place to the chapter order.
+pub fn join_file_streams_sequential<F: Fn(f64), I: Read + Seek, O: Write>(files: &mut [(I, usize)], output_file: O, file_metadata: &[Option<std::time::SystemTime>], options: &MergeOptions, progress_cb: F) -> Result<MergeReport> {
+    if options.max_output_size.is_some() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "max_output_size requires multiple outputs, use join_file_streams_split"));
+    }
+    options.validate_stream()?;
+    let _diagnostics = diagnostics::scope(options.diagnostics.as_ref());
+    let _events = progress_stream::event_scope(options.progress_events.as_ref());
+    let output_file = cancel::Cancellable::new(output_file, options.cancellation.as_ref());
+    let mut scan = scan_files(files, file_metadata, options, &progress_cb)?;
+    let total_size = scan.total_size;
+
+    let reporter = ProgressReporter::new(options.progress_listener.as_ref(), total_size as u64);
+    scan.desc.set_table_progress(reporter.clone());
+    let progress = |total: usize| {
+        let fraction = (0.1 + ((total as f64 / total_size as f64) * 0.9)).min(0.9999);
+        progress_cb(fraction);
+        if let Some(reporter) = &reporter { reporter.bytes(total as u64, fraction); }
+    };
+    if options.output_format == OutputFormat::Matroska {
+        write_mkv(files, output_file, &scan.desc, progress)?;
+    } else {
+        write_merged_sequential(files, output_file, &scan.first_boxes, &mut scan.desc, scan.insta360_max_read, scan.gpmf_detected, &copy::Synchronous, progress)?;
+    }
+    scan.verify_inputs(files)?;
+
+    progress_cb(1.0);
+
+    Ok(MergeReport::from_desc(&scan.desc, &scan.input_order))
+}
+
+/// Single input: copy the file instead of running the full merge, applying `faststart`, `strip_free_boxes` and `force_co64` if requested
+fn copy_single_file<F: Fn(f64), I: Read + Seek, O: Read + Write + Seek>(input: &mut I, size: usize, output_file: O, options: &MergeOptions, progress_cb: F) -> Result<MergeReport> {
+    diag!(Debug, "Single input file, passing it through");
+    external::check_self_contained(input, 0)?;
+    let mut desc = desc_reader::read_file_desc(input)?;
+    for (track, entries) in desc.moov_tracks.iter_mut().zip(stsd::read_sample_entries(input)?) {
+        track.sample_entries = entries;
+    }
+    desc.file_cameras.push(gopro::read_udta_info(input)?.camera);
+    let fingerprint = fingerprint::Fingerprint::read(input)?;
+
+    let mut debounce = Instant::now();
+    let reporter = ProgressReporter::new(options.progress_listener.as_ref(), size as u64);
+    let f_out = ProgressStream::new(output_file, |total| {
+        if (Instant::now() - debounce).as_millis() > 100 {
+            let fraction = (total as f64 / size.max(1) as f64).min(0.9999);
+            progress_cb(fraction);
+            if let Some(reporter) = &reporter { reporter.bytes(total as u64, fraction); }
+            debounce = Instant::now();
+        }
+    });
+    let mut f_out = std::io::BufWriter::with_capacity(64*1024, f_out);
+    desc.mdat_final_position = passthrough::copy_single(input, &mut f_out, options)?;
+    f_out.flush()?;
+    drop(f_out);
+    fingerprint::verify_unchanged(&mut [(input, size)], &[fingerprint], &[0])?;
+
+    progress_cb(1.0);
+
+    Ok(MergeReport::from_desc(&desc, &[0]))
+}
+
+/// Merge the files into a series of outputs, each smaller than `options.max_output_size` and starting at a keyframe.
+/// `create_output` is called with the 0-based part index to create each output.
+/// Camera-specific trailers (Insta360, GPMF) are not written to the split outputs.
+pub fn join_file_streams_split<F: Fn(f64), I: Read + Seek, O: Read + Write + Seek, C: FnMut(usize) -> Result<O>>(files: &mut [(I, usize)], create_output: C, file_metadata: &[Option<std::time::SystemTime>], options: &MergeOptions, progress_cb: F) -> Result<MergeReport> {
+    options.validate_stream()?;
+    join_file_streams_split_with_copier(files, create_output, file_metadata, options, &copy::Synchronous, progress_cb)
+}
+
+/// `join_file_streams_split` reading the mdat data with `copier`, see `join_file_streams_with_copier`
+pub(crate) fn join_file_streams_split_with_copier<F: Fn(f64), I: Read + Seek, O: Read + Write + Seek, C: FnMut(usize) -> Result<O>, T: RangeCopier<I>>(files: &mut [(I, usize)], mut create_output: C, file_metadata: &[Option<std::time::SystemTime>], options: &MergeOptions, copier: &T, progress_cb: F) -> Result<MergeReport> {
+    let max_size = options.max_output_size.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "max_output_size is not set"))?;
+    let _diagnostics = diagnostics::scope(options.diagnostics.as_ref());
+    let _events = progress_stream::event_scope(options.progress_events.as_ref());
+    let scan = scan_files(files, file_metadata, options, &progress_cb)?;
+    let total_size = scan.total_size;
+
+    let mut parts = split::split_desc(&scan.desc, max_size);
+    diag!(Debug, "Splitting the output into {} parts", parts.len());
+
+    let mut written_before = 0;
+    let reporter = ProgressReporter::new(options.progress_listener.as_ref(), total_size as u64);
+    let mut report = MergeReport::from_desc(&scan.desc, &scan.input_order);
+    for file in &mut report.files {
+        file.mdat_range = 0..0; // Not meaningful for split outputs
+    }
+    for (i, part) in parts.iter_mut().enumerate() {
+        let output = cancel::Cancellable::new(create_output(i)?, options.cancellation.as_ref());
+        part.desc.set_table_progress(reporter.clone());
+        let size = write_merged(files, output, &scan.first_boxes, &mut part.desc, None, false, copier, |total| {
+            let fraction = (0.1 + (((written_before + total) as f64 / total_size as f64) * 0.9)).min(0.9999);
+            progress_cb(fraction);
+            if let Some(reporter) = &reporter { reporter.bytes((written_before + total) as u64, fraction); }
+        })?;
+        written_before += size as usize;
+        report.parts.push(report::PartReport {
+            size,
+            time_range: part.time_range.clone(),
+            track_sample_ranges: part.track_sample_ranges.clone(),
+        });
+    }
+    scan.verify_inputs(files)?;
+
+    progress_cb(1.0);
+
+    Ok(report)
+}
+
+/// Merged description and everything else gathered while scanning the input files
+pub(crate) struct ScanResult {
+    pub desc: desc_reader::Desc,
+    pub total_size: usize,
+    pub insta360_max_read: Option<u64>,
+    pub gpmf_detected: bool,
+    pub input_order: Vec<usize>,
+    pub first_boxes: box_cache::BoxCache,
+    pub fingerprints: Vec<fingerprint::Fingerprint>,
+}
+
+impl ScanResult {
+    /// Fail if an input was modified since it was scanned
+    pub fn verify_inputs<I: Read + Seek>(&self, files: &mut [(I, usize)]) -> Result<()> {
+        fingerprint::verify_unchanged(files, &self.fingerprints, &self.input_order)
+    }
+}
+
+pub(crate) fn scan_files<F: Fn(f64), I: Read + Seek>(files: &mut [(I, usize)], file_metadata: &[Option<std::time::SystemTime>], options: &MergeOptions, progress_cb: &F) -> Result<ScanResult> {
+    options.validate(files.len())?;
+    // Only the path API rebuilds fragmented files (InputFile::open), a stream with movie fragments would merge without samples
+    for (i, file) in files.iter_mut().enumerate() {
+        if defragment::is_fragmented(&mut std::io::BufReader::with_capacity(16*1024, &mut file.0))? {
+            return Err(Error::UnsupportedBox { path: "moof".into(), reason: format!("File {i} is fragmented, fragmented inputs can only be merged from paths") }.into());
+        }
+    }
+
+    let mut file_metadata = file_metadata.to_vec();
+    let mut input_order = (0..files.len()).collect::<Vec<_>>();
+    let mut udta_info = gopro::read_all_udta_info(files)?;
+    if let Some(order) = gopro::sort_by_chapters(files, &mut udta_info)? {
+        gopro::apply_order(&mut file_metadata, &order);
+        input_order = order;
+    }
+    // Compared again before and after writing, the inputs may still be recording or copying
+    let fingerprints = files.iter_mut().map(|x| fingerprint::Fingerprint::read(&mut x.0)).collect::<Result<Vec<_>>>()?;
+
     // Get the merged description from all source files
     let mut desc = desc_reader::Desc::default();
     desc.moov_tracks.resize(10, Default::default());
-    desc.file_creation_times = file_metadata.to_vec();
+    desc.file_creation_times = file_metadata;
+    desc.file_cameras = udta_info.into_iter().map(|x| x.camera).collect();
+    desc.file_gps_times.resize(files.len(), None);
+    desc.file_mvhd_creation_times.resize(files.len(), None);
+    desc.file_has_insta360.resize(files.len(), false);
+    desc.gap_model = options.gap_model.clone();
+    desc.edit_list_editor = options.edit_list_editor.clone();
+    desc.trim_overlaps = options.trim_overlaps;
+    desc.disable_gaps = options.disable_gaps;
+    desc.gap_threshold = options.gap_threshold.map(|x| x.as_secs_f64());
+    desc.repair_chunk_offsets = options.repair_chunk_offsets;
+    desc.sidx = options.sidx;
+    desc.output_creation_time = options.creation_time;
+    desc.output_modification_time = options.modification_time;
+    if !files.is_empty() {
+        desc.movie_metadata = metadata::MovieMetadata::from_options(options, metadata::is_quicktime(&mut files[0].0)?);
+    }
+    desc.copy = copy::CopySettings { block_size: options.copy_block_size.unwrap_or(0), buffers: options.copy_buffers.unwrap_or(copy::DEFAULT_BUFFERS), stall_timeout: options.stall_timeout, reader_threads: options.copy_threads.unwrap_or(1) };
+    desc.gap_overrides = options.explicit_gaps.as_ref().map(|x| x.iter().map(|d| d.as_secs_f64()).collect());
+    desc.file_duration_overrides = options.file_durations.as_ref().map(|x| x.iter().map(|d| d.as_secs_f64()).collect());
     desc.file_durations.resize(files.len(), 0.0);
     // Initialize track_file_durations[track_index][file_index]
     desc.track_file_durations.resize(10, vec![0.0; files.len()]);
     let mut total_size = 0;
     let num_files = files.len() as f64;
     let mut insta360_max_read = None;
-    let mut gpmf_detected = false;
+    let mut first_boxes = box_cache::BoxCache::default();
+    let mut first_entries = Vec::new();
+    // The boxes of the template are written to the output, its tracks are taken over after reading the first file
+    let template_index = template::select(files, options.template, &input_order)?;
+    let template_tracks = if template_index > 0 { template::read_tracks(&mut files[template_index].0)? } else { Vec::new() };
     
     // Check for GPMF metadata in files
-    if let Ok(gpmf_flags) = gpmf::detect_gpmf_files(files) {
-        gpmf_detected = gpmf_flags.iter().any(|&has_gpmf| has_gpmf);
-        if gpmf_detected {
-            log::debug!("GPMF metadata detected in one or more files");
-        }
+    let gpmf_flags = gpmf::detect_gpmf_files(files).unwrap_or_default();
+    let gpmf_detected = gpmf_flags.iter().any(|&has_gpmf| has_gpmf);
+    if gpmf_detected {
+        diag!(Debug, "GPMF metadata detected in one or more files");
     }
     
+    let input_size = files.iter().map(|x| x.1 as u64).sum::<u64>();
     for (i, fs) in files.iter_mut().enumerate() {
+        CancellationToken::check(options.cancellation.as_ref())?;
+        diagnostics::set_phase(diagnostics::Phase::Scan, Some(i));
+        progress_stream::emit_event(ProgressPhase::Scanning, Some(i), total_size as u64, input_size);
         let filesize = fs.1;
         let mut fs = std::io::BufReader::with_capacity(16*1024, &mut fs.0);
         total_size += filesize;
@@ -111,8 +518,10 @@ pub fn join_file_streams_with_metadata<F: Fn(f64), I: Read + Seek, O: Read + Wri
         { // Find mdat first
             while let Ok((typ, offs, size, header_size)) = read_box(&mut fs) {
                 let org_pos = fs.stream_position()?;
+                // A box with size 0 extends to the end of the file
+                let size = if size == 0 { (filesize as u64).saturating_sub(offs) } else { size };
                 if typ == fourcc("mdat") {
-                    log::debug!("Reading {}, offset: {}, size: {size}, header_size: {header_size}", typ_to_str(typ), offs);
+                    diag!(Debug, "Reading {}, offset: {}, size: {size}, header_size: {header_size}", typ_to_str(typ), offs);
                     desc.mdat_position.push((None, org_pos, size - header_size as u64));
                     desc.mdat_final_position = org_pos;
                     break;
@@ -120,100 +529,344 @@ pub fn join_file_streams_with_metadata<F: Fn(f64), I: Read + Seek, O: Read + Wri
                 fs.seek(std::io::SeekFrom::Start(org_pos + size - header_size as u64))?;
             }
 
-            if insta360_max_read.is_none() {
-                fs.seek(std::io::SeekFrom::End(-40))?;
-                let mut buf = vec![0u8; 40];
-                fs.read_exact(&mut buf)?;
-                // Check if it's Insta360
-                if &buf[8..] == insta360::MAGIC {
-                    insta360_max_read = Some(filesize as u64 - (&buf[..]).read_u32::<LittleEndian>()? as u64);
-                }
+            // Check if it's Insta360. Only the trailer of the template limits the boxes read for the output
+            let trailer_start = insta360::trailer_start(&mut fs)?;
+            desc.file_has_insta360[i] = trailer_start.is_some();
+            if i == template_index && trailer_start.is_some() {
+                insta360_max_read = trailer_start;
             }
 
+            external::check_self_contained(&mut fs, i)?;
             fs.seek(std::io::SeekFrom::Start(0))?;
         }
 
-        desc_reader::read_desc(&mut fs, &mut desc, 0, u64::MAX, i)?;
+        if i == template_index {
+            // Keep the boxes of the template in memory, they are needed again when writing the output
+            first_boxes = box_cache::BoxCache::read(&mut fs, insta360_max_read.unwrap_or(u64::MAX))?;
+            fs.seek(std::io::SeekFrom::Start(0))?;
+        }
+        if i == 0 {
+            if template_index == 0 {
+                desc_reader::read_desc(&mut first_boxes.reader(), &mut desc, 0, u64::MAX, i)?;
+                first_entries = stsd::read_sample_entries(&mut first_boxes.reader())?;
+                for (track, entries) in desc.moov_tracks.iter_mut().zip(&first_entries) {
+                    track.sample_entries = entries.clone();
+                }
+            } else {
+                desc_reader::read_desc(&mut fs, &mut desc, 0, u64::MAX, i)?;
+                first_entries = stsd::read_sample_entries(&mut fs)?;
+                template::apply(&mut desc, &template_tracks);
+                missing_tracks::add_missing_tracks(&mut desc, &mut fs, i, template_tracks.len(), &first_entries)?;
+            }
+            encryption::check_unencrypted(&desc, &first_entries, &options.drop_tracks, i)?;
+            for track in desc.moov_tracks.iter_mut() {
+                if let Some(telemetry) = track.sample_entries.first().and_then(|x| TelemetryFormat::from_codec(&x.codec)) {
+                    diag!(Debug, "Track {} carries {telemetry:?} telemetry", track.track_id);
+                }
+                // GoPro file description, only valid for a single chapter. Its format isn't documented, so it can't be updated
+                if num_files > 1.0 && track.sample_entries.iter().any(|x| x.codec == "fdsc") {
+                    diag!(Debug, "Dropping the fdsc track {}", track.track_id);
+                    track.dropped = true;
+                }
+            }
+        } else {
+            let keys = track_match::read_track_keys(&mut fs)?;
+            desc.track_map = track_match::match_tracks(&desc, &keys, first_entries.len(), &options.drop_tracks, i)?;
+            desc_reader::read_desc(&mut fs, &mut desc, 0, u64::MAX, i)?;
+            let entries = stsd::read_sample_entries(&mut fs)?;
+            let template_track_count = if template_index > 0 { template_tracks.len() } else { first_entries.len() };
+            missing_tracks::add_missing_tracks(&mut desc, &mut fs, i, template_track_count, &entries)?;
+            let entries = track_match::reorder(&entries, &std::mem::take(&mut desc.track_map));
+            encryption::check_unencrypted(&desc, &entries, &options.drop_tracks, i)?;
+            if options.strict_parameter_sets {
+                let diffs = param_sets::diff_tracks(&first_entries, &entries, i);
+                for diff in diffs.iter().filter(|x| !x.fatal) {
+                    diag!(Warn, "{diff}");
+                }
+                let fatal = diffs.iter().filter(|x| x.fatal).map(|x| x.to_string()).collect::<Vec<_>>();
+                if !fatal.is_empty() {
+                    return Err(Error::IncompatibleTracks { file_index: i, reason: format!("Incompatible parameter sets: {}", fatal.join("; ")) }.into());
+                }
+            }
+            let incompatible = stsd::incompatible_entries(&first_entries, &entries);
+            if !incompatible.is_empty() {
+                return Err(Error::IncompatibleTracks { file_index: i, reason: format!("Different sample descriptions: {}", incompatible.join("; ")) }.into());
+            }
+            for issue in stsd::check_compatibility(&first_entries, &entries) {
+                diag!(Warn, "File {i} is not compatible with the first file: {issue}");
+            }
+        }
+
+        if gpmf_flags.get(i).copied().unwrap_or(false) {
+            // GPS UTC time is used for gap computation, so a broken GPMF track shouldn't fail the merge
+            match gpmf::read_gpsu_range(&mut fs) {
+                Ok(range) => desc.file_gps_times[i] = range,
+                Err(e) => diag!(Warn, "Failed to read GPSU timestamps from file {i}: {e:?}")
+            }
+        }
+
+        let itunsmpb = encoder_delay::read_itunsmpb(&mut fs).unwrap_or_else(|e| {
+            diag!(Warn, "Failed to read the iTunSMPB tag of file {i}: {e:?}");
+            None
+        });
+        encoder_delay::store_file_delay(&mut desc, i, itunsmpb);
 
         // Store file duration in seconds
         if desc.moov_mvhd_timescale > 0 {
             let file_duration_in_movie_timescale = *desc.mvhd_timescale_per_file.get(i).unwrap_or(&desc.moov_mvhd_timescale);
             if file_duration_in_movie_timescale > 0 {
                 // Calculate duration based on the first track (assuming all tracks have similar duration)
-                if let Some(first_track) = desc.moov_tracks.first() {
-                    if first_track.mdhd_timescale > 0 && first_track.mdhd_duration > 0 {
-                        desc.file_durations[i] = first_track.mdhd_duration as f64 / first_track.mdhd_timescale as f64;
-                        log::debug!("File {} duration: {:.2}s", i, desc.file_durations[i]);
-                    }
+                if let Some(duration) = desc.first_track_file_duration(i) {
+                    desc.file_durations[i] = duration;
+                    diag!(Debug, "File {} duration: {:.2}s", i, desc.file_durations[i]);
                 }
             }
         }
 
+        for t in desc.moov_tracks.iter_mut().filter(|t| !t.skip || i == 0) {
+            t.normalize_stsc(i);
+        }
+        if let Some(&(_, _, mdat_size)) = desc.mdat_position.last() {
+            repair::check_chunk_offsets(&mut desc, i, mdat_size)?;
+        }
         if let Some(mdat) = desc.mdat_position.last_mut() {
             mdat.0 = Some(i);
             desc.mdat_offset += mdat.2;
-            for t in &mut desc.moov_tracks {
+            for (t, durations) in desc.moov_tracks.iter_mut().zip(&desc.track_file_durations) {
+                rescale::rescale_file_media(t, i)?;
+                if !t.skip && t.handler_type != "vide" && t.handler_type != "soun" {
+                    let duration = durations[..=i].iter().map(|x| (x * t.mdhd_timescale as f64).round() as u64).sum();
+                    t.note_rounding_error(duration as f64 / t.mdhd_timescale.max(1) as f64 - durations[..=i].iter().sum::<f64>());
+                    t.extend_last_sample(duration);
+                }
+                t.file_sample_ranges.push(t.sample_offset..t.stsz_count);
+                t.file_has_stss.push(std::mem::take(&mut t.stss_present));
+                t.file_cslg.push(t.cslg.take());
+                if !t.ctts.is_empty() { t.pad_ctts(t.stsz_count); }
                 t.sample_offset = t.stsz_count;
                 t.chunk_offset = t.stco.len() as u32;
+                t.stsc_offset = t.stsc.len() as u32;
             }
         }
 
         progress_cb(((i as f64 + 1.0) / num_files) * 0.1);
+        progress_stream::emit_event(ProgressPhase::Scanning, Some(i), total_size as u64, input_size);
     }
 
+    diagnostics::set_phase(diagnostics::Phase::Scan, None);
+    if template_index > 0 && desc.output_creation_time.is_none() {
+        // The merged recording starts with the first file, not with the template
+        desc.output_creation_time = desc.file_mvhd_creation_times.first().copied().flatten();
+    }
+    if desc.file_has_insta360.contains(&true) && desc.file_has_insta360.contains(&false) {
+        let missing = desc.file_has_insta360.iter().enumerate().filter(|x| !x.1).map(|x| x.0).collect::<Vec<_>>();
+        diag!(Warn, "Files {missing:?} have no Insta360 metadata, it's merged from the other files");
+    }
+    for t in &mut desc.moov_tracks {
+        t.fill_missing_sync_samples();
+    }
+    track_filter::apply(&mut desc, &options.drop_tracks)?;
+
+    desc.start_timecode = timecode::read_start_timecode(files, &desc)?;
+
     // Compute gaps between files and create edit list entries
     desc_reader::compute_gaps_and_edit_lists(&mut desc)?;
+    highlights::merge_highlight_tracks(files, &mut desc)?;
+    timecode::merge_timecode_tracks(files, &mut desc)?;
+    if options.interpolate_telemetry_gaps {
+        desc.interpolated_telemetry = telemetry::interpolate_gaps(files, &mut desc)?;
+    }
+    desc_reader::apply_edit_list_editor(&mut desc);
+    rescale::apply_timescales(&mut desc, options.movie_timescale, &options.track_timescales)?;
+    if let Some(audio) = &options.replacement_audio {
+        audio_replacement::replace_audio_from_file(&mut desc, audio)?;
+    }
+    if let Some(gpx) = &options.gpx_track {
+        gpx::add_gpx_track(&mut desc, gpx)?;
+    }
+    if options.chapter_markers {
+        chapter_markers::add_chapter_markers(&mut desc, &options.file_names, &input_order);
+    }
+    if let Some(max_error) = options.max_rounding_error {
+        rescale::check_rounding_error(&desc, max_error.as_secs_f64())?;
+    }
+    fingerprint::verify_unchanged(files, &fingerprints, &input_order)?;
+    if options.keep_stco {
+        // The merged file is at most as large as the inputs and the synthesized samples, plus the tables added by the merge
+        let max_size = total_size as u64 + desc.synthesized_data.len() as u64 + STCO_SIZE_MARGIN;
+        desc.stco_32bit = max_size <= u32::MAX as u64;
+        if !desc.stco_32bit {
+            diag!(Info, "The merged file can be larger than 4 GiB, writing co64 instead of stco");
+        }
+    }
+    if options.faststart {
+        // The chunk offsets are patched once the mdat position is known, so the moov can be written first
+        first_boxes = first_boxes.moov_first();
+    }
+
+    Ok(ScanResult { desc, total_size, insta360_max_read, gpmf_detected, input_order, first_boxes, fingerprints })
+}
 
+/// Write the merged file described by `desc`. `progress` receives the number of bytes written so far.
+/// Returns the size of the output.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn write_merged<I: Read + Seek, O: Read + Write + Seek, T: RangeCopier<I>, P: FnMut(usize)>(files: &mut [(I, usize)], output_file: O, first_boxes: &box_cache::BoxCache, desc: &mut desc_reader::Desc, insta360_max_read: Option<u64>, gpmf_detected: bool, copier: &T, mut progress: P) -> Result<u64> {
     // Write it to the file
     let mut debounce = Instant::now();
     let f_out = ProgressStream::new(output_file, |total| {
         if (Instant::now() - debounce).as_millis() > 100 {
-            progress_cb((0.1 + ((total as f64 / total_size as f64) * 0.9)).min(0.9999));
+            progress(total);
             debounce = Instant::now();
         }
     });
     let mut f_out = std::io::BufWriter::with_capacity(64*1024, f_out);
 
-    writer::get_first(files).seek(std::io::SeekFrom::Start(0))?;
-    writer::rewrite_from_desc(files, &mut f_out, &mut desc, 0, insta360_max_read.unwrap_or(u64::MAX))?;
+    diagnostics::set_phase(diagnostics::Phase::Write, None);
+    writer::rewrite_from_desc(&mut first_boxes.reader(), files, &mut f_out, desc, 0, insta360_max_read.unwrap_or(u64::MAX), copier)?;
 
-    // Patch final mdat positions
-    for track in &desc.moov_tracks {
-        f_out.seek(std::io::SeekFrom::Start(track.co64_final_position))?;
-        for x in &track.stco {
-            f_out.write_u64::<BigEndian>(*x + desc.mdat_final_position)?;
-        }
-    }
+    patch_chunk_offsets(&mut f_out, desc)?;
 
-    if insta360_max_read.is_some() {
+    diagnostics::set_phase(diagnostics::Phase::Metadata, None);
+    if desc.file_has_insta360.contains(&true) {
         // Merge Insta360 metadata
         f_out.seek(std::io::SeekFrom::End(0))?;
         let offsets = insta360::get_insta360_offsets(files)?;
         insta360::merge_metadata(files, &offsets, &mut f_out)?;
     } else if gpmf_detected {
         // Merge GPMF metadata (only if no Insta360 metadata)
-        log::debug!("Merging GPMF GPS metadata from {} files", files.len());
+        diag!(Debug, "Merging GPMF GPS metadata from {} files", files.len());
         f_out.seek(std::io::SeekFrom::End(0))?;
         gpmf::merge_gpmf_metadata(files, &desc.file_durations, &mut f_out)?;
     }
 
-    progress_cb(1.0);
+    let size = f_out.seek(std::io::SeekFrom::End(0))?;
+    f_out.flush()?;
+    Ok(size)
+}
+
+/// Remux the merged samples into a Matroska output
+fn write_mkv<I: Read + Seek, O: Write, P: FnMut(usize)>(files: &mut [(I, usize)], output_file: O, desc: &desc_reader::Desc, mut progress: P) -> Result<u64> {
+    diagnostics::set_phase(diagnostics::Phase::Write, None);
+    let mut debounce = Instant::now();
+    let f_out = ProgressStream::new(output_file, |total| {
+        if (Instant::now() - debounce).as_millis() > 100 {
+            progress(total);
+            debounce = Instant::now();
+        }
+    });
+    let mut f_out = std::io::BufWriter::with_capacity(64*1024, f_out);
+    let size = mkv::write_mkv(files, &mut f_out, desc)?;
+    f_out.flush()?;
+    Ok(size)
+}
 
+/// Write the final chunk offsets, once the position of the mdat data is known
+pub(crate) fn patch_chunk_offsets<O: Write + Seek>(output_file: &mut O, desc: &desc_reader::Desc) -> Result<()> {
+    let progress = desc.table_progress.as_ref();
+    if let Some(progress) = progress { progress.set_stage(WriteStage::Patching); }
+    let entry_size = if desc.stco_32bit { 4 } else { 8 };
+    let total = desc.moov_tracks.iter().filter(|x| !x.dropped).map(|x| x.stco.len() as u64 * entry_size).sum::<u64>();
+    let mut done = 0;
+    for track in desc.moov_tracks.iter().filter(|x| !x.dropped) {
+        output_file.seek(std::io::SeekFrom::Start(track.co64_final_position))?;
+        done += writer::write_chunk_offsets(output_file, &track.stco, desc.mdat_final_position, desc.stco_32bit, progress)?;
+        progress_stream::emit_event(ProgressPhase::Patching, None, done, total);
+    }
+    if let Some(progress) = progress { progress.set_stage(WriteStage::Data); }
     Ok(())
 }
 
-pub fn update_file_times(input_path: &PathBuf, output_path: &PathBuf) {
-    if let Err(e) = || -> std::io::Result<()> {
-        let org_time = filetime_creation::FileTime::from_creation_time(&std::fs::metadata(&input_path)?).ok_or(std::io::ErrorKind::Other)?;
-        if cfg!(target_os = "windows") {
-            ::log::debug!("Updating creation time of {} to {}", output_path.display(), org_time.to_string());
-            filetime_creation::set_file_ctime(output_path, org_time)?;
-        } else {
-            ::log::debug!("Updating modification time of {} to {}", output_path.display(), org_time.to_string());
-            filetime_creation::set_file_mtime(output_path, org_time)?;
+/// Write the merged file described by `desc` front to back, without seeking in the output.
+/// All boxes except the mdat data are laid out in memory first, so the final chunk offsets are known before anything is written.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn write_merged_sequential<I: Read + Seek, O: Write, T: RangeCopier<I>, P: FnMut(usize)>(files: &mut [(I, usize)], output_file: O, first_boxes: &box_cache::BoxCache, desc: &mut desc_reader::Desc, insta360_max_read: Option<u64>, gpmf_detected: bool, copier: &T, mut progress: P) -> Result<u64> {
+    let (layout, trailer) = sequential_layout(files, first_boxes, desc, insta360_max_read, gpmf_detected)?;
+
+    let mut debounce = Instant::now();
+    let f_out = ProgressStream::new(output_file, |total| {
+        if (Instant::now() - debounce).as_millis() > 100 {
+            progress(total);
+            debounce = Instant::now();
         }
-        Ok(())
-    }() {
-        ::log::warn!("Failed to update file times: {e:?}");
+    });
+    let mut f_out = std::io::BufWriter::with_capacity(64*1024, f_out);
+
+    diagnostics::set_phase(diagnostics::Phase::Write, None);
+    let (before_data, after_data) = layout.split_at(desc.mdat_final_position as usize);
+    f_out.write_all(before_data)?;
+    let data_size = writer::copy_mdat_data(files, desc, &mut f_out, copier)?;
+    f_out.write_all(after_data)?;
+    f_out.write_all(&trailer)?;
+    f_out.flush()?;
+    Ok(layout.len() as u64 + data_size + trailer.len() as u64)
+}
+
+/// All boxes of the merged file without the mdat data, which belongs at `desc.mdat_final_position`, and the camera trailer written after them
+pub(crate) fn sequential_layout<I: Read + Seek>(files: &mut [(I, usize)], first_boxes: &box_cache::BoxCache, desc: &mut desc_reader::Desc, insta360_max_read: Option<u64>, gpmf_detected: bool) -> Result<(Vec<u8>, Vec<u8>)> {
+    diagnostics::set_phase(diagnostics::Phase::Write, None);
+    let mut layout = std::io::Cursor::new(Vec::new());
+    desc.skip_mdat_data = true;
+    writer::rewrite_from_desc(&mut first_boxes.reader(), files, &mut layout, desc, 0, insta360_max_read.unwrap_or(u64::MAX), &copy::Synchronous)?;
+    patch_chunk_offsets(&mut layout, desc)?;
+
+    diagnostics::set_phase(diagnostics::Phase::Metadata, None);
+    let mut trailer = std::io::Cursor::new(Vec::new());
+    if desc.file_has_insta360.contains(&true) {
+        let offsets = insta360::get_insta360_offsets(files)?;
+        insta360::merge_metadata(files, &offsets, &mut trailer)?;
+    } else if gpmf_detected {
+        diag!(Debug, "Merging GPMF GPS metadata from {} files", files.len());
+        gpmf::merge_gpmf_metadata(files, &desc.file_durations, &mut trailer)?;
+    }
+    Ok((layout.into_inner(), trailer.into_inner()))
+}
+
+/// Set the filesystem times of the output file. The creation time is only settable on Windows,
+/// the modification time defaults to the creation time when not given.
+pub fn set_file_times<P: AsRef<Path>>(output_path: P, creation_time: Option<std::time::SystemTime>, modification_time: Option<std::time::SystemTime>) -> Result<()> {
+    let output_path = output_path.as_ref();
+    if let Some(time) = creation_time.filter(|_| cfg!(target_os = "windows")) {
+        diag!(Debug, "Updating creation time of {} to {time:?}", output_path.display());
+        filetime_creation::set_file_ctime(output_path, filetime_creation::FileTime::from_system_time(time))?;
+    }
+    if let Some(time) = modification_time.or(creation_time) {
+        diag!(Debug, "Updating modification time of {} to {time:?}", output_path.display());
+        filetime_creation::set_file_mtime(output_path, filetime_creation::FileTime::from_system_time(time))?;
     }
+    Ok(())
+}
+
+/// Where `update_file_times_with_source` takes the time of the input file from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FileTimeSource {
+    /// Creation time from the filesystem (modification time if the filesystem doesn't store it)
+    #[default]
+    Filesystem,
+    /// Creation time stored in mvhd, falling back to the filesystem when it's not set
+    Embedded,
+}
+
+/// Set the creation and modification times of the output file to the creation time of the input file from the filesystem
+pub fn update_file_times<P: AsRef<Path>, Q: AsRef<Path>>(input_path: P, output_path: Q) -> Result<()> {
+    update_file_times_with_source(input_path, output_path, FileTimeSource::Filesystem)
+}
+
+/// Set the creation and modification times of the output file to the creation time of the input file, taken from `source`
+pub fn update_file_times_with_source<P: AsRef<Path>, Q: AsRef<Path>>(input_path: P, output_path: Q, source: FileTimeSource) -> Result<()> {
+    let input_path = input_path.as_ref();
+    let embedded = match source {
+        FileTimeSource::Embedded => {
+            let mut reader = std::io::BufReader::with_capacity(16*1024, std::fs::File::open(input_path)?);
+            desc_reader::read_mvhd_creation_time(&mut reader)?
+        },
+        FileTimeSource::Filesystem => None
+    };
+    let time = match embedded {
+        Some(x) => x,
+        None => {
+            let metadata = std::fs::metadata(input_path)?;
+            metadata.created().or_else(|_| metadata.modified())?
+        }
+    };
+    set_file_times(output_path, Some(time), Some(time))
 }
@@ -0,0 +1,459 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, Write, Result, SeekFrom };
+use std::path::Path;
+use crate::desc_reader::{ self, Desc, TrackDesc };
+use crate::stsd::{ self, SampleEntry };
+use crate::{ box_cache, fourcc, writer, diagnostics::diag };
+
+/// Summary of a file repaired with `repair_from_lrv`
+#[derive(Debug, Clone, Default)]
+pub struct LrvRepairReport {
+    /// Samples found in the main file and samples of the LRV, for each track of the LRV
+    pub track_samples: Vec<(u32, u32)>,
+    /// The main file ends before the last sample of the LRV, its tracks are shortened
+    pub truncated: bool,
+    /// Tracks whose data in the main file differs from the LRV. Their sample sizes are taken from the LRV anyway
+    pub mismatched_tracks: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VideoCodec { H264, Hevc }
+
+/// Video track whose samples are found by parsing their NAL units
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct VideoFormat {
+    codec: VideoCodec,
+    /// Size of the NAL unit length prefix
+    length_size: usize,
+}
+
+impl VideoFormat {
+    pub(crate) fn from_entry(entry: &SampleEntry) -> Option<Self> {
+        match entry.codec.as_str() {
+            "avc1" | "avc3" => Some(Self { codec: VideoCodec::H264, length_size: entry.child("avcC").and_then(|x| x.get(4)).map(|x| (x & 3) as usize + 1).unwrap_or(4) }),
+            "hvc1" | "hev1" => Some(Self { codec: VideoCodec::Hevc, length_size: entry.child("hvcC").and_then(|x| x.get(21)).map(|x| (x & 3) as usize + 1).unwrap_or(4) }),
+            _ => None
+        }
+    }
+
+    /// Whether the NAL unit starting with `header` is a slice, and whether it starts a new access unit. None if it isn't a valid header
+    fn classify(&self, header: &[u8]) -> Option<(bool, bool)> {
+        if header.len() < 2 || header[0] & 0x80 != 0 { return None; }
+        match self.codec {
+            VideoCodec::H264 => {
+                let typ = header[0] & 0x1f;
+                // first_mb_in_slice is 0 in the first slice of a picture
+                match typ {
+                    1..=5 => Some((true, header[1] & 0x80 != 0)),
+                    6..=9 => Some((false, true)), // SEI, SPS, PPS, access unit delimiter
+                    10..=23 => Some((false, false)),
+                    _ => None
+                }
+            },
+            VideoCodec::Hevc => {
+                let typ = (header[0] >> 1) & 0x3f;
+                if header[1] & 7 == 0 { return None; } // nuh_temporal_id_plus1
+                // first_slice_segment_in_pic_flag
+                match typ {
+                    0..=31 => Some((true, *header.get(2)? & 0x80 != 0)),
+                    32..=35 | 39 => Some((false, true)), // VPS, SPS, PPS, access unit delimiter, prefix SEI
+                    36..=47 => Some((false, false)),
+                    _ => None
+                }
+            }
+        }
+    }
+
+    /// Whether the NAL unit starting with `header` is a slice of a random access picture (IDR, CRA or BLA)
+    fn is_sync_slice(&self, header: &[u8]) -> bool {
+        match self.codec {
+            VideoCodec::H264 => header[0] & 0x1f == 5,
+            VideoCodec::Hevc => (16..=23).contains(&((header[0] >> 1) & 0x3f)),
+        }
+    }
+}
+
+/// A chunk of the template file, in file order
+pub(crate) struct TemplateChunk {
+    track: usize,
+    /// Offset in the template mdat payload
+    offset: u64,
+    sizes: Vec<u32>,
+    description_index: u32,
+}
+
+/// A chunk found in the damaged file
+pub(crate) struct FoundChunk {
+    /// Offset in the damaged mdat payload
+    offset: u64,
+    sizes: Vec<u32>,
+    description_index: u32,
+    /// Whether each frame is a sync sample, only for the video tracks
+    sync: Vec<bool>,
+}
+
+/// Chunks found in the mdat of a damaged file
+pub(crate) struct ChunkScan {
+    /// Found chunks of each track
+    pub found: Vec<Vec<FoundChunk>>,
+    /// Size of the mdat payload covered by the found chunks
+    pub size: u64,
+    /// The file ends before the last chunk of the template
+    pub truncated: bool,
+    /// Tracks whose data differs from the template file
+    pub mismatched_tracks: Vec<usize>,
+}
+
+/// Read up to `buf.len()` bytes at `offset`, fewer at the end of the stream
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, buf: &mut [u8]) -> Result<usize> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n
+        }
+    }
+    Ok(read)
+}
+
+/// Size of the frame at `start`: its NAL units up to the first unit of the next access unit, or up to the start of the next chunk
+/// of another track, whose first bytes are `next_chunk`. Also returns whether it's a sync sample. None if there's no complete frame before `end`
+fn frame_size<R: Read + Seek>(reader: &mut R, start: u64, end: u64, format: VideoFormat, next_chunk: &[u8]) -> Result<Option<(u64, bool)>> {
+    let header_len = format.length_size + 2;
+    let mut buf = vec![0u8; (header_len + 1).max(next_chunk.len())];
+    let mut pos = start;
+    let mut has_slice = false;
+    let mut sync = false;
+    loop {
+        let len = (end - pos).min(buf.len() as u64) as usize;
+        let read = read_at(reader, pos, &mut buf[..len])?;
+        let data = &buf[..read];
+        if has_slice && (pos == end || (!next_chunk.is_empty() && data.starts_with(next_chunk))) {
+            return Ok(Some((pos - start, sync)));
+        }
+        if data.len() < header_len {
+            return Ok(has_slice.then_some((pos - start, sync)));
+        }
+        let nal_size = data[..format.length_size].iter().fold(0u64, |acc, x| (acc << 8) | *x as u64);
+        let header = &data[format.length_size..(format.length_size + nal_size.min(3) as usize).min(data.len())];
+        let unit = format.classify(header).filter(|_| pos + format.length_size as u64 + nal_size <= end);
+        let Some((is_slice, starts_access_unit)) = unit else {
+            // Not a NAL unit: the data of another track, or a frame cut off at the end of the file
+            return Ok(has_slice.then_some((pos - start, sync)));
+        };
+        if has_slice && starts_access_unit {
+            return Ok(Some((pos - start, sync)));
+        }
+        has_slice |= is_slice;
+        sync |= is_slice && format.is_sync_slice(header);
+        pos += format.length_size as u64 + nal_size;
+    }
+}
+
+/// Whether a complete frame starts at `start`
+fn starts_frame<R: Read + Seek>(reader: &mut R, start: u64, end: u64, format: VideoFormat) -> Result<bool> {
+    let mut header = [0u8; 7];
+    let header_len = format.length_size + 3;
+    if read_at(reader, start, &mut header[..header_len])? < header_len { return Ok(false); }
+    if format.classify(&header[format.length_size..header_len]).is_none_or(|x| !x.1) { return Ok(false); }
+    Ok(frame_size(reader, start, end, format, &[])?.is_some())
+}
+
+/// Offset and size of the mdat payload. The size of an unfinished mdat isn't reliable, it extends to the end of the file
+pub(crate) fn mdat_payload<R: Read + Seek>(reader: &mut R) -> Result<(u64, u64)> {
+    let file_size = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    let mut pos = 0;
+    while pos + 8 <= file_size {
+        let (typ, offs, size, header_size) = crate::read_box(reader)?;
+        if typ == fourcc("mdat") {
+            let start = offs + header_size as u64;
+            let end = if size < header_size as u64 { file_size } else { (offs + size).min(file_size) };
+            return Ok((start, end.max(start) - start));
+        }
+        if size < 8 { break; }
+        pos = offs + size;
+        reader.seek(SeekFrom::Start(pos))?;
+    }
+    Err(crate::Error::TruncatedFile { path: "mdat".into(), offset: 0, reason: "mdat not found in the damaged file".into() }.into())
+}
+
+/// Video format of the first `num_tracks` tracks, None for the other tracks
+pub(crate) fn video_formats<R: Read + Seek>(template: &mut R, num_tracks: usize) -> Result<Vec<Option<VideoFormat>>> {
+    let entries = stsd::read_sample_entries(template)?;
+    Ok((0..num_tracks).map(|i| entries.get(i).and_then(|x| x.first()).and_then(VideoFormat::from_entry)).collect())
+}
+
+/// Chunks of the first `num_tracks` tracks of the template, in file order
+pub(crate) fn template_chunks(desc: &Desc, num_tracks: usize) -> Vec<TemplateChunk> {
+    let mut template = Vec::new();
+    for (track_index, track) in desc.moov_tracks[..num_tracks].iter().enumerate() {
+        for sample in track.sample_infos() {
+            match template.last_mut() {
+                Some(TemplateChunk { track, offset, sizes, .. }) if *track == track_index && *offset + sizes.iter().map(|x| *x as u64).sum::<u64>() == sample.offset => sizes.push(sample.size),
+                _ => template.push(TemplateChunk { track: track_index, offset: sample.offset, sizes: vec![sample.size], description_index: sample.description_index }),
+            }
+        }
+    }
+    template.sort_by_key(|x| x.offset);
+    template
+}
+
+/// Find the chunks of the template in the mdat of the damaged file, in the same order. The video frames are found by parsing their NAL units.
+/// With `template_data` (the template file and the offset of its mdat payload), the other tracks are expected to have the same data as the template.
+/// Without it, they're expected to have about the same size, and their end is searched where the next video frame starts
+pub(crate) fn scan_chunks<M: Read + Seek, L: Read + Seek>(main: &mut M, template: &[TemplateChunk], formats: &[Option<VideoFormat>], mut template_data: Option<(&mut L, u64)>) -> Result<ChunkScan> {
+    let (main_start, main_size) = mdat_payload(main)?;
+    let main_end = main_start + main_size;
+    let mut scan = ChunkScan { found: formats.iter().map(|_| Vec::new()).collect(), size: 0, truncated: false, mismatched_tracks: Vec::new() };
+    let mut pos = 0;
+    for (i, chunk) in template.iter().enumerate() {
+        let next = template.get(i + 1);
+        let found_chunk = match formats[chunk.track] {
+            Some(format) => {
+                // The next chunk of another track is recognized by its data in the template
+                let mut next_chunk = Vec::new();
+                if let (Some(next), Some((lrv, lrv_payload))) = (next.filter(|x| formats[x.track].is_none()), template_data.as_mut()) {
+                    next_chunk = vec![0u8; next.sizes.iter().map(|x| *x as usize).sum::<usize>().min(16)];
+                    read_at(*lrv, *lrv_payload + next.offset, &mut next_chunk)?;
+                }
+                let mut sizes = Vec::with_capacity(chunk.sizes.len());
+                let mut sync = Vec::with_capacity(chunk.sizes.len());
+                let mut end = pos;
+                for _ in &chunk.sizes {
+                    let Some((size, is_sync)) = frame_size(main, main_start + end, main_end, format, &next_chunk)? else { scan.truncated = true; break; };
+                    sizes.push(size as u32);
+                    sync.push(is_sync);
+                    end += size;
+                }
+                FoundChunk { offset: pos, sizes, description_index: chunk.description_index, sync }
+            },
+            None => {
+                let size = chunk.sizes.iter().map(|x| *x as u64).sum::<u64>();
+                let mut sizes = chunk.sizes.clone();
+                match template_data.as_mut() {
+                    Some((lrv, lrv_payload)) => {
+                        if pos + size <= main_size {
+                            let mut expected = vec![0u8; size as usize];
+                            let mut data = vec![0u8; size as usize];
+                            read_at(*lrv, *lrv_payload + chunk.offset, &mut expected)?;
+                            read_at(main, main_start + pos, &mut data)?;
+                            if data != expected && !scan.mismatched_tracks.contains(&chunk.track) {
+                                diag!(Warn, "Data of track {} in the damaged file differs from the template, using the sample sizes of the template", chunk.track);
+                                scan.mismatched_tracks.push(chunk.track);
+                            }
+                        }
+                    },
+                    None => if let Some(format) = next.and_then(|x| formats[x.track]) {
+                        // Nearest position after the expected size where a video frame starts
+                        let window = (size / 2).min(64 * 1024) as i64;
+                        let found = (0..=window).flat_map(|x| [x, -x]).skip(1).map(|x| (size as i64 + x) as u64).find_map(|x| {
+                            match starts_frame(main, main_start + pos + x, main_end, format) {
+                                Ok(true) => Some(Ok(x)),
+                                Ok(false) => None,
+                                Err(e) => Some(Err(e))
+                            }
+                        }).transpose()?;
+                        match found {
+                            Some(found_size) if found_size != size => {
+                                diag!(Debug, "Chunk of track {} is {found_size} bytes instead of {size}, its sample sizes are scaled", chunk.track);
+                                scale_sizes(&mut sizes, found_size);
+                            },
+                            Some(_) => { },
+                            None if pos + size < main_size => {
+                                diag!(Warn, "No video frame found after the chunk of track {} at {pos}", chunk.track);
+                                scan.truncated = true;
+                                break;
+                            },
+                            None => { }
+                        }
+                    }
+                }
+                if pos + sizes.iter().map(|x| *x as u64).sum::<u64>() > main_size {
+                    scan.truncated = true;
+                    break;
+                }
+                FoundChunk { offset: pos, sizes, description_index: chunk.description_index, sync: Vec::new() }
+            }
+        };
+        pos += found_chunk.sizes.iter().map(|x| *x as u64).sum::<u64>();
+        let complete = found_chunk.sizes.len() == chunk.sizes.len();
+        if !found_chunk.sizes.is_empty() {
+            scan.found[chunk.track].push(found_chunk);
+        }
+        if !complete { break; }
+    }
+    diag!(Debug, "Found {pos} bytes of samples in the {main_size} bytes of the damaged mdat");
+    scan.size = pos;
+    Ok(scan)
+}
+
+/// Scale the sample sizes to add up to `total`
+fn scale_sizes(sizes: &mut [u32], total: u64) {
+    let sum = sizes.iter().map(|x| *x as u64).sum::<u64>().max(1);
+    for x in sizes.iter_mut() {
+        *x = (*x as u64 * total / sum) as u32;
+    }
+    let remainder = total - sizes.iter().map(|x| *x as u64).sum::<u64>();
+    if let Some(last) = sizes.last_mut() { *last += remainder as u32; }
+}
+
+/// Set the sample sizes and chunks of the tracks to the found chunks, cutting the tracks to the found samples.
+/// With `sync_from_frames`, the sync samples of the video tracks are taken from the found frames.
+/// Returns the number of found samples and the number of samples of the template for each track
+pub(crate) fn apply_chunks(desc: &mut Desc, found: &[Vec<FoundChunk>], sync_from_frames: bool) -> Vec<(u32, u32)> {
+    let mut track_samples = Vec::with_capacity(found.len());
+    for (track, chunks) in desc.moov_tracks.iter_mut().zip(found) {
+        let sizes = chunks.iter().flat_map(|x| x.sizes.iter().copied()).collect::<Vec<_>>();
+        let count = sizes.len() as u32;
+        track_samples.push((count, track.stsz_count));
+        if count < track.stsz_count {
+            track.truncate_samples(count);
+        }
+        if track.stsz_sample_size > 0 && sizes.iter().all(|x| *x == track.stsz_sample_size) {
+            track.stsz.clear();
+        } else {
+            track.stsz_sample_size = 0;
+            track.stsz = sizes;
+        }
+        track.stsz_count = count;
+        track.set_chunks(&chunks.iter().map(|x| (x.offset, x.sizes.len() as u32, x.description_index)).collect::<Vec<_>>());
+        if sync_from_frames && !track.stss.is_empty() && chunks.iter().any(|x| !x.sync.is_empty()) {
+            track.stss = chunks.iter().flat_map(|x| &x.sync).enumerate().filter(|x| *x.1).map(|x| x.0 as u32 + 1).collect();
+        }
+    }
+    track_samples
+}
+
+/// Set the tkhd, elst and mvhd durations from the media durations of the tracks
+pub(crate) fn update_durations(desc: &mut Desc) {
+    for track in desc.moov_tracks.iter_mut().filter(|x| !x.dropped) {
+        track.tkhd_duration = desc_reader::convert_duration_ceil(track.mdhd_duration, track.mdhd_timescale, desc.moov_mvhd_timescale);
+        track.elst_segment_duration = track.tkhd_duration;
+    }
+    desc.moov_mvhd_duration = desc.moov_tracks.iter().filter(|x| !x.dropped).map(|x| x.tkhd_duration).max().unwrap_or(0);
+}
+
+/// Rebuild the moov of a GoPro file whose recording was interrupted, using the intact LRV (low resolution proxy) recorded with it as a template.
+/// The LRV has the same tracks, sample counts, timing and chunk interleaving: the video frames of the main file are found by parsing their NAL units
+/// and the other tracks (audio, telemetry, timecode) are expected to have the same data as in the LRV.
+/// The sample descriptions are copied from `reference`, e.g. another chapter of the same recording, otherwise from the LRV, whose resolution differs.
+/// The tracks are shortened when the main file ends early.
+pub fn repair_from_lrv<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>>(main: P, lrv: Q, reference: Option<R>, output: impl AsRef<Path>) -> Result<LrvRepairReport> {
+    let mut main = std::fs::File::open(main)?;
+    let mut lrv = std::io::BufReader::with_capacity(64*1024, std::fs::File::open(lrv)?);
+    let mut reference = match reference {
+        Some(x) => Some(std::io::BufReader::with_capacity(64*1024, std::fs::File::open(x)?)),
+        None => None
+    };
+    let mut output = std::io::BufWriter::with_capacity(64*1024, std::fs::File::create(output)?);
+    let report = repair_streams_from_lrv(&mut main, &mut lrv, reference.as_mut(), &mut output)?;
+    output.flush()?;
+    Ok(report)
+}
+
+/// `repair_from_lrv` with streams
+pub fn repair_streams_from_lrv<M: Read + Seek, L: Read + Seek, R: Read + Seek, O: Write + Seek>(main: &mut M, lrv: &mut L, reference: Option<&mut R>, output: &mut O) -> Result<LrvRepairReport> {
+    let lrv_desc = desc_reader::read_file_desc(lrv)?;
+    let lrv_payload = lrv_desc.mdat_final_position;
+    let num_tracks = lrv_desc.moov_tracks.iter().rposition(|t| !t.handler_type.is_empty()).map(|x| x + 1).unwrap_or(0);
+    let formats = video_formats(lrv, num_tracks)?;
+    let template = template_chunks(&lrv_desc, num_tracks);
+
+    let scan = scan_chunks(main, &template, &formats, Some((&mut *lrv, lrv_payload)))?;
+    let (main_start, _) = mdat_payload(main)?;
+    let mut report = LrvRepairReport { truncated: scan.truncated, mismatched_tracks: scan.mismatched_tracks, ..Default::default() };
+
+    let mut desc = Desc { mdat_position: vec![(Some(0), main_start, scan.size)], ..lrv_desc.clone() };
+    desc.moov_tracks.truncate(num_tracks);
+    report.track_samples = apply_chunks(&mut desc, &scan.found, false);
+
+    // The boxes of the output: the reference with its sample descriptions, or the LRV
+    let tree = match reference {
+        Some(reference) => {
+            let reference_desc = desc_reader::read_file_desc(reference)?;
+            let handlers = reference_desc.moov_tracks.iter().map(|x| x.handler_type.clone()).take_while(|x| !x.is_empty()).collect::<Vec<_>>();
+            let mut tracks = std::mem::take(&mut desc.moov_tracks).into_iter().map(Some).collect::<Vec<_>>();
+            desc.moov_tracks = handlers.iter().map(|handler| {
+                tracks.iter_mut().find(|x| x.as_ref().is_some_and(|x| &x.handler_type == handler)).and_then(|x| x.take())
+                    .unwrap_or_else(|| TrackDesc { handler_type: handler.clone(), dropped: true, ..Default::default() })
+            }).collect();
+            desc.moov_mvhd_timescale = reference_desc.moov_mvhd_timescale;
+            box_cache::BoxCache::read(reference, u64::MAX)?
+        },
+        None => {
+            diag!(Warn, "Repairing without a reference file, the sample descriptions of the LRV don't match the resolution of the main file");
+            box_cache::BoxCache::read(lrv, u64::MAX)?
+        }
+    };
+    update_durations(&mut desc);
+
+    let main_size = main.seek(SeekFrom::End(0))? as usize;
+    let mut files = [(main, main_size)];
+    writer::rewrite_from_desc(&mut tree.reader(), &mut files, output, &mut desc, 0, u64::MAX, &crate::copy::Synchronous)?;
+    crate::patch_chunk_offsets(output, &desc)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ mp4_box, SyntheticMp4, SyntheticTrack };
+
+    #[test]
+    fn test_repair_from_lrv() {
+        let lrv = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 12)).track(SyntheticTrack::audio(48000, 14));
+        let lrv_data = lrv.build();
+        let lrv_desc = desc_reader::read_file_desc(&mut std::io::Cursor::new(&lrv_data)).unwrap();
+
+        // The main file has larger H.264 frames with the same chunk layout and the same audio
+        let frame = |i: u32| {
+            let mut frame = Vec::new();
+            if i.is_multiple_of(5) { frame.extend([&2u32.to_be_bytes()[..], &[0x09, 0xf0]].concat()); }
+            for slice in 0..2u8 {
+                let size = 300 + i as usize * 7 + slice as usize;
+                let mut nal = vec![if i.is_multiple_of(5) { 0x65 } else { 0x41 }, if slice == 0 { 0x88 } else { 0x08 }];
+                nal.resize(size, i as u8);
+                frame.extend((nal.len() as u32).to_be_bytes());
+                frame.extend(nal);
+            }
+            frame
+        };
+        let mut payload = Vec::new();
+        let mut template = lrv_desc.moov_tracks[..2].iter().enumerate().flat_map(|(t, track)| track.sample_infos().into_iter().map(move |x| (x.offset, t, x))).collect::<Vec<_>>();
+        template.sort_by_key(|x| x.0);
+        let mut video_samples = Vec::new();
+        for (_, track, sample) in &template {
+            let index = (sample.decode_time / if *track == 0 { 1 } else { 1024 }) as u32;
+            if *track == 0 {
+                video_samples.push((payload.len(), frame(index)));
+                payload.extend(frame(index));
+            } else {
+                payload.extend(lrv.sample_data(1, index));
+            }
+        }
+        let ftyp = mp4_box(b"ftyp", b"mp41\0\0\0\0mp41");
+        // The recording stopped in the middle of the 10th frame
+        let cut = video_samples[9].0 + 100;
+        let main_data = [ftyp.clone(), 0u32.to_be_bytes().to_vec(), b"mdat".to_vec(), payload[..cut].to_vec()].concat();
+
+        let mut output = std::io::Cursor::new(Vec::new());
+        let report = repair_streams_from_lrv(&mut std::io::Cursor::new(main_data), &mut std::io::Cursor::new(&lrv_data), None::<&mut std::io::Cursor<Vec<u8>>>, &mut output).unwrap();
+        assert!(report.truncated && report.mismatched_tracks.is_empty());
+        assert_eq!(report.track_samples[0], (9, 12));
+
+        let index = crate::RandomAccessIndex::from_reader(&mut output).unwrap();
+        let output = output.into_inner();
+        assert_eq!(index.sample_count(0), 9);
+        for (i, (_, expected)) in video_samples[..9].iter().enumerate() {
+            let sample = index.sample(0, i).unwrap();
+            assert_eq!(&output[sample.offset as usize..sample.offset as usize + sample.size as usize], &expected[..], "frame {i}");
+        }
+        let last_audio = index.sample(1, index.sample_count(1) - 1).unwrap();
+        assert_eq!(&output[last_audio.offset as usize..][..last_audio.size as usize], &lrv.sample_data(1, index.sample_count(1) as u32 - 1)[..]);
+        assert!(last_audio.time < 9.0 / 25.0);
+    }
+}
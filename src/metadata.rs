@@ -0,0 +1,222 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, SeekFrom, Result };
+use crate::{ fourcc, MergeOptions };
+
+const TITLE: [u8; 4] = *b"\xa9nam";
+const COMMENT: [u8; 4] = *b"\xa9cmt";
+const ARTIST: [u8; 4] = *b"\xa9ART";
+
+/// Title, comment, artist and custom values written to the movie metadata of the output
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MovieMetadata {
+    /// ©-atoms and values, for the title, comment and artist
+    pub items: Vec<([u8; 4], String)>,
+    pub custom: Vec<(String, String)>,
+    /// QuickTime output: ©-atoms in udta and the custom values in moov/meta with mdta keys.
+    /// Otherwise an iTunes-style ilst in udta/meta, with `----` items for the custom values
+    pub quicktime: bool,
+}
+
+impl MovieMetadata {
+    /// Metadata set in the options, or `None` when there's nothing to write
+    pub(crate) fn from_options(options: &MergeOptions, quicktime: bool) -> Option<Self> {
+        let items = [(TITLE, &options.title), (COMMENT, &options.comment), (ARTIST, &options.artist)].into_iter()
+            .filter_map(|(typ, value)| Some((typ, value.clone()?)))
+            .collect::<Vec<_>>();
+        if items.is_empty() && options.metadata.is_empty() { return None; }
+        Some(Self { items, custom: options.metadata.clone(), quicktime })
+    }
+
+    /// Whether a box of moov is rewritten with the metadata
+    pub(crate) fn rewrites(&self, typ: u32) -> bool {
+        typ == fourcc("udta") || (typ == fourcc("meta") && self.quicktime && !self.custom.is_empty())
+    }
+
+    /// New payload of a udta or meta box of moov, `payload` is the existing one or empty when moov has no such box.
+    /// The values of the first file are kept, unless they're replaced
+    pub(crate) fn rewrite(&self, typ: u32, payload: &[u8]) -> Vec<u8> {
+        if typ == fourcc("meta") { return self.rewrite_mdta_meta(payload); }
+        let mut out = Vec::new();
+        let mut has_meta = false;
+        for (child_typ, child) in children(payload) {
+            if self.quicktime && self.items.iter().any(|x| x.0 == child_typ) { continue; }
+            if !self.quicktime && &child_typ == b"meta" && child.len() >= 4 {
+                has_meta = true;
+                out.extend(mp4_box(b"meta", &self.rewrite_itunes_meta(child)));
+                continue;
+            }
+            out.extend(mp4_box(&child_typ, child));
+        }
+        if self.quicktime {
+            for (typ, value) in &self.items {
+                // Text length, language code (undetermined) and the text
+                let mut text = (value.len() as u16).to_be_bytes().to_vec();
+                text.extend(0x55c4u16.to_be_bytes());
+                text.extend(value.as_bytes());
+                out.extend(mp4_box(typ, &text));
+            }
+        } else if !has_meta {
+            out.extend(mp4_box(b"meta", &self.rewrite_itunes_meta(&[0; 4])));
+        }
+        out
+    }
+
+    /// Payload of the udta/meta full box with the values added to its ilst
+    fn rewrite_itunes_meta(&self, payload: &[u8]) -> Vec<u8> {
+        let mut out = payload[..4].to_vec();
+        let mut ilst = Vec::new();
+        let mut has_hdlr = false;
+        for (typ, child) in children(&payload[4..]) {
+            match &typ {
+                b"ilst" => {
+                    for (item_typ, item) in children(child) {
+                        let replaced = self.items.iter().any(|x| x.0 == item_typ)
+                            || (&item_typ == b"----" && self.custom.iter().any(|(key, _)| freeform_name(item) == Some(key.as_bytes())));
+                        if !replaced { ilst.extend(mp4_box(&item_typ, item)); }
+                    }
+                },
+                _ => {
+                    has_hdlr |= &typ == b"hdlr";
+                    out.extend(mp4_box(&typ, child));
+                }
+            }
+        }
+        if !has_hdlr { out.extend(hdlr(b"mdir")); }
+        for (typ, value) in &self.items {
+            ilst.extend(mp4_box(typ, &data(value)));
+        }
+        for (key, value) in &self.custom {
+            let mut item = mp4_box(b"mean", &[&[0; 4], &b"com.apple.iTunes"[..]].concat());
+            item.extend(mp4_box(b"name", &[&[0; 4], key.as_bytes()].concat()));
+            item.extend(data(value));
+            ilst.extend(mp4_box(b"----", &item));
+        }
+        out.extend(mp4_box(b"ilst", &ilst));
+        out
+    }
+
+    /// Payload of the QuickTime moov/meta box with the custom values added to its keys and ilst
+    fn rewrite_mdta_meta(&self, payload: &[u8]) -> Vec<u8> {
+        let mut other = Vec::new();
+        let mut keys = Vec::new();
+        let mut items = Vec::new();
+        for (typ, child) in children(payload) {
+            match &typ {
+                b"keys" => {
+                    let mut pos = 8; // Version, flags and entry_count
+                    while let Some(entry) = child.get(pos..pos + 8) {
+                        let size = u32::from_be_bytes(entry[..4].try_into().unwrap()) as usize;
+                        let Some(key) = child.get(pos + 8..pos + size.max(8)) else { break; };
+                        keys.push(key.to_vec());
+                        pos += size.max(8);
+                    }
+                },
+                b"ilst" => items = children(child).map(|(typ, item)| (u32::from_be_bytes(typ), item.to_vec())).collect(),
+                _ => other.extend(mp4_box(&typ, child)),
+            }
+        }
+        // Items are indexed by their key, starting at 1
+        let mut values = items.into_iter()
+            .filter_map(|(index, item)| Some((keys.get((index as usize).checked_sub(1)?)?.clone(), item)))
+            .filter(|(key, _)| !self.custom.iter().any(|(k, _)| k.as_bytes() == &key[..]))
+            .collect::<Vec<_>>();
+        values.extend(self.custom.iter().map(|(key, value)| (key.as_bytes().to_vec(), data(value))));
+
+        let mut out = if other.is_empty() { hdlr(b"mdta") } else { other };
+        let mut keys = vec![0; 4];
+        keys.extend((values.len() as u32).to_be_bytes());
+        let mut ilst = Vec::new();
+        for (i, (key, item)) in values.iter().enumerate() {
+            keys.extend(mp4_box(b"mdta", key));
+            ilst.extend(mp4_box(&(i as u32 + 1).to_be_bytes(), item));
+        }
+        out.extend(mp4_box(b"keys", &keys));
+        out.extend(mp4_box(b"ilst", &ilst));
+        out
+    }
+}
+
+/// Whether the first file is a QuickTime movie, from its ftyp brand. The reader is rewound
+pub(crate) fn is_quicktime<R: Read + Seek>(reader: &mut R) -> Result<bool> {
+    let mut brand = [0u8; 4];
+    if let Some(ftyp) = crate::boxes::find_box(reader, &["ftyp"])? {
+        reader.seek(SeekFrom::Start(ftyp.offset + ftyp.header_size))?;
+        reader.read_exact(&mut brand)?;
+    }
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(&brand == b"qt  ")
+}
+
+/// Child boxes of a payload, as (type, payload)
+fn children(payload: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        let header = payload.get(pos..pos + 8)?;
+        let size = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let child = payload.get(pos + 8..pos + size.max(8))?;
+        pos += size.max(8);
+        Some((header[4..].try_into().unwrap(), child))
+    })
+}
+
+fn mp4_box(typ: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+    data.extend(typ);
+    data.extend(payload);
+    data
+}
+
+/// UTF-8 data box of an ilst item
+fn data(value: &str) -> Vec<u8> {
+    mp4_box(b"data", &[&[0, 0, 0, 1, 0, 0, 0, 0], value.as_bytes()].concat())
+}
+
+fn hdlr(handler: &[u8; 4]) -> Vec<u8> {
+    let mut payload = vec![0; 8];
+    payload.extend(handler);
+    payload.extend([0; 13]);
+    mp4_box(b"hdlr", &payload)
+}
+
+/// Name of a `----` item
+fn freeform_name(item: &[u8]) -> Option<&[u8]> {
+    children(item).find(|x| &x.0 == b"name").and_then(|x| x.1.get(4..))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_udta() {
+        let options = MergeOptions::default().title("Trip day 3").metadata("com.example.chapters", "5");
+        let metadata = MovieMetadata::from_options(&options, false).unwrap();
+        // udta of the first file with a GoPro box and an ilst with a title and an encoder tag
+        let ilst = [mp4_box(&TITLE, &data("GX010042")), mp4_box(b"\xa9too", &data("Lavf"))].concat();
+        let meta = [&[0; 4][..], &hdlr(b"mdir"), &mp4_box(b"ilst", &ilst)].concat();
+        let udta = [mp4_box(b"FIRM", b"H22.01"), mp4_box(b"meta", &meta)].concat();
+
+        let udta = metadata.rewrite(fourcc("udta"), &udta);
+        let boxes = children(&udta).collect::<Vec<_>>();
+        assert_eq!(boxes.iter().map(|x| &x.0).collect::<Vec<_>>(), vec![b"FIRM", b"meta"]);
+        let meta = children(&boxes[1].1[4..]).collect::<Vec<_>>();
+        let ilst = children(meta.iter().find(|x| &x.0 == b"ilst").unwrap().1).collect::<Vec<_>>();
+        assert_eq!(ilst.iter().map(|x| &x.0).collect::<Vec<_>>(), vec![b"\xa9too", &TITLE, b"----"]);
+        assert_eq!(children(ilst[1].1).next().unwrap().1[8..], *b"Trip day 3");
+        assert_eq!(freeform_name(ilst[2].1), Some(&b"com.example.chapters"[..]));
+
+        // QuickTime: ©-atoms in udta, and the custom value appended to the mdta keys
+        let metadata = MovieMetadata { quicktime: true, ..metadata };
+        let udta = metadata.rewrite(fourcc("udta"), &mp4_box(&TITLE, b"\0\x03\x55\xc4old"));
+        assert_eq!(udta, mp4_box(&TITLE, b"\0\x0a\x55\xc4Trip day 3"));
+        let keys = [&[0; 4][..], &1u32.to_be_bytes(), &mp4_box(b"mdta", b"com.apple.quicktime.make")].concat();
+        let meta = [hdlr(b"mdta"), mp4_box(b"keys", &keys), mp4_box(b"ilst", &mp4_box(&1u32.to_be_bytes(), &data("GoPro")))].concat();
+        let meta = metadata.rewrite(fourcc("meta"), &meta);
+        let boxes = children(&meta).collect::<Vec<_>>();
+        assert_eq!(boxes.iter().map(|x| &x.0).collect::<Vec<_>>(), vec![b"hdlr", b"keys", b"ilst"]);
+        assert_eq!(&boxes[1].1[4..8], &2u32.to_be_bytes());
+        assert_eq!(children(boxes[2].1).map(|x| x.0).collect::<Vec<_>>(), vec![1u32.to_be_bytes(), 2u32.to_be_bytes()]);
+    }
+}
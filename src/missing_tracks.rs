@@ -0,0 +1,71 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, Result };
+use crate::desc_reader::Desc;
+use crate::boxes::{ read_trak, set_track_id };
+use crate::template::TemplateTrack;
+use crate::stsd::SampleEntry;
+use crate::diagnostics::diag;
+
+/// Add the tracks of a later file which the first file doesn't have, e.g. a GPS track of a camera which got a lock during
+/// the second chapter. The trak of the file is used as the template of the output track, and the tracks start with an
+/// empty edit until that file. `template_track_count` is the number of tracks of the template file, `desc.track_map` maps the tracks of the file
+pub(crate) fn add_missing_tracks<R: Read + Seek>(desc: &mut Desc, reader: &mut R, file_index: usize, template_track_count: usize, entries: &[Vec<SampleEntry>]) -> Result<()> {
+    for (position, track_entries) in entries.iter().enumerate() {
+        let index = desc.track_map.get(position).copied().unwrap_or(position);
+        if index < template_track_count || desc.appended_traks.iter().any(|x| x.0 == index) { continue; }
+        let Some(mut trak) = read_trak(reader, position)? else { continue; };
+        let Some(track) = desc.moov_tracks.get(index) else {
+            diag!(Warn, "Track {index} of file {file_index} is missing from the first file and can't be added");
+            continue;
+        };
+        if track.skip || track.handler_type.is_empty() { continue; }
+
+        let used_ids = desc.moov_tracks.iter().enumerate().filter(|(i, _)| *i < template_track_count || desc.appended_traks.iter().any(|x| x.0 == *i))
+            .map(|(_, x)| x.track_id).collect::<Vec<_>>();
+        let mut template = TemplateTrack { sample_entries: track_entries.clone(), ..TemplateTrack::from_trak(&trak)? };
+        if template.track_id == 0 || used_ids.contains(&template.track_id) {
+            template.track_id = used_ids.iter().max().copied().unwrap_or(0) + 1;
+            set_track_id(&mut trak, template.track_id);
+        }
+        diag!(Info, "Track {} ({}) is missing from the template, it's added from file {file_index}", template.track_id, track.handler_type);
+
+        crate::template::apply_track(&mut desc.moov_tracks[index], template);
+        desc.appended_traks.push((index, trak));
+    }
+    Ok(())
+}
+
+/// Whether a track only starts in a later file, and has to be preceded by an empty edit
+pub(crate) fn starts_late(desc: &Desc, index: usize) -> bool {
+    desc.moov_tracks.get(index).is_some_and(|x| !x.skip && !x.handler_type.is_empty() && x.stsz_count > 0 && x.file_sample_ranges.first().is_some_and(|r| r.is_empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+
+    #[test]
+    fn test_track_missing_from_first_file() {
+        // The metadata track only appears in the second 2-second chapter
+        let first = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50));
+        let second = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50)).track(SyntheticTrack::metadata(*b"mett", 2));
+        let mut files = [first.cursor(), second.cursor()];
+        let mut output = std::io::Cursor::new(Vec::new());
+        crate::join_file_streams(&mut files, &mut output, |_| {}).unwrap();
+
+        let tracks = crate::list_tracks(&mut output).unwrap();
+        assert_eq!(tracks.iter().map(|x| (x.handler_type.as_str(), x.track_id)).collect::<Vec<_>>(), vec![("vide", 1), ("meta", 2)]);
+        let index = crate::RandomAccessIndex::from_reader(&mut output).unwrap();
+        assert_eq!(index.sample_count(1), 2);
+        let sample = index.sample(1, 1).unwrap();
+        assert_eq!(&output.get_ref()[sample.offset as usize..][..sample.size as usize], &second.sample_data(1, 1)[..]);
+
+        // Presented after an empty edit of the first chapter
+        let desc = crate::desc_reader::read_file_desc(&mut output).unwrap();
+        let track = &desc.moov_tracks[1];
+        assert_eq!(track.file_edit, Some((0, 2.0)));
+        assert_eq!(track.tkhd_duration, 4 * desc.moov_mvhd_timescale as u64);
+    }
+}
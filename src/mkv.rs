@@ -0,0 +1,323 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Write, Seek, Result, SeekFrom };
+use std::ops::Range;
+use std::time::{ Duration, SystemTime };
+use crate::desc_reader::{ Desc, TrackDesc };
+use crate::{ split, stsd, track_info, diagnostics::diag };
+
+// Element IDs
+const EBML: u32 = 0x1A45DFA3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const SEGMENT: u32 = 0x18538067;
+const SEEK_HEAD: u32 = 0x114D9B74;
+const SEEK: u32 = 0x4DBB;
+const SEEK_ID: u32 = 0x53AB;
+const SEEK_POSITION: u32 = 0x53AC;
+const INFO: u32 = 0x1549A966;
+const TIMESTAMP_SCALE: u32 = 0x2AD7B1;
+const DURATION: u32 = 0x4489;
+const DATE_UTC: u32 = 0x4461;
+const MUXING_APP: u32 = 0x4D80;
+const WRITING_APP: u32 = 0x5741;
+const TRACKS: u32 = 0x1654AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const TRACK_TYPE: u32 = 0x83;
+const FLAG_LACING: u32 = 0x9C;
+const LANGUAGE: u32 = 0x22B59C;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const VIDEO: u32 = 0xE0;
+const PIXEL_WIDTH: u32 = 0xB0;
+const PIXEL_HEIGHT: u32 = 0xBA;
+const AUDIO: u32 = 0xE1;
+const SAMPLING_FREQUENCY: u32 = 0xB5;
+const CHANNELS: u32 = 0x9F;
+const BIT_DEPTH: u32 = 0x6264;
+const CLUSTER: u32 = 0x1F43B675;
+const TIMESTAMP: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+const CUES: u32 = 0x1C53BB6B;
+const CUE_POINT: u32 = 0xBB;
+const CUE_TIME: u32 = 0xB3;
+const CUE_TRACK_POSITIONS: u32 = 0xB7;
+const CUE_TRACK: u32 = 0xF7;
+const CUE_CLUSTER_POSITION: u32 = 0xF1;
+
+/// Nanoseconds per timestamp unit, all timestamps are in milliseconds
+const NS_PER_TICK: u64 = 1_000_000;
+/// Block timestamps are signed 16-bit offsets from the cluster timestamp
+const MAX_CLUSTER_DURATION: i64 = 30_000;
+/// Cluster interval of files without a video track
+const AUDIO_CLUSTER_DURATION: i64 = 5_000;
+/// Seconds between 1970-01-01 and the Matroska epoch 2001-01-01
+const MATROSKA_EPOCH: u64 = 978_307_200;
+
+fn id_bytes(id: u32) -> Vec<u8> {
+    id.to_be_bytes().into_iter().skip_while(|x| *x == 0).collect()
+}
+
+fn size_bytes(size: u64) -> Vec<u8> {
+    // All ones is reserved for unknown sizes
+    let len = (1..8).find(|n| size < (1u64 << (7 * n)) - 1).unwrap_or(8);
+    (size | (1u64 << (7 * len))).to_be_bytes()[8 - len..].to_vec()
+}
+
+fn element(id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut ret = id_bytes(id);
+    ret.extend(size_bytes(payload.len() as u64));
+    ret.extend_from_slice(payload);
+    ret
+}
+
+fn uint(id: u32, value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    element(id, &bytes[bytes.iter().position(|x| *x != 0).unwrap_or(7)..])
+}
+
+fn float(id: u32, value: f64) -> Vec<u8> { element(id, &value.to_be_bytes()) }
+fn string(id: u32, value: &str) -> Vec<u8> { element(id, value.as_bytes()) }
+fn master(id: u32, children: &[Vec<u8>]) -> Vec<u8> { element(id, &children.concat()) }
+
+/// Header of a master element written in pieces, with an 8-byte size
+fn master_header(id: u32, size: u64) -> Vec<u8> {
+    let mut ret = id_bytes(id);
+    ret.extend((size | (1u64 << 56)).to_be_bytes());
+    ret
+}
+
+/// AudioSpecificConfig from the DecoderSpecificInfo descriptor of an esds box
+fn audio_specific_config(esds: &[u8]) -> Option<Vec<u8>> {
+    stsd::decoder_config(esds)?.1
+}
+
+/// Matroska codec ID and codec private data of a sample entry
+fn codec(entry: &stsd::SampleEntry) -> Option<(&'static str, Option<Vec<u8>>)> {
+    let config = |typ: &str| entry.child(typ).map(<[u8]>::to_vec);
+    match entry.codec.as_str() {
+        "avc1" | "avc3" => Some(("V_MPEG4/ISO/AVC", config("avcC"))),
+        "hvc1" | "hev1" => Some(("V_MPEGH/ISO/HEVC", config("hvcC"))),
+        "av01"          => Some(("V_AV1", config("av1C"))),
+        "mp4a" => Some(("A_AAC", entry.esds().and_then(audio_specific_config))),
+        "sowt" => Some(("A_PCM/INT/LIT", None)),
+        "twos" => Some(("A_PCM/INT/BIG", None)),
+        _ => None
+    }
+}
+
+fn track_entry(number: u64, track: &TrackDesc, entry: &stsd::SampleEntry, codec_id: &str, codec_private: Option<Vec<u8>>) -> Vec<u8> {
+    let mut info = track_info::TrackInfo { handler_type: track.handler_type.clone(), ..Default::default() };
+    track_info::apply_sample_entry(&mut info, entry);
+
+    let mut children = vec![
+        uint(TRACK_NUMBER, number),
+        uint(TRACK_UID, if track.track_id > 0 { track.track_id as u64 } else { number }),
+        uint(TRACK_TYPE, if track.handler_type == "vide" { 1 } else { 2 }),
+        uint(FLAG_LACING, 0),
+        string(LANGUAGE, if track.language.len() == 3 { &track.language } else { "und" }),
+        string(CODEC_ID, codec_id),
+    ];
+    if let Some(data) = codec_private {
+        children.push(element(CODEC_PRIVATE, &data));
+    }
+    if track.handler_type == "vide" {
+        children.push(master(VIDEO, &[uint(PIXEL_WIDTH, info.width.unwrap_or(0) as u64), uint(PIXEL_HEIGHT, info.height.unwrap_or(0) as u64)]));
+    } else {
+        let mut audio = vec![float(SAMPLING_FREQUENCY, info.sample_rate.unwrap_or(0.0)), uint(CHANNELS, info.channels.unwrap_or(1) as u64)];
+        if codec_id.starts_with("A_PCM") {
+            audio.push(uint(BIT_DEPTH, entry.fields.get(18..20).map(|x| u16::from_be_bytes([x[0], x[1]])).unwrap_or(16) as u64));
+        }
+        children.push(master(AUDIO, &audio));
+    }
+    master(TRACK_ENTRY, &children)
+}
+
+/// A sample of the merged tracks, at its place on the output timeline
+struct Block {
+    track_number: u8,
+    time: i64, // In milliseconds
+    keyframe: bool,
+    offset: u64, // Relative to the start of the merged mdat payload
+    size: u32,
+}
+
+impl Block {
+    fn element_size(&self) -> u64 {
+        let payload = 4 + self.size as u64; // Track number, timestamp and flags
+        1 + size_bytes(payload).len() as u64 + payload
+    }
+}
+
+/// Times of the samples on the output timeline in seconds. Edit lists are applied by shifting the samples of each segment,
+/// so gaps between files are kept
+fn presentation_times(track: &TrackDesc, samples: &[crate::desc_reader::SampleInfo], movie_timescale: u32) -> Vec<f64> {
+    let media_timescale = track.mdhd_timescale.max(1) as f64;
+    let mut segments = Vec::new(); // (start on the timeline in seconds, media time)
+    let mut time = 0.0;
+    for entry in &track.elst_entries {
+        if entry.media_time >= 0 {
+            segments.push((time, entry.media_time));
+        }
+        time += entry.segment_duration as f64 / movie_timescale.max(1) as f64;
+    }
+    samples.iter().map(|x| {
+        let decode_time = x.decode_time as i64;
+        match segments.iter().rev().find(|s| s.1 <= decode_time).or(segments.first()) {
+            Some((start, media_time)) => (start + (decode_time - media_time) as f64 / media_timescale).max(0.0),
+            None => decode_time as f64 / media_timescale
+        }
+    }).collect()
+}
+
+/// Remux the merged samples into Matroska, reusing the merged sample tables and the codec configuration of the first file.
+/// Only video and audio tracks are written (H.264, HEVC, AV1, AAC and PCM), camera metadata is not.
+/// Blocks are timestamped with the decode times, as composition offsets are not merged.
+pub(crate) fn write_mkv<I: Read + Seek, O: Write>(files: &mut [(I, usize)], mut output: O, desc: &Desc) -> Result<u64> {
+    let num_tracks = desc.moov_tracks.iter().rposition(|t| !t.handler_type.is_empty()).map(|x| x + 1).unwrap_or(0);
+
+    let mut track_entries = Vec::new();
+    let mut blocks = Vec::new();
+    let mut reference = None; // Track number of the video track, clusters start at its keyframes
+    let mut duration = 0.0f64;
+    for (i, track) in desc.moov_tracks[..num_tracks].iter().enumerate() {
+        if track.dropped || track.skip || (track.handler_type != "vide" && track.handler_type != "soun") { continue; }
+        let Some(entry) = track.sample_entries.first() else { continue; };
+        let Some((codec_id, codec_private)) = codec(entry) else {
+            diag!(Warn, "Track {i} with codec {} can't be written to Matroska, skipping it", entry.codec);
+            continue;
+        };
+        let number = track_entries.len() as u8 + 1;
+        track_entries.push(track_entry(number as u64, track, entry, codec_id, codec_private));
+        if track.handler_type == "vide" && reference.is_none() { reference = Some(number); }
+
+        let samples = track.sample_infos();
+        for (sample, time) in samples.iter().zip(presentation_times(track, &samples, desc.moov_mvhd_timescale)) {
+            duration = duration.max(time + sample.duration as f64 / track.mdhd_timescale.max(1) as f64);
+            blocks.push(Block { track_number: number, time: (time * 1000.0).round() as i64, keyframe: sample.is_sync, offset: sample.offset, size: sample.size });
+        }
+    }
+    blocks.sort_by_key(|x| (x.time, x.track_number));
+
+    // Clusters start at the keyframes of the video track
+    let mut clusters: Vec<Range<usize>> = Vec::new();
+    let mut start = 0;
+    for (i, block) in blocks.iter().enumerate().skip(1) {
+        let elapsed = block.time - blocks[start].time;
+        let at_keyframe = match reference {
+            Some(r) => block.track_number == r && block.keyframe,
+            None => elapsed >= AUDIO_CLUSTER_DURATION
+        };
+        if at_keyframe || elapsed >= MAX_CLUSTER_DURATION {
+            clusters.push(start..i);
+            start = i;
+        }
+    }
+    if start < blocks.len() { clusters.push(start..blocks.len()); }
+
+    let info = {
+        let mut children = vec![uint(TIMESTAMP_SCALE, NS_PER_TICK), float(DURATION, duration * 1000.0)];
+        if let Some(time) = desc.output_creation_time.or(desc.file_mvhd_creation_times.first().copied().flatten()) {
+            let since_epoch = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos() as i64 - Duration::from_secs(MATROSKA_EPOCH).as_nanos() as i64;
+            children.push(element(DATE_UTC, &since_epoch.to_be_bytes()));
+        }
+        children.push(string(MUXING_APP, "mp4-merge"));
+        children.push(string(WRITING_APP, "mp4-merge"));
+        master(INFO, &children)
+    };
+    let tracks = master(TRACKS, &track_entries);
+    let seek_head = |positions: &[(u32, u64)]| master(SEEK_HEAD, &positions.iter().map(|(id, pos)| {
+        master(SEEK, &[element(SEEK_ID, &id_bytes(*id)), element(SEEK_POSITION, &pos.to_be_bytes())])
+    }).collect::<Vec<_>>());
+
+    // All sizes are known, so the positions are computed before writing. Positions are relative to the segment data
+    let seek_head_size = seek_head(&[(INFO, 0), (TRACKS, 0), (CUES, 0)]).len() as u64;
+    let info_position = seek_head_size;
+    let tracks_position = info_position + info.len() as u64;
+    let mut position = tracks_position + tracks.len() as u64;
+    let mut cluster_headers = Vec::with_capacity(clusters.len());
+    let mut cue_points = Vec::new();
+    for range in &clusters {
+        let first = &blocks[range.start];
+        let timestamp = uint(TIMESTAMP, first.time as u64);
+        let size = timestamp.len() as u64 + blocks[range.clone()].iter().map(Block::element_size).sum::<u64>();
+        if reference.is_none() || (Some(first.track_number) == reference && first.keyframe) {
+            cue_points.push(master(CUE_POINT, &[
+                uint(CUE_TIME, first.time as u64),
+                master(CUE_TRACK_POSITIONS, &[uint(CUE_TRACK, first.track_number as u64), uint(CUE_CLUSTER_POSITION, position)])
+            ]));
+        }
+        let mut header = master_header(CLUSTER, size);
+        position += header.len() as u64 + size;
+        header.extend(timestamp);
+        cluster_headers.push(header);
+    }
+    let cues = master(CUES, &cue_points);
+    let seek_head = seek_head(&[(INFO, info_position), (TRACKS, tracks_position), (CUES, position)]);
+    let segment_size = position + cues.len() as u64;
+
+    diag!(Debug, "Writing Matroska with {} tracks, {} blocks in {} clusters", track_entries.len(), blocks.len(), clusters.len());
+    let ebml_header = master(EBML, &[
+        uint(EBML_VERSION, 1), uint(EBML_READ_VERSION, 1), uint(EBML_MAX_ID_LENGTH, 4), uint(EBML_MAX_SIZE_LENGTH, 8),
+        string(DOC_TYPE, "matroska"), uint(DOC_TYPE_VERSION, 4), uint(DOC_TYPE_READ_VERSION, 2)
+    ]);
+    let segment_header = master_header(SEGMENT, segment_size);
+    for x in [&ebml_header, &segment_header, &seek_head, &info, &tracks] {
+        output.write_all(x)?;
+    }
+
+    let mut buf = Vec::new();
+    for (range, header) in clusters.iter().zip(cluster_headers) {
+        output.write_all(&header)?;
+        let cluster_time = blocks[range.start].time;
+        for block in &blocks[range.clone()] {
+            output.write_all(&id_bytes(SIMPLE_BLOCK))?;
+            output.write_all(&size_bytes(4 + block.size as u64))?;
+            output.write_all(&[0x80 | block.track_number])?;
+            output.write_all(&((block.time - cluster_time) as i16).to_be_bytes())?;
+            output.write_all(&[if block.keyframe { 0x80 } else { 0 }])?;
+            for (file_index, offset, size) in split::map_to_source_ranges(desc, &[(block.offset, block.size as u64)]) {
+                let Some(file_index) = file_index else {
+                    // Sample created by the merge
+                    let data = desc.synthesized_data.get(offset as usize..(offset + size) as usize).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Synthesized sample data out of range"))?;
+                    output.write_all(data)?;
+                    continue;
+                };
+                let file = files.get_mut(file_index).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Sample data is outside of the source files"))?;
+                file.0.seek(SeekFrom::Start(offset))?;
+                buf.resize(size as usize, 0);
+                file.0.read_exact(&mut buf)?;
+                output.write_all(&buf)?;
+            }
+        }
+    }
+    output.write_all(&cues)?;
+
+    Ok(ebml_header.len() as u64 + segment_header.len() as u64 + segment_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ebml_encoding() {
+        assert_eq!(size_bytes(0), vec![0x80]);
+        assert_eq!(size_bytes(126), vec![0xFE]);
+        assert_eq!(size_bytes(127), vec![0x40, 0x7F]);
+        assert_eq!(uint(TRACK_NUMBER, 1), vec![0xD7, 0x81, 0x01]);
+        assert_eq!(uint(TIMESTAMP_SCALE, NS_PER_TICK), vec![0x2A, 0xD7, 0xB1, 0x83, 0x0F, 0x42, 0x40]);
+
+        // ES_Descriptor > DecoderConfigDescriptor > DecoderSpecificInfo (AAC-LC, 48 kHz, stereo)
+        let esds = [0, 0, 0, 0, 0x03, 0x19, 0, 1, 0, 0x04, 0x11, 0x40, 0x15, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x05, 0x02, 0x11, 0x90];
+        assert_eq!(audio_specific_config(&esds), Some(vec![0x11, 0x90]));
+    }
+}
@@ -0,0 +1,202 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::collections::BTreeMap;
+use std::io::Result;
+use std::path::{ Path, PathBuf };
+use std::time::Duration;
+use crate::{ FileInfo, GapDecision, MergeOptions, MergeReport, join_files_with_options };
+
+/// Reports of both lens sequences of an Insta360 session
+#[derive(Debug, Clone, Default)]
+pub struct DualLensReport {
+    /// Lens `00`
+    pub front: MergeReport,
+    /// Lens `10`
+    pub back: MergeReport,
+}
+
+fn invalid(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, msg)
+}
+
+/// Session prefix, lens and chapter number of an Insta360 file name, e.g. `VID_20230101_120000_00_001.insv`
+fn parse_name(path: &Path) -> Option<(String, String, u32)> {
+    let stem = path.file_stem()?.to_str()?;
+    let mut parts = stem.rsplitn(3, '_');
+    let number = parts.next()?.parse().ok()?;
+    let lens = parts.next()?.to_string();
+    let prefix = parts.next()?.to_string();
+    Some((prefix, lens, number))
+}
+
+/// Split the files of a session into the lens `00` and lens `10` sequences, each sorted by chapter number
+fn split_lenses<P: AsRef<Path>>(files: &[P]) -> Result<(Vec<&P>, Vec<&P>)> {
+    let mut front = Vec::new();
+    let mut back = Vec::new();
+    let mut session = None;
+    for x in files {
+        let (prefix, lens, number) = parse_name(x.as_ref()).ok_or_else(|| invalid(format!("{} is not an Insta360 file name", x.as_ref().display())))?;
+        if session.get_or_insert_with(|| prefix.clone()) != &prefix {
+            return Err(invalid(format!("{} belongs to a different session", x.as_ref().display())));
+        }
+        match lens.as_str() {
+            "00" => front.push((number, x)),
+            "10" => back.push((number, x)),
+            _ => return Err(invalid(format!("Unknown lens '{lens}' in {}", x.as_ref().display())))
+        }
+    }
+    front.sort_by_key(|x| x.0);
+    back.sort_by_key(|x| x.0);
+    if front.iter().map(|x| x.0).ne(back.iter().map(|x| x.0)) {
+        return Err(invalid(format!("Lens sequences don't match: {:?} and {:?}", front.iter().map(|x| x.0).collect::<Vec<_>>(), back.iter().map(|x| x.0).collect::<Vec<_>>())));
+    }
+    Ok((front.into_iter().map(|x| x.1).collect(), back.into_iter().map(|x| x.1).collect()))
+}
+
+/// Path of the lens `10` file matching a lens `00` path, by replacing the last `_00_` or `_00.` of its file name
+pub fn back_lens_path(front: &Path) -> Option<PathBuf> {
+    let name = front.file_name()?.to_str()?;
+    let pos = name.rfind("_00_").or_else(|| name.rfind("_00."))?;
+    Some(front.with_file_name(format!("{}_10{}", &name[..pos], &name[pos + 3..])))
+}
+
+/// Lens and segment number of an Insta360 Pro/Titan file name: `origin_<lens>.mp4` or `origin_<lens>_<segment>.mp4`
+fn parse_pro_name(path: &Path) -> Option<(u32, u32)> {
+    let stem = path.file_stem()?.to_str()?;
+    let mut parts = stem.strip_prefix("origin_")?.split('_');
+    let lens = parts.next()?.parse().ok()?;
+    let segment = parts.next().map(|x| x.parse().ok()).unwrap_or(Some(0))?;
+    parts.next().is_none().then_some((lens, segment))
+}
+
+/// Group the files of an Insta360 Pro/Titan session by lens. Segments are ordered by their folder and segment number.
+/// Returns the lens numbers and the files of each lens.
+fn group_pro_lenses<P: AsRef<Path>>(files: &[P]) -> Result<(Vec<u32>, Vec<Vec<&P>>)> {
+    // (folder, segment) -> lens -> file
+    let mut segments: BTreeMap<(PathBuf, u32), BTreeMap<u32, &P>> = BTreeMap::new();
+    for x in files {
+        let path = x.as_ref();
+        let (lens, segment) = parse_pro_name(path).ok_or_else(|| invalid(format!("{} is not an Insta360 Pro file name", path.display())))?;
+        let folder = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        if segments.entry((folder, segment)).or_default().insert(lens, x).is_some() {
+            return Err(invalid(format!("Duplicated lens {lens} in {}", path.display())));
+        }
+    }
+    let Some(lenses) = segments.values().next().map(|x| x.keys().copied().collect::<Vec<_>>()) else { return Ok((Vec::new(), Vec::new())); };
+    let mut groups = vec![Vec::new(); lenses.len()];
+    for ((folder, segment), files) in &segments {
+        if files.keys().ne(lenses.iter()) {
+            return Err(invalid(format!("Segment {segment} in {} has lenses {:?}, expected {lenses:?}", folder.display(), files.keys().collect::<Vec<_>>())));
+        }
+        for (group, file) in groups.iter_mut().zip(files.values()) {
+            group.push(*file);
+        }
+    }
+    Ok((lenses, groups))
+}
+
+/// Merge the files of multiple lenses recorded together. `lenses[i]` are the files of lens `i`, written to `outputs[i]`.
+/// The merge order and gaps are decided from the first lens and applied to all others, so the outputs stay aligned for stitching.
+pub fn merge_lens_groups<P: AsRef<Path>, F: Fn(f64)>(lenses: &[Vec<P>], outputs: &[P], options: &MergeOptions, progress_cb: F) -> Result<Vec<MergeReport>> {
+    if lenses.len() != outputs.len() {
+        return Err(invalid(format!("Expected {} outputs, got {}", lenses.len(), outputs.len())));
+    }
+    let Some(first) = lenses.first() else { return Ok(Vec::new()); };
+    if let Some(lens) = lenses.iter().position(|x| x.len() != first.len()) {
+        return Err(invalid(format!("Lens {lens} has {} files, the first lens has {}", lenses[lens].len(), first.len())));
+    }
+    let num_lenses = lenses.len() as f64;
+
+    let first_report = join_files_with_options(first, &outputs[0], options, |p| progress_cb(p / num_lenses))?;
+
+    let mut lens_options = options.clone();
+    if !first_report.gaps.is_empty() {
+        let gaps = first_report.gaps.clone();
+        lens_options.explicit_gaps = None;
+        lens_options = lens_options.gap_model(move |prev: &FileInfo, _next: &FileInfo| match gaps.get(prev.index) {
+            Some(gap) if *gap < 0.0 => GapDecision::Overlap(Duration::from_secs_f64(-gap)),
+            Some(gap) if *gap > 0.0 => GapDecision::Gap(Duration::from_secs_f64(*gap)),
+            _ => GapDecision::NoGap
+        });
+    }
+    let order = first_report.files.iter().map(|x| x.input_index).collect::<Vec<_>>();
+
+    let mut reports = vec![first_report];
+    for (i, (files, output)) in lenses.iter().zip(outputs).enumerate().skip(1) {
+        let files = order.iter().map(|x| &files[*x]).collect::<Vec<_>>();
+        reports.push(join_files_with_options(&files, &output, &lens_options, |p| progress_cb((i as f64 + p) / num_lenses))?);
+    }
+    Ok(reports)
+}
+
+/// Merge both lens sequences of an Insta360 session (`..._00_###.insv` and `..._10_###.insv` files, in any order).
+/// The gaps are decided from the lens `00` files and reused for lens `10`, so both outputs stay aligned for stitching.
+pub fn merge_dual_lens<P: AsRef<Path>, F: Fn(f64)>(files: &[P], front_output: &P, back_output: &P, options: &MergeOptions, progress_cb: F) -> Result<DualLensReport> {
+    let (front_files, back_files) = split_lenses(files)?;
+
+    let mut reports = merge_lens_groups(&[front_files, back_files], &[front_output, back_output], options, progress_cb)?;
+    let back = reports.pop().unwrap_or_default();
+    let front = reports.pop().unwrap_or_default();
+    Ok(DualLensReport { front, back })
+}
+
+/// Merge Insta360 `.insv` files. Files of a single lens, or with both lenses as two video tracks (single-file mode of newer cameras),
+/// are merged into `output`. When the files contain both the lens `00` and `10` sequences, each sequence is merged with `merge_dual_lens`:
+/// lens `00` to `output` and lens `10` next to it, named with `back_lens_path`, so Insta360 Studio pairs them again.
+/// Returns each output with its report
+pub fn merge_insv<P: AsRef<Path>, F: Fn(f64)>(files: &[P], output: &Path, options: &MergeOptions, progress_cb: F) -> Result<Vec<(PathBuf, MergeReport)>> {
+    let files = files.iter().map(|x| x.as_ref().to_path_buf()).collect::<Vec<_>>();
+    let output = output.to_path_buf();
+    let lenses = files.iter().filter_map(|x| parse_name(x)).map(|x| x.1).collect::<Vec<_>>();
+    if !(lenses.iter().any(|x| x == "00") && lenses.iter().any(|x| x == "10")) {
+        let report = join_files_with_options(&files, &output, options, progress_cb)?;
+        return Ok(vec![(output, report)]);
+    }
+    let back_output = back_lens_path(&output).ok_or_else(|| invalid(format!("{} must be named like a lens 00 file (..._00_...) to name the lens 10 output", output.display())))?;
+    let report = merge_dual_lens(&files, &output, &back_output, options, progress_cb)?;
+    Ok(vec![(output, report.front), (back_output, report.back)])
+}
+
+/// Merge all lens streams of an Insta360 Pro/Titan session (`origin_<lens>.mp4` in one folder per segment, in any order).
+/// Each lens is written to `output_dir/origin_<lens>.mp4`. Returns the report of each lens, in the order of the lens numbers.
+pub fn merge_insta360_pro<P: AsRef<Path>, F: Fn(f64)>(files: &[P], output_dir: &Path, options: &MergeOptions, progress_cb: F) -> Result<Vec<MergeReport>> {
+    let (lenses, groups) = group_pro_lenses(files)?;
+    let groups = groups.into_iter().map(|x| x.into_iter().map(|p| p.as_ref().to_path_buf()).collect::<Vec<_>>()).collect::<Vec<_>>();
+    let outputs = lenses.iter().map(|x| output_dir.join(format!("origin_{x}.mp4"))).collect::<Vec<_>>();
+    merge_lens_groups(&groups, &outputs, options, progress_cb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_lenses() {
+        let files = ["VID_20230101_120000_10_002.insv", "VID_20230101_120000_00_002.insv", "VID_20230101_120000_00_001.insv", "VID_20230101_120000_10_001.insv"];
+        let (front, back) = split_lenses(&files).unwrap();
+        assert_eq!(front, vec![&files[2], &files[1]]);
+        assert_eq!(back, vec![&files[3], &files[0]]);
+
+        assert!(split_lenses(&["VID_20230101_120000_00_001.insv", "VID_20230101_120000_10_002.insv"]).is_err());
+        assert!(split_lenses(&["VID_20230101_120000_00_001.insv", "VID_20230102_090000_10_001.insv"]).is_err());
+    }
+
+    #[test]
+    fn test_back_lens_path() {
+        assert_eq!(back_lens_path(Path::new("a/VID_20230101_120000_00_001.insv_joined.insv")), Some(PathBuf::from("a/VID_20230101_120000_10_001.insv_joined.insv")));
+        assert_eq!(back_lens_path(Path::new("trip_00.insv")), Some(PathBuf::from("trip_10.insv")));
+        assert_eq!(back_lens_path(Path::new("trip.insv")), None);
+    }
+
+    #[test]
+    fn test_group_pro_lenses() {
+        let files = ["b/origin_2.mp4", "a/origin_1.mp4", "b/origin_1.mp4", "a/origin_2.mp4"];
+        let (lenses, groups) = group_pro_lenses(&files).unwrap();
+        assert_eq!(lenses, vec![1, 2]);
+        assert_eq!(groups, vec![vec![&files[1], &files[2]], vec![&files[3], &files[0]]]);
+
+        assert!(group_pro_lenses(&["a/origin_1.mp4", "a/origin_2.mp4", "b/origin_1.mp4"]).is_err());
+        assert!(group_pro_lenses(&["a/origin_1.mp4", "a/preview.mp4"]).is_err());
+    }
+}
@@ -0,0 +1,502 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{ Duration, SystemTime };
+use crate::desc_reader::{ GapModel, EditListEditor };
+use crate::progress_stream::{ ProgressListener, ProgressEventListener };
+use crate::diagnostics::DiagnosticsSink;
+use crate::gpx::GpxTrack;
+use crate::template::TemplateSelection;
+use crate::cancel::CancellationToken;
+use crate::track_filter::TrackFilter;
+
+/// Container of the merged output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Mp4,
+    /// Remux the merged video and audio tracks into Matroska. Camera metadata (GPMF, Insta360) is not written
+    Matroska,
+}
+
+/// Options controlling how the files are merged
+#[derive(Default, Clone, Debug)]
+pub struct MergeOptions {
+    /// Pauses between consecutive files. When set, the gaps are not derived from the file timestamps.
+    pub explicit_gaps: Option<Vec<Duration>>,
+    /// Duration of each file on the merged timeline, overriding the durations stored in the files.
+    pub file_durations: Option<Vec<Duration>>,
+    /// Custom gap logic invoked between each pair of consecutive files.
+    pub gap_model: Option<Arc<dyn GapModel>>,
+    /// Replaces or adjusts the computed edit list of each track before writing
+    pub edit_list_editor: Option<Arc<dyn EditListEditor>>,
+    /// When a file starts before the previous one ended, skip the overlapping part (extended to the next video keyframe)
+    /// instead of presenting the duplicated frames. Overlaps are only detected from GPS time or the gap model.
+    pub trim_overlaps: bool,
+    /// Gaps derived from the creation times or GPS time up to this long are treated as continuous recording.
+    /// 1 second by default, the resolution of the creation times
+    pub gap_threshold: Option<Duration>,
+    /// Concatenate the files without gaps, trims or delays, so every track keeps a single-entry edit list.
+    /// For players which don't handle empty edits (media_time -1)
+    pub disable_gaps: bool,
+    /// Re-derive the chunk offsets which point outside of the mdat of their file from the sample sizes, e.g. in files
+    /// damaged by a failed recording. Without it, such files are only reported
+    pub repair_chunk_offsets: bool,
+    /// Fill the gaps between files in the GPMF and CAMM telemetry tracks with samples interpolated between the last values
+    /// before the gap and the first ones after it, at the native rate of each track. Interpolated GPMF streams are flagged
+    /// with an `INTP` entry. Without it, the telemetry has a hole during the pause
+    pub interpolate_telemetry_gaps: bool,
+    /// Timescale of the output movie (mvhd), e.g. 90000 for broadcast pipelines. The edit lists and the movie and track
+    /// durations are rescaled to it. When not set, the timescale of the first file is kept
+    pub movie_timescale: Option<u32>,
+    /// Media timescale (mdhd) of the output tracks, by track ID. The sample durations, edit list media times and media
+    /// durations are rescaled to it
+    pub track_timescales: HashMap<u32, u32>,
+    /// Fail the merge when the timescale conversions shift the timing of any track by more than this, e.g. half a frame.
+    /// The error of each track is reported in `TrackReport::rounding_error`
+    pub max_rounding_error: Option<Duration>,
+    /// Split the output into multiple files, each smaller than this many bytes
+    pub max_output_size: Option<u64>,
+    /// Creation time of the output, written to mvhd/tkhd/mdhd and to the filesystem by `join_files_with_options`.
+    /// When not set, the times of the first file are kept.
+    pub creation_time: Option<SystemTime>,
+    /// Modification time of the output, written to mvhd/tkhd/mdhd and to the filesystem by `join_files_with_options`
+    pub modification_time: Option<SystemTime>,
+    /// Number of groups merged at the same time by `merge_groups`, 2 by default
+    pub max_concurrent_merges: Option<usize>,
+    /// Write moov before mdat, so the output can be streamed and seeked over HTTP without another remux.
+    /// Also applies when a single file is passed through
+    pub faststart: bool,
+    /// Remove free and skip boxes when a single file is passed through
+    pub strip_free_boxes: bool,
+    /// Always write 64-bit chunk offsets (co64) when a single file is passed through
+    pub force_co64: bool,
+    /// Write 32-bit chunk offsets (stco) when the merged file stays below 4 GiB, instead of always converting them to co64
+    pub keep_stco: bool,
+    /// Write a segment index (sidx) before the mdat, referencing the data and duration of each input, so players can seek
+    /// across the merged files with byte-range requests. Meant for `faststart` outputs, not written for split outputs
+    pub sidx: bool,
+    /// Receives the written bytes, throughput and estimated remaining time, in addition to the progress callback
+    pub progress_listener: Option<Arc<dyn ProgressListener>>,
+    /// Receives the phase of the merge (scanning, copying, patching), the current input file and the bytes done in the phase
+    pub progress_events: Option<Arc<dyn ProgressEventListener>>,
+    /// Receives the diagnostic messages of the merge, in addition to the `log` crate
+    pub diagnostics: Option<Arc<dyn DiagnosticsSink>>,
+    /// Keep the partially written output of `join_files_with_options` when merging fails, e.g. for debugging.
+    /// It's deleted by default
+    pub keep_incomplete_output: bool,
+    /// Size of the blocks copied from the mdat of the source files, 1 MiB by default
+    pub copy_block_size: Option<usize>,
+    /// Number of blocks in flight between the reader thread and the writer of the mdat copy, 2 (double-buffered) by default.
+    /// Overlapping the reads and writes helps on spinning disks and network mounts. 1 copies synchronously.
+    /// The reader threads are only used by `join_files_with_options`, the stream functions fail with `InvalidInput` for more than 1
+    pub copy_buffers: Option<usize>,
+    /// Number of threads reading the mdat data of different input files at the same time, each `copy_buffers` blocks ahead of the writer.
+    /// Helps to saturate fast storage like NVMe drives. 1 (a single reader thread) by default.
+    /// Only supported by `join_files_with_options`, like `copy_buffers`
+    pub copy_threads: Option<usize>,
+    /// Copy the mdat data with the read/write loop in `join_files_with_options`, instead of `copy_file_range` on Linux.
+    /// The kernel copy is used when the inputs and the single output are plain files and neither `stall_timeout` nor `copy_threads` is set,
+    /// and falls back to reading and writing on its own
+    pub disable_kernel_copy: bool,
+    /// Don't reserve the disk space of the output before writing it in `join_files_with_options`. The space is reserved on Linux,
+    /// so a full disk fails the merge right away and the output is less fragmented
+    pub disable_preallocation: bool,
+    /// Don't compare the free space at the output paths with the estimated size of the merged file in `join_files_with_options`,
+    /// e.g. on a filesystem which reports the free space wrongly. The merge fails with `Error::InsufficientSpace` by default
+    pub disable_space_check: bool,
+    /// Fail the merge when no data is read from the inputs or written to the output for this long, e.g. on a network mount
+    /// which stopped responding. The error names the stalled file and offset. A blocked read can't be interrupted,
+    /// so the merge returns when it completes, but the error is sent to the diagnostics sink right away.
+    /// Only checked by `join_files_with_options`, which reads the inputs on their own threads. The stream functions fail with `InvalidInput`
+    pub stall_timeout: Option<Duration>,
+    /// Compute the final layout before writing, so the output is written front to back without patching
+    /// the chunk offsets and the mdat size afterwards
+    pub precompute_layout: bool,
+    /// Container of the output, MP4 by default
+    pub output_format: OutputFormat,
+    /// Folders searched for the media referenced by inputs which aren't self-contained, e.g. QuickTime reference movies.
+    /// The path of a file URL and the folder of the input are tried first. Only `join_files_with_options` resolves external media
+    pub external_media_paths: Vec<PathBuf>,
+    /// GPX log converted to a CAMM or GPMF track of the output, aligned to the merged timeline with the start time of each file
+    pub gpx_track: Option<GpxTrack>,
+    /// MP4/M4A file whose first audio track replaces the audio tracks of the output, e.g. a soundtrack mixed separately.
+    /// It starts with the merged movie and is cut at its end. Its samples are held in memory until they're written after the merged samples
+    pub replacement_audio: Option<PathBuf>,
+    /// Decode and compare the SPS, PPS and VPS of the H.264 and HEVC tracks of every file with the first file, and report every differing field.
+    /// Harmless differences (e.g. VUI timing or a lower level) are warnings. Incompatible sample descriptions fail the merge also without it
+    pub strict_parameter_sets: bool,
+    /// Title of the output, written to moov/udta: a ©nam atom for QuickTime movies, an iTunes-style ilst item otherwise
+    pub title: Option<String>,
+    /// Comment of the output (©cmt), written like the title
+    pub comment: Option<String>,
+    /// Artist of the output (©ART), written like the title
+    pub artist: Option<String>,
+    /// Custom key/values of the output: mdta keys in moov/meta for QuickTime movies, `----` items of the udta ilst otherwise.
+    /// Values of the first file with the same keys are replaced
+    pub metadata: Vec<(String, String)>,
+    /// Input whose moov is used as the template of the output, e.g. a later chapter which has a track or a codec configuration
+    /// missing from the first file. The samples are still merged in the order of the files
+    pub template: TemplateSelection,
+    /// Add a timed metadata track (`mett`, application/json) with one sample per input, presented from its start on the
+    /// merged timeline, with its index, name and wall-clock start time. Editors and players can use it to find the original splits
+    pub chapter_markers: bool,
+    /// Names of the inputs written to the chapter markers, "File N" when not set. `join_files_with_options` uses the file names
+    pub file_names: Vec<String>,
+    /// Additional paths written by `join_files_with_options` with the same content as the output, in the same pass.
+    /// The inputs are read only once, see `TeeWriter` to write to other sinks
+    pub tee_outputs: Vec<PathBuf>,
+    /// Stops the merge when cancelled from another thread
+    pub cancellation: Option<CancellationToken>,
+    /// Tracks left out of the output, e.g. the audio. The remaining tracks are renumbered from 1
+    pub drop_tracks: Vec<TrackFilter>,
+    /// Rebuild an approximate moov for the last input when it has none, e.g. the last chapter of a recording cut off when the camera
+    /// lost power, from the moov of the previous input and the video frames found in its mdat. Only `join_files_with_options` repairs the input
+    pub repair_truncated_last: bool,
+}
+
+impl MergeOptions {
+    pub fn new() -> Self { Self::default() }
+
+    /// Set the pauses between consecutive files (one less than the number of files)
+    pub fn explicit_gaps(mut self, gaps: Vec<Duration>) -> Self {
+        self.explicit_gaps = Some(gaps);
+        self
+    }
+
+    /// Set the duration of each file on the merged timeline (one per file)
+    pub fn file_durations(mut self, durations: Vec<Duration>) -> Self {
+        self.file_durations = Some(durations);
+        self
+    }
+
+    /// Set a custom gap model, e.g. a closure `|prev: &FileInfo, next: &FileInfo| GapDecision::Default`
+    pub fn gap_model<G: GapModel + 'static>(mut self, model: G) -> Self {
+        self.gap_model = Some(Arc::new(model));
+        self
+    }
+
+    /// Set an edit list editor, e.g. a closure `|track: &EditListTrack, entries: &mut Vec<EditListEntry>| entries.retain(|x| x.media_time >= 0)`
+    pub fn edit_list_editor<E: EditListEditor + 'static>(mut self, editor: E) -> Self {
+        self.edit_list_editor = Some(Arc::new(editor));
+        self
+    }
+
+    /// Skip the overlapping part at the start of files which begin before the previous file ended
+    pub fn trim_overlaps(mut self, trim: bool) -> Self {
+        self.trim_overlaps = trim;
+        self
+    }
+
+    /// Shortest pause between files which is kept as a gap, shorter ones are treated as continuous recording
+    pub fn gap_threshold(mut self, threshold: Duration) -> Self {
+        self.gap_threshold = Some(threshold);
+        self
+    }
+
+    /// Concatenate the files without any gap or edit list entries, even when the timestamps show pauses
+    pub fn disable_gaps(mut self, disable: bool) -> Self {
+        self.disable_gaps = disable;
+        self
+    }
+
+    /// Re-derive broken chunk offsets by walking the mdat with the known sample sizes
+    pub fn repair_chunk_offsets(mut self, repair: bool) -> Self {
+        self.repair_chunk_offsets = repair;
+        self
+    }
+
+    /// Fill the gaps between files in the telemetry tracks with interpolated samples
+    pub fn interpolate_telemetry_gaps(mut self, interpolate: bool) -> Self {
+        self.interpolate_telemetry_gaps = interpolate;
+        self
+    }
+
+    /// Set the timescale of the output movie
+    pub fn movie_timescale(mut self, timescale: u32) -> Self {
+        self.movie_timescale = Some(timescale);
+        self
+    }
+
+    /// Set the media timescale of the output track with the given track ID
+    pub fn track_timescale(mut self, track_id: u32, timescale: u32) -> Self {
+        self.track_timescales.insert(track_id, timescale);
+        self
+    }
+
+    /// Fail the merge when the timescale conversions shift the timing of a track by more than `error`
+    pub fn max_rounding_error(mut self, error: Duration) -> Self {
+        self.max_rounding_error = Some(error);
+        self
+    }
+
+    /// Split the output into multiple files at keyframes, each smaller than `size` bytes
+    pub fn max_output_size(mut self, size: u64) -> Self {
+        self.max_output_size = Some(size);
+        self
+    }
+
+    /// Set the creation time of the output, e.g. to the session start derived from telemetry
+    pub fn creation_time(mut self, time: SystemTime) -> Self {
+        self.creation_time = Some(time);
+        self
+    }
+
+    /// Set the modification time of the output
+    pub fn modification_time(mut self, time: SystemTime) -> Self {
+        self.modification_time = Some(time);
+        self
+    }
+
+    /// Set the number of groups merged at the same time by `merge_groups`
+    pub fn max_concurrent_merges(mut self, count: usize) -> Self {
+        self.max_concurrent_merges = Some(count);
+        self
+    }
+
+    /// Write moov before mdat in the output
+    pub fn faststart(mut self, faststart: bool) -> Self {
+        self.faststart = faststart;
+        self
+    }
+
+    /// Remove free and skip boxes when passing through a single file
+    pub fn strip_free_boxes(mut self, strip: bool) -> Self {
+        self.strip_free_boxes = strip;
+        self
+    }
+
+    /// Convert stco to co64 when passing through a single file
+    pub fn force_co64(mut self, force: bool) -> Self {
+        self.force_co64 = force;
+        self
+    }
+
+    /// Keep 32-bit stco chunk offsets when the merged file is small enough, for players which don't support co64
+    pub fn keep_stco(mut self, keep: bool) -> Self {
+        self.keep_stco = keep;
+        self
+    }
+
+    /// Write a segment index (sidx) before the mdat of the output
+    pub fn sidx(mut self, sidx: bool) -> Self {
+        self.sidx = sidx;
+        self
+    }
+
+    /// Set a listener for the phases of the merge, e.g. a closure `|event: &ProgressEvent| println!("{:?} {:?}", event.phase, event.file_index)`
+    pub fn progress_events<L: ProgressEventListener + 'static>(mut self, listener: L) -> Self {
+        self.progress_events = Some(Arc::new(listener));
+        self
+    }
+
+    /// Set a listener for detailed progress, e.g. a closure `|info: &ProgressInfo| println!("{:?}", info.eta)`
+    pub fn progress_listener<L: ProgressListener + 'static>(mut self, listener: L) -> Self {
+        self.progress_listener = Some(Arc::new(listener));
+        self
+    }
+
+    /// Set a sink for diagnostic messages, e.g. a closure `|d: &Diagnostic| eprintln!("{:?} {}", d.level, d.message)`
+    pub fn diagnostics<S: DiagnosticsSink + 'static>(mut self, sink: S) -> Self {
+        self.diagnostics = Some(Arc::new(sink));
+        self
+    }
+
+    /// Keep the partially written output when merging fails
+    pub fn keep_incomplete_output(mut self, keep: bool) -> Self {
+        self.keep_incomplete_output = keep;
+        self
+    }
+
+    /// Set the size of the blocks copied from the mdat of the source files
+    pub fn copy_block_size(mut self, size: usize) -> Self {
+        self.copy_block_size = Some(size);
+        self
+    }
+
+    /// Set the number of blocks in flight between the reader thread and the writer of the mdat copy
+    pub fn copy_buffers(mut self, count: usize) -> Self {
+        self.copy_buffers = Some(count);
+        self
+    }
+
+    /// Set the number of threads reading the mdat data of different input files in parallel
+    pub fn copy_threads(mut self, threads: usize) -> Self {
+        self.copy_threads = Some(threads);
+        self
+    }
+
+    /// Always copy the mdat data through memory, without `copy_file_range`
+    pub fn disable_kernel_copy(mut self, disable: bool) -> Self {
+        self.disable_kernel_copy = disable;
+        self
+    }
+
+    /// Write the output without reserving its disk space first
+    pub fn disable_preallocation(mut self, disable: bool) -> Self {
+        self.disable_preallocation = disable;
+        self
+    }
+
+    /// Write the output without checking the free disk space first
+    pub fn disable_space_check(mut self, disable: bool) -> Self {
+        self.disable_space_check = disable;
+        self
+    }
+
+    /// Fail the merge when the mdat copy makes no progress for `timeout`
+    pub fn stall_timeout(mut self, timeout: Duration) -> Self {
+        self.stall_timeout = Some(timeout);
+        self
+    }
+
+    /// Compute the final layout before writing and write the output in a single pass
+    pub fn precompute_layout(mut self, precompute: bool) -> Self {
+        self.precompute_layout = precompute;
+        self
+    }
+
+    /// Set the container of the output
+    pub fn output_format(mut self, format: OutputFormat) -> Self {
+        self.output_format = format;
+        self
+    }
+
+    /// Add a folder searched for the media referenced by inputs which aren't self-contained
+    pub fn external_media_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.external_media_paths.push(path.into());
+        self
+    }
+
+    /// Add a GPX log as a telemetry track of the output
+    pub fn gpx_track(mut self, track: GpxTrack) -> Self {
+        self.gpx_track = Some(track);
+        self
+    }
+
+    /// Replace the audio tracks of the output with the first audio track of an MP4/M4A file
+    pub fn replace_audio<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.replacement_audio = Some(path.into());
+        self
+    }
+
+    /// Report the differences of the parameter sets of each file with the first file field by field
+    pub fn strict_parameter_sets(mut self, strict: bool) -> Self {
+        self.strict_parameter_sets = strict;
+        self
+    }
+
+    /// Set the title of the output, e.g. "Trip day 3 — merged from 5 chapters"
+    pub fn title<S: Into<String>>(mut self, title: S) -> Self {
+        self.title = Some(title.into());
+        self
+    }
+
+    /// Set the comment of the output
+    pub fn comment<S: Into<String>>(mut self, comment: S) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    /// Set the artist of the output
+    pub fn artist<S: Into<String>>(mut self, artist: S) -> Self {
+        self.artist = Some(artist.into());
+        self
+    }
+
+    /// Add a custom key/value to the metadata of the output
+    pub fn metadata<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.metadata.push((key.into(), value.into()));
+        self
+    }
+
+    /// Choose the input whose moov is used as the template of the output
+    pub fn template(mut self, template: TemplateSelection) -> Self {
+        self.template = template;
+        self
+    }
+
+    /// Add a timed metadata track marking the start of each input
+    pub fn chapter_markers(mut self, markers: bool) -> Self {
+        self.chapter_markers = markers;
+        self
+    }
+
+    /// Token to stop the merge from another thread, the merge fails with `Error::Cancelled`
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Leave the tracks matching `filter` out of the output
+    pub fn drop_track(mut self, filter: TrackFilter) -> Self {
+        self.drop_tracks.push(filter);
+        self
+    }
+
+    /// Rebuild the moov of the last input when it's missing, using the previous input as a template
+    pub fn repair_truncated_last(mut self, repair: bool) -> Self {
+        self.repair_truncated_last = repair;
+        self
+    }
+
+    /// Write a copy of the output to another path in the same pass
+    pub fn tee_output<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.tee_outputs.push(path.into());
+        self
+    }
+
+    /// Whether a single input can be copied instead of running the full merge
+    pub(crate) fn allows_passthrough(&self, num_files: usize) -> bool {
+        num_files == 1 && self.file_durations.is_none() && self.max_output_size.is_none() && self.creation_time.is_none() && self.modification_time.is_none()
+            && self.movie_timescale.is_none() && self.track_timescales.is_empty() && self.output_format == OutputFormat::Mp4 && self.gpx_track.is_none() && self.replacement_audio.is_none()
+            && self.title.is_none() && self.comment.is_none() && self.artist.is_none() && self.metadata.is_empty() && !self.chapter_markers && self.drop_tracks.is_empty() && !self.sidx
+            && self.edit_list_editor.is_none()
+    }
+
+    /// Fail for the options of the reader threads, which only `join_files_with_options` uses. The stream functions read
+    /// the inputs on the calling thread, as they can't be sent to other threads
+    pub(crate) fn validate_stream(&self) -> std::io::Result<()> {
+        if self.copy_buffers.is_some_and(|x| x > 1) || self.copy_threads.is_some_and(|x| x > 1) || self.stall_timeout.is_some() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "copy_buffers, copy_threads and stall_timeout are only supported by join_files_with_options"));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn validate(&self, num_files: usize) -> std::io::Result<()> {
+        if let Some(gaps) = &self.explicit_gaps {
+            if gaps.len() != num_files.saturating_sub(1) {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Expected {} gaps for {num_files} files, got {}", num_files.saturating_sub(1), gaps.len())));
+            }
+        }
+        if self.disable_gaps && (self.explicit_gaps.is_some() || self.gap_model.is_some()) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "disable_gaps can't be combined with explicit_gaps or a gap_model"));
+        }
+        if let Some(durations) = &self.file_durations {
+            if durations.len() != num_files {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Expected {num_files} file durations, got {}", durations.len())));
+            }
+        }
+        if let TemplateSelection::File(index) = self.template {
+            if index >= num_files {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Template file {index} is not one of the {num_files} inputs")));
+            }
+        }
+        if self.stall_timeout == Some(Duration::ZERO) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "stall_timeout must be greater than 0"));
+        }
+        if self.movie_timescale == Some(0) || self.track_timescales.values().any(|x| *x == 0) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Timescales must be greater than 0"));
+        }
+        if self.max_output_size.is_some() && !self.tee_outputs.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "tee_outputs are not supported with max_output_size"));
+        }
+        if self.max_output_size.is_some() && self.output_format != OutputFormat::Mp4 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "max_output_size is only supported for MP4 output"));
+        }
+        Ok(())
+    }
+}
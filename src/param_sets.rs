@@ -0,0 +1,405 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::Result;
+use std::path::Path;
+use crate::stsd::SampleEntry;
+
+/// A difference between the parameter sets (SPS, PPS, VPS) of the first file and another file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParameterSetDiff {
+    pub file: usize,
+    pub track: usize,
+    /// Parameter set, e.g. "SPS 0" (the first SPS of the avcC/hvcC) or "avcC" for the configuration record itself
+    pub parameter_set: String,
+    /// Decoded field, e.g. "resolution" or "VUI timing". "undecoded fields" when only fields which aren't decoded differ
+    pub field: String,
+    pub first: String,
+    pub other: String,
+    /// The samples of the file can't be decoded with the parameter sets of the first file, which are used for the whole merged track.
+    /// Differences of fields which aren't decoded are treated as fatal
+    pub fatal: bool,
+}
+
+impl std::fmt::Display for ParameterSetDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "File {}, track {}, {}: {} differs ({} vs {}){}", self.file, self.track, self.parameter_set, self.field, self.first, self.other, if self.fatal { "" } else { ", harmless" })
+    }
+}
+
+/// How a difference of a field affects decoding
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Rule {
+    Fatal,
+    /// Only metadata for the display or the decoder setup, e.g. the VUI timing
+    Benign,
+    /// Fatal when the other file needs more than the first, e.g. a higher level
+    FatalIfHigher,
+}
+
+/// Decoded field: name, value, numeric value for `Rule::FatalIfHigher` and the rule
+type Field = (&'static str, String, u64, Rule);
+
+/// Reads the RBSP of a NAL unit, without the emulation prevention bytes
+struct BitReader {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl BitReader {
+    fn new(nal_payload: &[u8]) -> Self {
+        let mut data = Vec::with_capacity(nal_payload.len());
+        let mut zeros = 0;
+        for &x in nal_payload {
+            if x == 3 && zeros >= 2 { zeros = 0; continue; }
+            zeros = if x == 0 { zeros + 1 } else { 0 };
+            data.push(x);
+        }
+        Self { data, pos: 0 }
+    }
+
+    fn bits(&mut self, n: usize) -> Option<u64> {
+        let mut value = 0;
+        for _ in 0..n {
+            let byte = *self.data.get(self.pos / 8)?;
+            value = (value << 1) | ((byte >> (7 - self.pos % 8)) & 1) as u64;
+            self.pos += 1;
+        }
+        Some(value)
+    }
+
+    fn flag(&mut self) -> Option<bool> { self.bits(1).map(|x| x == 1) }
+
+    fn ue(&mut self) -> Option<u64> {
+        let mut zeros = 0;
+        while !self.flag()? {
+            zeros += 1;
+            if zeros > 32 { return None; }
+        }
+        Some((1 << zeros) - 1 + self.bits(zeros)?)
+    }
+
+    fn se(&mut self) -> Option<i64> {
+        let x = self.ue()? as i64;
+        Some(if x % 2 == 1 { (x + 1) / 2 } else { -x / 2 })
+    }
+}
+
+/// Fields of an H.264 SPS, up to the VUI timing. Decoding stops at the first field which can't be read
+fn h264_sps_fields(nal: &[u8]) -> Vec<Field> {
+    let mut fields = Vec::new();
+    let _ = (|| -> Option<()> {
+        let mut r = BitReader::new(nal.get(1..)?);
+        let profile = r.bits(8)?;
+        fields.push(("profile", profile.to_string(), profile, Rule::Fatal));
+        let constraints = r.bits(8)?;
+        fields.push(("constraint flags", format!("{constraints:#04x}"), constraints, Rule::Benign));
+        let level = r.bits(8)?;
+        fields.push(("level", format!("{:.1}", level as f64 / 10.0), level, Rule::FatalIfHigher));
+        let id = r.ue()?;
+        fields.push(("id", id.to_string(), id, Rule::Fatal));
+        let mut chroma_format = 1;
+        if [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135].contains(&profile) {
+            chroma_format = r.ue()?;
+            if chroma_format == 3 { r.flag()?; }
+            fields.push(("chroma format", chroma_format.to_string(), chroma_format, Rule::Fatal));
+            let (luma, chroma) = (r.ue()? + 8, r.ue()? + 8);
+            fields.push(("bit depth", format!("{luma}/{chroma}"), 0, Rule::Fatal));
+            r.flag()?; // qpprime_y_zero_transform_bypass_flag
+            let scaling_matrix = r.flag()?;
+            if scaling_matrix {
+                for i in 0..if chroma_format == 3 { 12 } else { 8 } {
+                    if r.flag()? {
+                        let (mut last, mut next) = (8i64, 8i64);
+                        for _ in 0..if i < 6 { 16 } else { 64 } {
+                            if next != 0 { next = (last + r.se()? + 256) % 256; }
+                            if next != 0 { last = next; }
+                        }
+                    }
+                }
+            }
+            fields.push(("scaling matrix", scaling_matrix.to_string(), 0, Rule::Fatal));
+        }
+        let log2_max_frame_num = r.ue()? + 4;
+        fields.push(("log2_max_frame_num", log2_max_frame_num.to_string(), 0, Rule::Fatal));
+        let poc_type = r.ue()?;
+        fields.push(("picture order count type", poc_type.to_string(), 0, Rule::Fatal));
+        if poc_type == 0 {
+            let lsb = r.ue()? + 4;
+            fields.push(("log2_max_pic_order_cnt_lsb", lsb.to_string(), 0, Rule::Fatal));
+        } else if poc_type == 1 {
+            r.flag()?;
+            r.se()?;
+            r.se()?;
+            for _ in 0..r.ue()? { r.se()?; }
+        }
+        let ref_frames = r.ue()?;
+        fields.push(("reference frames", ref_frames.to_string(), ref_frames, Rule::FatalIfHigher));
+        r.flag()?; // gaps_in_frame_num_value_allowed_flag
+        let (width_mbs, height_units) = (r.ue()? + 1, r.ue()? + 1);
+        let frame_mbs_only = r.flag()?;
+        if !frame_mbs_only { r.flag()?; }
+        r.flag()?; // direct_8x8_inference_flag
+        let crop = if r.flag()? { [r.ue()?, r.ue()?, r.ue()?, r.ue()?] } else { [0; 4] };
+        let (crop_x, crop_y) = match chroma_format { 0 | 3 => (1, 1), 1 => (2, 2), _ => (2, 1) };
+        let crop_y = crop_y * (2 - frame_mbs_only as u64);
+        let width = (width_mbs * 16).saturating_sub(crop_x * (crop[0] + crop[1]));
+        let height = ((2 - frame_mbs_only as u64) * height_units * 16).saturating_sub(crop_y * (crop[2] + crop[3]));
+        fields.push(("resolution", format!("{width}x{height}"), 0, Rule::Fatal));
+        fields.push(("interlaced", (!frame_mbs_only).to_string(), 0, Rule::Fatal));
+        if !r.flag()? { return Some(()); } // vui_parameters_present_flag
+        h264_vui_fields(&mut r, &mut fields)
+    })();
+    fields
+}
+
+/// The display and timing fields of the VUI of an H.264 SPS
+fn h264_vui_fields(r: &mut BitReader, fields: &mut Vec<Field>) -> Option<()> {
+    if r.flag()? {
+        let idc = r.bits(8)?;
+        let aspect = if idc == 255 { format!("{}:{}", r.bits(16)?, r.bits(16)?) } else { format!("idc {idc}") };
+        fields.push(("VUI sample aspect ratio", aspect, 0, Rule::Benign));
+    }
+    if r.flag()? { r.flag()?; } // overscan
+    if r.flag()? {
+        r.bits(3)?; // video_format
+        let full_range = r.flag()?;
+        fields.push(("VUI full range", full_range.to_string(), 0, Rule::Benign));
+        if r.flag()? {
+            let (primaries, transfer, matrix) = (r.bits(8)?, r.bits(8)?, r.bits(8)?);
+            fields.push(("VUI colour description", format!("{primaries}/{transfer}/{matrix}"), 0, Rule::Benign));
+        }
+    }
+    if r.flag()? { r.ue()?; r.ue()?; } // chroma_loc_info
+    if r.flag()? {
+        let (num_units_in_tick, time_scale) = (r.bits(32)?, r.bits(32)?);
+        let fixed = r.flag()?;
+        fields.push(("VUI timing", format!("{num_units_in_tick}/{time_scale}{}", if fixed { " fixed" } else { "" }), 0, Rule::Benign));
+    }
+    Some(())
+}
+
+/// Fields of an HEVC SPS, up to the picture order count. Decoding stops at the first field which can't be read
+fn hevc_sps_fields(nal: &[u8]) -> Vec<Field> {
+    let mut fields = Vec::new();
+    let _ = (|| -> Option<()> {
+        let mut r = BitReader::new(nal.get(2..)?);
+        r.bits(4)?; // sps_video_parameter_set_id
+        let sub_layers = r.bits(3)? as usize;
+        r.flag()?; // sps_temporal_id_nesting_flag
+        r.bits(2)?; // general_profile_space
+        let tier = r.bits(1)?;
+        let profile = r.bits(5)?;
+        fields.push(("profile", profile.to_string(), profile, Rule::Fatal));
+        fields.push(("tier", if tier == 1 { "high" } else { "main" }.to_string(), tier, Rule::FatalIfHigher));
+        r.bits(32)?; // general_profile_compatibility_flags
+        r.bits(48)?; // Source flags and constraints
+        let level = r.bits(8)?;
+        fields.push(("level", format!("{:.1}", level as f64 / 30.0), level, Rule::FatalIfHigher));
+        let sub_layer_flags = (0..sub_layers).map(|_| Some((r.flag()?, r.flag()?))).collect::<Option<Vec<_>>>()?;
+        if sub_layers > 0 {
+            r.bits(2 * (8 - sub_layers))?;
+        }
+        for (profile_present, level_present) in sub_layer_flags {
+            if profile_present { r.bits(88)?; }
+            if level_present { r.bits(8)?; }
+        }
+        let id = r.ue()?;
+        fields.push(("id", id.to_string(), id, Rule::Fatal));
+        let chroma_format = r.ue()?;
+        if chroma_format == 3 { r.flag()?; }
+        fields.push(("chroma format", chroma_format.to_string(), chroma_format, Rule::Fatal));
+        let (width, height) = (r.ue()?, r.ue()?);
+        let crop = if r.flag()? { [r.ue()?, r.ue()?, r.ue()?, r.ue()?] } else { [0; 4] };
+        let (crop_x, crop_y) = match chroma_format { 1 => (2, 2), 2 => (2, 1), _ => (1, 1) };
+        let width = width.saturating_sub(crop_x * (crop[0] + crop[1]));
+        let height = height.saturating_sub(crop_y * (crop[2] + crop[3]));
+        fields.push(("resolution", format!("{width}x{height}"), 0, Rule::Fatal));
+        let (luma, chroma) = (r.ue()? + 8, r.ue()? + 8);
+        fields.push(("bit depth", format!("{luma}/{chroma}"), 0, Rule::Fatal));
+        let lsb = r.ue()? + 4;
+        fields.push(("log2_max_pic_order_cnt_lsb", lsb.to_string(), 0, Rule::Fatal));
+        Some(())
+    })();
+    fields
+}
+
+/// NAL units of one parameter set type, e.g. ("SPS", [...])
+type NalList = (&'static str, Vec<Vec<u8>>);
+
+/// Parameter sets of an avcC or hvcC: the NAL length size and the NAL unit lists
+fn config_parameter_sets(codec: &str, config: &[u8]) -> Option<(u8, Vec<NalList>)> {
+    let mut lists = Vec::new();
+    let read_nals = |pos: &mut usize, count: usize| -> Option<Vec<Vec<u8>>> {
+        (0..count).map(|_| {
+            let len = u16::from_be_bytes(config.get(*pos..*pos + 2)?.try_into().ok()?) as usize;
+            let nal = config.get(*pos + 2..*pos + 2 + len)?.to_vec();
+            *pos += 2 + len;
+            Some(nal)
+        }).collect()
+    };
+    match codec {
+        "avc1" | "avc3" => {
+            let length_size = (config.get(4)? & 3) + 1;
+            let mut pos = 6;
+            let sps = read_nals(&mut pos, (config.get(5)? & 0x1f) as usize)?;
+            let count = *config.get(pos)? as usize;
+            pos += 1;
+            let pps = read_nals(&mut pos, count)?;
+            lists.push(("SPS", sps));
+            lists.push(("PPS", pps));
+            Some((length_size, lists))
+        },
+        "hvc1" | "hev1" => {
+            let length_size = (config.get(21)? & 3) + 1;
+            let mut pos = 23;
+            for _ in 0..*config.get(22)? {
+                let typ = config.get(pos)? & 0x3f;
+                let count = u16::from_be_bytes(config.get(pos + 1..pos + 3)?.try_into().ok()?) as usize;
+                pos += 3;
+                let nals = read_nals(&mut pos, count)?;
+                let name = match typ { 32 => "VPS", 33 => "SPS", 34 => "PPS", 39 | 40 => "SEI", _ => "NAL unit" };
+                match lists.iter_mut().find(|(x, _)| *x == name) {
+                    Some((_, list)) => list.extend(nals),
+                    None => lists.push((name, nals))
+                }
+            }
+            Some((length_size, lists))
+        },
+        _ => None
+    }
+}
+
+/// Compare the parameter sets of two H.264 or HEVC sample entries field by field. `file` and `track` are copied to the differences
+pub(crate) fn diff_entries(first: &SampleEntry, other: &SampleEntry, file: usize, track: usize) -> Vec<ParameterSetDiff> {
+    let config_type = if first.codec.starts_with("av") { "avcC" } else { "hvcC" };
+    let mut diffs = Vec::new();
+    let mut diff = |parameter_set: String, field: &str, first: String, other: String, fatal: bool| {
+        diffs.push(ParameterSetDiff { file, track, parameter_set, field: field.into(), first, other, fatal });
+    };
+    if first.codec != other.codec {
+        diff("sample entry".into(), "codec", first.codec.clone(), other.codec.clone(), true);
+        return diffs;
+    }
+    let (Some(a), Some(b)) = (first.child(config_type), other.child(config_type)) else { return diffs; };
+    if a == b { return diffs; }
+    let (Some((a_length, a_lists)), Some((b_length, b_lists))) = (config_parameter_sets(&first.codec, a), config_parameter_sets(&other.codec, b)) else {
+        diff(config_type.into(), "undecoded fields", format!("{} bytes", a.len()), format!("{} bytes", b.len()), true);
+        return diffs;
+    };
+    // Samples with in-band parameter sets don't depend on the ones of the sample entry
+    let in_band = first.codec == "avc3" || first.codec == "hev1";
+    if a_length != b_length {
+        diff(config_type.into(), "NAL length size", a_length.to_string(), b_length.to_string(), true);
+    }
+    for (name, a_nals) in &a_lists {
+        let b_nals = b_lists.iter().find(|(x, _)| x == name).map(|x| &x.1[..]).unwrap_or_default();
+        if a_nals.len() != b_nals.len() {
+            diff(config_type.into(), &format!("{name} count"), a_nals.len().to_string(), b_nals.len().to_string(), !in_band);
+        }
+        for (i, (a, b)) in a_nals.iter().zip(b_nals).enumerate().filter(|(_, (a, b))| a != b) {
+            let parameter_set = format!("{name} {i}");
+            let decode = match (*name, config_type) {
+                ("SPS", "avcC") => h264_sps_fields,
+                ("SPS", _) => hevc_sps_fields,
+                _ => |_: &[u8]| Vec::new()
+            };
+            let (a_fields, b_fields) = (decode(a), decode(b));
+            let mut decoded_diff = false;
+            for ((field, a_value, a_number, rule), (_, b_value, b_number, _)) in a_fields.iter().zip(&b_fields).filter(|(a, b)| a.0 == b.0 && a.1 != b.1) {
+                let fatal = match rule {
+                    Rule::Fatal => true,
+                    Rule::Benign => false,
+                    Rule::FatalIfHigher => b_number > a_number,
+                };
+                diff(parameter_set.clone(), field, a_value.clone(), b_value.clone(), fatal && !in_band);
+                decoded_diff = true;
+            }
+            if !decoded_diff {
+                diff(parameter_set, "undecoded fields", format!("{} bytes", a.len()), format!("{} bytes", b.len()), !in_band);
+            }
+        }
+    }
+    diffs
+}
+
+/// Compare the parameter sets of the H.264 and HEVC tracks of every file with the first file
+pub(crate) fn diff_tracks(first: &[Vec<SampleEntry>], other: &[Vec<SampleEntry>], file: usize) -> Vec<ParameterSetDiff> {
+    first.iter().zip(other).enumerate()
+        .flat_map(|(track, (a, b))| a.iter().zip(b).filter(|(a, _)| matches!(a.codec.as_str(), "avc1" | "avc3" | "hvc1" | "hev1")).flat_map(move |(a, b)| diff_entries(a, b, file, track)))
+        .collect()
+}
+
+/// Decode and compare the SPS, PPS and VPS of the H.264 and HEVC tracks of the files with the first file, e.g. to tell whether
+/// chapters recorded with different settings can be merged. The merge uses the parameter sets of the first file for the whole track
+pub fn compare_parameter_sets<P: AsRef<Path>>(files: &[P]) -> Result<Vec<ParameterSetDiff>> {
+    let mut entries = Vec::with_capacity(files.len());
+    for x in files {
+        entries.push(crate::stsd::read_sample_entries(&mut std::io::BufReader::with_capacity(16*1024, std::fs::File::open(x)?))?);
+    }
+    Ok(entries.iter().enumerate().skip(1).flat_map(|(i, x)| diff_tracks(&entries[0], x, i)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a baseline H.264 SPS with the given level, resolution in macroblocks and VUI timing, with emulation prevention bytes
+    fn baseline_sps(level: u64, mb_width: u64, mb_height: u64, time_scale: u64) -> Vec<u8> {
+        let mut bits = Vec::new();
+        let mut put = |value: u64, n: usize| bits.extend((0..n).rev().map(|i| (value >> i) & 1 == 1));
+        let ue = |put: &mut dyn FnMut(u64, usize), x: u64| {
+            let len = 64 - (x + 1).leading_zeros() as usize;
+            put(0, len - 1);
+            put(x + 1, len);
+        };
+        put(66, 8); put(0xc0, 8); put(level, 8);
+        ue(&mut put, 0); // id
+        ue(&mut put, 0); // log2_max_frame_num - 4
+        ue(&mut put, 2); // poc type
+        ue(&mut put, 1); // max_num_ref_frames
+        put(0, 1);
+        ue(&mut put, mb_width - 1);
+        ue(&mut put, mb_height - 1);
+        put(1, 1); put(1, 1); // frame_mbs_only, direct_8x8_inference
+        put(1, 1); // Cropping
+        for crop in [0, 0, 0, 4] { ue(&mut put, crop); }
+        put(1, 1); // VUI
+        put(0, 4); // Aspect ratio, overscan, video signal and chroma location
+        put(1, 1); put(1, 32); put(time_scale, 32); put(1, 1);
+        put(1, 1);
+        let mut data = vec![0x67];
+        let mut zeros = 0;
+        for byte in bits.chunks(8).map(|x| x.iter().enumerate().fold(0u8, |acc, (i, &b)| acc | ((b as u8) << (7 - i)))) {
+            if zeros >= 2 && byte <= 3 { data.push(3); zeros = 0; }
+            zeros = if byte == 0 { zeros + 1 } else { 0 };
+            data.push(byte);
+        }
+        data
+    }
+
+    fn entry(sps: &[u8]) -> SampleEntry {
+        let mut avcc = vec![1, 66, 0xc0, 30, 0xff, 0xe1];
+        avcc.extend((sps.len() as u16).to_be_bytes());
+        avcc.extend(sps);
+        avcc.extend([1, 0, 4, 0x68, 0xce, 0x38, 0x80]);
+        SampleEntry { codec: "avc1".into(), fields: vec![0; 78], boxes: vec![("avcC".into(), avcc)] }
+    }
+
+    #[test]
+    fn test_parameter_set_diffs() {
+        let fields = h264_sps_fields(&baseline_sps(40, 120, 68, 60));
+        let field = |name: &str| fields.iter().find(|x| x.0 == name).map(|x| x.1.clone());
+        assert_eq!(field("resolution").as_deref(), Some("1920x1080"));
+        assert_eq!(field("VUI timing").as_deref(), Some("1/60 fixed"));
+
+        let first = entry(&baseline_sps(40, 120, 68, 60));
+        let summary = |other: &SampleEntry| diff_entries(&first, other, 1, 0).into_iter().map(|x| (x.field, x.fatal)).collect::<Vec<_>>();
+        assert!(summary(&first).is_empty());
+        assert_eq!(summary(&entry(&baseline_sps(40, 120, 68, 50))), vec![("VUI timing".into(), false)]);
+        assert_eq!(summary(&entry(&baseline_sps(31, 120, 68, 60))), vec![("level".into(), false)]);
+        assert_eq!(summary(&entry(&baseline_sps(42, 120, 68, 60))), vec![("level".into(), true)]);
+        let diffs = diff_entries(&first, &entry(&baseline_sps(40, 80, 45, 60)), 1, 0);
+        assert_eq!(diffs.iter().map(|x| x.to_string()).collect::<Vec<_>>(), vec!["File 1, track 0, SPS 0: resolution differs (1920x1080 vs 1280x712)"]);
+    }
+}
@@ -0,0 +1,259 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Write, Seek, Result, SeekFrom };
+use crate::{ fourcc, read_box, typ_to_str, MergeOptions, diagnostics::diag };
+
+const CONTAINERS: &[&str] = &["moov", "trak", "edts", "mdia", "minf", "stbl", "dinf", "mvex", "udta"];
+
+/// In-memory box of the moov tree
+struct MemBox {
+    typ: u32,
+    data: Vec<u8>,                 // Payload of leaf boxes
+    children: Option<Vec<MemBox>>, // Children of container boxes
+}
+
+impl MemBox {
+    fn parse_list(data: &[u8]) -> Vec<MemBox> {
+        let mut ret = Vec::new();
+        let mut pos = 0;
+        while pos + 8 <= data.len() {
+            let size32 = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+            let typ = u32::from_be_bytes(data[pos + 4..pos + 8].try_into().unwrap());
+            let (size, header_size) = match size32 {
+                0 => (data.len() - pos, 8),
+                1 if pos + 16 <= data.len() => (u64::from_be_bytes(data[pos + 8..pos + 16].try_into().unwrap()) as usize, 16),
+                _ => (size32, 8)
+            };
+            if size < header_size || pos + size > data.len() { break; }
+            let payload = &data[pos + header_size..pos + size];
+            let children = CONTAINERS.contains(&typ_to_str(typ).as_str()).then(|| MemBox::parse_list(payload));
+            ret.push(MemBox { typ, data: if children.is_some() { Vec::new() } else { payload.to_vec() }, children });
+            pos += size;
+        }
+        ret
+    }
+
+    fn size(&self) -> u64 {
+        let payload = match &self.children {
+            Some(children) => children.iter().map(|x| x.size()).sum(),
+            None => self.data.len() as u64
+        };
+        if payload + 8 > u32::MAX as u64 { payload + 16 } else { payload + 8 }
+    }
+
+    fn write<W: Write>(&self, out: &mut W) -> Result<()> {
+        let size = self.size();
+        if size > u32::MAX as u64 {
+            out.write_all(&1u32.to_be_bytes())?;
+            out.write_all(&self.typ.to_be_bytes())?;
+            out.write_all(&size.to_be_bytes())?;
+        } else {
+            out.write_all(&(size as u32).to_be_bytes())?;
+            out.write_all(&self.typ.to_be_bytes())?;
+        }
+        match &self.children {
+            Some(children) => { for x in children { x.write(out)?; } },
+            None => out.write_all(&self.data)?
+        }
+        Ok(())
+    }
+
+    fn visit_mut(&mut self, f: &mut impl FnMut(&mut MemBox)) {
+        f(self);
+        if let Some(children) = &mut self.children {
+            for x in children { x.visit_mut(f); }
+        }
+    }
+
+    fn strip_free(&mut self) {
+        if let Some(children) = &mut self.children {
+            children.retain(|x| x.typ != fourcc("free") && x.typ != fourcc("skip"));
+            for x in children { x.strip_free(); }
+        }
+    }
+
+    /// Chunk offsets of a stco or co64 box
+    fn chunk_offsets(&self) -> Vec<u64> {
+        let count = self.data.get(4..8).map(|x| u32::from_be_bytes(x.try_into().unwrap())).unwrap_or(0) as usize;
+        let entry_size = if self.typ == fourcc("co64") { 8 } else { 4 };
+        self.data.get(8..).unwrap_or_default().chunks_exact(entry_size).take(count).map(|x| match entry_size {
+            8 => u64::from_be_bytes(x.try_into().unwrap()),
+            _ => u32::from_be_bytes(x.try_into().unwrap()) as u64
+        }).collect()
+    }
+
+    fn set_chunk_offsets(&mut self, offsets: &[u64], co64: bool) {
+        self.typ = if co64 { fourcc("co64") } else { fourcc("stco") };
+        self.data.truncate(4); // Version and flags
+        self.data.extend((offsets.len() as u32).to_be_bytes());
+        for x in offsets {
+            if co64 { self.data.extend(x.to_be_bytes()); } else { self.data.extend((*x as u32).to_be_bytes()); }
+        }
+    }
+}
+
+/// Whether the single-file path has to rewrite the file instead of copying it as is
+pub(crate) fn needs_rewrite(options: &MergeOptions) -> bool {
+    options.faststart || options.strip_free_boxes || options.force_co64
+}
+
+/// Copy a single file, optionally moving moov before mdat, removing free boxes and converting stco to co64.
+/// Returns the position of the first mdat payload in the output.
+pub(crate) fn copy_single<I: Read + Seek, O: Write + Seek>(input: &mut I, output: &mut O, options: &MergeOptions) -> Result<u64> {
+    let mut top_level = Vec::new(); // (typ, offset, size, header_size)
+    input.seek(SeekFrom::Start(0))?;
+    let file_size = input.seek(SeekFrom::End(0))?;
+    input.seek(SeekFrom::Start(0))?;
+    while let Ok((typ, offs, size, header_size)) = read_box(input) {
+        let size = if size == 0 { file_size - offs } else { size };
+        if typ == 0 || size < header_size as u64 { break; }
+        top_level.push((typ, offs, size, header_size));
+        input.seek(SeekFrom::Start(offs + size))?;
+    }
+    let first_mdat = top_level.iter().find(|x| x.0 == fourcc("mdat")).map(|x| x.1 + x.3 as u64);
+
+    if !needs_rewrite(options) {
+        input.seek(SeekFrom::Start(0))?;
+        std::io::copy(input, output)?;
+        return Ok(first_mdat.unwrap_or(0));
+    }
+
+    let Some(&(_, moov_offs, moov_size, moov_header)) = top_level.iter().find(|x| x.0 == fourcc("moov")) else {
+        return Err(crate::Error::TruncatedFile { path: "moov".into(), offset: 0, reason: "moov not found".into() }.into());
+    };
+    let mut moov_data = vec![0u8; (moov_size - moov_header as u64) as usize];
+    input.seek(SeekFrom::Start(moov_offs + moov_header as u64))?;
+    input.read_exact(&mut moov_data)?;
+    let mut moov = MemBox { typ: fourcc("moov"), data: Vec::new(), children: Some(MemBox::parse_list(&moov_data)) };
+    if options.strip_free_boxes {
+        moov.strip_free();
+    }
+
+    // Order of the top-level boxes in the output
+    let order = top_level.iter().filter(|x| x.0 != fourcc("moov") && !(options.strip_free_boxes && (x.0 == fourcc("free") || x.0 == fourcc("skip")))).copied().collect::<Vec<_>>();
+    let moov_index = if options.faststart {
+        order.iter().position(|x| x.0 == fourcc("mdat")).unwrap_or(order.len())
+    } else {
+        order.iter().position(|x| x.1 > moov_offs).unwrap_or(order.len())
+    };
+
+    // Original chunk offsets, converted to co64 when requested or when they don't fit in 32 bits after moving
+    let mut original_offsets = Vec::new();
+    moov.visit_mut(&mut |b| if b.typ == fourcc("stco") || b.typ == fourcc("co64") { original_offsets.push(b.chunk_offsets()); });
+    let mut co64 = options.force_co64;
+    loop {
+        let mut index = 0;
+        moov.visit_mut(&mut |b| if b.typ == fourcc("stco") || b.typ == fourcc("co64") {
+            b.set_chunk_offsets(&original_offsets[index], co64);
+            index += 1;
+        });
+        let moov_size = moov.size();
+
+        // New position of each top-level box
+        let mut position = 0;
+        let mut moved = Vec::new(); // (original offset, original size, new offset)
+        for (i, (_, offs, size, _)) in order.iter().enumerate() {
+            if i == moov_index { position += moov_size; }
+            moved.push((*offs, *size, position));
+            position += size;
+        }
+        let map = |x: u64| moved.iter().find(|(offs, size, _)| x >= *offs && x < offs + size).map(|(offs, _, new)| x - offs + new).unwrap_or(x);
+
+        let new_offsets = original_offsets.iter().map(|x| x.iter().map(|o| map(*o)).collect::<Vec<_>>()).collect::<Vec<_>>();
+        if !co64 && new_offsets.iter().flatten().any(|x| *x > u32::MAX as u64) {
+            diag!(Debug, "Chunk offsets don't fit in 32 bits, converting to co64");
+            co64 = true;
+            continue;
+        }
+        let mut index = 0;
+        moov.visit_mut(&mut |b| if b.typ == fourcc("stco") || b.typ == fourcc("co64") {
+            b.set_chunk_offsets(&new_offsets[index], co64);
+            index += 1;
+        });
+
+        let mut first_mdat = None;
+        for (i, (typ, offs, size, header_size)) in order.iter().enumerate() {
+            if i == moov_index { moov.write(output)?; }
+            if *typ == fourcc("mdat") && first_mdat.is_none() {
+                first_mdat = Some(output.stream_position()? + *header_size as u64);
+            }
+            input.seek(SeekFrom::Start(*offs))?;
+            std::io::copy(&mut input.take(*size), output)?;
+        }
+        if moov_index >= order.len() { moov.write(output)?; }
+
+        // Data after the last box, e.g. the Insta360 trailer
+        let boxes_end = top_level.last().map(|x| x.1 + x.2).unwrap_or(0);
+        if boxes_end < file_size {
+            input.seek(SeekFrom::Start(boxes_end))?;
+            std::io::copy(&mut input.take(file_size - boxes_end), output)?;
+        }
+
+        return Ok(first_mdat.unwrap_or(0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn mp4_box(typ: &str, payload: &[u8]) -> Vec<u8> {
+        let mut ret = (payload.len() as u32 + 8).to_be_bytes().to_vec();
+        ret.extend(typ.as_bytes());
+        ret.extend(payload);
+        ret
+    }
+
+    // ftyp, moov, free, mdat or ftyp, mdat, free, moov with a single chunk
+    fn mp4(moov_first: bool) -> Vec<u8> {
+        let moov_size = 8 * 5 + 20;
+        let mdat_payload = if moov_first { 16 + moov_size + 10 + 8 } else { 16 + 8 };
+        let mut stco = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        stco.extend((mdat_payload as u32).to_be_bytes());
+        let mut moov = mp4_box("stco", &stco);
+        for typ in ["stbl", "minf", "mdia", "trak", "moov"] {
+            moov = mp4_box(typ, &moov);
+        }
+        let ftyp = mp4_box("ftyp", b"isom\0\0\0\0");
+        let mdat = mp4_box("mdat", &[1, 2, 3, 4]);
+        let free = mp4_box("free", &[0, 0]);
+        if moov_first { [ftyp, moov, free, mdat].concat() } else { [ftyp, mdat, free, moov].concat() }
+    }
+
+    fn stco_value(data: &[u8]) -> u32 {
+        let pos = data.windows(4).position(|x| x == b"stco" || x == b"co64").unwrap();
+        if &data[pos..pos + 4] == b"co64" { u64::from_be_bytes(data[pos + 12..pos + 20].try_into().unwrap()) as u32 } else { u32::from_be_bytes(data[pos + 12..pos + 16].try_into().unwrap()) }
+    }
+
+    #[test]
+    fn test_faststart_and_strip() {
+        let input = mp4(false);
+        let mut output = Cursor::new(Vec::new());
+        let options = MergeOptions { faststart: true, strip_free_boxes: true, ..Default::default() };
+        let mdat = copy_single(&mut Cursor::new(&input), &mut output, &options).unwrap();
+        let output = output.into_inner();
+
+        assert_eq!(&output[4..8], b"ftyp");
+        assert_eq!(&output[20..24], b"moov");
+        assert!(!output.windows(4).any(|x| x == b"free"));
+        assert_eq!(stco_value(&output) as u64, mdat);
+        assert_eq!(&output[mdat as usize..mdat as usize + 4], &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_passthrough_and_co64() {
+        let input = mp4(true);
+        let mut output = Cursor::new(Vec::new());
+        copy_single(&mut Cursor::new(&input), &mut output, &MergeOptions::default()).unwrap();
+        assert_eq!(output.into_inner(), input);
+
+        let mut output = Cursor::new(Vec::new());
+        let mdat = copy_single(&mut Cursor::new(&input), &mut output, &MergeOptions { force_co64: true, ..Default::default() }).unwrap();
+        let output = output.into_inner();
+        assert!(output.windows(4).any(|x| x == b"co64"));
+        assert_eq!(stco_value(&output) as u64, mdat);
+        assert_eq!(&output[mdat as usize..mdat as usize + 4], &[1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,72 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::Result;
+use std::path::{ Path, PathBuf };
+use crate::{ MergeOptions, MergeReport, diagnostics::diag };
+
+/// Read the files listed in a playlist: plain text with one path per line, or M3U/M3U8.
+/// Empty lines and lines starting with `#` (comments and M3U directives) are skipped, `file://` URLs are accepted
+/// and relative paths are resolved against the directory of the playlist.
+pub fn read_playlist<P: AsRef<Path>>(playlist: P) -> Result<Vec<PathBuf>> {
+    let playlist = playlist.as_ref();
+    let data = std::fs::read(playlist)?;
+    let base = playlist.parent().unwrap_or(Path::new(""));
+    let files = parse_playlist(&String::from_utf8_lossy(&data), base);
+    if files.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Playlist {} doesn't list any files", playlist.display())));
+    }
+    diag!(Debug, "Playlist {} lists {} files", playlist.display(), files.len());
+    Ok(files)
+}
+
+fn parse_playlist(text: &str, base: &Path) -> Vec<PathBuf> {
+    text.trim_start_matches('\u{feff}').lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .map(|x| match x.strip_prefix("file://") {
+            // file:///C:/x.mp4 on Windows, file:///home/x.mp4 elsewhere
+            Some(path) => PathBuf::from(percent_decode(if cfg!(windows) { path.trim_start_matches('/') } else { path })),
+            None => PathBuf::from(x)
+        })
+        .map(|x| if x.is_relative() { base.join(x) } else { x })
+        .collect()
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|x| std::str::from_utf8(x).ok()).and_then(|x| u8::from_str_radix(x, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => { ret.push(byte); i += 3; }
+            (byte, _) => { ret.push(byte); i += 1; }
+        }
+    }
+    String::from_utf8_lossy(&ret).into_owned()
+}
+
+/// Merge the files listed in a playlist, in order
+pub fn join_from_playlist<P: AsRef<Path>, Q: AsRef<Path>>(playlist: P, output_file: Q) -> Result<MergeReport> {
+    join_from_playlist_with_options(playlist, output_file, &MergeOptions::default(), |_| {})
+}
+
+pub fn join_from_playlist_with_options<P: AsRef<Path>, Q: AsRef<Path>, F: Fn(f64)>(playlist: P, output_file: Q, options: &MergeOptions, progress_cb: F) -> Result<MergeReport> {
+    let files = read_playlist(playlist)?;
+    crate::join_files_with_options(&files, &output_file.as_ref().to_path_buf(), options, progress_cb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_playlist() {
+        let m3u = "\u{feff}#EXTM3U\n#EXTINF:60,Chapter 1\nGX010001.MP4\r\n\n#EXTINF:60,Chapter 2\n  sub/GX020001.MP4  \nfile:///videos/GX030001%20copy.MP4\n";
+        let files = parse_playlist(m3u, Path::new("/cards"));
+        assert_eq!(files[..2], [PathBuf::from("/cards/GX010001.MP4"), PathBuf::from("/cards/sub/GX020001.MP4")]);
+        assert!(files[2].to_string_lossy().ends_with("videos/GX030001 copy.MP4"));
+        assert_eq!(files.len(), 3);
+    }
+}
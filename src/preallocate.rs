@@ -0,0 +1,198 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::fs::File;
+use std::io::{ Read, Seek, SeekFrom, Result };
+use std::path::Path;
+use crate::boxes::{ fourcc, BoxIter };
+use crate::diagnostics::diag;
+
+/// Room for the boxes the merge adds to the moov, e.g. edit lists, chapters and the sample tables of synthesized samples
+const MOOV_MARGIN: u64 = 1024 * 1024;
+
+/// Estimated size of the merged file from the top-level boxes of the inputs, without scanning them: the mdat data of every input
+/// plus their moovs, as the merged sample tables are about as large as those of the inputs together. Data past the last box
+/// (e.g. the Insta360 trailer) is counted as is. The files are rewound
+pub(crate) fn estimate_output_size<R: Read + Seek>(files: &mut [(R, usize)]) -> Result<u64> {
+    let mut estimate = MOOV_MARGIN;
+    for (file, _) in files.iter_mut() {
+        let mut boxes_end = 0;
+        let mut iter = BoxIter::top_level(file)?;
+        for header in iter.by_ref() {
+            let Ok(header) = header else { break; };
+            if header.typ == fourcc("mdat") || header.typ == fourcc("moov") { estimate += header.size; }
+            boxes_end = header.end();
+        }
+        let end = file.seek(SeekFrom::End(0))?;
+        estimate += end.saturating_sub(boxes_end);
+        file.seek(SeekFrom::Start(0))?;
+    }
+    Ok(estimate)
+}
+
+/// Reserve `size` bytes of disk space for `file` without changing its length, so the output is written to contiguous space
+/// and a full disk fails the merge before anything is written. Only on Linux, skipped where the filesystem can't reserve space
+pub(crate) fn reserve(file: &File, size: u64) -> Result<()> {
+    match fallocate(file, size)? {
+        true => diag!(Debug, "Reserved {size} bytes for the output"),
+        false => diag!(Debug, "The filesystem can't reserve space for the output")
+    }
+    Ok(())
+}
+
+/// Free the space reserved past the end of the output at `path`, when the merged file is smaller than the estimate
+pub(crate) fn release_unused(path: &Path, reserved: u64) {
+    let result = std::fs::OpenOptions::new().write(true).open(path).and_then(|file| {
+        let len = file.metadata()?.len();
+        // Truncating to the current length frees the blocks past the end, punching a hole there doesn't on ext4
+        if reserved > len { file.set_len(len)?; }
+        Ok(())
+    });
+    if let Err(e) = result {
+        diag!(Debug, "Failed to free the unused space of {}: {e:?}", path.display());
+    }
+}
+
+/// Fail with `Error::InsufficientSpace` when a filesystem has less free space than the outputs `paths` on it need together,
+/// `required` bytes each. Passes when the free space is unknown
+pub(crate) fn check_free_space(paths: &[&Path], required: u64) -> Result<()> {
+    // Directory, device and required space of each filesystem, the first output on it names it in the error
+    let mut filesystems: Vec<(&Path, &Path, Option<u64>, u64)> = Vec::new();
+    for &path in paths {
+        let dir = match path.parent() {
+            Some(x) if !x.as_os_str().is_empty() => x,
+            _ => Path::new(".")
+        };
+        let device = device_id(dir);
+        match filesystems.iter_mut().find(|x| if device.is_some() { x.2 == device } else { x.1 == dir }) {
+            Some(filesystem) => filesystem.3 += required,
+            None => filesystems.push((path, dir, device, required))
+        }
+    }
+    for (path, dir, _, required) in filesystems {
+        match available_space(dir) {
+            Some(available) if available < required => return Err(crate::Error::InsufficientSpace { path: path.to_path_buf(), required, available }.into()),
+            Some(available) => diag!(Debug, "{available} bytes free for {}, about {required} needed", path.display()),
+            None => { }
+        }
+    }
+    Ok(())
+}
+
+/// Device of the filesystem of `dir`, to add up the outputs written to the same one
+#[cfg(unix)]
+fn device_id(dir: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(dir).ok().map(|x| x.dev())
+}
+
+#[cfg(not(unix))]
+fn device_id(_dir: &Path) -> Option<u64> {
+    None
+}
+
+/// Free space of the filesystem of `dir` for unprivileged users
+#[cfg(unix)]
+fn available_space(dir: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let dir = std::ffi::CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: the path is NUL-terminated and statvfs fills the struct when it returns 0
+    if unsafe { libc::statvfs(dir.as_ptr(), stat.as_mut_ptr()) } != 0 { return None; }
+    let stat = unsafe { stat.assume_init() };
+    // The field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn available_space(_dir: &Path) -> Option<u64> {
+    None
+}
+
+/// fallocate of the first `len` bytes with FALLOC_FL_KEEP_SIZE. Returns false when the filesystem doesn't support it
+#[cfg(target_os = "linux")]
+fn fallocate(file: &File, len: u64) -> Result<bool> {
+    use std::os::fd::AsRawFd;
+    if len == 0 { return Ok(true); }
+    // SAFETY: the descriptor is open for the lifetime of the borrow
+    if unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len as libc::off_t) } == 0 {
+        return Ok(true);
+    }
+    let e = std::io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EOPNOTSUPP | libc::ENOSYS | libc::EINVAL) => Ok(false),
+        _ => Err(e)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fallocate(_file: &File, _len: u64) -> Result<bool> {
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+
+    #[test]
+    fn test_preallocated_output() {
+        let file = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50)).track(SyntheticTrack::audio(48000, 50));
+        let dir = std::env::temp_dir().join(format!("mp4_merge_preallocate_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let reserved = dir.join("reserved.bin");
+        reserve(&File::create(&reserved).unwrap(), 1024 * 1024).unwrap();
+        // The length is only set by writing
+        assert_eq!(std::fs::metadata(&reserved).unwrap().len(), 0);
+
+        let inputs = [dir.join("a.mp4"), dir.join("b.mp4")];
+        for input in &inputs {
+            std::fs::write(input, file.build()).unwrap();
+        }
+        let mut outputs = Vec::new();
+        for disable_preallocation in [false, true] {
+            let output = dir.join(format!("out_{disable_preallocation}.mp4"));
+            let options = crate::MergeOptions { disable_preallocation, disable_kernel_copy: true, ..Default::default() };
+            crate::join_files_with_options(&inputs, &output, &options, |_| {}).unwrap();
+            outputs.push(std::fs::read(&output).unwrap());
+        }
+        let estimate = estimate_output_size(&mut [file.cursor(), file.cursor()]).unwrap();
+
+        // The reserved space of an incomplete output is freed too
+        let kept = dir.join("kept.mp4");
+        let cancellation = crate::CancellationToken::new();
+        cancellation.cancel();
+        let options = crate::MergeOptions { keep_incomplete_output: true, cancellation: Some(cancellation), ..Default::default() };
+        assert!(crate::join_files_with_options(&inputs, &kept, &options, |_| {}).is_err());
+        #[cfg(unix)]
+        let blocks = [dir.join("out_false.mp4"), kept].map(|x| std::os::unix::fs::MetadataExt::blocks(&std::fs::metadata(x).unwrap()));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // The moov margin covers the boxes added by the merge
+        assert!(estimate >= outputs[0].len() as u64 && estimate < outputs[0].len() as u64 + 2 * MOOV_MARGIN, "{estimate}");
+        // Only the written data stays allocated
+        #[cfg(unix)]
+        assert!(blocks[0] * 512 < outputs[0].len() as u64 + 64 * 1024 && blocks[1] == 0, "{blocks:?}");
+        assert_eq!(outputs[0], outputs[1]);
+        assert!(crate::verify::check(&mut std::io::Cursor::new(&outputs[0])).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_check_free_space() {
+        let output = std::env::temp_dir().join("out.mp4");
+        check_free_space(&[&output], 0).unwrap();
+        if cfg!(unix) {
+            let e = check_free_space(&[&output], u64::MAX).unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::StorageFull);
+            assert!(matches!(crate::Error::from(e), crate::Error::InsufficientSpace { required: u64::MAX, .. }));
+
+            // Two outputs on the same filesystem need the space twice
+            let available = available_space(&std::env::temp_dir()).unwrap();
+            let tee = std::env::temp_dir().join("tee.mp4");
+            check_free_space(&[&output], available / 3 * 2).unwrap();
+            let e = check_free_space(&[&output, &tee], available / 3 * 2).unwrap_err();
+            assert!(matches!(crate::Error::from(e), crate::Error::InsufficientSpace { required, .. } if required == available / 3 * 4));
+        }
+    }
+}
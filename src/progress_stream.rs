@@ -1,19 +1,22 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 // Copyright © 2022 Adrian <adrian.eddy at gmail>
 
+use std::cell::RefCell;
 use std::io::{ Read, Write, Seek, Result, SeekFrom };
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
 
-pub struct ProgressStream<R: Read + Write + Seek, C: FnMut(usize)> {
+pub struct ProgressStream<R, C: FnMut(usize)> {
     inner: R,
     callback: C,
     total: usize
 }
-impl<R: Read + Write + Seek, C: FnMut(usize)> ProgressStream<R, C> {
+impl<R, C: FnMut(usize)> ProgressStream<R, C> {
     pub fn new(inner: R, callback: C) -> Self {
         Self { inner, callback, total: 0 }
     }
 }
-impl<R: Read + Write + Seek, C: FnMut(usize)> Read for ProgressStream<R, C> {
+impl<R: Read, C: FnMut(usize)> Read for ProgressStream<R, C> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         let read = self.inner.read(buf)?;
         self.total += read;
@@ -21,10 +24,10 @@ impl<R: Read + Write + Seek, C: FnMut(usize)> Read for ProgressStream<R, C> {
         Ok(read)
     }
 }
-impl<R: Read + Write + Seek, C: FnMut(usize)> Seek for ProgressStream<R, C> {
+impl<R: Seek, C: FnMut(usize)> Seek for ProgressStream<R, C> {
     fn seek(&mut self, pos: SeekFrom) -> Result<u64> { self.inner.seek(pos) }
 }
-impl<R: Read + Write + Seek, C: FnMut(usize)> Write for ProgressStream<R, C> {
+impl<R: Write, C: FnMut(usize)> Write for ProgressStream<R, C> {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
         let written = self.inner.write(buf)?;
         self.total += written;
@@ -33,3 +36,296 @@ impl<R: Read + Write + Seek, C: FnMut(usize)> Write for ProgressStream<R, C> {
     }
     fn flush(&mut self) -> Result<()> { self.inner.flush() }
 }
+
+/// Detailed progress of writing the output
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ProgressInfo {
+    /// Overall progress, the same value as passed to the progress callback
+    pub fraction: f64,
+    pub bytes_written: u64,
+    /// Expected size of the output(s)
+    pub total_bytes: u64,
+    /// Smoothed write speed in bytes per second
+    pub throughput: f64,
+    /// Estimated time until the remaining bytes are written, None until the throughput is known
+    pub eta: Option<Duration>,
+    pub elapsed: Duration,
+    /// What is being written
+    pub stage: WriteStage,
+    /// Sample table entries written in the current stage, 0 while writing data
+    pub entries_written: u64,
+    pub total_entries: u64,
+}
+
+/// Stage of writing the output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WriteStage {
+    /// Boxes and sample data
+    #[default]
+    Data,
+    /// Sample tables of the merged moov
+    Tables,
+    /// Final chunk offsets, patched once the position of the sample data is known
+    Patching,
+}
+
+/// Receives `ProgressInfo` updates while the output is written
+pub trait ProgressListener: Send + Sync {
+    fn progress(&self, info: &ProgressInfo);
+}
+impl<F: Fn(&ProgressInfo) + Send + Sync> ProgressListener for F {
+    fn progress(&self, info: &ProgressInfo) { self(info) }
+}
+impl std::fmt::Debug for dyn ProgressListener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { f.write_str("ProgressListener") }
+}
+
+/// Part of the merge a `ProgressEvent` is sent from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressPhase {
+    /// Reading the headers of the input files
+    Scanning,
+    /// Copying the mdat data of the input files to the output
+    Copying,
+    /// Writing the final chunk offsets, once the position of the mdat data is known
+    Patching,
+}
+
+/// Progress of the current phase of the merge. The bytes are the input sizes while scanning,
+/// the mdat data while copying and the chunk offset tables while patching
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgressEvent {
+    pub phase: ProgressPhase,
+    /// Input file being scanned or copied, in the merge order
+    pub file_index: Option<usize>,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+}
+
+/// Receives a `ProgressEvent` when the merge moves to another phase or file, and at most every 100 ms in between
+pub trait ProgressEventListener: Send + Sync {
+    fn event(&self, event: &ProgressEvent);
+}
+impl<F: Fn(&ProgressEvent) + Send + Sync> ProgressEventListener for F {
+    fn event(&self, event: &ProgressEvent) { self(event) }
+}
+impl std::fmt::Debug for dyn ProgressEventListener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { f.write_str("ProgressEventListener") }
+}
+
+struct EventContext {
+    listener: Arc<dyn ProgressEventListener>,
+    last: Option<(ProgressPhase, Option<usize>)>,
+    debounce: Instant,
+}
+
+thread_local! {
+    // Event listener of the merge running on this thread
+    static EVENTS: RefCell<Option<EventContext>> = const { RefCell::new(None) };
+}
+
+/// Restores the previous event listener when the merge finishes
+pub(crate) struct EventGuard {
+    previous: Option<EventContext>,
+}
+impl Drop for EventGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        EVENTS.with(|c| *c.borrow_mut() = previous);
+    }
+}
+
+/// Send the events of this thread to `listener` until the returned guard is dropped
+pub(crate) fn event_scope(listener: Option<&Arc<dyn ProgressEventListener>>) -> EventGuard {
+    let context = listener.map(|listener| EventContext { listener: listener.clone(), last: None, debounce: Instant::now() });
+    EventGuard { previous: EVENTS.with(|c| std::mem::replace(&mut *c.borrow_mut(), context)) }
+}
+
+/// Send a `ProgressEvent` to the listener of the current merge. The first and the last event of a phase and file are always sent
+pub(crate) fn emit_event(phase: ProgressPhase, file_index: Option<usize>, bytes_done: u64, bytes_total: u64) {
+    EVENTS.with(|c| if let Some(context) = &mut *c.borrow_mut() {
+        let changed = context.last != Some((phase, file_index));
+        if changed || bytes_done >= bytes_total || context.debounce.elapsed().as_millis() > 100 {
+            context.last = Some((phase, file_index));
+            context.debounce = Instant::now();
+            context.listener.event(&ProgressEvent { phase, file_index, bytes_done, bytes_total });
+        }
+    });
+}
+
+/// Measures the write throughput and estimates the remaining time from the byte counts
+pub struct ThroughputMeter {
+    total_bytes: u64,
+    start: Instant,
+    last_update: Instant,
+    last_bytes: u64,
+    throughput: f64,
+}
+impl ThroughputMeter {
+    // Weight of the latest measurement in the moving average
+    const SMOOTHING: f64 = 0.2;
+
+    pub fn new(total_bytes: u64) -> Self {
+        let now = Instant::now();
+        Self { total_bytes, start: now, last_update: now, last_bytes: 0, throughput: 0.0 }
+    }
+
+    pub fn update(&mut self, bytes_written: u64, fraction: f64) -> ProgressInfo {
+        let now = Instant::now();
+        let interval = (now - self.last_update).as_secs_f64();
+        if interval > 0.0 && bytes_written >= self.last_bytes {
+            let current = (bytes_written - self.last_bytes) as f64 / interval;
+            self.throughput = if self.throughput > 0.0 { self.throughput + (current - self.throughput) * Self::SMOOTHING } else { current };
+        }
+        self.last_update = now;
+        self.last_bytes = bytes_written;
+
+        let remaining = self.total_bytes.saturating_sub(bytes_written) as f64;
+        ProgressInfo {
+            fraction,
+            bytes_written,
+            total_bytes: self.total_bytes,
+            throughput: self.throughput,
+            eta: (self.throughput > 0.0).then(|| Duration::from_secs_f64(remaining / self.throughput)),
+            elapsed: now - self.start,
+            ..Default::default()
+        }
+    }
+}
+
+struct ReporterState {
+    meter: ThroughputMeter,
+    last: ProgressInfo,
+    /// Written and total entries of the table and patch stages
+    tables: (u64, u64),
+    patching: (u64, u64),
+    debounce: Instant,
+}
+
+/// Sends `ProgressInfo` to the listener, combining the written bytes with the entries written by the table and patch stages.
+/// Cloned into the `Desc`, so the writer can report the progress of tables with millions of entries
+#[derive(Clone)]
+pub(crate) struct ProgressReporter {
+    listener: Arc<dyn ProgressListener>,
+    state: Arc<Mutex<ReporterState>>,
+}
+impl ProgressReporter {
+    /// None without a listener
+    pub fn new(listener: Option<&Arc<dyn ProgressListener>>, total_bytes: u64) -> Option<Self> {
+        Some(Self {
+            listener: listener?.clone(),
+            state: Arc::new(Mutex::new(ReporterState {
+                meter: ThroughputMeter::new(total_bytes),
+                last: ProgressInfo { total_bytes, ..Default::default() },
+                tables: (0, 0),
+                patching: (0, 0),
+                debounce: Instant::now(),
+            }))
+        })
+    }
+
+    /// Number of entries written by the table and patch stages
+    pub fn set_total_entries(&self, tables: u64, patching: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.tables = (0, tables);
+        state.patching = (0, patching);
+    }
+
+    pub fn bytes(&self, bytes_written: u64, fraction: f64) {
+        let mut state = self.state.lock().unwrap();
+        let info = ProgressInfo { stage: state.last.stage, entries_written: state.last.entries_written, total_entries: state.last.total_entries, ..state.meter.update(bytes_written, fraction) };
+        state.last = info;
+        drop(state);
+        self.listener.progress(&info);
+    }
+
+    /// Switch to another stage. Entries written in an earlier visit of the stage are kept
+    pub fn set_stage(&self, stage: WriteStage) {
+        let mut state = self.state.lock().unwrap();
+        if state.last.stage == stage { return; }
+        let (entries_written, total_entries) = match stage {
+            WriteStage::Data => (0, 0),
+            WriteStage::Tables => state.tables,
+            WriteStage::Patching => state.patching,
+        };
+        state.last = ProgressInfo { stage, entries_written, total_entries, ..state.last };
+    }
+
+    /// Entries written in the current stage. Events are sent at most every 100 ms, and when the stage completes
+    pub fn add_entries(&self, entries: u64) {
+        let mut state = self.state.lock().unwrap();
+        let stage = state.last.stage;
+        let counter = match stage {
+            WriteStage::Data => return,
+            WriteStage::Tables => &mut state.tables,
+            WriteStage::Patching => &mut state.patching,
+        };
+        counter.0 += entries;
+        let (entries_written, total_entries) = *counter;
+        state.last.entries_written = entries_written;
+        state.last.total_entries = total_entries;
+        if state.debounce.elapsed().as_millis() > 100 || entries_written == total_entries {
+            state.debounce = Instant::now();
+            let info = ProgressInfo { elapsed: state.meter.start.elapsed(), ..state.last };
+            drop(state);
+            self.listener.progress(&info);
+        }
+    }
+}
+impl std::fmt::Debug for ProgressReporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { f.write_str("ProgressReporter") }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_reporter() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let listener: Arc<dyn ProgressListener> = { let events = events.clone(); Arc::new(move |x: &ProgressInfo| events.lock().unwrap().push(*x)) };
+        let reporter = ProgressReporter::new(Some(&listener), 1000).unwrap();
+        reporter.set_total_entries(10, 4);
+        reporter.set_stage(WriteStage::Tables);
+        reporter.add_entries(6);
+        reporter.bytes(100, 0.1);
+        reporter.add_entries(4);
+        reporter.set_stage(WriteStage::Patching);
+        reporter.add_entries(4);
+        reporter.set_stage(WriteStage::Data);
+        reporter.bytes(1000, 0.9999);
+
+        let events = events.lock().unwrap();
+        let stages = events.iter().map(|x| (x.stage, x.entries_written, x.total_entries, x.bytes_written)).collect::<Vec<_>>();
+        // The first block is within the debounce interval
+        assert_eq!(stages, vec![
+            (WriteStage::Tables, 6, 10, 100),
+            (WriteStage::Tables, 10, 10, 100),
+            (WriteStage::Patching, 4, 4, 100),
+            (WriteStage::Data, 0, 0, 1000),
+        ]);
+    }
+
+    #[test]
+    fn test_progress_events() {
+        use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+        let file = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50)).track(SyntheticTrack::audio(48000, 50));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let options = crate::MergeOptions::default().progress_events({ let events = events.clone(); move |x: &ProgressEvent| events.lock().unwrap().push(*x) });
+        let mut files = [file.cursor(), file.cursor()];
+        crate::join_file_streams_with_options(&mut files, std::io::Cursor::new(Vec::new()), &[None, None], &options, |_| {}).unwrap();
+
+        let events = events.lock().unwrap();
+        let mut steps = events.iter().map(|x| (x.phase, x.file_index)).collect::<Vec<_>>();
+        steps.dedup();
+        assert_eq!(steps, [
+            (ProgressPhase::Scanning, Some(0)), (ProgressPhase::Scanning, Some(1)),
+            (ProgressPhase::Copying, Some(0)), (ProgressPhase::Copying, Some(1)),
+            (ProgressPhase::Patching, None),
+        ]);
+        for phase in [ProgressPhase::Scanning, ProgressPhase::Copying, ProgressPhase::Patching] {
+            let last = events.iter().rfind(|x| x.phase == phase).unwrap();
+            assert!(last.bytes_total > 0 && last.bytes_done == last.bytes_total, "{last:?}");
+        }
+    }
+}
@@ -0,0 +1,149 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Write, Result };
+use std::path::Path;
+use byteorder::{ WriteBytesExt, BigEndian };
+use crate::desc_reader::Desc;
+use crate::{ fourcc, writer, MergeOptions, MergeReport, diagnostics::{ self, diag } };
+
+/// Write a metadata-only MP4 with the merged moov, whose samples are read from the original files through `dref` entries.
+/// It's written instantly, e.g. to preview the merge before copying all the data. The output breaks when the files are moved.
+/// Camera metadata trailers (Insta360, GPMF) are not written.
+pub fn write_reference_movie<P: AsRef<Path>, Q: AsRef<Path>>(files: &[P], output_file: Q, options: &MergeOptions) -> Result<MergeReport> {
+    let _diagnostics = diagnostics::scope(options.diagnostics.as_ref());
+    let _events = crate::progress_stream::event_scope(options.progress_events.as_ref());
+    let mut open_files = Vec::with_capacity(files.len());
+    let mut file_metadata = Vec::with_capacity(files.len());
+    for x in files {
+        let f = std::fs::File::open(x)?;
+        let metadata = f.metadata()?;
+        file_metadata.push(crate::filesystem_creation_time(&metadata));
+        open_files.push((f, metadata.len() as usize));
+    }
+    let mut scan = crate::scan_files(&mut open_files, &file_metadata, options, &|_| {})?;
+    if !scan.desc.synthesized_data.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "The merge synthesizes samples (interpolated telemetry or shifted highlights), which can't be referenced from the original files"));
+    }
+    let urls = scan.input_order.iter().map(|x| std::fs::canonicalize(&files[*x]).map(|x| file_url(&x))).collect::<Result<Vec<_>>>()?;
+    reference_sources(&mut scan.desc, urls)?;
+
+    diagnostics::set_phase(diagnostics::Phase::Write, None);
+    let mut output = std::io::BufWriter::with_capacity(64*1024, std::fs::File::create(output_file)?);
+    writer::rewrite_from_desc(&mut scan.first_boxes.reader(), &mut open_files, &mut output, &mut scan.desc, 0, scan.insta360_max_read.unwrap_or(u64::MAX), &crate::copy::Synchronous)?;
+    output.flush()?;
+    Ok(MergeReport::from_desc(&scan.desc, &scan.input_order))
+}
+
+/// Point the chunk offsets at the source files and select the sample description of each file, which references its dref entry
+fn reference_sources(desc: &mut Desc, urls: Vec<String>) -> Result<()> {
+    // Start of each file in the merged mdat payload, and in the file
+    let mut sources = Vec::new();
+    let mut start = 0;
+    for &(file_index, offset, size) in &desc.mdat_position {
+        if let Some(file_index) = file_index { sources.push((start..start + size, file_index, offset)); }
+        start += size;
+    }
+    let locate = |x: u64| sources.iter().find(|s| s.0.contains(&x)).map(|s| (s.1, s.2 + x - s.0.start));
+
+    for (track_index, track) in desc.moov_tracks.iter_mut().enumerate().filter(|x| !x.1.dropped && !x.1.stco.is_empty()) {
+        let entries = track.sample_entries.len() as u32;
+        let mut chunk_files = Vec::with_capacity(track.stco.len());
+        for offset in &mut track.stco {
+            let (file_index, file_offset) = locate(*offset).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Chunk offset {offset} of track {track_index} is outside of the source files")))?;
+            *offset = file_offset;
+            chunk_files.push(file_index as u32);
+        }
+        if entries == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Track {track_index} has no sample descriptions")));
+        }
+        for entry in &mut track.stsc {
+            entry.2 += chunk_files.get(entry.0 as usize - 1).copied().unwrap_or(0) * entries;
+        }
+    }
+    diag!(Debug, "Referencing {} source files", urls.len());
+    desc.data_references = urls;
+    desc.mdat_final_position = 0;
+    Ok(())
+}
+
+/// file:// URL of an absolute path
+fn file_url(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let path = path.trim_start_matches("//?/");
+    let mut url = String::from(if path.starts_with('/') { "file://" } else { "file:///" });
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-_.~:".contains(&byte) {
+            url.push(byte as char);
+        } else {
+            url.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    url
+}
+
+/// dinf with a dref entry for each source file
+pub(crate) fn write_dinf<W: Write>(output: &mut W, urls: &[String]) -> Result<u64> {
+    let dref_size = 16 + urls.iter().map(|x| 12 + x.len() as u64 + 1).sum::<u64>();
+    output.write_u32::<BigEndian>(8 + dref_size as u32)?;
+    output.write_all(&fourcc("dinf").to_be_bytes())?;
+    output.write_u32::<BigEndian>(dref_size as u32)?;
+    output.write_all(&fourcc("dref").to_be_bytes())?;
+    output.write_u32::<BigEndian>(0)?; // Version and flags
+    output.write_u32::<BigEndian>(urls.len() as u32)?;
+    for url in urls {
+        output.write_u32::<BigEndian>(12 + url.len() as u32 + 1)?;
+        output.write_all(&fourcc("url ").to_be_bytes())?;
+        output.write_u32::<BigEndian>(0)?; // Not in the same file
+        output.write_all(url.as_bytes())?;
+        output.write_u8(0)?;
+    }
+    Ok(8 + dref_size)
+}
+
+/// stsd with the sample descriptions repeated for each source file, each copy referencing the dref entry of its file.
+/// `content` is the content of the original stsd box
+pub(crate) fn write_stsd<W: Write>(output: &mut W, content: &[u8], num_files: usize) -> Result<u64> {
+    let mut entries = Vec::new();
+    let mut pos = 8;
+    while pos + 16 <= content.len() {
+        let size = u32::from_be_bytes([content[pos], content[pos + 1], content[pos + 2], content[pos + 3]]) as usize;
+        if size < 16 || pos + size > content.len() { break; }
+        entries.push(&content[pos..pos + size]);
+        pos += size;
+    }
+    let size = 16 + entries.iter().map(|x| x.len() as u64).sum::<u64>() * num_files as u64;
+    output.write_u32::<BigEndian>(size as u32)?;
+    output.write_all(&fourcc("stsd").to_be_bytes())?;
+    output.write_all(&content[..4])?; // Version and flags
+    output.write_u32::<BigEndian>((entries.len() * num_files) as u32)?;
+    for file_index in 0..num_files {
+        for entry in &entries {
+            output.write_all(&entry[..14])?;
+            output.write_u16::<BigEndian>(file_index as u16 + 1)?; // data_reference_index
+            output.write_all(&entry[16..])?;
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::desc_reader::TrackDesc;
+
+    #[test]
+    fn test_reference_sources() {
+        let mut desc = Desc { mdat_position: vec![(Some(0), 48, 1000), (Some(1), 32, 500)], ..Default::default() };
+        desc.moov_tracks.push(TrackDesc {
+            stco: vec![0, 600, 1000, 1200],
+            stsc: vec![(1, 5, 1), (3, 4, 1)],
+            sample_entries: vec![Default::default()],
+            ..Default::default()
+        });
+        reference_sources(&mut desc, vec!["file:///a.mp4".into(), "file:///b.mp4".into()]).unwrap();
+        assert_eq!(desc.moov_tracks[0].stco, vec![48, 648, 32, 232]);
+        assert_eq!(desc.moov_tracks[0].stsc, vec![(1, 5, 1), (3, 4, 2)]);
+        assert_eq!(file_url(Path::new("/media/GX01 0001.MP4")), "file:///media/GX01%200001.MP4");
+    }
+}
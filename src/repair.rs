@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::Result;
+use crate::desc_reader::Desc;
+use crate::diagnostics::diag;
+
+/// A chunk of the file being checked. Offsets are relative to the start of its mdat payload
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    track: usize,
+    index: usize, // Index in the merged stco
+    decode_time: f64, // Of the first sample, in seconds
+    offset: i64,
+    size: u64,
+}
+
+/// Check the chunk offsets of the file just read against the extent of its mdat payload, which starts at `desc.mdat_offset` of the merged mdat.
+/// With `desc.repair_chunk_offsets`, the broken ones are re-derived by walking the mdat with the known sample sizes: in decode order, each broken chunk
+/// is placed in the first free space after the previous chunk of its track. Otherwise they are only reported.
+pub(crate) fn check_chunk_offsets(desc: &mut Desc, file_index: usize, mdat_size: u64) -> Result<()> {
+    let mdat_start = desc.mdat_offset;
+    if !desc.repair_chunk_offsets {
+        // Only the start of the chunks, computing their sizes needs the full sample tables
+        let broken = desc.moov_tracks.iter().filter(|t| !t.skip || file_index == 0)
+            .flat_map(|t| &t.stco[(t.chunk_offset as usize).min(t.stco.len())..])
+            .filter(|x| **x < mdat_start || **x >= mdat_start + mdat_size).count();
+        if broken > 0 {
+            diag!(Warn, "File {file_index} has {broken} chunk offsets outside of its mdat, the merged output will be broken. Enable the chunk offset repair to re-derive them");
+        }
+        return Ok(());
+    }
+
+    let mut chunks = Vec::new();
+    for (track_index, track) in desc.moov_tracks.iter().enumerate() {
+        if track.chunk_offset as usize >= track.stco.len() || track.mdhd_timescale == 0 { continue; }
+        for sample in track.sample_infos().iter().filter(|x| x.chunk >= track.chunk_offset) {
+            match chunks.last_mut() {
+                Some(Chunk { track, index, size, .. }) if *track == track_index && *index == sample.chunk as usize => *size += sample.size as u64,
+                _ => chunks.push(Chunk {
+                    track: track_index,
+                    index: sample.chunk as usize,
+                    decode_time: sample.decode_time as f64 / track.mdhd_timescale as f64,
+                    offset: track.stco[sample.chunk as usize] as i64 - mdat_start as i64,
+                    size: sample.size as u64,
+                })
+            }
+        }
+    }
+    let is_valid = |x: &Chunk| x.offset >= 0 && x.offset as u64 + x.size <= mdat_size;
+    let (mut placed, mut broken): (Vec<Chunk>, Vec<Chunk>) = chunks.into_iter().partition(is_valid);
+    if broken.is_empty() { return Ok(()); }
+    diag!(Warn, "File {file_index} has {} chunks outside of its mdat, re-deriving their offsets", broken.len());
+
+    broken.sort_by(|a, b| a.decode_time.total_cmp(&b.decode_time).then(a.track.cmp(&b.track)));
+    placed.sort_by_key(|x| x.offset);
+    for mut chunk in broken {
+        // The chunks of a track are stored in order, so it's the first free position after the previous chunk of the same track
+        let mut position = placed.iter().filter(|x| x.track == chunk.track && x.index < chunk.index).max_by_key(|x| x.index).map(|x| x.offset as u64 + x.size).unwrap_or(0);
+        for x in &placed {
+            let (start, end) = (x.offset as u64, x.offset as u64 + x.size);
+            if start < position + chunk.size && end > position { position = end; }
+        }
+        if position + chunk.size > mdat_size {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Chunk offsets of file {file_index} can't be repaired, the samples don't fit in its mdat")));
+        }
+        chunk.offset = position as i64;
+        desc.moov_tracks[chunk.track].stco[chunk.index] = mdat_start + position;
+        let index = placed.partition_point(|x| x.offset < chunk.offset);
+        placed.insert(index, chunk);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::desc_reader::TrackDesc;
+
+    #[test]
+    fn test_repair_chunk_offsets() {
+        let track = |stco: Vec<u64>| TrackDesc {
+            mdhd_timescale: 1000,
+            stts: vec![(4, 500)],
+            stsz_sample_size: 100,
+            stsz_count: 4,
+            stsc: vec![(1, 2, 1)],
+            stco,
+            ..Default::default()
+        };
+        // The second file's mdat starts at 1000 of the merged mdat. The audio chunks are fine, the video ones point past the mdat
+        let mut desc = Desc { moov_tracks: vec![track(vec![90000, 90200]), track(vec![1200, 1600])], mdat_offset: 1000, ..Default::default() };
+        check_chunk_offsets(&mut desc, 1, 800).unwrap();
+        assert_eq!(desc.moov_tracks[0].stco, vec![90000, 90200]);
+
+        desc.repair_chunk_offsets = true;
+        check_chunk_offsets(&mut desc, 1, 800).unwrap();
+        // Interleaved: video 0..200, audio 200..400, video 400..600, audio 600..800
+        assert_eq!(desc.moov_tracks[0].stco, vec![1000, 1400]);
+        assert_eq!(desc.moov_tracks[1].stco, vec![1200, 1600]);
+
+        let mut desc = Desc { moov_tracks: vec![track(vec![90000, 90200]), track(vec![1200, 1600])], mdat_offset: 1000, repair_chunk_offsets: true, ..Default::default() };
+        assert!(check_chunk_offsets(&mut desc, 1, 700).is_err());
+    }
+}
@@ -0,0 +1,233 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::ops::Range;
+use crate::desc_reader::{ Desc, EditListEntry };
+use crate::gopro::CameraInfo;
+use crate::stsd::SampleEntry;
+use crate::track_info::TelemetryFormat;
+use crate::diagnostics::diag;
+
+/// Summary of a finished merge
+#[derive(Debug, Clone, Default)]
+pub struct MergeReport {
+    /// Information about each input file, in the order they were merged
+    pub files: Vec<FileReport>,
+    /// Output files when the output was split with `max_output_size`
+    pub parts: Vec<PartReport>,
+    /// Information about each track of the output
+    pub tracks: Vec<TrackReport>,
+    /// Keyframe alignment at the start of each appended file, for tracks with non-sync samples
+    pub boundaries: Vec<BoundaryReport>,
+    /// The files were recorded with different camera firmware versions, which often changes the codec configuration mid-session
+    pub mixed_firmware: bool,
+    /// Gaps between consecutive files in seconds, negative for overlaps. Empty when the gaps couldn't be determined
+    pub gaps: Vec<f64>,
+    /// Input indexes of the files without the Insta360 metadata trailer, when other files have it.
+    /// The metadata of these files is missing from the merged trailer
+    pub missing_insta360_metadata: Vec<usize>,
+    /// Ranges of the merged timeline filled with interpolated telemetry samples, in seconds
+    pub interpolated_telemetry: Vec<Range<f64>>,
+}
+
+/// Keyframe alignment of a track at the point where the next file was appended
+#[derive(Debug, Clone, Default)]
+pub struct BoundaryReport {
+    /// Position of the appended file in the merge order (1 for the second file)
+    pub file_position: usize,
+    pub track_index: usize,
+    /// Whether the first sample of the appended file is a sync sample. Players seek poorly around the boundary otherwise
+    pub starts_with_keyframe: bool,
+    /// Time from the boundary to the first keyframe of the appended file, in seconds
+    pub next_keyframe: Option<f64>,
+    /// Time from the last keyframe before the boundary to the boundary, in seconds
+    pub previous_keyframe: Option<f64>,
+}
+
+/// A single track of the merged output
+#[derive(Debug, Clone, Default)]
+pub struct TrackReport {
+    /// Handler type, e.g. "vide" or "soun"
+    pub handler_type: String,
+    /// Sample entry type of the first file, e.g. "avc1" or "av01"
+    pub codec: Option<String>,
+    /// Number of samples in the merged track
+    pub sample_count: u32,
+    /// Number of sync samples (keyframes) in the merged track
+    pub sync_sample_count: u32,
+    /// Format of timed metadata tracks: the MIME type of `mett`, the namespace of `metx`, the URI of `urim` entries, otherwise the codec
+    pub metadata_format: Option<String>,
+    /// Camera telemetry carried by the track, e.g. DJI flight metadata
+    pub telemetry: Option<TelemetryFormat>,
+    /// Duration of the merged media in seconds
+    pub duration: f64,
+    /// Edit list written to the output, empty when the default single entry is used
+    pub edit_list: Vec<EditListEntry>,
+    /// Largest shift of the sample or edit timing caused by rounding to the movie and media timescales, in seconds
+    pub rounding_error: f64,
+}
+
+/// A single output file of a split merge
+#[derive(Debug, Clone, Default)]
+pub struct PartReport {
+    /// Size of the output file in bytes
+    pub size: u64,
+    /// Range of the merged timeline covered by this part, in seconds
+    pub time_range: Range<f64>,
+    /// Range of sample indexes (0-based) per track in the merged sample tables
+    pub track_sample_ranges: Vec<Range<u32>>,
+}
+
+/// Where the data of a single input file ended up in the merged output
+#[derive(Debug, Clone, Default)]
+pub struct FileReport {
+    /// Index of the file in the input list, before any reordering
+    pub input_index: usize,
+    /// Absolute byte range of this file's mdat payload in the output file
+    pub mdat_range: Range<u64>,
+    /// Range of sample indexes (0-based) per track in the merged sample tables
+    pub track_sample_ranges: Vec<Range<u32>>,
+    /// Camera model, serial and firmware read from udta
+    pub camera: CameraInfo,
+    /// Timecode at the start of this file on the merged timeline, continued from the timecode track of the first file.
+    /// Formatted as "hh:mm:ss:ff", or "hh:mm:ss;ff" for drop-frame timecode
+    pub start_timecode: Option<String>,
+}
+
+impl MergeReport {
+    pub(crate) fn from_desc(desc: &Desc, input_order: &[usize]) -> Self {
+        let num_tracks = desc.moov_tracks.iter().rposition(|t| !t.handler_type.is_empty()).map(|x| x + 1).unwrap_or(0);
+        let mut offset = desc.mdat_final_position;
+        let timeline_starts = desc.file_timeline_starts();
+        let files = desc.mdat_position.iter().filter_map(|(file_index, _, size)| {
+            let file_index = (*file_index)?;
+            let mdat_range = offset..offset + size;
+            offset += size;
+            Some(FileReport {
+                input_index: input_order.get(file_index).copied().unwrap_or(file_index),
+                mdat_range,
+                track_sample_ranges: desc.moov_tracks[..num_tracks].iter().filter(|t| !t.dropped).map(|t| t.file_sample_ranges.get(file_index).cloned().unwrap_or_default()).collect(),
+                camera: desc.file_cameras.get(file_index).cloned().unwrap_or_default(),
+                start_timecode: desc.start_timecode.map(|(format, frame)| format.format(format.advance(frame, timeline_starts.get(file_index).copied().unwrap_or(0.0)))),
+            })
+        }).collect();
+
+        let tracks = desc.moov_tracks[..num_tracks].iter().filter(|t| !t.dropped).map(|t| TrackReport {
+            handler_type: t.handler_type.clone(),
+            codec: t.sample_entries.first().map(|x| x.codec.clone()),
+            sample_count: t.stsz_count,
+            sync_sample_count: if t.stss.is_empty() { t.stsz_count } else { t.stss.len() as u32 },
+            metadata_format: if t.handler_type == "meta" || t.handler_type == "text" { t.sample_entries.first().map(metadata_format) } else { None },
+            telemetry: t.sample_entries.first().and_then(|x| TelemetryFormat::from_codec(&x.codec)),
+            duration: t.mdhd_duration as f64 / t.mdhd_timescale.max(1) as f64,
+            edit_list: t.elst_entries.clone(),
+            rounding_error: t.rounding_error,
+        }).collect();
+
+        let missing_insta360_metadata = if desc.file_has_insta360.contains(&true) {
+            desc.file_has_insta360.iter().enumerate().filter(|x| !x.1).map(|(i, _)| input_order.get(i).copied().unwrap_or(i)).collect()
+        } else {
+            Vec::new()
+        };
+
+        Self {
+            files,
+            parts: Vec::new(),
+            tracks,
+            boundaries: keyframe_alignment(desc, num_tracks),
+            mixed_firmware: mixed_firmware(&desc.file_cameras),
+            gaps: desc.file_gaps.clone(),
+            missing_insta360_metadata,
+            interpolated_telemetry: desc.interpolated_telemetry.clone(),
+        }
+    }
+}
+
+fn metadata_format(entry: &SampleEntry) -> String {
+    // Null-terminated strings after the reserved bytes and data_reference_index
+    let mut strings = entry.fields.get(8..).unwrap_or_default().split(|x| *x == 0).map(|x| String::from_utf8_lossy(x).into_owned());
+    let value = match entry.codec.as_str() {
+        "mett" | "metx" => strings.nth(1), // After content_encoding
+        // The uri box (size, type, version and flags, string) follows the fixed fields
+        "urim" => entry.fields.get(12..16).filter(|x| x == b"uri ").and(entry.fields.get(20..)).map(|x| String::from_utf8_lossy(x).trim_end_matches('\0').to_string()),
+        _ => None
+    };
+    value.filter(|x| !x.is_empty()).unwrap_or_else(|| entry.codec.clone())
+}
+
+fn mixed_firmware(cameras: &[CameraInfo]) -> bool {
+    let mut firmware = cameras.iter().filter_map(|x| x.firmware.as_deref()).collect::<Vec<_>>();
+    firmware.dedup();
+    if firmware.len() > 1 {
+        diag!(Warn, "Input files were recorded with different firmware versions: {firmware:?}");
+    }
+    firmware.len() > 1
+}
+
+fn keyframe_alignment(desc: &Desc, num_tracks: usize) -> Vec<BoundaryReport> {
+    let mut ret = Vec::new();
+    for (track_index, track) in desc.moov_tracks[..num_tracks].iter().enumerate() {
+        if track.skip || track.stss.is_empty() || track.mdhd_timescale == 0 { continue; }
+        let samples = track.sample_infos();
+        let seconds = |x: u64| x as f64 / track.mdhd_timescale as f64;
+        for (file_position, range) in track.file_sample_ranges.iter().enumerate().skip(1) {
+            let Some(first) = samples.get(range.start as usize) else { continue; };
+            let report = BoundaryReport {
+                file_position,
+                track_index,
+                starts_with_keyframe: first.is_sync,
+                next_keyframe: samples[range.start as usize..range.end as usize].iter().find(|x| x.is_sync).map(|x| seconds(x.decode_time - first.decode_time)),
+                previous_keyframe: samples[..range.start as usize].iter().rfind(|x| x.is_sync).map(|x| seconds(first.decode_time - x.decode_time)),
+            };
+            if !report.starts_with_keyframe {
+                diag!(Warn, "File {file_position} doesn't start with a keyframe in track {track_index}, the next keyframe is at {:?}s", report.next_keyframe);
+            }
+            ret.push(report);
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::desc_reader::TrackDesc;
+
+    #[test]
+    fn test_keyframe_alignment() {
+        let mut desc = Desc::default();
+        desc.moov_tracks.push(TrackDesc {
+            mdhd_timescale: 10,
+            handler_type: "vide".into(),
+            stts: vec![(40, 1)],
+            stsz_sample_size: 1,
+            stsz_count: 40,
+            stss: vec![1, 11, 25],
+            stsc: vec![(1, 1, 1)],
+            stco: (0..40).collect(),
+            file_sample_ranges: vec![0..20, 20..40],
+            ..Default::default()
+        });
+
+        let boundaries = keyframe_alignment(&desc, 1);
+        assert_eq!(boundaries.len(), 1);
+        assert!(!boundaries[0].starts_with_keyframe);
+        assert_eq!(boundaries[0].next_keyframe, Some(0.4));
+        assert_eq!(boundaries[0].previous_keyframe, Some(1.0));
+    }
+
+    #[test]
+    fn test_mixed_firmware() {
+        let camera = |firmware: Option<&str>| CameraInfo { firmware: firmware.map(Into::into), ..Default::default() };
+        assert!(!mixed_firmware(&[camera(Some("H21.01")), camera(None), camera(Some("H21.01"))]));
+        assert!(mixed_firmware(&[camera(Some("H21.01")), camera(Some("H22.01"))]));
+    }
+
+    #[test]
+    fn test_metadata_format() {
+        let mut fields = vec![0u8; 8];
+        fields.extend(b"\0application/x-mdpm\0");
+        assert_eq!(metadata_format(&SampleEntry { codec: "mett".into(), fields, ..Default::default() }), "application/x-mdpm");
+        assert_eq!(metadata_format(&SampleEntry { codec: "gpmd".into(), fields: vec![0; 8], ..Default::default() }), "gpmd");
+    }
+}
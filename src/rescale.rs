@@ -0,0 +1,214 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::collections::HashMap;
+use std::io::Result;
+use crate::desc_reader::{ Desc, TrackDesc };
+use crate::diagnostics::diag;
+
+/// `value` converted from timescale `from` to `to`, rounded to the nearest unit
+pub(crate) fn rescale(value: u64, from: u32, to: u32) -> u64 {
+    ((value as u128 * to as u128 + from as u128 / 2) / from.max(1) as u128) as u64
+}
+
+/// Timing error (in seconds) of `value` converted from timescale `from` to `to`
+fn rescale_error(value: u64, from: u32, to: u32) -> f64 {
+    rescale(value, from, to) as f64 / to.max(1) as f64 - value as f64 / from.max(1) as f64
+}
+
+/// Convert the merged description to the requested movie and media timescales.
+/// Durations are rescaled from their accumulated start and end times, so the rounding doesn't drift over long tracks.
+/// The error of each conversion adds to the rounding error of the track
+pub(crate) fn apply_timescales(desc: &mut Desc, movie_timescale: Option<u32>, track_timescales: &HashMap<u32, u32>) -> Result<()> {
+    let old_movie = desc.moov_mvhd_timescale;
+    let new_movie = movie_timescale.filter(|x| *x != old_movie && old_movie > 0);
+    if let Some(new_movie) = new_movie {
+        diag!(Debug, "Rescaling the movie timescale from {old_movie} to {new_movie}");
+        desc.moov_mvhd_duration = rescale(desc.moov_mvhd_duration, old_movie, new_movie);
+        for track in &mut desc.moov_tracks {
+            let (mut end, mut error) = (0, 0.0f64);
+            for entry in &mut track.elst_entries {
+                let start = rescale(end, old_movie, new_movie);
+                end += entry.segment_duration;
+                entry.segment_duration = rescale(end, old_movie, new_movie) - start;
+                error = error.max(rescale_error(end, old_movie, new_movie).abs());
+            }
+            track.rounding_error += error;
+            if track.elst_entries.is_empty() {
+                track.tkhd_duration = rescale(track.tkhd_duration, old_movie, new_movie);
+            }
+            track.elst_segment_duration = rescale(track.elst_segment_duration, old_movie, new_movie);
+        }
+        desc.moov_mvhd_timescale = new_movie;
+    }
+
+    let mut changed = new_movie.is_some();
+    for track in &mut desc.moov_tracks {
+        let Some(&new_media) = track_timescales.get(&track.track_id) else { continue; };
+        if new_media == track.mdhd_timescale || track.mdhd_timescale == 0 { continue; }
+        rescale_media(track, new_media)?;
+        changed = true;
+    }
+    if changed {
+        let movie_timescale = desc.moov_mvhd_timescale;
+        for track in desc.moov_tracks.iter_mut().filter(|x| !x.elst_entries.is_empty()) {
+            track.update_edit_list_durations(movie_timescale);
+        }
+    }
+    Ok(())
+}
+
+/// Fail when the rounding error of a track exceeds `max_error`, e.g. for merges which must be sample-accurate
+pub(crate) fn check_rounding_error(desc: &Desc, max_error: f64) -> Result<()> {
+    for track in desc.moov_tracks.iter().filter(|x| !x.skip && !x.dropped) {
+        if track.rounding_error > max_error {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!(
+                "Timescale conversions shift the timing of track {} by up to {:.6}s, more than the allowed {max_error:.6}s", track.track_id, track.rounding_error
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Convert the samples of the file just read from its media timescale to the one of the first file, e.g. when a firmware update
+/// changed the timescale between chapters. The stts deltas are rescaled from their accumulated times, so the rounding doesn't drift
+pub(crate) fn rescale_file_media(track: &mut TrackDesc, file_index: usize) -> Result<()> {
+    let (from, to) = (track.file_mdhd_timescale, track.mdhd_timescale);
+    if from == to || from == 0 || to == 0 || track.skip { return Ok(()); }
+    diag!(Info, "Track {} of file {file_index} has timescale {from}, it's rescaled to {to}", track.track_id);
+    // Index of the first entry describing the samples of the file
+    let first_entry = |table: &[(u32, u32)]| {
+        let mut start = 0;
+        table.iter().position(|x| { start += x.0; start > track.sample_offset }).unwrap_or(table.len())
+    };
+
+    let first = first_entry(&track.stts);
+    let (mut end, mut error) = (0u64, 0.0f64);
+    let mut stts: Vec<(u32, u32)> = Vec::new();
+    for (count, delta) in track.stts.split_off(first) {
+        for _ in 0..count {
+            let start = rescale(end, from, to);
+            end += delta as u64;
+            error = error.max(rescale_error(end, from, to).abs());
+            let delta = u32::try_from(rescale(end, from, to) - start)
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Sample durations of track {} don't fit in timescale {to}", track.track_id)))?;
+            match stts.last_mut() {
+                Some(last) if last.1 == delta => last.0 += 1,
+                _ => stts.push((1, delta))
+            }
+        }
+    }
+    track.stts.extend(stts);
+
+    let ctts = track.ctts.iter().map(|x| (x.0, 0)).collect::<Vec<_>>();
+    let first = first_entry(&ctts);
+    let rescale_signed = |x: i64| if x < 0 { -(rescale(x.unsigned_abs(), from, to) as i64) } else { rescale(x as u64, from, to) as i64 };
+    for (_, offset) in &mut track.ctts[first..] {
+        *offset = rescale_signed(*offset as i64) as i32;
+    }
+    if let Some(cslg) = &mut track.cslg {
+        cslg.iter_mut().for_each(|x| *x = rescale_signed(*x));
+    }
+    if let Some((media_time, _)) = track.file_edit.as_mut().filter(|x| x.0 > 0) {
+        *media_time = rescale(*media_time as u64, from, to) as i64;
+    }
+    track.note_rounding_error(error);
+    Ok(())
+}
+
+fn rescale_media(track: &mut TrackDesc, new_media: u32) -> Result<()> {
+    let old_media = track.mdhd_timescale;
+    diag!(Debug, "Rescaling the media timescale of track {} from {old_media} to {new_media}", track.track_id);
+    if track.stts.iter().any(|(_, delta)| !(*delta as u64 * new_media as u64).is_multiple_of(old_media as u64)) {
+        diag!(Warn, "Sample durations of track {} can't be represented exactly in timescale {new_media}, they are rounded", track.track_id);
+    }
+
+    let mut stts: Vec<(u32, u32)> = Vec::with_capacity(track.stts.len());
+    let (mut end, mut error) = (0u64, 0.0f64);
+    for delta in track.sample_deltas() {
+        let start = rescale(end, old_media, new_media);
+        end += delta as u64;
+        error = error.max(rescale_error(end, old_media, new_media).abs());
+        let delta = u32::try_from(rescale(end, old_media, new_media) - start)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Sample durations of track {} don't fit in timescale {new_media}", track.track_id)))?;
+        match stts.last_mut() {
+            Some(last) if last.1 == delta => last.0 += 1,
+            _ => stts.push((1, delta))
+        }
+    }
+    track.stts = stts;
+    for (_, offset) in &mut track.ctts {
+        let rescaled = rescale(offset.unsigned_abs() as u64, old_media, new_media) as i32;
+        *offset = if *offset < 0 { -rescaled } else { rescaled };
+    }
+    track.mdhd_duration = rescale(track.mdhd_duration, old_media, new_media);
+    for entry in track.elst_entries.iter_mut().filter(|x| x.media_time >= 0) {
+        error = error.max(rescale_error(entry.media_time as u64, old_media, new_media).abs());
+        entry.media_time = rescale(entry.media_time as u64, old_media, new_media) as i64;
+    }
+    track.mdhd_timescale = new_media;
+    track.rounding_error += error;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::desc_reader::EditListEntry;
+
+    #[test]
+    fn test_apply_timescales() {
+        let entry = |segment_duration, media_time| EditListEntry { segment_duration, media_time, ..Default::default() };
+        let video = TrackDesc {
+            track_id: 1,
+            mdhd_timescale: 30000,
+            mdhd_duration: 6006,
+            stts: vec![(6, 1001)],
+            elst_entries: vec![entry(100, 0), entry(1000, -1), entry(100, 3003)],
+            ..Default::default()
+        };
+        let audio = TrackDesc { track_id: 2, mdhd_timescale: 48000, mdhd_duration: 3072, stts: vec![(3, 1024)], ..Default::default() };
+        let mut desc = Desc { moov_mvhd_timescale: 600, moov_mvhd_duration: 1200, moov_tracks: vec![video, audio], ..Default::default() };
+
+        apply_timescales(&mut desc, Some(90000), &HashMap::from([(1, 90000), (2, 44100)])).unwrap();
+        assert_eq!(desc.moov_mvhd_duration, 180000);
+        let video = &desc.moov_tracks[0];
+        assert_eq!(video.stts, vec![(6, 3003)]);
+        assert_eq!((video.mdhd_timescale, video.mdhd_duration), (90000, 18018));
+        assert_eq!(video.elst_entries, vec![entry(15000, 0), entry(150000, -1), entry(15000, 9009)]);
+        assert_eq!(video.elst_segment_duration, 180000);
+
+        // 1024 / 48000 * 44100 = 940.8, the total stays exact
+        let audio = &desc.moov_tracks[1];
+        assert_eq!(audio.stts, vec![(2, 941), (1, 940)]);
+        assert_eq!(audio.mdhd_duration, 2822);
+
+        // Half a sample of 44100 Hz at most
+        assert_eq!(desc.moov_tracks[0].rounding_error, 0.0);
+        assert!(desc.moov_tracks[1].rounding_error > 0.0 && desc.moov_tracks[1].rounding_error <= 0.5 / 44100.0);
+        assert!(check_rounding_error(&desc, 0.5 / 44100.0).is_ok());
+        assert!(check_rounding_error(&desc, 0.000001).is_err());
+    }
+
+    #[test]
+    fn test_differing_media_timescales() {
+        use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+        // The second chapter was recorded after a firmware update, with timescales of 90000 and 44100
+        let first = SyntheticMp4::new().track(SyntheticTrack { composition_offsets: vec![1001, 0], ..SyntheticTrack::video(30000, 1001, 30) }).track(SyntheticTrack::audio(48000, 47));
+        let second = SyntheticMp4::new().track(SyntheticTrack { composition_offsets: vec![3003, 0], ..SyntheticTrack::video(90000, 3003, 30) }).track(SyntheticTrack { sample_delta: 940, ..SyntheticTrack::audio(44100, 47) });
+        let mut files = [first.cursor(), second.cursor()];
+        let mut output = std::io::Cursor::new(Vec::new());
+        crate::join_file_streams(&mut files, &mut output, |_| {}).unwrap();
+        assert_eq!(crate::verify::check(&mut output).unwrap(), Default::default());
+
+        let desc = crate::desc_reader::read_file_desc(&mut output).unwrap();
+        let video = &desc.moov_tracks[0];
+        assert_eq!((video.mdhd_timescale, video.mdhd_duration), (30000, 60060));
+        assert_eq!(video.stts, vec![(60, 1001)]);
+        assert_eq!(video.composition_offsets().collect::<Vec<_>>(), [1001, 0].repeat(30));
+        // 940 / 44100 * 48000 = 1023.13, without drift
+        let audio = &desc.moov_tracks[1];
+        let second_half = audio.sample_deltas().skip(47).map(|x| x as u64).sum::<u64>();
+        assert_eq!(second_half, (47.0 * 940.0 / 44100.0 * 48000.0_f64).round() as u64);
+    }
+}
@@ -0,0 +1,162 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use crate::desc_reader::Desc;
+use crate::diagnostics::diag;
+
+/// Largest part of the mdat covered by one reference, the referenced size has 31 bits
+const MAX_REFERENCE_SIZE: u64 = 1 << 30;
+
+/// Segment index (sidx) of the merged mdat, written right before it: a reference to the data and duration of each input file,
+/// split at sync samples of the reference track where the data is larger than `MAX_REFERENCE_SIZE`. The reference track is the
+/// first video track, otherwise the first track. None when there's nothing to index or the files aren't in timeline order
+pub(crate) fn segment_index(desc: &Desc, num_files: usize) -> Option<Vec<u8>> {
+    segment_index_with_limit(desc, num_files, MAX_REFERENCE_SIZE)
+}
+
+fn segment_index_with_limit(desc: &Desc, num_files: usize, max_reference_size: u64) -> Option<Vec<u8>> {
+    let tracks = || desc.moov_tracks.iter().filter(|x| !x.dropped && !x.handler_type.is_empty() && x.stsz_count > 0);
+    let track = tracks().find(|x| x.handler_type == "vide").or_else(|| tracks().next())?;
+
+    // Start of the data of each file in the merged mdat payload
+    let mut file_data_starts = vec![None; num_files];
+    let mut data_size = 0;
+    for &(file_index, _, size) in &desc.mdat_position {
+        match file_index {
+            Some(i) if i < num_files => { file_data_starts[i].get_or_insert(data_size); data_size += size; },
+            Some(_) => { },
+            None => data_size += size
+        }
+    }
+
+    // Offset in the mdat payload, decode time and whether the reference track starts with a sync sample there
+    let samples = track.sample_infos();
+    let decode_starts = track.file_decode_starts();
+    let mut boundaries = Vec::new();
+    let mut earliest_presentation_time = None;
+    for (file_index, range) in track.file_sample_ranges.iter().enumerate() {
+        let (Some(Some(offset)), Some(&time)) = (file_data_starts.get(file_index), decode_starts.get(file_index)) else { continue; };
+        let Some(file_samples) = samples.get(range.start as usize..range.end as usize).filter(|x| !x.is_empty()) else { continue; };
+        boundaries.push((*offset, time, file_samples[0].is_sync));
+        earliest_presentation_time.get_or_insert(file_samples[0].decode_time as i64 + file_samples[0].composition_offset as i64);
+        let mut start = *offset;
+        let mut previous_sync = None;
+        for sample in file_samples.iter().filter(|x| x.is_sync) {
+            if sample.offset > start + max_reference_size {
+                if let Some((sync, sync_time)) = previous_sync.take() {
+                    boundaries.push((sync, sync_time, true));
+                    start = sync;
+                }
+            }
+            if sample.offset > start { previous_sync = Some((sample.offset, sample.decode_time)); }
+        }
+    }
+    let total_duration = track.stts.iter().map(|(count, delta)| *count as u64 * *delta as u64).sum::<u64>();
+    if boundaries.is_empty() || boundaries.len() > u16::MAX as usize || boundaries.windows(2).any(|x| x[1].0 < x[0].0 || x[1].1 < x[0].1) {
+        diag!(Warn, "The data of the files isn't in timeline order, no sidx written");
+        return None;
+    }
+
+    let mut references = Vec::with_capacity(boundaries.len());
+    for (i, &(offset, time, is_sync)) in boundaries.iter().enumerate() {
+        let (end, end_time) = boundaries.get(i + 1).map_or((data_size, total_duration), |x| (x.0, x.1));
+        let size = u32::try_from(end - offset).ok().filter(|x| *x < 1 << 31);
+        let duration = u32::try_from(end_time.saturating_sub(time)).ok();
+        let (Some(size), Some(duration)) = (size, duration) else {
+            diag!(Warn, "A segment of the merged file is too large for sidx, no sidx written");
+            return None;
+        };
+        // starts_with_SAP and SAP type 1 for a sync sample
+        let sap = if is_sync { 0x9000_0000u32 } else { 0 };
+        references.extend([size, duration, sap].iter().flat_map(|x| x.to_be_bytes()));
+    }
+    diag!(Debug, "Writing sidx with {} references", boundaries.len());
+
+    // On the presentation timeline, which starts at the media time of the edit list
+    let media_time = track.elst_entries.iter().find(|x| x.media_time >= 0).map_or(0, |x| x.media_time);
+    let earliest_presentation_time = earliest_presentation_time.map_or(0, |x| (x - media_time).max(0) as u64);
+
+    let track_id = desc.renumbered_track_ids.iter().find(|x| x.0 == track.track_id).map_or(track.track_id, |x| x.1);
+    let mut sidx = Vec::with_capacity(40 + references.len());
+    sidx.extend((40 + references.len() as u32).to_be_bytes());
+    sidx.extend(b"sidx");
+    sidx.extend(0x0100_0000u32.to_be_bytes()); // Version 1
+    sidx.extend(track_id.to_be_bytes());
+    sidx.extend(track.mdhd_timescale.to_be_bytes());
+    sidx.extend(earliest_presentation_time.to_be_bytes());
+    sidx.extend((16 + boundaries[0].0).to_be_bytes()); // First offset, from the end of the sidx past the mdat header
+    sidx.extend(0u16.to_be_bytes());
+    sidx.extend((boundaries.len() as u16).to_be_bytes());
+    sidx.extend(references);
+    Some(sidx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+
+    /// Referenced size, duration and SAP flags of each reference of a sidx box
+    fn references(sidx: &[u8]) -> Vec<[u32; 3]> {
+        sidx[40..].chunks_exact(12).map(|x| [0, 4, 8].map(|i| u32::from_be_bytes(x[i..i + 4].try_into().unwrap()))).collect()
+    }
+
+    #[test]
+    fn test_sidx() {
+        let file = SyntheticMp4::new().track(SyntheticTrack::audio(48000, 50)).track(SyntheticTrack::video(25, 1, 50));
+        let mut files = [file.cursor(), file.cursor()];
+        let mut output = Cursor::new(Vec::new());
+        let options = crate::MergeOptions::default().faststart(true).sidx(true);
+        crate::join_file_streams_with_options(&mut files, &mut output, &[None, None], &options, |_| {}).unwrap();
+        let merged = output.into_inner();
+        let report = crate::verify::check(&mut Cursor::new(&merged)).unwrap();
+        assert!(report.is_ok(), "{report:?}");
+
+        let structure = crate::inspect::read_structure(&mut Cursor::new(&merged)).unwrap();
+        let types = structure.boxes.iter().map(|x| x.header.typ_str()).collect::<Vec<_>>();
+        assert_eq!(types, ["ftyp", "moov", "sidx", "mdat"]);
+        let (sidx, mdat) = (structure.find(&["sidx"]).unwrap().header, structure.find(&["mdat"]).unwrap().header);
+        let sidx = &merged[sidx.offset as usize..sidx.end() as usize];
+        // The video track, with 25 frames per second
+        assert_eq!(u32::from_be_bytes(sidx[12..16].try_into().unwrap()), 2);
+        assert_eq!(u32::from_be_bytes(sidx[16..20].try_into().unwrap()), 25);
+        let refs = references(sidx);
+        assert_eq!(refs.len(), 2);
+        assert_eq!(refs[0][0] + refs[1][0], mdat.content_size() as u32);
+        assert_eq!(refs.iter().map(|x| x[1]).collect::<Vec<_>>(), [50, 50]);
+        assert!(refs.iter().all(|x| x[2] == 0x9000_0000));
+
+        // A single input isn't copied as is
+        let mut output = Cursor::new(Vec::new());
+        crate::join_file_streams_with_options(&mut [file.cursor()], &mut output, &[None], &options, |_| {}).unwrap();
+        let structure = crate::inspect::read_structure(&mut Cursor::new(output.into_inner())).unwrap();
+        assert!(structure.find(&["sidx"]).is_some());
+
+        // Split at the keyframes, every second
+        let mut files = [file.cursor(), file.cursor()];
+        let scan = crate::scan_files(&mut files, &[None, None], &crate::MergeOptions::default(), &|_| {}).unwrap();
+        let sidx = segment_index_with_limit(&scan.desc, 2, 1000).unwrap();
+        let refs = references(&sidx);
+        assert_eq!(refs.len(), 4);
+        assert_eq!(refs.iter().map(|x| x[1]).sum::<u32>(), 100);
+        assert!(refs.iter().all(|x| x[1] % 25 == 0 && x[2] == 0x9000_0000));
+
+        // B-frames present the first frame after its decode time, unless the edit list starts there
+        let mut video = SyntheticTrack::video(25, 1, 50);
+        video.composition_offsets = vec![1, 3, 0, 0];
+        let file = SyntheticMp4::new().track(video);
+        for (media_time, expected) in [(0, 1), (1, 0)] {
+            let options = options.clone().edit_list_editor(move |track: &crate::EditListTrack, entries: &mut Vec<crate::EditListEntry>| {
+                *entries = vec![crate::EditListEntry { segment_duration: track.movie_timescale as u64, media_time, ..Default::default() }];
+            });
+            let mut output = Cursor::new(Vec::new());
+            crate::join_file_streams_with_options(&mut [file.cursor(), file.cursor()], &mut output, &[None, None], &options, |_| {}).unwrap();
+            let merged = output.into_inner();
+            let structure = crate::inspect::read_structure(&mut Cursor::new(&merged)).unwrap();
+            let sidx = structure.find(&["sidx"]).unwrap().header;
+            let sidx = &merged[sidx.offset as usize..sidx.end() as usize];
+            assert_eq!(u64::from_be_bytes(sidx[20..28].try_into().unwrap()), expected);
+        }
+    }
+}
@@ -0,0 +1,324 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::ops::Range;
+use std::path::{ Path, PathBuf };
+use crate::desc_reader::{ Desc, TrackDesc, SampleInfo, EditListEntry };
+use crate::diagnostics::diag;
+
+// Conservative estimate of the moov size, used when deciding where to split
+const MOOV_OVERHEAD: u64 = 64 * 1024;
+const TABLE_BYTES_PER_SAMPLE: u64 = 4 + 8 + 8 + 4 + 8 + 12; // stsz, stts, ctts, stss, co64 and stsc in the worst case
+const TIME_EPSILON: f64 = 1e-9;
+
+/// A single output of a split merge
+pub(crate) struct DescPart {
+    pub desc: Desc,
+    pub time_range: Range<f64>,
+    pub track_sample_ranges: Vec<Range<u32>>,
+}
+
+/// Path of the split output with the given 0-based index, e.g. `out.mp4` -> `out_001.mp4`
+pub(crate) fn part_path(output: &Path, index: usize) -> PathBuf {
+    let stem = output.file_stem().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+    match output.extension() {
+        Some(ext) => output.with_file_name(format!("{stem}_{:03}.{}", index + 1, ext.to_string_lossy())),
+        None      => output.with_file_name(format!("{stem}_{:03}", index + 1)),
+    }
+}
+
+fn track_count(desc: &Desc) -> usize {
+    desc.moov_tracks.iter().rposition(|t| !t.handler_type.is_empty()).map(|x| x + 1).unwrap_or(0)
+}
+
+/// Split the merged description into parts starting at keyframes of the video track, each estimated to be smaller than `max_size`.
+pub(crate) fn split_desc(desc: &Desc, max_size: u64) -> Vec<DescPart> {
+    let num_tracks = track_count(desc);
+    let samples = desc.moov_tracks[..num_tracks].iter().map(|t| t.sample_infos()).collect::<Vec<_>>();
+    let seconds = |track: &TrackDesc, time: u64| time as f64 / track.mdhd_timescale.max(1) as f64;
+
+    // Byte cost of all samples ordered by time, including their sample table entries
+    let mut costs = desc.moov_tracks[..num_tracks].iter().zip(&samples)
+        .flat_map(|(t, s)| s.iter().map(move |x| (seconds(t, x.decode_time), x.size as u64 + TABLE_BYTES_PER_SAMPLE)))
+        .collect::<Vec<_>>();
+    costs.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut prefix = Vec::with_capacity(costs.len() + 1);
+    prefix.push(0u64);
+    for (_, cost) in &costs { prefix.push(prefix.last().unwrap() + cost); }
+    let bytes_before = |time: f64| prefix[costs.partition_point(|x| x.0 < time - TIME_EPSILON)];
+
+    // Split candidates are the keyframes of the video track (or the first track with samples)
+    let reference = desc.moov_tracks[..num_tracks].iter().position(|t| t.handler_type == "vide" && !t.skip && !t.stco.is_empty())
+        .or_else(|| samples.iter().position(|s| !s.is_empty()));
+    let candidates = reference.map(|r| samples[r].iter().filter(|x| x.is_sync).map(|x| seconds(&desc.moov_tracks[r], x.decode_time)).collect::<Vec<_>>()).unwrap_or_default();
+
+    let total_duration = desc.moov_tracks[..num_tracks].iter().zip(&samples)
+        .filter_map(|(t, s)| s.last().map(|x| seconds(t, x.decode_time + x.duration as u64)))
+        .fold(0.0, f64::max);
+
+    let budget = max_size.saturating_sub(MOOV_OVERHEAD);
+    let mut split_times = vec![0.0];
+    loop {
+        let start = *split_times.last().unwrap();
+        let start_bytes = bytes_before(start);
+        if prefix.last().unwrap() - start_bytes <= budget { break; }
+
+        let next = candidates.iter().copied().filter(|&c| c > start + TIME_EPSILON);
+        let fitting = next.clone().take_while(|&c| bytes_before(c) - start_bytes <= budget).last();
+        match fitting.or_else(|| next.clone().next()) {
+            Some(time) => {
+                if fitting.is_none() {
+                    diag!(Warn, "Part starting at {start:.3}s can't fit in {max_size} bytes, keyframes are too far apart");
+                }
+                split_times.push(time);
+            },
+            None => break
+        }
+    }
+
+    let mut parts = Vec::with_capacity(split_times.len());
+    for (i, start) in split_times.iter().enumerate() {
+        let end = split_times.get(i + 1).copied().unwrap_or(total_duration);
+        parts.push(slice_desc(desc, &samples, *start..end));
+    }
+    parts
+}
+
+/// Build the description of the part of the merged timeline within `time_range` (in seconds)
+fn slice_desc(desc: &Desc, samples: &[Vec<SampleInfo>], time_range: Range<f64>) -> DescPart {
+    let mvhd_timescale = desc.moov_mvhd_timescale.max(1) as u64;
+    let mut part = Desc {
+        moov_mvhd_timescale: desc.moov_mvhd_timescale,
+        moov_tracks: vec![TrackDesc::default(); desc.moov_tracks.len()],
+        output_creation_time: desc.output_creation_time,
+        output_modification_time: desc.output_modification_time,
+        copy: desc.copy,
+        synthesized_data: desc.synthesized_data.clone(),
+        appended_traks: desc.appended_traks.clone(),
+        renumbered_track_ids: desc.renumbered_track_ids.clone(),
+        ..Default::default()
+    };
+    let mut track_sample_ranges = Vec::with_capacity(samples.len());
+    let mut chunks = Vec::new(); // (merged offset, size, track, chunk index in the part)
+
+    for (track_index, (track, samples)) in desc.moov_tracks.iter().zip(samples).enumerate() {
+        let timescale = track.mdhd_timescale.max(1) as f64;
+        let first = samples.partition_point(|x| (x.decode_time as f64 / timescale) < time_range.start - TIME_EPSILON);
+        let last  = samples.partition_point(|x| (x.decode_time as f64 / timescale) < time_range.end - TIME_EPSILON);
+        let selected = &samples[first..last];
+        track_sample_ranges.push(first as u32..last as u32);
+
+        let new_track = &mut part.moov_tracks[track_index];
+        new_track.mdhd_timescale = track.mdhd_timescale;
+        new_track.handler_type = track.handler_type.clone();
+        new_track.track_id = track.track_id;
+        new_track.language = track.language.clone();
+        new_track.has_edts = track.has_edts;
+        new_track.dropped = track.dropped;
+        new_track.skip = track.skip;
+        new_track.stsz_sample_size = track.stsz_sample_size;
+        new_track.file_has_stss = track.file_has_stss.clone();
+        new_track.has_stps = track.has_stps;
+        new_track.has_ctts = track.has_ctts;
+        new_track.stps = track.stps.iter().filter(|x| (first as u32 + 1..=last as u32).contains(x)).map(|x| x - first as u32).collect();
+        new_track.sample_entries = track.sample_entries.clone();
+        new_track.stsz_count = selected.len() as u32;
+        if track.sdtp.len() >= last {
+            new_track.sdtp = track.sdtp[first..last].to_vec();
+        }
+
+        let mut prev_chunk = None;
+        for (i, sample) in selected.iter().enumerate() {
+            match new_track.stts.last_mut() {
+                Some(x) if x.1 == sample.duration => x.0 += 1,
+                _ => new_track.stts.push((1, sample.duration))
+            }
+            if !track.ctts.is_empty() {
+                match new_track.ctts.last_mut() {
+                    Some(x) if x.1 == sample.composition_offset => x.0 += 1,
+                    _ => new_track.ctts.push((1, sample.composition_offset))
+                }
+            }
+            if track.stsz_sample_size == 0 { new_track.stsz.push(sample.size); }
+            if !track.stss.is_empty() && sample.is_sync { new_track.stss.push(i as u32 + 1); }
+            new_track.mdhd_duration += sample.duration as u64;
+
+            if prev_chunk == Some(sample.chunk) {
+                let chunk: &mut (u64, u64, usize, usize) = chunks.last_mut().unwrap();
+                chunk.1 += sample.size as u64;
+                new_track.stsc.last_mut().unwrap().1 += 1;
+            } else {
+                chunks.push((sample.offset, sample.size as u64, track_index, new_track.stco.len()));
+                new_track.stco.push(0);
+                new_track.stsc.push((new_track.stco.len() as u32, 1, sample.description_index));
+            }
+            prev_chunk = Some(sample.chunk);
+        }
+        // Collapse stsc entries with the same layout as the previous chunk
+        let mut stsc: Vec<(u32, u32, u32)> = Vec::with_capacity(new_track.stsc.len());
+        for x in &new_track.stsc {
+            if stsc.last().map(|p| (p.1, p.2) != (x.1, x.2)).unwrap_or(true) { stsc.push(*x); }
+        }
+        new_track.stsc = stsc;
+
+        // Edit list clipped to the media range of this part
+        let media_range = selected.first().map(|x| x.decode_time).unwrap_or(0)..selected.last().map(|x| x.decode_time + x.duration as u64).unwrap_or(0);
+        new_track.elst_entries = clip_edit_list(&track.elst_entries, media_range, track.mdhd_timescale.max(1) as u64, mvhd_timescale);
+        new_track.elst_segment_duration = new_track.elst_entries.iter().map(|x| x.segment_duration).sum();
+        new_track.tkhd_duration = new_track.elst_segment_duration;
+        part.moov_mvhd_duration = part.moov_mvhd_duration.max(new_track.tkhd_duration);
+    }
+
+    // Lay out the chunks in the order they were in the source files and map them back to the source mdat ranges
+    chunks.sort_by_key(|x| x.0);
+    let mut position = 0;
+    let mut ranges: Vec<(u64, u64)> = Vec::new(); // (merged offset, size)
+    for (offset, size, track_index, chunk_index) in &chunks {
+        part.moov_tracks[*track_index].stco[*chunk_index] = position;
+        position += size;
+        match ranges.last_mut() {
+            Some(x) if x.0 + x.1 == *offset => x.1 += size,
+            _ => ranges.push((*offset, *size))
+        }
+    }
+    part.mdat_position = map_to_source_ranges(desc, &ranges);
+
+    DescPart { desc: part, time_range, track_sample_ranges }
+}
+
+/// Clip the edit list to the given media range (in media timescale), keeping the gaps between the clipped segments
+fn clip_edit_list(entries: &[EditListEntry], media_range: Range<u64>, media_timescale: u64, movie_timescale: u64) -> Vec<EditListEntry> {
+    if media_range.is_empty() { return Vec::new(); }
+    let to_movie = |x: u64| (x as f64 * movie_timescale as f64 / media_timescale as f64).round() as u64;
+    let to_media = |x: u64| (x as f64 * media_timescale as f64 / movie_timescale as f64).round() as u64;
+
+    if entries.is_empty() {
+        return vec![EditListEntry { segment_duration: to_movie(media_range.end - media_range.start), media_time: 0, ..Default::default() }];
+    }
+
+    let mut ret = Vec::new();
+    let mut pending_gap = 0;
+    for entry in entries {
+        if entry.media_time < 0 {
+            if !ret.is_empty() { pending_gap += entry.segment_duration; }
+            continue;
+        }
+        let start = (entry.media_time as u64).max(media_range.start);
+        let end = (entry.media_time as u64 + to_media(entry.segment_duration)).min(media_range.end);
+        if end > start {
+            if pending_gap > 0 {
+                ret.push(EditListEntry { segment_duration: pending_gap, media_time: -1, ..Default::default() });
+                pending_gap = 0;
+            }
+            ret.push(EditListEntry { segment_duration: to_movie(end - start), media_time: (start - media_range.start) as i64, ..Default::default() });
+        }
+    }
+    ret
+}
+
+/// Map ranges of the merged mdat payload to (file index, offset, size) ranges in the source files
+pub(crate) fn map_to_source_ranges(desc: &Desc, ranges: &[(u64, u64)]) -> Vec<(Option<usize>, u64, u64)> {
+    let mut ret = Vec::new();
+    for (mut offset, mut size) in ranges.iter().copied() {
+        let mut file_start = 0;
+        for (file_index, source_offset, source_size) in &desc.mdat_position {
+            if size > 0 && offset >= file_start && offset < file_start + source_size {
+                let len = size.min(file_start + source_size - offset);
+                ret.push((*file_index, source_offset + offset - file_start, len));
+                offset += len;
+                size -= len;
+            }
+            file_start += source_size;
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+
+    /// Merge two copies of `file` into parts of up to 200 KiB
+    fn split_merge(file: &SyntheticMp4, options: crate::MergeOptions) -> Vec<Vec<u8>> {
+        let mut files = [file.cursor(), file.cursor()];
+        let mut outputs = vec![Cursor::new(Vec::new()); 16];
+        let mut next_output = outputs.iter_mut();
+        let report = crate::join_file_streams_split(&mut files, |_| Ok(next_output.next().unwrap()), &[None, None], &options.max_output_size(200 * 1024), |_| {}).unwrap();
+        outputs.into_iter().take(report.parts.len()).map(Cursor::into_inner).collect()
+    }
+
+    #[test]
+    fn test_clip_edit_list_keeps_inner_gaps() {
+        let entries = vec![
+            EditListEntry { segment_duration: 2000, media_time: 0, ..Default::default() },
+            EditListEntry { segment_duration: 5000, media_time: -1, ..Default::default() },
+            EditListEntry { segment_duration: 3000, media_time: 2000, ..Default::default() },
+        ];
+        let clipped = clip_edit_list(&entries, 1000..4000, 1000, 1000);
+        assert_eq!(clipped.iter().map(|x| (x.segment_duration, x.media_time)).collect::<Vec<_>>(), vec![(1000, 0), (5000, -1), (2000, 1000)]);
+
+        let clipped = clip_edit_list(&entries, 2500..5000, 1000, 1000);
+        assert_eq!(clipped.iter().map(|x| (x.segment_duration, x.media_time)).collect::<Vec<_>>(), vec![(2500, 0)]);
+    }
+
+    #[test]
+    fn test_split_at_keyframes() {
+        let mut desc = Desc {
+            moov_mvhd_timescale: 1000,
+            mdat_position: vec![(Some(0), 1000, 50 * 1024), (Some(1), 500, 50 * 1024)],
+            ..Default::default()
+        };
+        // 100 samples of 1 KiB, one chunk each, keyframe every 10 samples
+        desc.moov_tracks.push(TrackDesc {
+            mdhd_timescale: 1000,
+            handler_type: "vide".into(),
+            stts: vec![(100, 100)],
+            stsz_sample_size: 1024,
+            stsz_count: 100,
+            stss: (0..10).map(|x| x * 10 + 1).collect(),
+            stps: (0..10).map(|x| x * 10 + 6).collect(),
+            stsc: vec![(1, 1, 1)],
+            stco: (0..100).map(|x| x * 1024).collect(),
+            ..Default::default()
+        });
+
+        let parts = split_desc(&desc, MOOV_OVERHEAD + 35 * (1024 + TABLE_BYTES_PER_SAMPLE));
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts.iter().map(|x| x.track_sample_ranges[0].clone()).collect::<Vec<_>>(), vec![0..30, 30..60, 60..90, 90..100]);
+
+        // Second part crosses the file boundary at sample 50
+        let second = &parts[1].desc;
+        assert_eq!(second.mdat_position, vec![(Some(0), 1000 + 30 * 1024, 20 * 1024), (Some(1), 500, 10 * 1024)]);
+        assert_eq!(second.moov_tracks[0].stss, vec![1, 11, 21]);
+        assert_eq!(second.moov_tracks[0].stps, vec![6, 16, 26]);
+        assert_eq!(second.moov_tracks[0].stco[1], 1024);
+        assert_eq!(second.moov_mvhd_duration, 3000);
+    }
+
+    #[test]
+    fn test_split_keeps_single_edit_list() {
+        let file = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 1500)).track(SyntheticTrack::audio(48000, 1500));
+        let parts = split_merge(&file, crate::MergeOptions::default());
+        assert!(parts.len() > 1);
+        for part in &parts {
+            let structure = crate::inspect::read_structure(&mut Cursor::new(part)).unwrap();
+            let moov = structure.find(&["moov"]).unwrap();
+            for trak in moov.children.iter().filter(|x| x.header.typ_str() == "trak") {
+                assert_eq!(trak.children.iter().filter(|x| x.header.typ_str() == "edts").count(), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_drops_tracks() {
+        let file = SyntheticMp4::new().track(SyntheticTrack::audio(48000, 1500)).track(SyntheticTrack::video(25, 1, 1500));
+        let parts = split_merge(&file, crate::MergeOptions::default().drop_track(crate::TrackFilter::Handler("soun".into())));
+        assert!(parts.len() > 1);
+        for part in &parts {
+            let tracks = crate::list_tracks(&mut Cursor::new(part)).unwrap();
+            assert_eq!(tracks.iter().map(|x| (x.handler_type.as_str(), x.track_id)).collect::<Vec<_>>(), [("vide", 1)]);
+        }
+    }
+}
@@ -0,0 +1,353 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, Result, SeekFrom };
+use byteorder::{ ReadBytesExt, BigEndian };
+use crate::{ fourcc, read_box, typ_to_str, diagnostics::diag };
+
+/// A single sample entry from moov/trak/mdia/minf/stbl/stsd
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SampleEntry {
+    pub codec: String,                   // Sample entry type, e.g. "avc1", "av01", "mp4a"
+    pub fields: Vec<u8>,                 // Fixed fields of the visual/audio sample entry, before the child boxes
+    pub boxes: Vec<(String, Vec<u8>)>,   // Child boxes (type, payload), e.g. the codec configuration
+}
+
+impl SampleEntry {
+    pub fn child(&self, typ: &str) -> Option<&[u8]> {
+        self.boxes.iter().find(|(t, _)| t == typ).map(|(_, data)| &data[..])
+    }
+
+    /// esds box of an mp4a entry. QuickTime sound sample descriptions have it inside a wave box
+    pub fn esds(&self) -> Option<&[u8]> {
+        self.child("esds").or_else(|| {
+            let wave = self.child("wave")?;
+            let mut pos = 0;
+            std::iter::from_fn(|| next_box(wave, &mut pos)).find(|x| x.0 == "esds").map(|x| x.1)
+        })
+    }
+}
+
+/// objectTypeIndication of the DecoderConfigDescriptor of an esds box, and the DecoderSpecificInfo (e.g. the AudioSpecificConfig) if it has one
+pub(crate) fn decoder_config(esds: &[u8]) -> Option<(u8, Option<Vec<u8>>)> {
+    let read_descriptor = |pos: &mut usize| -> Option<(u8, usize)> {
+        let tag = *esds.get(*pos)?;
+        *pos += 1;
+        let mut len = 0;
+        for _ in 0..4 {
+            let b = *esds.get(*pos)?;
+            *pos += 1;
+            len = (len << 7) | (b & 0x7f) as usize;
+            if b & 0x80 == 0 { break; }
+        }
+        Some((tag, len))
+    };
+    let mut pos = 4; // Version and flags
+    if read_descriptor(&mut pos)?.0 != 0x03 { return None; } // ES_Descriptor
+    let flags = *esds.get(pos + 2)?;
+    pos += 3;
+    if flags & 0x80 != 0 { pos += 2; } // dependsOn_ES_ID
+    if flags & 0x40 != 0 { pos += 1 + *esds.get(pos)? as usize; } // URL
+    if flags & 0x20 != 0 { pos += 2; } // OCR_ES_ID
+    if read_descriptor(&mut pos)?.0 != 0x04 { return None; } // DecoderConfigDescriptor
+    let object_type = *esds.get(pos)?;
+    // Stream type, buffer size and bitrates, which may differ between files
+    pos += 13;
+    let info = read_descriptor(&mut pos).filter(|x| x.0 == 0x05).and_then(|(_, len)| esds.get(pos..pos + len)).map(|x| x.to_vec());
+    Some((object_type, info))
+}
+
+/// Read the sample entries of every track, in the order of the trak boxes
+pub fn read_sample_entries<R: Read + Seek>(reader: &mut R) -> Result<Vec<Vec<SampleEntry>>> {
+    let mut tracks = Vec::new();
+    let mut handler = String::new();
+    reader.seek(SeekFrom::Start(0))?;
+    read_boxes(reader, u64::MAX, &mut tracks, &mut handler)?;
+    Ok(tracks)
+}
+
+fn read_boxes<R: Read + Seek>(reader: &mut R, max_read: u64, tracks: &mut Vec<Vec<SampleEntry>>, handler: &mut String) -> Result<()> {
+    let start_pos = reader.stream_position()?;
+    while reader.stream_position()? - start_pos < max_read {
+        let Ok((typ, offs, size, header_size)) = read_box(reader) else { break; };
+        if size == 0 || typ == 0 { break; }
+        let content_size = size - header_size as u64;
+
+        if typ == fourcc("trak") {
+            tracks.push(Vec::new());
+            handler.clear();
+        }
+        if typ == fourcc("moov") || typ == fourcc("trak") || typ == fourcc("mdia") || typ == fourcc("minf") || typ == fourcc("stbl") {
+            read_boxes(reader, content_size, tracks, handler)?;
+        } else if typ == fourcc("hdlr") {
+            reader.seek(SeekFrom::Current(8))?; // Version, flags and pre_defined
+            *handler = typ_to_str(reader.read_u32::<BigEndian>()?);
+        } else if typ == fourcc("stsd") {
+            let mut data = Vec::with_capacity(content_size as usize);
+            reader.take(content_size).read_to_end(&mut data)?;
+            if let Some(track) = tracks.last_mut() {
+                *track = parse_stsd(&data, handler);
+            }
+        }
+        reader.seek(SeekFrom::Start(offs + size))?;
+    }
+    Ok(())
+}
+
+fn parse_stsd(data: &[u8], handler: &str) -> Vec<SampleEntry> {
+    let mut entries = Vec::new();
+    let mut pos = 8; // Version, flags and entry_count
+    while let Some((typ, payload)) = next_box(data, &mut pos) {
+        let fields_len = match handler {
+            "vide" => 78,
+            // QuickTime sound sample description version 1 and 2 have more fields
+            "soun" => match payload.get(8..10).map(|x| u16::from_be_bytes([x[0], x[1]])) {
+                Some(1) => 28 + 16,
+                Some(2) => 28 + 36,
+                _ => 28
+            },
+            _ => payload.len()
+        }.min(payload.len());
+
+        let mut boxes = Vec::new();
+        let mut child_pos = fields_len;
+        while let Some((child_typ, child)) = next_box(payload, &mut child_pos) {
+            boxes.push((child_typ, child.to_vec()));
+        }
+        entries.push(SampleEntry { codec: typ, fields: payload[..fields_len].to_vec(), boxes });
+    }
+    entries
+}
+
+pub(crate) fn next_box<'a>(data: &'a [u8], pos: &mut usize) -> Option<(String, &'a [u8])> {
+    let header = data.get(*pos..*pos + 8)?;
+    let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let typ = typ_to_str(u32::from_be_bytes([header[4], header[5], header[6], header[7]]));
+    let payload = data.get(*pos + 8..*pos + size.max(8))?;
+    *pos += size.max(8);
+    Some((typ, payload))
+}
+
+/// Compare the sample entry of a file with the one of the first file.
+/// Returns a description of the difference if the samples can't be decoded with the first file's sample entry.
+pub fn compare_entries(first: &SampleEntry, other: &SampleEntry) -> Option<String> {
+    if first.codec != other.codec {
+        return Some(format!("codec differs ({} vs {})", first.codec, other.codec));
+    }
+    match first.codec.as_str() {
+        "av01" => compare_av1c(first.child("av1C")?, other.child("av1C")?),
+        "vp09" => compare_vpcc(first.child("vpcC")?, other.child("vpcC")?),
+        "ipcm" | "fpcm" => compare_pcm(first, other),
+        "avc1" | "avc3" => compare_parameter_sets("avcC", first, other),
+        "hvc1" | "hev1" => compare_parameter_sets("hvcC", first, other),
+        "mp4a" => compare_esds(first, other),
+        _ => None
+    }
+}
+
+/// avcC/hvcC: the parameter sets must be decodable with the ones of the first file. Harmless differences, e.g. of the VUI timing, are allowed
+fn compare_parameter_sets(typ: &str, first: &SampleEntry, other: &SampleEntry) -> Option<String> {
+    if first.child(typ).is_some() != other.child(typ).is_some() {
+        return Some(format!("{typ} is missing"));
+    }
+    let fatal = crate::param_sets::diff_entries(first, other, 0, 0).into_iter().filter(|x| x.fatal)
+        .map(|x| format!("{} {} differs ({} vs {})", x.parameter_set, x.field, x.first, x.other)).collect::<Vec<_>>();
+    (!fatal.is_empty()).then(|| fatal.join(", "))
+}
+
+/// esds: the object type and the AudioSpecificConfig must match, the bitrates may differ
+fn compare_esds(first: &SampleEntry, other: &SampleEntry) -> Option<String> {
+    match (first.esds().and_then(decoder_config), other.esds().and_then(decoder_config)) {
+        (Some((a_type, _)), Some((b_type, _))) if a_type != b_type => Some(format!("esds object type differs (0x{a_type:02x} vs 0x{b_type:02x})")),
+        (Some((_, a_info)), Some((_, b_info))) if a_info != b_info => Some("esds decoder specific info (AudioSpecificConfig) differs".into()),
+        (a, b) if a.is_some() != b.is_some() => Some("esds is missing".into()),
+        _ => None
+    }
+}
+
+fn compare_exact(typ: &str, first: &SampleEntry, other: &SampleEntry) -> Option<String> {
+    (first.child(typ) != other.child(typ)).then(|| format!("{typ} differs"))
+}
+
+/// AV1CodecConfigurationRecord: the profile, bit depth and chroma format must match.
+/// The level may differ between chapters, the first file's level is kept.
+fn compare_av1c(first: &[u8], other: &[u8]) -> Option<String> {
+    if first.len() < 4 || other.len() < 4 {
+        return (first != other).then(|| "av1C differs".into());
+    }
+    if first[1] >> 5 != other[1] >> 5 {
+        return Some(format!("AV1 profile differs ({} vs {})", first[1] >> 5, other[1] >> 5));
+    }
+    if first[2] & 0x7f != other[2] & 0x7f {
+        return Some("AV1 bit depth or chroma format differs".into());
+    }
+    if first[4..] != other[4..] {
+        return Some("AV1 sequence header differs".into());
+    }
+    if first[1] & 0x1f != other[1] & 0x1f {
+        diag!(Debug, "AV1 level differs ({} vs {})", first[1] & 0x1f, other[1] & 0x1f);
+    }
+    None
+}
+
+/// VPCodecConfigurationRecord: the profile, bit depth, chroma subsampling and colour description must match.
+/// Colour primaries, transfer characteristics and matrix coefficients of 2 mean "unspecified" and match anything.
+fn compare_vpcc(first: &[u8], other: &[u8]) -> Option<String> {
+    if first.len() < 12 || other.len() < 12 || first[0] != 1 || other[0] != 1 {
+        // Version 0 has a different layout, only ignore the level
+        let strip_level = |x: &[u8]| x.iter().enumerate().filter(|(i, _)| *i != 5).map(|(_, v)| *v).collect::<Vec<_>>();
+        return (strip_level(first) != strip_level(other)).then(|| "vpcC differs".into());
+    }
+    if first[4] != other[4] {
+        return Some(format!("VP9 profile differs ({} vs {})", first[4], other[4]));
+    }
+    if first[6] >> 4 != other[6] >> 4 {
+        return Some(format!("VP9 bit depth differs ({} vs {})", first[6] >> 4, other[6] >> 4));
+    }
+    if (first[6] >> 1) & 0x07 != (other[6] >> 1) & 0x07 {
+        return Some("VP9 chroma subsampling differs".into());
+    }
+    if first[6] & 1 != other[6] & 1 {
+        return Some("VP9 video full range flag differs".into());
+    }
+    const UNSPECIFIED: u8 = 2;
+    for (i, name) in [(7, "colour primaries"), (8, "transfer characteristics"), (9, "matrix coefficients")] {
+        if first[i] != other[i] && first[i] != UNSPECIFIED && other[i] != UNSPECIFIED {
+            return Some(format!("VP9 {name} differ ({} vs {})", first[i], other[i]));
+        }
+    }
+    if first[12..] != other[12..] {
+        return Some("VP9 codec initialization data differs".into());
+    }
+    if first[5] != other[5] {
+        diag!(Debug, "VP9 level differs ({} vs {})", first[5], other[5]);
+    }
+    None
+}
+
+/// ISO 23003-5 PCM: the channel count, sample rate and the pcmC format must match
+fn compare_pcm(first: &SampleEntry, other: &SampleEntry) -> Option<String> {
+    let channels    = |x: &SampleEntry| x.fields.get(16..18).map(|x| u16::from_be_bytes([x[0], x[1]]));
+    let sample_rate = |x: &SampleEntry| x.fields.get(24..28).map(|x| u32::from_be_bytes([x[0], x[1], x[2], x[3]]) >> 16);
+    if channels(first) != channels(other) {
+        return Some(format!("PCM channel count differs ({:?} vs {:?})", channels(first), channels(other)));
+    }
+    if sample_rate(first) != sample_rate(other) {
+        return Some(format!("PCM sample rate differs ({:?} vs {:?})", sample_rate(first), sample_rate(other)));
+    }
+    // pcmC: version and flags, format_flags (endianness), PCM_sample_size
+    let format = |x: &SampleEntry| x.child("pcmC").and_then(|x| x.get(4..6)).map(|x| (x[0] & 1, x[1]));
+    match (format(first), format(other)) {
+        (Some((a_le, a_size)), Some((b_le, b_size))) if a_le != b_le || a_size != b_size => {
+            Some(format!("PCM format differs ({a_size} bit {} vs {b_size} bit {})", if a_le == 1 { "LE" } else { "BE" }, if b_le == 1 { "LE" } else { "BE" }))
+        },
+        (a, b) if a.is_some() != b.is_some() => Some("pcmC is missing".into()),
+        _ => compare_exact("chnl", first, other)
+    }
+}
+
+/// Compare the sample entries of all tracks of a file with the first file and return the found problems
+pub fn check_compatibility(first: &[Vec<SampleEntry>], other: &[Vec<SampleEntry>]) -> Vec<String> {
+    let mut issues = Vec::new();
+    if first.len() != other.len() {
+        issues.push(format!("track count differs ({} vs {})", first.len(), other.len()));
+    }
+    for (track, (a, b)) in first.iter().zip(other).enumerate() {
+        if a.len() != b.len() {
+            issues.push(format!("track {track}: sample entry count differs ({} vs {})", a.len(), b.len()));
+        }
+    }
+    issues.extend(incompatible_entries(first, other));
+    issues
+}
+
+/// Sample entries of a file whose samples can't be decoded with the sample entries of the first file, one description per entry
+pub fn incompatible_entries(first: &[Vec<SampleEntry>], other: &[Vec<SampleEntry>]) -> Vec<String> {
+    first.iter().zip(other).enumerate()
+        .flat_map(|(track, (a, b))| a.iter().zip(b).filter_map(move |(a, b)| compare_entries(a, b).map(|issue| format!("track {track}: {issue}"))))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn av01(av1c: &[u8]) -> SampleEntry {
+        SampleEntry { codec: "av01".into(), fields: vec![0; 78], boxes: vec![("av1C".into(), av1c.to_vec())] }
+    }
+
+    #[test]
+    fn test_av1c_comparison() {
+        let base = [0x81, 0x08, 0x0c, 0x00, 0x0a, 0x0b];
+        assert_eq!(compare_entries(&av01(&base), &av01(&base)), None);
+        assert_eq!(compare_entries(&av01(&base), &av01(&[0x81, 0x09, 0x0c, 0x00, 0x0a, 0x0b])), None); // Level only
+        assert!(compare_entries(&av01(&base), &av01(&[0x81, 0x28, 0x0c, 0x00, 0x0a, 0x0b])).unwrap().contains("profile"));
+        assert!(compare_entries(&av01(&base), &av01(&[0x81, 0x08, 0x4c, 0x00, 0x0a, 0x0b])).unwrap().contains("bit depth"));
+        assert!(compare_entries(&av01(&base), &av01(&[0x81, 0x08, 0x0c, 0x00, 0x0a, 0x0c])).unwrap().contains("sequence header"));
+    }
+
+    #[test]
+    fn test_vpcc_comparison() {
+        let vp09 = |vpcc: &[u8]| SampleEntry { codec: "vp09".into(), fields: vec![0; 78], boxes: vec![("vpcC".into(), vpcc.to_vec())] };
+        let base = [1, 0, 0, 0, 0, 31, 0x82, 1, 1, 1, 0, 0];
+        assert_eq!(compare_entries(&vp09(&base), &vp09(&[1, 0, 0, 0, 0, 40, 0x82, 1, 1, 1, 0, 0])), None); // Level only
+        assert_eq!(compare_entries(&vp09(&base), &vp09(&[1, 0, 0, 0, 0, 31, 0x82, 2, 2, 2, 0, 0])), None); // Unspecified colour
+        assert!(compare_entries(&vp09(&base), &vp09(&[1, 0, 0, 0, 2, 31, 0xa2, 1, 1, 1, 0, 0])).unwrap().contains("profile"));
+        assert!(compare_entries(&vp09(&base), &vp09(&[1, 0, 0, 0, 0, 31, 0xa2, 1, 1, 1, 0, 0])).unwrap().contains("bit depth"));
+        assert!(compare_entries(&vp09(&base), &vp09(&[1, 0, 0, 0, 0, 31, 0x83, 1, 1, 1, 0, 0])).unwrap().contains("full range"));
+        assert!(compare_entries(&vp09(&base), &vp09(&[1, 0, 0, 0, 0, 31, 0x82, 9, 16, 9, 0, 0])).unwrap().contains("colour primaries"));
+    }
+
+    #[test]
+    fn test_pcm_comparison() {
+        let ipcm = |channels: u16, rate: u32, pcmc: &[u8]| {
+            let mut fields = vec![0u8; 28];
+            fields[16..18].copy_from_slice(&channels.to_be_bytes());
+            fields[24..28].copy_from_slice(&(rate << 16).to_be_bytes());
+            SampleEntry { codec: "ipcm".into(), fields, boxes: vec![("pcmC".into(), pcmc.to_vec())] }
+        };
+        assert_eq!(compare_entries(&ipcm(2, 48000, &[0, 0, 0, 0, 1, 24]), &ipcm(2, 48000, &[0, 0, 0, 0, 1, 24])), None);
+        assert!(compare_entries(&ipcm(2, 48000, &[0, 0, 0, 0, 1, 24]), &ipcm(1, 48000, &[0, 0, 0, 0, 1, 24])).unwrap().contains("channel"));
+        assert!(compare_entries(&ipcm(2, 48000, &[0, 0, 0, 0, 1, 24]), &ipcm(2, 44100, &[0, 0, 0, 0, 1, 24])).unwrap().contains("sample rate"));
+        assert!(compare_entries(&ipcm(2, 48000, &[0, 0, 0, 0, 1, 24]), &ipcm(2, 48000, &[0, 0, 0, 0, 0, 16])).unwrap().contains("format"));
+    }
+
+    #[test]
+    fn test_esds_comparison() {
+        // ES_Descriptor, DecoderConfigDescriptor with AAC LC and the bitrates, DecoderSpecificInfo
+        let mp4a = |bitrate: u32, config: [u8; 2]| {
+            let esds = [&[0, 0, 0, 0, 0x03, 25, 0, 1, 0, 0x04, 17, 0x40, 0x15, 0, 0, 0][..], &bitrate.to_be_bytes(), &bitrate.to_be_bytes(), &[0x05, 2], &config].concat();
+            SampleEntry { codec: "mp4a".into(), fields: vec![0; 28], boxes: vec![("esds".into(), esds)] }
+        };
+        assert_eq!(compare_entries(&mp4a(128000, [0x11, 0x90]), &mp4a(96000, [0x11, 0x90])), None);
+        assert!(compare_entries(&mp4a(128000, [0x11, 0x90]), &mp4a(128000, [0x11, 0x88])).unwrap().contains("AudioSpecificConfig"));
+        let wave = SampleEntry { boxes: vec![("wave".into(), crate::test_util::mp4_box(b"esds", &mp4a(128000, [0x11, 0x88]).boxes[0].1))], ..mp4a(0, [0; 2]) };
+        assert_eq!(compare_entries(&wave, &mp4a(128000, [0x11, 0x88])), None);
+
+        // A chapter recorded with another codec fails the merge
+        use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+        let first = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50)).track(SyntheticTrack::audio(48000, 50));
+        let mut second = first.clone();
+        second.tracks[0].codec = *b"hvc1";
+        let mut files = [first.cursor(), second.cursor()];
+        let err = crate::join_file_streams(&mut files, std::io::Cursor::new(Vec::new()), |_| {}).unwrap_err();
+        assert!(err.to_string().contains("track 0: codec differs (avc1 vs hvc1)"), "{err}");
+    }
+
+    #[test]
+    fn test_parse_visual_sample_entry() {
+        let mut entry = vec![0u8; 78];
+        entry.extend(12u32.to_be_bytes());
+        entry.extend(b"av1C");
+        entry.extend([0x81, 0x08, 0x0c, 0x00]);
+        let mut stsd = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        stsd.extend((entry.len() as u32 + 8).to_be_bytes());
+        stsd.extend(b"av01");
+        stsd.extend(entry);
+
+        let entries = parse_stsd(&stsd, "vide");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].codec, "av01");
+        assert_eq!(entries[0].child("av1C"), Some(&[0x81, 0x08, 0x0c, 0x00][..]));
+    }
+}
@@ -0,0 +1,96 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Write, Seek, SeekFrom, Result };
+
+/// Writer duplicating everything written to it to multiple sinks, e.g. a local scratch disk and a network archive,
+/// so the merge reads the inputs only once. Seeks are applied to every sink, reads come from the first one.
+/// Sinks which can't seek can be used with `join_file_streams_sequential`, which writes front to back
+pub struct TeeWriter<W> {
+    sinks: Vec<W>,
+}
+
+impl<W> TeeWriter<W> {
+    pub fn new(sinks: Vec<W>) -> Self {
+        Self { sinks }
+    }
+
+    pub fn into_inner(self) -> Vec<W> {
+        self.sinks
+    }
+
+    pub fn sinks(&self) -> &[W] {
+        &self.sinks
+    }
+}
+
+impl<W: Write> Write for TeeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        for sink in &mut self.sinks {
+            sink.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        for sink in &mut self.sinks {
+            sink.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Seek> Seek for TeeWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let mut position = None;
+        for (i, sink) in self.sinks.iter_mut().enumerate() {
+            let new_position = sink.seek(pos)?;
+            if position.is_some_and(|x| x != new_position) {
+                return Err(std::io::Error::other(format!("Output {i} is at {new_position}, the first one at {}", position.unwrap_or_default())));
+            }
+            position = Some(new_position);
+        }
+        Ok(position.unwrap_or_default())
+    }
+}
+
+impl<W: Read + Seek> Read for TeeWriter<W> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let Some((first, rest)) = self.sinks.split_first_mut() else { return Ok(0); };
+        let read = first.read(buf)?;
+        if read > 0 {
+            // Keep the other sinks at the same position
+            let position = first.stream_position()?;
+            for sink in rest {
+                sink.seek(SeekFrom::Start(position))?;
+            }
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+
+    #[test]
+    fn test_tee_output() {
+        let chapter = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50)).track(SyntheticTrack::audio(48000, 94));
+        let mut single = Cursor::new(Vec::new());
+        crate::join_file_streams(&mut [chapter.cursor(), chapter.cursor()], &mut single, |_| {}).unwrap();
+
+        let mut tee = TeeWriter::new(vec![Cursor::new(Vec::new()), Cursor::new(Vec::new())]);
+        crate::join_file_streams(&mut [chapter.cursor(), chapter.cursor()], &mut tee, |_| {}).unwrap();
+        for sink in tee.into_inner() {
+            assert!(sink.get_ref() == single.get_ref());
+        }
+
+        // Sinks which can't seek, written front to back
+        let mut tee = TeeWriter::new(vec![Vec::new(), Vec::new()]);
+        let options = crate::MergeOptions::default().precompute_layout(true);
+        crate::join_file_streams_sequential(&mut [chapter.cursor(), chapter.cursor()], &mut tee, &[None, None], &options, |_| {}).unwrap();
+        assert!(tee.sinks().iter().all(|x| x == single.get_ref()));
+    }
+}
@@ -0,0 +1,354 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, Result, SeekFrom };
+use std::ops::Range;
+use std::time::Duration;
+use crate::desc_reader::{ Desc, SampleInfo, TrackDesc };
+use crate::gpmf::{ self, parse_klv, write_klv, Klv, KlvHeader };
+use crate::{ fourcc, split, diagnostics::diag };
+
+const GPMF_TYPE_ID: u32 = fourcc("TYPE"); // TYPE = layout of the '?' complex structures
+const GPMF_GPS_TIME_ID: u32 = fourcc("GPSU");
+/// Added to every interpolated GPMF stream, before its data. Not part of the GoPro key list, so parsers skip it
+const GPMF_INTERPOLATED_ID: u32 = fourcc("INTP");
+
+/// Value types of the CAMM packets (little-endian, after the reserved and type fields), indexed by packet type
+const CAMM_LAYOUTS: [&[u8]; 8] = [b"fff", b"ll", b"fff", b"fff", b"fff", b"ddd", b"dlddfffffff", b"fff"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format { Gpmf, Camm }
+
+fn telemetry_format(track: &TrackDesc) -> Option<Format> {
+    match track.sample_entries.first()?.codec.as_str() {
+        "gpmd" => Some(Format::Gpmf),
+        "camm" => Some(Format::Camm),
+        _ => None
+    }
+}
+
+/// Fill the gaps between the files with interpolated samples in the GPMF and CAMM tracks, at the native rate of each track.
+/// The samples are added to the merged tables and the gap of the edit list is replaced with them.
+/// Returns the ranges of the merged timeline which were filled, in seconds.
+pub(crate) fn interpolate_gaps<R: Read + Seek>(files: &mut [(R, usize)], desc: &mut Desc) -> Result<Vec<Range<f64>>> {
+    let boundaries = desc.file_gaps.iter().enumerate().filter(|(_, gap)| **gap > 0.0).map(|(i, _)| i).collect::<Vec<_>>();
+    let mut ranges = Vec::new();
+    if boundaries.is_empty() { return Ok(ranges); }
+
+    for track_index in 0..desc.moov_tracks.len() {
+        let track = &desc.moov_tracks[track_index];
+        if track.skip || track.dropped || track.mdhd_timescale == 0 { continue; }
+        let Some(format) = telemetry_format(track) else { continue; };
+        let samples = track.sample_infos();
+        let timescale = track.mdhd_timescale as f64;
+        let file_sample_ranges = track.file_sample_ranges.clone();
+
+        // (file index, synthesized samples inserted after its samples)
+        let mut inserts: Vec<(usize, Vec<SampleInfo>)> = Vec::new();
+        for &file_index in &boundaries {
+            let (Some(prev), Some(next)) = (file_sample_ranges.get(file_index), file_sample_ranges.get(file_index + 1)) else { continue; };
+            let (Some(prev), Some(next)) = (samples.get(prev.start as usize..prev.end as usize), samples.get(next.start as usize..next.end as usize)) else { continue; };
+            let (Some(last), Some(first)) = (prev.last(), next.first()) else { continue; };
+
+            let spacing = if prev.len() > 1 { (last.decode_time - prev[0].decode_time) as f64 / (prev.len() - 1) as f64 } else { timescale };
+            let gap = (desc.file_gaps[file_index] * timescale).round() as u64;
+            let count = ((gap as f64 / spacing.max(1.0)).round() as u64).clamp(1, gap.max(1));
+
+            let payloads = match format {
+                Format::Gpmf => {
+                    let (template, target) = (read_sample(files, desc, last)?, read_sample(files, desc, first)?);
+                    (0..count).map(|k| interpolate_gpmf(&template, &target, k as f64 / count as f64, (k + 1) as f64 / count as f64)).collect::<Vec<_>>()
+                }
+                Format::Camm => {
+                    // The packet types of the last second are repeated, each interpolated towards the first packet of that type in the next file
+                    let pattern_start = (last.decode_time + last.duration as u64).saturating_sub(timescale as u64);
+                    let pattern = prev.iter().filter(|x| x.decode_time >= pattern_start).map(|x| read_sample(files, desc, x)).collect::<Result<Vec<_>>>()?;
+                    let targets = next.iter().take(pattern.len() * 2).map(|x| read_sample(files, desc, x)).collect::<Result<Vec<_>>>()?;
+                    (0..count as usize).map(|k| {
+                        let template = &pattern[k % pattern.len()];
+                        let target = targets.iter().find(|x| x.get(2..4) == template.get(2..4)).unwrap_or(template);
+                        interpolate_camm(template, target, (k + 1) as f64 / (count + 1) as f64)
+                    }).collect()
+                }
+            };
+
+            let mut synthesized = Vec::with_capacity(payloads.len());
+            for (k, payload) in payloads.into_iter().enumerate() {
+                let k = k as u64;
+                synthesized.push(SampleInfo {
+                    offset: desc.append_synthesized(&payload),
+                    size: payload.len() as u32,
+                    decode_time: first.decode_time + gap * k / count,
+                    duration: (gap * (k + 1) / count - gap * k / count) as u32,
+                    composition_offset: 0,
+                    chunk: 0,
+                    description_index: last.description_index,
+                    is_sync: true,
+                });
+            }
+            diag!(Debug, "Interpolated {count} samples of track {track_index} in the gap after file {file_index}");
+            inserts.push((file_index, synthesized));
+        }
+        if inserts.is_empty() { continue; }
+
+        let track = &mut desc.moov_tracks[track_index];
+        insert_samples(track, &samples, &inserts);
+
+        // Present the interpolated samples instead of the empty edits
+        let movie_timescale = desc.moov_mvhd_timescale.max(1) as f64;
+        let mut inserts = inserts.iter().map(|(file_index, x)| (*file_index, x[0].decode_time as i64, x.iter().map(|s| s.duration as i64).sum::<i64>())).peekable();
+        let mut gaps = boundaries.iter();
+        let (mut shift, mut time) = (0i64, 0u64);
+        for entry in &mut track.elst_entries {
+            if entry.media_time < 0 {
+                let gap_file = gaps.next();
+                if let Some((_, media_time, duration)) = inserts.next_if(|x| Some(&x.0) == gap_file) {
+                    entry.media_time = media_time + shift;
+                    shift += duration;
+                    let range = time as f64 / movie_timescale..(time + entry.segment_duration) as f64 / movie_timescale;
+                    if !ranges.contains(&range) { ranges.push(range); }
+                }
+            } else {
+                entry.media_time += shift;
+            }
+            time += entry.segment_duration;
+        }
+    }
+    ranges.sort_by(|a, b| a.start.total_cmp(&b.start));
+    Ok(ranges)
+}
+
+/// Read the data of a single sample of the merged track
+pub(crate) fn read_sample<R: Read + Seek>(files: &mut [(R, usize)], desc: &Desc, sample: &SampleInfo) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(sample.size as usize);
+    for (file_index, offset, size) in split::map_to_source_ranges(desc, &[(sample.offset, sample.size as u64)]) {
+        match file_index.and_then(|x| files.get_mut(x)) {
+            Some(file) => {
+                file.0.seek(SeekFrom::Start(offset))?;
+                (&mut file.0).take(size).read_to_end(&mut data)?;
+            }
+            None => data.extend_from_slice(desc.synthesized_data.get(offset as usize..(offset + size) as usize).unwrap_or_default())
+        }
+    }
+    Ok(data)
+}
+
+/// Insert the synthesized samples after the samples of the given files and rebuild the sample tables.
+/// Every synthesized sample gets its own chunk, since its data isn't next to the source samples
+fn insert_samples(track: &mut TrackDesc, samples: &[SampleInfo], inserts: &[(usize, Vec<SampleInfo>)]) {
+    let mut all = Vec::with_capacity(samples.len() + inserts.iter().map(|x| x.1.len()).sum::<usize>());
+    let mut prev_end = 0;
+    for (file_index, synthesized) in inserts {
+        let end = track.file_sample_ranges[*file_index].end as usize;
+        all.extend(samples[prev_end..end].iter().map(|x| (x, false)));
+        all.extend(synthesized.iter().map(|x| (x, true)));
+        prev_end = end;
+    }
+    all.extend(samples[prev_end..].iter().map(|x| (x, false)));
+
+    let mut added = 0;
+    for (file_index, range) in track.file_sample_ranges.iter_mut().enumerate() {
+        range.start += added;
+        added += inserts.iter().filter(|x| x.0 == file_index).map(|x| x.1.len() as u32).sum::<u32>();
+        range.end += added;
+    }
+
+    let constant_size = track.stsz_sample_size > 0 && all.iter().all(|x| x.0.size == track.stsz_sample_size);
+    let has_stss = !track.stss.is_empty();
+    let has_ctts = !track.ctts.is_empty();
+    let mut sdtp = (track.sdtp.len() == samples.len()).then(|| std::mem::take(&mut track.sdtp).into_iter());
+    track.stts.clear();
+    track.ctts.clear();
+    track.stsz.clear();
+    track.stss.clear();
+    track.stco.clear();
+    track.stsc.clear();
+    let mut prev_chunk = None;
+    for (i, (sample, synthesized)) in all.iter().enumerate() {
+        match track.stts.last_mut() {
+            Some(x) if x.1 == sample.duration => x.0 += 1,
+            _ => track.stts.push((1, sample.duration))
+        }
+        if has_ctts {
+            match track.ctts.last_mut() {
+                Some(x) if x.1 == sample.composition_offset => x.0 += 1,
+                _ => track.ctts.push((1, sample.composition_offset))
+            }
+        }
+        if !constant_size { track.stsz.push(sample.size); }
+        if has_stss && sample.is_sync { track.stss.push(i as u32 + 1); }
+        if let Some(sdtp) = &mut sdtp {
+            track.sdtp.push(if *synthesized { 0 } else { sdtp.next().unwrap_or(0) });
+        }
+        if *synthesized { track.mdhd_duration += sample.duration as u64; }
+
+        let chunk = (!synthesized).then_some(sample.chunk);
+        if chunk.is_some() && prev_chunk == chunk {
+            track.stsc.last_mut().unwrap().1 += 1;
+        } else {
+            track.stco.push(sample.offset);
+            track.stsc.push((track.stco.len() as u32, 1, sample.description_index));
+        }
+        prev_chunk = chunk;
+    }
+    track.stsc.dedup_by(|x, prev| (x.1, x.2) == (prev.1, prev.2));
+    if !constant_size { track.stsz_sample_size = 0; }
+    track.stsz_count = all.len() as u32;
+    track.sample_offset = track.stsz_count;
+    track.chunk_offset = track.stco.len() as u32;
+}
+
+/// Interpolate the last GPMF payload before the gap towards the first one after it.
+/// `start` and `end` are the part of the gap covered by this payload (0 to 1).
+fn interpolate_gpmf(template: &[u8], target: &[u8], start: f64, end: f64) -> Vec<u8> {
+    let target = parse_klv(target);
+    let mut payload = parse_klv(template);
+    if payload.is_empty() { return template.to_vec(); }
+    for (i, device) in payload.iter_mut().filter(|x| x.header.typ == 0).enumerate() {
+        let next_device = target.iter().filter(|x| x.header.typ == 0).nth(i);
+        for (j, stream) in device.children.iter_mut().filter(|x| x.header.typ == 0).enumerate() {
+            let next_stream = next_device.and_then(|x| x.children.iter().filter(|x| x.header.typ == 0).nth(j));
+            interpolate_stream(stream, next_stream, start, end);
+        }
+    }
+    let mut ret = Vec::with_capacity(template.len() + 64);
+    write_klv(&payload, &mut ret);
+    ret
+}
+
+/// Interpolate the data of a single GPMF stream (its last entry), holding the values which can't be interpolated
+fn interpolate_stream(stream: &mut Klv, next: Option<&Klv>, start: f64, end: f64) {
+    let Some(last) = stream.children.last().filter(|x| x.header.typ != 0 && x.header.struct_size > 0 && x.data.len() >= x.header.struct_size as usize) else { return; };
+    let size = last.header.struct_size as usize;
+    let types = if last.header.typ == b'?' {
+        stream.children.iter().find(|x| x.header.key == GPMF_TYPE_ID).map(|x| x.data.iter().copied().take_while(|x| *x != 0).collect()).unwrap_or_default()
+    } else {
+        element_size(last.header.typ).map(|x| vec![last.header.typ; size / x]).unwrap_or_default()
+    };
+    if types.iter().map(|x| element_size(*x).unwrap_or(usize::MAX)).try_fold(0usize, |a, x| a.checked_add(x)) == Some(size) {
+        let a = &last.data[last.data.len() - size..];
+        let b = next.and_then(|x| x.children.last())
+            .filter(|x| (x.header.key, x.header.typ, x.header.struct_size) == (last.header.key, last.header.typ, last.header.struct_size) && x.data.len() >= size)
+            .map(|x| &x.data[..size]).unwrap_or(a);
+        let repeat = last.header.repeat as usize;
+        let data = (0..repeat).flat_map(|k| interpolate_struct(&types, a, b, start + (end - start) * k as f64 / repeat as f64, false)).collect();
+        stream.children.last_mut().unwrap().data = data;
+    }
+
+    let gps_time = |klv: &Klv| klv.children.iter().find(|x| x.header.key == GPMF_GPS_TIME_ID).and_then(|x| gpmf::parse_gpsu(&x.data));
+    if let (Some(a), Some(b)) = (gps_time(stream), next.and_then(gps_time)) {
+        if let (Ok(diff), Some(gpsu)) = (b.duration_since(a), stream.children.iter_mut().find(|x| x.header.key == GPMF_GPS_TIME_ID)) {
+            gpsu.data = gpmf::format_gpsu(a + Duration::from_secs_f64(diff.as_secs_f64() * start));
+        }
+    }
+
+    let flag = Klv { header: KlvHeader { key: GPMF_INTERPOLATED_ID, typ: b'B', struct_size: 1, repeat: 1 }, data: vec![1], children: Vec::new() };
+    stream.children.insert(stream.children.len() - 1, flag);
+}
+
+/// Interpolate a CAMM packet towards the next packet of the same type
+fn interpolate_camm(template: &[u8], target: &[u8], fraction: f64) -> Vec<u8> {
+    let layout = template.get(2..4).and_then(|x| CAMM_LAYOUTS.get(u16::from_le_bytes([x[0], x[1]]) as usize));
+    let Some(layout) = layout.filter(|x| x.iter().map(|x| element_size(*x).unwrap_or(0)).sum::<usize>() + 4 == template.len() && target.len() == template.len()) else {
+        return template.to_vec();
+    };
+    let mut ret = template[..4].to_vec();
+    ret.extend(interpolate_struct(layout, &template[4..], &target[4..], fraction, true));
+    ret
+}
+
+/// Size of a single GPMF value of the given type
+fn element_size(typ: u8) -> Option<usize> {
+    Some(match typ {
+        b'b' | b'B' | b'c' => 1,
+        b's' | b'S' => 2,
+        b'l' | b'L' | b'f' | b'q' | b'F' => 4,
+        b'd' | b'j' | b'J' | b'Q' => 8,
+        b'G' | b'U' => 16,
+        _ => return None
+    })
+}
+
+/// Linear interpolation of a structure of values with the given types. Values which aren't numbers are taken from `a`
+fn interpolate_struct(types: &[u8], a: &[u8], b: &[u8], fraction: f64, little_endian: bool) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(a.len());
+    let mut pos = 0;
+    for typ in types {
+        let size = element_size(*typ).unwrap_or(0);
+        let (a, b) = (&a[pos..pos + size], &b[pos..pos + size]);
+        macro_rules! lerp {
+            ($t:ty, $round:expr) => {{
+                let read = |x: &[u8]| { let x = x.try_into().unwrap(); if little_endian { <$t>::from_le_bytes(x) } else { <$t>::from_be_bytes(x) } };
+                let (a, b) = (read(a) as f64, read(b) as f64);
+                let round: fn(f64) -> f64 = $round;
+                let value = round(a + (b - a) * fraction) as $t;
+                ret.extend(if little_endian { value.to_le_bytes() } else { value.to_be_bytes() });
+            }};
+        }
+        match typ {
+            b'b' => lerp!(i8, f64::round),
+            b'B' => lerp!(u8, f64::round),
+            b's' => lerp!(i16, f64::round),
+            b'S' => lerp!(u16, f64::round),
+            b'l' | b'q' => lerp!(i32, f64::round),
+            b'L' => lerp!(u32, f64::round),
+            b'j' | b'Q' => lerp!(i64, f64::round),
+            b'J' => lerp!(u64, f64::round),
+            b'f' => lerp!(f32, |x| x),
+            b'd' => lerp!(f64, |x| x),
+            _ => ret.extend_from_slice(a)
+        }
+        pos += size;
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpmf::klv;
+
+    fn payload(gpsu: &[u8], values: &[i16]) -> Vec<u8> {
+        let data = values.iter().flat_map(|x| x.to_be_bytes()).collect::<Vec<_>>();
+        let stream = [klv(b"GPSU", b'U', 16, gpsu), klv(b"GYRO", b's', 6, &data)].concat();
+        klv(b"DEVC", 0, 1, &klv(b"STRM", 0, 1, &stream))
+    }
+
+    #[test]
+    fn test_interpolate_gpmf() {
+        let template = payload(b"230615123456.000", &[0, 100, -100, 10, 110, -90]);
+        let target = payload(b"230615123506.000", &[20, 300, 100, 30, 310, 110]);
+        let interpolated = parse_klv(&interpolate_gpmf(&template, &target, 0.5, 1.0));
+        let stream = &interpolated[0].children[0].children;
+        assert_eq!(stream.iter().map(|x| x.header.key).collect::<Vec<_>>(), vec![fourcc("GPSU"), fourcc("INTP"), fourcc("GYRO")]);
+        assert_eq!(stream[0].data, b"230615123501.000");
+        // Both samples move from the last value before the gap (10, 110, -90) towards the first one after it (20, 300, 100)
+        let values = stream[2].data.chunks(2).map(|x| i16::from_be_bytes([x[0], x[1]])).collect::<Vec<_>>();
+        assert_eq!(values, vec![15, 205, 5, 18, 253, 53]);
+        assert_eq!(stream[2].header.repeat, 2);
+    }
+
+    #[test]
+    fn test_insert_samples() {
+        let mut track = TrackDesc {
+            mdhd_timescale: 1000,
+            mdhd_duration: 3000,
+            stts: vec![(3, 1000)],
+            stsz_sample_size: 10,
+            stsz_count: 3,
+            stco: vec![0, 20],
+            stsc: vec![(1, 2, 1), (2, 1, 1)],
+            file_sample_ranges: vec![0..2, 2..3],
+            ..Default::default()
+        };
+        let samples = track.sample_infos();
+        let synthesized = (0..2).map(|k| SampleInfo { offset: 30 + k * 12, size: 12, duration: 1500, description_index: 1, is_sync: true, ..Default::default() }).collect();
+        insert_samples(&mut track, &samples, &[(0, synthesized)]);
+        assert_eq!(track.stts, vec![(2, 1000), (2, 1500), (1, 1000)]);
+        assert_eq!((track.stsz_sample_size, track.stsz.clone()), (0, vec![10, 10, 12, 12, 10]));
+        assert_eq!(track.stco, vec![0, 30, 42, 20]);
+        assert_eq!(track.stsc, vec![(1, 2, 1), (2, 1, 1)]);
+        assert_eq!(track.file_sample_ranges, vec![0..4, 4..5]);
+        assert_eq!(track.mdhd_duration, 6000);
+    }
+}
@@ -0,0 +1,153 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, Result, SeekFrom, Cursor };
+use crate::desc_reader::{ Desc, TrackDesc };
+use crate::boxes::{ find_box, read_trak, track_id };
+use crate::stsd::{ self, SampleEntry };
+use crate::diagnostics::diag;
+
+/// Input whose moov is used as the template of the output: its boxes, track IDs and sample descriptions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TemplateSelection {
+    /// The first file in the merge order
+    #[default]
+    First,
+    /// The file at this index of the inputs passed to the merge
+    File(usize),
+    /// The file with the most tracks. Among files with the same number of tracks, the last one, which has the newest codec configuration
+    Auto,
+}
+
+/// Header boxes of a template track which are taken over by the output
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TemplateTrack {
+    pub track_id: u32,
+    pub has_edts: bool,
+    pub has_stps: bool,
+    pub has_ctts: bool,
+    pub sample_entries: Vec<SampleEntry>,
+}
+
+impl TemplateTrack {
+    pub(crate) fn from_trak(trak: &[u8]) -> Result<Self> {
+        Ok(Self {
+            track_id: track_id(trak).unwrap_or(0),
+            has_edts: find_box(&mut Cursor::new(trak), &["trak", "edts"])?.is_some(),
+            has_stps: find_box(&mut Cursor::new(trak), &["trak", "mdia", "minf", "stbl", "stps"])?.is_some(),
+            has_ctts: find_box(&mut Cursor::new(trak), &["trak", "mdia", "minf", "stbl", "ctts"])?.is_some(),
+            sample_entries: Vec::new(),
+        })
+    }
+}
+
+/// Index of the template in the merge order. `input_order` maps the merge order to the inputs
+pub(crate) fn select<R: Read + Seek>(files: &mut [(R, usize)], selection: TemplateSelection, input_order: &[usize]) -> Result<usize> {
+    let index = match selection {
+        TemplateSelection::First => 0,
+        // Validated by MergeOptions
+        TemplateSelection::File(input) => input_order.iter().position(|x| *x == input).unwrap_or(0),
+        TemplateSelection::Auto => {
+            let mut counts = Vec::with_capacity(files.len());
+            for (file, _) in files.iter_mut() {
+                counts.push(stsd::read_sample_entries(file)?.len());
+                file.seek(SeekFrom::Start(0))?;
+            }
+            let max = counts.iter().max().copied().unwrap_or(0);
+            counts.iter().rposition(|x| *x == max).unwrap_or(0)
+        }
+    };
+    if index > 0 {
+        diag!(Info, "Using file {index} as the template of the output");
+    }
+    Ok(index)
+}
+
+/// Read the tracks of the template file
+pub(crate) fn read_tracks<R: Read + Seek>(reader: &mut R) -> Result<Vec<TemplateTrack>> {
+    let entries = stsd::read_sample_entries(reader)?;
+    let mut tracks = Vec::with_capacity(entries.len());
+    for (index, sample_entries) in entries.into_iter().enumerate() {
+        let trak = read_trak(reader, index)?.unwrap_or_default();
+        tracks.push(TemplateTrack { sample_entries, ..TemplateTrack::from_trak(&trak)? });
+    }
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(tracks)
+}
+
+/// Take over the track IDs, the presence of edts, stps and ctts and the sample descriptions of the template,
+/// after the description of the first file was read
+pub(crate) fn apply(desc: &mut Desc, tracks: &[TemplateTrack]) {
+    for (track, template) in desc.moov_tracks.iter_mut().zip(tracks) {
+        apply_track(track, template.clone());
+    }
+}
+
+pub(crate) fn apply_track(track: &mut TrackDesc, template: TemplateTrack) {
+    track.track_id = template.track_id;
+    track.has_edts = template.has_edts;
+    track.has_stps = template.has_stps;
+    track.has_ctts = template.has_ctts;
+    track.sample_entries = template.sample_entries;
+}
+
+/// trak box of a timed metadata track with the given sample entry, with empty sample tables and durations,
+/// which are written from the track description like for the other tracks
+pub(crate) fn metadata_trak(track: &TrackDesc, sample_entry: &[u8], handler_name: &str, creation_time: u32) -> Vec<u8> {
+    let matrix = [0x10000u32, 0, 0, 0, 0x10000, 0, 0, 0, 0x40000000];
+    let mut tkhd = [creation_time, creation_time, track.track_id, 0, 0, 0, 0, 0, 0].iter().flat_map(|x| x.to_be_bytes()).collect::<Vec<_>>();
+    tkhd.extend(matrix.iter().flat_map(|x| x.to_be_bytes()));
+    tkhd.extend_from_slice(&[0; 8]); // Width and height
+    let mdhd = [creation_time, creation_time, track.mdhd_timescale, 0, 0x55c4_0000].iter().flat_map(|x| x.to_be_bytes()).collect::<Vec<_>>();
+    let hdlr = [&[0; 4][..], b"meta", &[0; 12], handler_name.as_bytes(), &[0]].concat();
+    let dref = full_box(b"dref", 0, &[&1u32.to_be_bytes()[..], &full_box(b"url ", 1, &[])].concat());
+    let stbl = [
+        full_box(b"stsd", 0, &[&1u32.to_be_bytes()[..], sample_entry].concat()),
+        full_box(b"stts", 0, &[0; 4]),
+        full_box(b"stsc", 0, &[0; 4]),
+        full_box(b"stsz", 0, &[0; 8]),
+        full_box(b"co64", 0, &[0; 4]),
+    ].concat();
+    let minf = [full_box(b"nmhd", 0, &[]), mp4_box(b"dinf", &dref), mp4_box(b"stbl", &stbl)].concat();
+    let mdia = [full_box(b"mdhd", 0, &mdhd), full_box(b"hdlr", 0, &hdlr), mp4_box(b"minf", &minf)].concat();
+    mp4_box(b"trak", &[full_box(b"tkhd", 3, &tkhd), mp4_box(b"mdia", &mdia)].concat())
+}
+
+pub(crate) fn mp4_box(typ: &[u8; 4], content: &[u8]) -> Vec<u8> {
+    [&(content.len() as u32 + 8).to_be_bytes()[..], typ, content].concat()
+}
+
+fn full_box(typ: &[u8; 4], flags: u32, content: &[u8]) -> Vec<u8> {
+    mp4_box(typ, &[&flags.to_be_bytes()[..], content].concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+
+    #[test]
+    fn test_template_selection() {
+        // The second chapter has a telemetry track and a different width in its video sample description
+        let first = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50));
+        let second = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50)).track(SyntheticTrack::metadata(*b"mett", 2));
+        let mut second_data = second.build();
+        let entry = second_data.windows(4).rposition(|x| x == b"avc1").unwrap();
+        second_data[entry + 28..][..2].copy_from_slice(&1280u16.to_be_bytes());
+        for (selection, width) in [(TemplateSelection::Auto, 1280), (TemplateSelection::File(1), 1280), (TemplateSelection::First, 1920)] {
+            let mut files = [first.cursor(), (Cursor::new(second_data.clone()), second_data.len())];
+            let mut output = Cursor::new(Vec::new());
+            let options = crate::MergeOptions::default().template(selection);
+            crate::join_file_streams_with_options(&mut files, &mut output, &[None, None], &options, |_| {}).unwrap();
+
+            let tracks = crate::list_tracks(&mut output).unwrap();
+            assert_eq!(tracks.iter().map(|x| (x.codec.as_str(), x.track_id, x.sample_count)).collect::<Vec<_>>(), vec![("avc1", 1, 100), ("mett", 2, 2)]);
+            assert_eq!(tracks[0].width, Some(width));
+            let index = crate::RandomAccessIndex::from_reader(&mut output).unwrap();
+            let sample = index.sample(0, 10).unwrap();
+            assert_eq!(&output.get_ref()[sample.offset as usize..][..sample.size as usize], &first.sample_data(0, 10)[..]);
+            let desc = crate::desc_reader::read_file_desc(&mut output).unwrap();
+            assert_eq!(desc.moov_tracks[1].file_edit, Some((0, 2.0)));
+        }
+    }
+}
@@ -0,0 +1,510 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+//! Generator of small synthetic MP4 files, to test merges without camera footage. Enabled with the `test-util` feature
+
+use std::io::Cursor;
+use std::time::SystemTime;
+use crate::desc_reader::system_time_to_mp4_time;
+
+/// A track of a synthetic file. Sample `i` has a duration of `sample_delta` and a size of `sample_size`,
+/// plus `i % 7` bytes with `variable_sample_sizes`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntheticTrack {
+    /// Handler type, e.g. `*b"vide"`
+    pub handler: [u8; 4],
+    /// Sample entry type, e.g. `*b"avc1"`
+    pub codec: [u8; 4],
+    pub timescale: u32,
+    pub sample_count: u32,
+    pub sample_delta: u32,
+    pub sample_size: u32,
+    /// Write the size of each sample to stsz instead of a single constant size
+    pub variable_sample_sizes: bool,
+    /// Samples in each chunk, the last chunk has the remainder
+    pub samples_per_chunk: u32,
+    /// Every n-th sample is a sync sample, written to stss. All samples are sync samples without it
+    pub sync_interval: Option<u32>,
+    /// media_time of a single-entry edit list, no edts without it
+    pub edit_list_media_time: Option<i64>,
+    /// Composition offsets repeated over the samples, e.g. `[1, 3, 0, 0]` for B-frames. No ctts when empty
+    pub composition_offsets: Vec<i32>,
+}
+
+impl SyntheticTrack {
+    /// avc1 video with a keyframe every second
+    pub fn video(timescale: u32, sample_delta: u32, sample_count: u32) -> Self {
+        Self {
+            handler: *b"vide",
+            codec: *b"avc1",
+            timescale,
+            sample_count,
+            sample_delta,
+            sample_size: 100,
+            variable_sample_sizes: true,
+            samples_per_chunk: 5,
+            sync_interval: Some((timescale / sample_delta.max(1)).max(1)),
+            edit_list_media_time: Some(0),
+            composition_offsets: Vec::new(),
+        }
+    }
+
+    /// 16-bit PCM audio with 1024 frames per sample
+    pub fn audio(sample_rate: u32, sample_count: u32) -> Self {
+        Self {
+            handler: *b"soun",
+            codec: *b"sowt",
+            timescale: sample_rate,
+            sample_count,
+            sample_delta: 1024,
+            sample_size: 40,
+            variable_sample_sizes: false,
+            samples_per_chunk: 10,
+            sync_interval: None,
+            edit_list_media_time: Some(0),
+            composition_offsets: Vec::new(),
+        }
+    }
+
+    /// Timed metadata, e.g. `*b"gpmd"` telemetry with one sample per second
+    pub fn metadata(codec: [u8; 4], sample_count: u32) -> Self {
+        Self {
+            handler: *b"meta",
+            codec,
+            timescale: 1000,
+            sample_count,
+            sample_delta: 1000,
+            sample_size: 64,
+            variable_sample_sizes: false,
+            samples_per_chunk: 1,
+            sync_interval: None,
+            edit_list_media_time: None,
+            composition_offsets: Vec::new(),
+        }
+    }
+
+    /// QuickTime timecode with a single sample of `duration` seconds. The sample holds the frame number 01:00:00:00
+    pub fn timecode(frames_per_second: u32, duration: u32) -> Self {
+        Self {
+            handler: *b"tmcd",
+            codec: *b"tmcd",
+            timescale: frames_per_second,
+            sample_count: 1,
+            sample_delta: frames_per_second * duration,
+            sample_size: 4,
+            variable_sample_sizes: false,
+            samples_per_chunk: 1,
+            sync_interval: None,
+            edit_list_media_time: Some(0),
+            composition_offsets: Vec::new(),
+        }
+    }
+
+    /// Duration in seconds
+    pub fn duration(&self) -> f64 {
+        (self.sample_count as u64 * self.sample_delta as u64) as f64 / self.timescale as f64
+    }
+
+    pub fn sample_size(&self, sample_index: u32) -> u32 {
+        self.sample_size + if self.variable_sample_sizes { sample_index % 7 } else { 0 }
+    }
+}
+
+/// Description of a synthetic MP4 file
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntheticMp4 {
+    pub tracks: Vec<SyntheticTrack>,
+    pub movie_timescale: u32,
+    /// Written to mvhd, tkhd and mdhd
+    pub creation_time: Option<SystemTime>,
+    /// Write the moov before the mdat, otherwise after it like cameras do
+    pub moov_first: bool,
+    /// 64-bit chunk offsets
+    pub co64: bool,
+    /// Serialized boxes written after the ftyp, e.g. vendor uuid boxes
+    pub top_level_boxes: Vec<Vec<u8>>,
+    /// Serialized boxes appended to the moov, e.g. udta
+    pub moov_boxes: Vec<Vec<u8>>,
+}
+
+impl Default for SyntheticMp4 {
+    fn default() -> Self {
+        Self { tracks: Vec::new(), movie_timescale: 1000, creation_time: None, moov_first: false, co64: false, top_level_boxes: Vec::new(), moov_boxes: Vec::new() }
+    }
+}
+
+impl SyntheticMp4 {
+    /// A file without tracks, add them with `track`
+    pub fn new() -> Self { Self::default() }
+
+    pub fn track(mut self, track: SyntheticTrack) -> Self { self.tracks.push(track); self }
+    pub fn movie_timescale(mut self, timescale: u32) -> Self { self.movie_timescale = timescale; self }
+    pub fn creation_time(mut self, time: SystemTime) -> Self { self.creation_time = Some(time); self }
+    pub fn moov_first(mut self, moov_first: bool) -> Self { self.moov_first = moov_first; self }
+    pub fn co64(mut self, co64: bool) -> Self { self.co64 = co64; self }
+
+    /// Duration of the longest track in seconds
+    pub fn duration(&self) -> f64 {
+        self.tracks.iter().map(|x| x.duration()).fold(0.0, f64::max)
+    }
+
+    /// Content of a sample: the track and sample index, followed by a repeated byte
+    pub fn sample_data(&self, track_index: usize, sample_index: u32) -> Vec<u8> {
+        let track = &self.tracks[track_index];
+        if &track.codec == b"tmcd" {
+            return (track.timescale * 3600 + sample_index * track.sample_delta).to_be_bytes().to_vec();
+        }
+        let size = self.tracks[track_index].sample_size(sample_index) as usize;
+        let mut data = vec![(track_index as u32 * 31 + sample_index) as u8; size];
+        let id = [(track_index as u32).to_be_bytes(), sample_index.to_be_bytes()].concat();
+        let len = id.len().min(size);
+        data[..len].copy_from_slice(&id[..len]);
+        data
+    }
+
+    /// Serialize the file
+    pub fn build(&self) -> Vec<u8> {
+        let head = [mp4_box(b"ftyp", &[&b"isom"[..], &512u32.to_be_bytes(), b"isomiso2avc1mp41"].concat()), self.top_level_boxes.concat()].concat();
+
+        // Chunks interleaved by their start time
+        let mut chunks = Vec::new(); // start time, track, first sample, number of samples
+        for (track_index, track) in self.tracks.iter().enumerate() {
+            for first in (0..track.sample_count).step_by(track.samples_per_chunk.max(1) as usize) {
+                let start = (first as u64 * track.sample_delta as u64) as f64 / track.timescale as f64;
+                chunks.push((start, track_index, first, track.samples_per_chunk.max(1).min(track.sample_count - first)));
+            }
+        }
+        chunks.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        let mut data = Vec::new();
+        let mut offsets = vec![Vec::new(); self.tracks.len()];
+        for &(_, track_index, first, count) in &chunks {
+            offsets[track_index].push(data.len() as u64);
+            for sample_index in first..first + count {
+                data.extend_from_slice(&self.sample_data(track_index, sample_index));
+            }
+        }
+        let mdat_header = if self.co64 { [&1u32.to_be_bytes()[..], b"mdat", &(16 + data.len() as u64).to_be_bytes()].concat() } else { [&(8 + data.len() as u32).to_be_bytes()[..], b"mdat"].concat() };
+
+        if self.moov_first {
+            // The size of the moov doesn't depend on the offsets
+            let moov_size = self.build_moov(&offsets, 0).len() as u64;
+            let moov = self.build_moov(&offsets, head.len() as u64 + moov_size + mdat_header.len() as u64);
+            [head, moov, mdat_header, data].concat()
+        } else {
+            let moov = self.build_moov(&offsets, (head.len() + mdat_header.len()) as u64);
+            [head, mdat_header, data, moov].concat()
+        }
+    }
+
+    /// Serialize the file as a fragmented MP4, like dashcams record: a moov without samples and with an mvex,
+    /// followed by a moof and an mdat for every `samples_per_fragment` samples of each track
+    pub fn build_fragmented(&self, samples_per_fragment: u32) -> Vec<u8> {
+        let mut empty = self.clone().moov_first(true);
+        for track in &mut empty.tracks { track.sample_count = 0; }
+        let mut data = empty.build();
+        let structure = crate::inspect::read_structure(&mut Cursor::new(&data)).unwrap();
+        let moov = structure.find(&["moov"]).unwrap().header;
+        let trex = (0..self.tracks.len() as u32).flat_map(|i| full_box(b"trex", 0, 0, &u32_table(&[i + 1, 1, 0, 0, 0]))).collect::<Vec<_>>();
+        let mvex = mp4_box(b"mvex", &trex);
+        data.splice(moov.end() as usize..moov.end() as usize, mvex.iter().copied());
+        data[moov.offset as usize..][..4].copy_from_slice(&(moov.size as u32 + mvex.len() as u32).to_be_bytes());
+
+        let samples_per_fragment = samples_per_fragment.max(1);
+        let fragments = self.tracks.iter().map(|x| x.sample_count.div_ceil(samples_per_fragment)).max().unwrap_or(0);
+        for fragment in 0..fragments {
+            let first = fragment * samples_per_fragment;
+            let build_moof = |data_start: u32| {
+                let mut moof = full_box(b"mfhd", 0, 0, &(fragment + 1).to_be_bytes());
+                let mut offset = data_start;
+                for (track_index, track) in self.tracks.iter().enumerate() {
+                    let samples = first.min(track.sample_count)..(first + samples_per_fragment).min(track.sample_count);
+                    let cto = !track.composition_offsets.is_empty();
+                    let mut trun = vec![samples.len() as u32, offset];
+                    for sample_index in samples.clone() {
+                        let sync = track.sync_interval.is_none_or(|x| sample_index % x.max(1) == 0);
+                        trun.extend_from_slice(&[track.sample_delta, track.sample_size(sample_index), if sync { 0x2000000 } else { 0x1010000 }]);
+                        if cto { trun.push(track.composition_offsets[sample_index as usize % track.composition_offsets.len()] as u32); }
+                        offset += track.sample_size(sample_index);
+                    }
+                    let decode_time = first.min(track.sample_count) as u64 * track.sample_delta as u64;
+                    let traf = [
+                        full_box(b"tfhd", 0, 0x20000, &(track_index as u32 + 1).to_be_bytes()),
+                        full_box(b"tfdt", 1, 0, &decode_time.to_be_bytes()),
+                        full_box(b"trun", 1, 0x701 | if cto { 0x800 } else { 0 }, &u32_table(&trun)),
+                    ].concat();
+                    moof.extend(mp4_box(b"traf", &traf));
+                }
+                mp4_box(b"moof", &moof)
+            };
+            // The size of the moof doesn't depend on the data offsets
+            let moof = build_moof(build_moof(0).len() as u32 + 8);
+            let mdat = self.tracks.iter().enumerate().flat_map(|(track_index, track)| {
+                (first.min(track.sample_count)..(first + samples_per_fragment).min(track.sample_count)).flat_map(move |x| self.sample_data(track_index, x))
+            }).collect::<Vec<_>>();
+            data.extend(moof);
+            data.extend(mp4_box(b"mdat", &mdat));
+        }
+        data
+    }
+
+    /// The file and its size, as expected by the `join_file_streams` functions
+    pub fn cursor(&self) -> (Cursor<Vec<u8>>, usize) {
+        let data = self.build();
+        let size = data.len();
+        (Cursor::new(data), size)
+    }
+
+    fn build_moov(&self, offsets: &[Vec<u64>], data_start: u64) -> Vec<u8> {
+        let time = self.creation_time.map(system_time_to_mp4_time).unwrap_or(0) as u32;
+        let movie_duration = |x: f64| (x * self.movie_timescale as f64).round() as u32;
+        let mut mvhd = [time, time, self.movie_timescale, movie_duration(self.duration()), 0x10000].iter().flat_map(|x| x.to_be_bytes()).collect::<Vec<_>>();
+        mvhd.extend_from_slice(&[1, 0]); // Volume
+        mvhd.extend_from_slice(&[0; 10]);
+        mvhd.extend(MATRIX.iter().flat_map(|x| x.to_be_bytes()));
+        mvhd.extend_from_slice(&[0; 24]);
+        mvhd.extend_from_slice(&(self.tracks.len() as u32 + 1).to_be_bytes());
+        let mut moov = full_box(b"mvhd", 0, 0, &mvhd);
+
+        for (track_index, track) in self.tracks.iter().enumerate() {
+            let video = &track.handler == b"vide";
+            let media_duration = track.sample_count * track.sample_delta;
+            let mut tkhd = [time, time, track_index as u32 + 1, 0, movie_duration(track.duration()), 0, 0, 0, 0].iter().flat_map(|x| x.to_be_bytes()).collect::<Vec<_>>();
+            tkhd.extend(MATRIX.iter().flat_map(|x| x.to_be_bytes()));
+            tkhd.extend_from_slice(&(if video { 1920u32 << 16 } else { 0 }).to_be_bytes());
+            tkhd.extend_from_slice(&(if video { 1080u32 << 16 } else { 0 }).to_be_bytes());
+            let mut trak = full_box(b"tkhd", 0, 3, &tkhd);
+
+            if let Some(media_time) = track.edit_list_media_time {
+                let segment = (media_duration as i64 - media_time).max(0) as f64 / track.timescale as f64;
+                let elst = [&1u32.to_be_bytes()[..], &movie_duration(segment).to_be_bytes(), &(media_time as i32).to_be_bytes(), &0x10000u32.to_be_bytes()].concat();
+                trak.extend(mp4_box(b"edts", &full_box(b"elst", 0, 0, &elst)));
+            }
+
+            let mdhd = [time, time, track.timescale, media_duration, 0x55c4_0000].iter().flat_map(|x| x.to_be_bytes()).collect::<Vec<_>>();
+            let hdlr = [&[0; 4][..], &track.handler, &[0; 12], b"Synthetic\0"].concat();
+            let media_header = match &track.handler {
+                b"vide" => full_box(b"vmhd", 0, 1, &[0; 8]),
+                b"soun" => full_box(b"smhd", 0, 0, &[0; 4]),
+                _ => full_box(b"nmhd", 0, 0, &[]),
+            };
+            let dref = full_box(b"dref", 0, 0, &[&1u32.to_be_bytes()[..], &full_box(b"url ", 0, 1, &[])].concat());
+            let minf = [media_header, mp4_box(b"dinf", &dref), self.build_stbl(track, &offsets[track_index], data_start)].concat();
+            let mdia = [full_box(b"mdhd", 0, 0, &mdhd), full_box(b"hdlr", 0, 0, &hdlr), mp4_box(b"minf", &minf)].concat();
+            trak.extend(mp4_box(b"mdia", &mdia));
+            moov.extend(mp4_box(b"trak", &trak));
+        }
+        moov.extend(self.moov_boxes.concat());
+        mp4_box(b"moov", &moov)
+    }
+
+    fn build_stbl(&self, track: &SyntheticTrack, offsets: &[u64], data_start: u64) -> Vec<u8> {
+        let entry = match &track.handler {
+            b"vide" => {
+                let mut entry = vec![0; 6];
+                entry.extend_from_slice(&1u16.to_be_bytes()); // data_reference_index
+                entry.extend_from_slice(&[0; 16]);
+                entry.extend_from_slice(&1920u16.to_be_bytes());
+                entry.extend_from_slice(&1080u16.to_be_bytes());
+                entry.extend_from_slice(&[0, 0x48, 0, 0, 0, 0x48, 0, 0, 0, 0, 0, 0, 0, 1]); // Resolution, reserved, frame count
+                entry.extend_from_slice(&[0; 32]); // Compressor name
+                entry.extend_from_slice(&[0, 24, 0xff, 0xff]); // Depth, color table
+                if &track.codec == b"avc1" {
+                    entry.extend(mp4_box(b"avcC", &[1, 0x64, 0, 0x28, 0xff, 0xe1, 0, 4, 0x67, 0x64, 0, 0x28, 1, 0, 4, 0x68, 0xee, 0x3c, 0x80]));
+                }
+                entry
+            },
+            b"soun" => {
+                let mut entry = vec![0; 6];
+                entry.extend_from_slice(&1u16.to_be_bytes());
+                entry.extend_from_slice(&[0; 8]);
+                entry.extend_from_slice(&[0, 2, 0, 16, 0, 0, 0, 0]); // Channels, sample size
+                entry.extend_from_slice(&(track.timescale << 16).to_be_bytes());
+                entry
+            },
+            b"tmcd" => {
+                let mut entry = vec![0; 6];
+                entry.extend_from_slice(&1u16.to_be_bytes());
+                entry.extend_from_slice(&[0; 4]);
+                entry.extend_from_slice(&0x2u32.to_be_bytes()); // 24 hour max
+                entry.extend_from_slice(&track.timescale.to_be_bytes());
+                entry.extend_from_slice(&1u32.to_be_bytes()); // Frame duration
+                entry.extend_from_slice(&[track.timescale as u8, 0]);
+                entry
+            },
+            _ => [&[0; 6][..], &1u16.to_be_bytes()].concat()
+        };
+        let stsd = full_box(b"stsd", 0, 0, &[&1u32.to_be_bytes()[..], &mp4_box(&track.codec, &entry)].concat());
+        let stts = full_box(b"stts", 0, 0, &u32_table(&[1, track.sample_count, track.sample_delta]));
+
+        let mut stbl = [stsd, stts].concat();
+        if !track.composition_offsets.is_empty() {
+            let mut ctts: Vec<(u32, i32)> = Vec::new();
+            for offset in track.composition_offsets.iter().cycle().take(track.sample_count as usize) {
+                match ctts.last_mut() {
+                    Some(x) if x.1 == *offset => x.0 += 1,
+                    _ => ctts.push((1, *offset))
+                }
+            }
+            let entries = ctts.iter().flat_map(|(count, offset)| [*count, *offset as u32]).collect::<Vec<_>>();
+            let version = track.composition_offsets.iter().any(|x| *x < 0) as u8;
+            stbl.extend(full_box(b"ctts", version, 0, &[&(ctts.len() as u32).to_be_bytes()[..], &u32_table(&entries)].concat()));
+        }
+        if let Some(interval) = track.sync_interval {
+            let sync = (0..track.sample_count).step_by(interval.max(1) as usize).map(|x| x + 1).collect::<Vec<_>>();
+            stbl.extend(full_box(b"stss", 0, 0, &[&(sync.len() as u32).to_be_bytes()[..], &u32_table(&sync)].concat()));
+        }
+        stbl.extend(if track.variable_sample_sizes {
+            let sizes = (0..track.sample_count).map(|x| track.sample_size(x)).collect::<Vec<_>>();
+            full_box(b"stsz", 0, 0, &[&0u32.to_be_bytes()[..], &track.sample_count.to_be_bytes(), &u32_table(&sizes)].concat())
+        } else {
+            full_box(b"stsz", 0, 0, &u32_table(&[track.sample_size, track.sample_count]))
+        });
+
+        let samples_per_chunk = track.samples_per_chunk.max(1);
+        let mut stsc = vec![1, samples_per_chunk.min(track.sample_count), 1];
+        if track.sample_count > samples_per_chunk && !track.sample_count.is_multiple_of(samples_per_chunk) {
+            stsc.extend_from_slice(&[offsets.len() as u32, track.sample_count % samples_per_chunk, 1]);
+        }
+        stbl.extend(full_box(b"stsc", 0, 0, &[&(stsc.len() as u32 / 3).to_be_bytes()[..], &u32_table(&stsc)].concat()));
+
+        let count = (offsets.len() as u32).to_be_bytes();
+        stbl.extend(if self.co64 {
+            full_box(b"co64", 0, 0, &[&count[..], &offsets.iter().flat_map(|x| (data_start + x).to_be_bytes()).collect::<Vec<_>>()].concat())
+        } else {
+            full_box(b"stco", 0, 0, &[&count[..], &offsets.iter().flat_map(|x| (data_start as u32 + *x as u32).to_be_bytes()).collect::<Vec<_>>()].concat())
+        });
+        mp4_box(b"stbl", &stbl)
+    }
+}
+
+/// Identity matrix of mvhd and tkhd
+const MATRIX: [u32; 9] = [0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x40000000];
+
+/// Serialize a box
+pub fn mp4_box(typ: &[u8; 4], content: &[u8]) -> Vec<u8> {
+    [&(8 + content.len() as u32).to_be_bytes()[..], typ, content].concat()
+}
+
+fn full_box(typ: &[u8; 4], version: u8, flags: u32, content: &[u8]) -> Vec<u8> {
+    mp4_box(typ, &[&((version as u32) << 24 | flags).to_be_bytes()[..], content].concat())
+}
+
+fn u32_table(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|x| x.to_be_bytes()).collect()
+}
+
+/// Merge `chapters` in memory, parse the output and compare every track and sample with the values expected from the generator:
+/// the sample count and duration of each track, and the decode time, size, sync flag and data at the chunk offset of each sample.
+/// The chapters need the same tracks and timescales. Returns a description of the first mismatch
+pub fn check_round_trip(chapters: &[SyntheticMp4]) -> std::result::Result<(), String> {
+    let mut files = chapters.iter().map(|x| x.cursor()).collect::<Vec<_>>();
+    let mut output = Cursor::new(Vec::new());
+    crate::join_file_streams_with_options(&mut files, &mut output, &vec![None; chapters.len()], &Default::default(), |_| {}).map_err(|e| format!("Merge failed: {e}"))?;
+    let index = crate::RandomAccessIndex::from_reader(&mut output).map_err(|e| format!("Output can't be parsed: {e}"))?;
+    let tracks = crate::list_tracks(&mut output).map_err(|e| format!("Output can't be parsed: {e}"))?;
+    let output = output.into_inner();
+
+    let Some(first) = chapters.first() else { return Ok(()); };
+    if index.track_count() != first.tracks.len() {
+        return Err(format!("Expected {} tracks, got {}", first.tracks.len(), index.track_count()));
+    }
+    for (track_index, track) in first.tracks.iter().enumerate() {
+        let mut merged = 0;
+        let mut start_ticks = 0u64;
+        for (chapter_index, chapter) in chapters.iter().enumerate() {
+            let chapter_track = &chapter.tracks[track_index];
+            for sample_index in 0..chapter_track.sample_count {
+                let context = || format!("Track {track_index}, sample {sample_index} of chapter {chapter_index}");
+                let location = index.sample(track_index, merged).ok_or_else(|| format!("{}: missing from the output", context()))?;
+                let time = (location.time * track.timescale as f64).round() as u64;
+                let expected_time = start_ticks + sample_index as u64 * chapter_track.sample_delta as u64;
+                if time != expected_time {
+                    return Err(format!("{}: decode time {time}, expected {expected_time}", context()));
+                }
+                let expected_sync = chapter_track.sync_interval.is_none_or(|x| sample_index % x.max(1) == 0);
+                if location.is_sync != expected_sync {
+                    return Err(format!("{}: sync {}, expected {expected_sync}", context(), location.is_sync));
+                }
+                let expected_data = chapter.sample_data(track_index, sample_index);
+                let data = output.get(location.offset as usize..location.offset as usize + location.size as usize);
+                if data != Some(&expected_data[..]) {
+                    return Err(format!("{}: wrong data at offset {} with size {}, expected size {}", context(), location.offset, location.size, expected_data.len()));
+                }
+                merged += 1;
+            }
+            start_ticks += chapter_track.sample_count as u64 * chapter_track.sample_delta as u64;
+        }
+        if index.sample_count(track_index) != merged {
+            return Err(format!("Track {track_index}: {} samples, expected {merged}", index.sample_count(track_index)));
+        }
+        let duration = tracks.get(track_index).map(|x| x.duration).unwrap_or_default();
+        let expected_duration = start_ticks as f64 / track.timescale as f64;
+        if (duration - expected_duration).abs() > 0.5 / track.timescale as f64 {
+            return Err(format!("Track {track_index}: duration {duration}, expected {expected_duration}"));
+        }
+    }
+    Ok(())
+}
+
+/// Chapters of a recording for property-based tests: 1 to `max_chapters` files with the same video, audio and optionally metadata track,
+/// each with its own sample counts, chunk sizes, stsz and stss shapes, 32 or 64-bit chunk offsets and moov position
+pub fn arb_chapters(max_chapters: usize) -> impl proptest::strategy::Strategy<Value = Vec<SyntheticMp4>> {
+    use proptest::prelude::*;
+    let tracks = (prop::sample::select(vec![(24000, 1001), (30000, 1001), (90000, 3000), (1000, 33)]), prop::sample::select(vec![44100, 48000]), any::<bool>())
+        .prop_map(|((timescale, delta), sample_rate, metadata)| {
+            let mut tracks = vec![SyntheticTrack::video(timescale, delta, 1), SyntheticTrack::audio(sample_rate, 1)];
+            if metadata { tracks.push(SyntheticTrack::metadata(*b"gpmd", 1)); }
+            tracks
+        });
+    let track_shape = (1..200u32, 1..12u32, any::<bool>(), prop::option::of(1..40u32));
+    let chapter_shape = (prop::collection::vec(track_shape, 3), any::<bool>(), any::<bool>());
+    (tracks, prop::collection::vec(chapter_shape, 1..=max_chapters.max(1))).prop_map(|(tracks, chapters)| {
+        chapters.into_iter().map(|(shapes, moov_first, co64)| {
+            let tracks = tracks.iter().zip(shapes).map(|(track, (sample_count, samples_per_chunk, variable_sample_sizes, sync_interval))| SyntheticTrack {
+                sample_count,
+                samples_per_chunk,
+                variable_sample_sizes,
+                sync_interval: if &track.handler == b"vide" { sync_interval } else { None },
+                ..track.clone()
+            }).collect();
+            SyntheticMp4 { tracks, moov_first, co64, ..Default::default() }
+        }).collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_merge_synthetic_files() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let first = SyntheticMp4::new().track(SyntheticTrack::video(30000, 1001, 60)).track(SyntheticTrack::audio(48000, 94)).creation_time(start);
+        let second = SyntheticMp4 { moov_first: true, co64: true, ..first.clone() }.creation_time(start + Duration::from_secs(4));
+
+        let tracks = crate::list_tracks(&mut first.cursor().0).unwrap();
+        assert_eq!(tracks.iter().map(|x| (x.codec.as_str(), x.sample_count)).collect::<Vec<_>>(), vec![("avc1", 60), ("sowt", 94)]);
+        assert_eq!((tracks[0].width, tracks[1].sample_rate), (Some(1920), Some(48000.0)));
+
+        let mut files = [first.cursor(), second.cursor()];
+        let times = [Some(start), Some(start + Duration::from_secs(4))];
+        let mut output = Cursor::new(Vec::new());
+        let report = crate::join_file_streams_with_options(&mut files, &mut output, &times, &Default::default(), |_| {}).unwrap();
+        assert_eq!(report.tracks.iter().map(|x| x.sample_count).collect::<Vec<_>>(), vec![120, 188]);
+        assert!((report.gaps[0] - (4.0 - first.duration())).abs() < 0.01);
+
+        // The last sample of the second file is copied to the merged sample data
+        let output = output.into_inner();
+        let last = second.sample_data(1, 93);
+        assert!(output.windows(last.len()).any(|x| x == last));
+    }
+
+    proptest::proptest! {
+        #![proptest_config(proptest::prelude::ProptestConfig::with_cases(32))]
+        #[test]
+        fn test_round_trip(chapters in arb_chapters(4)) {
+            proptest::prop_assert_eq!(check_round_trip(&chapters), Ok(()));
+        }
+    }
+}
@@ -0,0 +1,186 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, Result, SeekFrom };
+use crate::desc_reader::Desc;
+use crate::stsd::SampleEntry;
+use crate::{ split, telemetry, diagnostics::diag };
+
+// Flags of the tmcd sample description
+const FLAG_DROP_FRAME: u32 = 0x1;
+const FLAG_24_HOUR_MAX: u32 = 0x2;
+const FLAG_NEGATIVE_OK: u32 = 0x4;
+const FLAG_COUNTER: u32 = 0x8;
+
+/// Timecode format from the tmcd sample description
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimecodeFormat {
+    pub flags: u32,
+    pub timescale: u32,
+    pub frame_duration: u32,
+    /// Nominal frames per second, e.g. 30 for 29.97 fps
+    pub frames_per_second: u8,
+}
+
+impl TimecodeFormat {
+    pub fn from_sample_entry(entry: &SampleEntry) -> Option<Self> {
+        if entry.codec != "tmcd" { return None; }
+        // Reserved, data_reference_index and another reserved field come first
+        let field = |pos: usize| entry.fields.get(pos..pos + 4).map(|x| u32::from_be_bytes([x[0], x[1], x[2], x[3]]));
+        let format = Self { flags: field(12)?, timescale: field(16)?, frame_duration: field(20)?, frames_per_second: *entry.fields.get(24)? };
+        (format.timescale > 0 && format.frame_duration > 0 && format.frames_per_second > 0).then_some(format)
+    }
+
+    pub fn drop_frame(&self) -> bool { self.flags & FLAG_DROP_FRAME != 0 }
+
+    /// Frame numbers skipped at the start of every minute except each tenth one, 2 for 29.97 fps and 4 for 59.94 fps
+    fn dropped_frames(&self) -> i64 {
+        if self.drop_frame() { (self.frames_per_second as i64 / 15).max(1) } else { 0 }
+    }
+
+    /// Number of frames in 24 hours of timecode
+    pub fn frames_per_day(&self) -> i64 {
+        self.frames_per_second as i64 * 86400 - self.dropped_frames() * (1440 - 144)
+    }
+
+    /// Frame number reached `elapsed` seconds after `frame`, following the limits of the flags
+    pub fn advance(&self, frame: i64, elapsed: f64) -> i64 {
+        let frames = (elapsed * self.timescale as f64 / self.frame_duration as f64).round() as i64;
+        let mut frame = frame + frames;
+        if self.flags & FLAG_24_HOUR_MAX != 0 {
+            frame = frame.rem_euclid(self.frames_per_day());
+        }
+        if self.flags & FLAG_NEGATIVE_OK == 0 {
+            frame = frame.max(0);
+        }
+        frame
+    }
+
+    /// Format a frame number as "hh:mm:ss:ff", or "hh:mm:ss;ff" for drop-frame timecode. Counters are formatted as the plain number
+    pub fn format(&self, frame: i64) -> String {
+        if self.flags & FLAG_COUNTER != 0 { return frame.to_string(); }
+        let sign = if frame < 0 { "-" } else { "" };
+        let mut frame = frame.abs();
+        let (fps, drop) = (self.frames_per_second as i64, self.dropped_frames());
+        if drop > 0 {
+            // Skip the dropped frame numbers
+            let frames_per_10_minutes = fps * 600 - drop * 9;
+            let frames_per_minute = fps * 60 - drop;
+            let (tens, rem) = (frame / frames_per_10_minutes, frame % frames_per_10_minutes);
+            frame += drop * 9 * tens + if rem > drop { drop * ((rem - drop) / frames_per_minute) } else { 0 };
+        }
+        let separator = if drop > 0 { ';' } else { ':' };
+        format!("{sign}{:02}:{:02}:{:02}{separator}{:02}", frame / (fps * 3600), frame / (fps * 60) % 60, frame / fps % 60, frame % fps)
+    }
+
+    /// Frame number of a timecode. Frame numbers skipped by drop-frame timecode are not valid
+    pub fn frame_number(&self, hours: u32, minutes: u32, seconds: u32, frames: u32) -> i64 {
+        let fps = self.frames_per_second as i64;
+        let total_minutes = hours as i64 * 60 + minutes as i64;
+        (total_minutes * 60 + seconds as i64) * fps + frames as i64 - self.dropped_frames() * (total_minutes - total_minutes / 10)
+    }
+
+    /// Frame number stored in the first 4 bytes of a timecode sample
+    fn read_frame(&self, data: [u8; 4]) -> i64 {
+        if self.flags & FLAG_NEGATIVE_OK != 0 { i32::from_be_bytes(data) as i64 } else { u32::from_be_bytes(data) as i64 }
+    }
+
+    fn write_frame(&self, frame: i64) -> [u8; 4] {
+        if self.flags & FLAG_NEGATIVE_OK != 0 { (frame as i32).to_be_bytes() } else { (frame as u32).to_be_bytes() }
+    }
+}
+
+/// Format and start frame of the first timecode track, read from the first sample of the first file
+pub(crate) fn read_start_timecode<R: Read + Seek>(files: &mut [(R, usize)], desc: &Desc) -> Result<Option<(TimecodeFormat, i64)>> {
+    for track in &desc.moov_tracks {
+        let Some(format) = track.sample_entries.first().and_then(TimecodeFormat::from_sample_entry) else { continue; };
+        let Some(sample) = track.sample_infos().into_iter().next().filter(|x| x.size >= 4) else { continue; };
+        let Some((Some(file_index), offset, _)) = split::map_to_source_ranges(desc, &[(sample.offset, 4)]).into_iter().next() else { continue; };
+        let Some(file) = files.get_mut(file_index) else { continue; };
+        let mut buf = [0u8; 4];
+        file.0.seek(SeekFrom::Start(offset))?;
+        file.0.read_exact(&mut buf)?;
+        let frame = format.read_frame(buf);
+        diag!(Debug, "Start timecode {} ({} fps{})", format.format(frame), format.frames_per_second, if format.drop_frame() { ", drop-frame" } else { "" });
+        return Ok(Some((format, frame)));
+    }
+    Ok(None)
+}
+
+/// Rewrite the samples of the timecode tracks of the later files, so the timecode continues from the first file over the merged timeline
+/// instead of restarting with the timecode of each file. The payloads go to the synthesized data, the samples keep their sizes and chunks
+pub(crate) fn merge_timecode_tracks<R: Read + Seek>(files: &mut [(R, usize)], desc: &mut Desc) -> Result<()> {
+    let starts = desc.file_timeline_starts();
+    for track_index in 0..desc.moov_tracks.len() {
+        let track = &desc.moov_tracks[track_index];
+        if track.skip || track.dropped { continue; }
+        let Some(format) = track.sample_entries.first().and_then(TimecodeFormat::from_sample_entry) else { continue; };
+        let samples = track.sample_infos();
+        let Some(first) = samples.first().filter(|x| x.size >= 4) else { continue; };
+        let start_frame = format.read_frame(telemetry::read_sample(files, desc, first)?[..4].try_into().unwrap());
+        let timescale = track.mdhd_timescale.max(1) as f64;
+        let mut prev_chunk = None;
+        for (file_index, range) in track.file_sample_ranges.clone().into_iter().enumerate().skip(1) {
+            let file_samples = samples.get(range.start as usize..range.end as usize).unwrap_or_default();
+            let Some(file_decode_time) = file_samples.first().map(|x| x.decode_time) else { continue; };
+            let trim = desc.file_trims.get(file_index).copied().unwrap_or(0.0);
+            let file_start = starts.get(file_index).copied().unwrap_or(0.0) - trim;
+            for sample in file_samples {
+                let mut data = telemetry::read_sample(files, desc, sample)?;
+                if data.len() >= 4 {
+                    let frame = format.advance(start_frame, file_start + (sample.decode_time - file_decode_time) as f64 / timescale);
+                    data[..4].copy_from_slice(&format.write_frame(frame));
+                }
+                let position = desc.append_synthesized(&data);
+                if prev_chunk != Some(sample.chunk) {
+                    desc.moov_tracks[track_index].stco[sample.chunk as usize] = position;
+                    prev_chunk = Some(sample.chunk);
+                }
+            }
+            diag!(Debug, "Timecode of file {file_index} in track {track_index} continues at {}", format.format(format.advance(start_frame, file_start)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drop_frame_timecode() {
+        let df = TimecodeFormat { flags: FLAG_DROP_FRAME | FLAG_24_HOUR_MAX, timescale: 30000, frame_duration: 1001, frames_per_second: 30 };
+        assert_eq!(df.format(1799), "00:00:59;29");
+        assert_eq!(df.format(1800), "00:01:00;02");
+        assert_eq!(df.format(17982), "00:10:00;00");
+        assert_eq!(df.frame_number(0, 1, 0, 2), 1800);
+        assert_eq!(df.frame_number(1, 0, 0, 0), 107892);
+        assert_eq!(df.frames_per_day(), 2589408);
+        // Drop-frame timecode follows the clock: an hour of 29.97 fps video ends at 01:00:00;00. The count wraps at 24 hours
+        assert_eq!(df.format(df.advance(0, 3600.0)), "01:00:00;00");
+        assert_eq!(df.format(df.advance(df.frame_number(23, 59, 59, 29), 1001.0 / 30000.0)), "00:00:00;00");
+
+        let ndf = TimecodeFormat { flags: 0, timescale: 25, frame_duration: 1, frames_per_second: 25 };
+        assert_eq!(ndf.format(ndf.advance(ndf.frame_number(10, 0, 0, 0), 61.0)), "10:01:01:00");
+    }
+
+    #[test]
+    fn test_merge_timecode_tracks() {
+        use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+        let file = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50)).track(SyntheticTrack::timecode(25, 2));
+        let mut files = [file.cursor(), file.cursor(), file.cursor()];
+        let mut output = std::io::Cursor::new(Vec::new());
+        let options = crate::MergeOptions::default().explicit_gaps(vec![std::time::Duration::ZERO, std::time::Duration::from_secs(3)]);
+        crate::join_file_streams_with_options(&mut files, &mut output, &[None, None, None], &options, |_| {}).unwrap();
+        assert!(crate::verify::check(&mut output).unwrap().is_ok());
+
+        let index = crate::RandomAccessIndex::from_reader(&mut output).unwrap();
+        assert_eq!(index.sample_count(1), 3);
+        let format = TimecodeFormat { flags: FLAG_24_HOUR_MAX, timescale: 25, frame_duration: 1, frames_per_second: 25 };
+        let timecodes = (0..3).map(|i| {
+            let sample = index.sample(1, i).unwrap();
+            format.format(format.read_frame(output.get_ref()[sample.offset as usize..][..4].try_into().unwrap()))
+        }).collect::<Vec<_>>();
+        assert_eq!(timecodes, ["01:00:00:00", "01:00:02:00", "01:00:07:00"]);
+    }
+}
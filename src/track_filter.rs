@@ -0,0 +1,101 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::Result;
+use crate::desc_reader::Desc;
+use crate::diagnostics::diag;
+
+/// Tracks left out of the output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackFilter {
+    /// Tracks with this handler type, e.g. `soun`
+    Handler(String),
+    /// Tracks whose first sample entry has this codec, e.g. the GoPro `fdsc`
+    Codec(String),
+    /// The track at this 0-based index in the moov of the template file
+    Index(usize),
+}
+
+impl TrackFilter {
+    pub(crate) fn matches(&self, desc: &Desc, index: usize) -> bool {
+        let track = &desc.moov_tracks[index];
+        match self {
+            Self::Handler(handler) => track.handler_type == *handler,
+            Self::Codec(codec) => track.sample_entries.first().is_some_and(|x| x.codec == *codec),
+            Self::Index(i) => *i == index,
+        }
+    }
+}
+
+/// Drop the tracks matching any of the filters and renumber the remaining ones from 1, in the order they're written
+pub(crate) fn apply(desc: &mut Desc, filters: &[TrackFilter]) -> Result<()> {
+    if filters.is_empty() { return Ok(()); }
+    let written = |desc: &Desc, index: usize| desc.moov_tracks[index].track_id != 0 && !desc.moov_tracks[index].dropped;
+
+    for index in 0..desc.moov_tracks.len() {
+        if written(desc, index) && filters.iter().any(|x| x.matches(desc, index)) {
+            diag!(Info, "Dropping track {} ({})", desc.moov_tracks[index].track_id, desc.moov_tracks[index].handler_type);
+            desc.moov_tracks[index].dropped = true;
+        }
+    }
+    if !(0..desc.moov_tracks.len()).any(|i| written(desc, i)) {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Every track of the output is dropped"));
+    }
+
+    let mut next_id = 1;
+    for index in 0..desc.moov_tracks.len() {
+        if !written(desc, index) { continue; }
+        let track = &mut desc.moov_tracks[index];
+        if track.track_id != next_id {
+            desc.renumbered_track_ids.push((track.track_id, next_id));
+            if let Some(trak) = desc.appended_traks.iter_mut().find(|x| x.0 == index) {
+                crate::boxes::set_track_id(&mut trak.1, next_id);
+            }
+            track.track_id = next_id;
+        }
+        next_id += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+
+    #[test]
+    fn test_drop_tracks() {
+        let file = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50)).track(SyntheticTrack::audio(48000, 50)).track(SyntheticTrack::metadata(*b"gpmd", 2));
+        let counts = [50, 50, 2];
+        // Filter, kept tracks of the input and their handler types
+        for (filter, kept, handlers) in [
+            (TrackFilter::Handler("soun".into()), [0, 2], ["vide", "meta"]),
+            (TrackFilter::Index(0), [1, 2], ["soun", "meta"]),
+            (TrackFilter::Codec("gpmd".into()), [0, 1], ["vide", "soun"]),
+        ] {
+            let mut files = [file.cursor(), file.cursor()];
+            let mut output = std::io::Cursor::new(Vec::new());
+            let options = crate::MergeOptions::default().drop_track(filter);
+            crate::join_file_streams_with_options(&mut files, &mut output, &[None, None], &options, |_| {}).unwrap();
+
+            let tracks = crate::list_tracks(&mut output).unwrap();
+            assert_eq!(tracks.iter().map(|x| (x.handler_type.as_str(), x.track_id)).collect::<Vec<_>>(), vec![(handlers[0], 1), (handlers[1], 2)]);
+            let index = crate::RandomAccessIndex::from_reader(&mut output).unwrap();
+            for (output_track, source_track) in kept.into_iter().enumerate() {
+                // Second sample of the second file
+                let sample = index.sample(output_track, counts[source_track] + 1).unwrap();
+                assert_eq!(&output.get_ref()[sample.offset as usize..][..sample.size as usize], &file.sample_data(source_track, 1)[..]);
+            }
+        }
+
+        // A single input isn't copied as is
+        let mut output = std::io::Cursor::new(Vec::new());
+        let options = crate::MergeOptions::default().drop_track(TrackFilter::Handler("soun".into()));
+        crate::join_file_streams_with_options(&mut [file.cursor()], &mut output, &[None], &options, |_| {}).unwrap();
+        assert_eq!(crate::list_tracks(&mut output).unwrap().iter().map(|x| x.codec.as_str()).collect::<Vec<_>>(), ["avc1", "gpmd"]);
+
+        let options = crate::MergeOptions::default().drop_track(TrackFilter::Index(0)).drop_track(TrackFilter::Index(1)).drop_track(TrackFilter::Index(2));
+        let mut files = [file.cursor(), file.cursor()];
+        assert!(crate::join_file_streams_with_options(&mut files, std::io::Cursor::new(Vec::new()), &[None, None], &options, |_| {}).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, Result };
+use crate::{ desc_reader, stsd };
+
+/// Summary of a single track of a file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrackInfo {
+    pub track_id: u32,
+    /// Handler type, e.g. "vide" or "soun"
+    pub handler_type: String,
+    /// Sample entry type, e.g. "avc1" or "mp4a"
+    pub codec: String,
+    /// Dimensions of video tracks
+    pub width: Option<u16>,
+    pub height: Option<u16>,
+    /// Sample rate and number of channels of audio tracks
+    pub sample_rate: Option<f64>,
+    pub channels: Option<u16>,
+    /// Duration in seconds
+    pub duration: f64,
+    pub sample_count: u32,
+    /// Average bitrate in bits per second
+    pub bitrate: f64,
+    /// ISO-639-2/T language code, e.g. "und" or "eng"
+    pub language: String,
+    /// Camera telemetry carried by timed metadata tracks
+    pub telemetry: Option<TelemetryFormat>,
+}
+
+/// Camera telemetry format of a timed metadata track, which is merged like the other tracks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TelemetryFormat {
+    /// GoPro Metadata Format (gpmd)
+    Gpmf,
+    /// Camera Motion Metadata (camm)
+    Camm,
+    /// DJI flight, gimbal and camera metadata (djmd)
+    DjiMetadata,
+    /// DJI debug information (dbgi)
+    DjiDebug,
+}
+
+impl TelemetryFormat {
+    /// Format of a sample entry type, e.g. "djmd"
+    pub fn from_codec(codec: &str) -> Option<Self> {
+        match codec {
+            "gpmd" => Some(Self::Gpmf),
+            "camm" => Some(Self::Camm),
+            "djmd" => Some(Self::DjiMetadata),
+            "dbgi" => Some(Self::DjiDebug),
+            _ => None
+        }
+    }
+}
+
+fn u16_at(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+pub(crate) fn apply_sample_entry(info: &mut TrackInfo, entry: &stsd::SampleEntry) {
+    info.codec = entry.codec.clone();
+    info.telemetry = TelemetryFormat::from_codec(&entry.codec);
+    let fields = &entry.fields;
+    match info.handler_type.as_str() {
+        "vide" => {
+            info.width = u16_at(fields, 24);
+            info.height = u16_at(fields, 26);
+        },
+        "soun" => match u16_at(fields, 8) {
+            // QuickTime sound sample description version 2 stores the rate as f64 and the channel count as u32
+            Some(2) => {
+                info.sample_rate = fields.get(32..40).map(|x| f64::from_bits(u64::from_be_bytes(x.try_into().unwrap())));
+                info.channels = fields.get(40..44).map(|x| u32::from_be_bytes(x.try_into().unwrap()) as u16);
+            },
+            _ => {
+                info.channels = u16_at(fields, 16);
+                info.sample_rate = u16_at(fields, 24).map(|x| x as f64); // Integer part of 16.16 fixed point
+            }
+        },
+        _ => { }
+    }
+}
+
+/// List the tracks of a file with their codec, dimensions or sample rate, duration, sample count, bitrate and language,
+/// e.g. to present a summary of the inputs before merging
+pub fn list_tracks<R: Read + Seek>(reader: &mut R) -> Result<Vec<TrackInfo>> {
+    let desc = desc_reader::read_file_desc(reader)?;
+    let entries = stsd::read_sample_entries(reader)?;
+    let num_tracks = desc.moov_tracks.iter().rposition(|t| !t.handler_type.is_empty()).map(|x| x + 1).unwrap_or(0);
+
+    Ok(desc.moov_tracks[..num_tracks].iter().enumerate().map(|(i, t)| {
+        let duration = t.mdhd_duration as f64 / t.mdhd_timescale.max(1) as f64;
+        let total_size = if t.stsz_sample_size > 0 { t.stsz_sample_size as u64 * t.stsz_count as u64 } else { t.stsz.iter().map(|x| *x as u64).sum() };
+        let mut info = TrackInfo {
+            track_id: t.track_id,
+            handler_type: t.handler_type.clone(),
+            duration,
+            sample_count: t.stsz_count,
+            bitrate: if duration > 0.0 { total_size as f64 * 8.0 / duration } else { 0.0 },
+            language: t.language.clone(),
+            ..Default::default()
+        };
+        if let Some(entry) = entries.get(i).and_then(|x| x.first()) {
+            apply_sample_entry(&mut info, entry);
+        }
+        info
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_sample_entry() {
+        let mut fields = vec![0u8; 28];
+        fields[16..18].copy_from_slice(&2u16.to_be_bytes());
+        fields[24..26].copy_from_slice(&48000u16.to_be_bytes());
+        let mut info = TrackInfo { handler_type: "soun".into(), ..Default::default() };
+        apply_sample_entry(&mut info, &stsd::SampleEntry { codec: "mp4a".into(), fields, ..Default::default() });
+        assert_eq!((info.codec.as_str(), info.channels, info.sample_rate), ("mp4a", Some(2), Some(48000.0)));
+
+        assert_eq!(desc_reader::decode_language(0x55C4), "und");
+
+        let mut info = TrackInfo { handler_type: "meta".into(), ..Default::default() };
+        apply_sample_entry(&mut info, &stsd::SampleEntry { codec: "djmd".into(), fields: vec![0; 8], ..Default::default() });
+        assert_eq!(info.telemetry, Some(TelemetryFormat::DjiMetadata));
+    }
+}
@@ -0,0 +1,127 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, Result, SeekFrom, Cursor };
+use crate::desc_reader::Desc;
+use crate::boxes::{ find_box, read_trak, track_id };
+use crate::track_filter::TrackFilter;
+use crate::{ Error, diagnostics::diag };
+
+/// Track ID and handler type of a trak
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TrackKey {
+    pub track_id: u32,
+    pub handler_type: String,
+}
+
+/// Track ID and handler type of every trak of a file, in file order
+pub(crate) fn read_track_keys<R: Read + Seek>(reader: &mut R) -> Result<Vec<TrackKey>> {
+    let mut keys = Vec::new();
+    while let Some(trak) = read_trak(reader, keys.len())? {
+        let handler_type = find_box(&mut Cursor::new(&trak), &["trak", "mdia", "hdlr"])?
+            .and_then(|x| trak.get(x.content_offset() as usize + 8..x.content_offset() as usize + 12))
+            .map(|x| String::from_utf8_lossy(x).into_owned())
+            .unwrap_or_default();
+        keys.push(TrackKey { track_id: track_id(&trak).unwrap_or(0), handler_type });
+    }
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(keys)
+}
+
+/// Index in `desc.moov_tracks` of every track of a later file. A track matches the track with the same ID and handler type,
+/// or with the same handler type at the same position or as the only one with that handler, when the camera numbered the tracks differently.
+/// Tracks which match none are new tracks, added after the known ones. `first_track_count` is the number of tracks of the first file,
+/// which are required in every file unless `filters` leave them out. A file without a moov, e.g. a truncated chapter, has nothing to match
+pub(crate) fn match_tracks(desc: &Desc, keys: &[TrackKey], first_track_count: usize, filters: &[TrackFilter], file_index: usize) -> Result<Vec<usize>> {
+    if keys.is_empty() { return Ok(Vec::new()); }
+    let known = desc.moov_tracks.iter().rposition(|t| !t.handler_type.is_empty() || t.track_id != 0).map(|x| x + 1).unwrap_or(0);
+    let tracks = &desc.moov_tracks[..known];
+    let mut map: Vec<Option<usize>> = vec![None; keys.len()];
+    let unused = |map: &[Option<usize>], index: usize| !map.contains(&Some(index));
+    let same_handler = |index: usize, key: &TrackKey| tracks[index].handler_type.is_empty() || tracks[index].handler_type == key.handler_type;
+
+    for (position, key) in keys.iter().enumerate() {
+        map[position] = (0..known).find(|&i| unused(&map, i) && tracks[i].track_id == key.track_id && same_handler(i, key));
+    }
+    for (position, key) in keys.iter().enumerate() {
+        if map[position].is_some() { continue; }
+        if position < known && unused(&map, position) && tracks[position].handler_type == key.handler_type {
+            map[position] = Some(position);
+            continue;
+        }
+        let candidates = (0..known).filter(|&i| tracks[i].handler_type == key.handler_type).collect::<Vec<_>>();
+        if let [index] = candidates[..] {
+            if unused(&map, index) { map[position] = Some(index); }
+        }
+    }
+
+    if let Some(missing) = (0..first_track_count.min(known)).find(|&i| unused(&map, i) && !tracks[i].dropped && !filters.iter().any(|f| f.matches(desc, i))) {
+        let track = &tracks[missing];
+        return Err(Error::IncompatibleTracks { file_index, reason: format!("Track {} ({}) is missing, leave it out with a track filter to merge the other tracks", track.track_id, track.handler_type) }.into());
+    }
+
+    let mut next = known;
+    Ok(map.into_iter().enumerate().map(|(position, index)| {
+        let index = index.unwrap_or_else(|| {
+            // A new track keeps its position when it's free, like when the tracks are in the same order
+            if position >= next { next = position; }
+            next += 1;
+            next - 1
+        });
+        if index != position {
+            diag!(Debug, "Track {} ({}) of file {file_index} is merged with track {index}", keys[position].track_id, keys[position].handler_type);
+        }
+        index
+    }).collect())
+}
+
+/// Sample entries of a later file in the order of the merged tracks, for comparing them with the first file
+pub(crate) fn reorder<T: Clone + Default>(items: &[T], map: &[usize]) -> Vec<T> {
+    let mut ordered = vec![T::default(); map.iter().map(|x| x + 1).max().unwrap_or(0)];
+    for (item, index) in items.iter().zip(map) {
+        ordered[*index] = item.clone();
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+
+    #[test]
+    fn test_match_tracks_by_id() {
+        let first = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50)).track(SyntheticTrack::audio(48000, 50)).track(SyntheticTrack::metadata(*b"gpmd", 2));
+        // The same tracks in another order, with the same track IDs
+        let mut second = first.clone();
+        second.tracks.swap(0, 2);
+        let mut data = second.build();
+        let structure = crate::inspect::read_structure(&mut std::io::Cursor::new(&data)).unwrap();
+        let traks = structure.find(&["moov"]).unwrap().children.iter().filter(|x| x.header.typ == crate::fourcc("trak")).collect::<Vec<_>>();
+        for (trak, id) in traks.iter().zip([3u32, 2, 1]) {
+            let tkhd = trak.children.iter().find(|x| x.header.typ == crate::fourcc("tkhd")).unwrap().header;
+            data[tkhd.content_offset() as usize + 12..][..4].copy_from_slice(&id.to_be_bytes());
+        }
+        let size = data.len();
+        let mut files = [first.cursor(), (std::io::Cursor::new(data), size)];
+        let mut output = std::io::Cursor::new(Vec::new());
+        crate::join_file_streams(&mut files, &mut output, |_| {}).unwrap();
+        assert!(crate::verify::check(&mut output).unwrap().is_ok());
+
+        let index = crate::RandomAccessIndex::from_reader(&mut output).unwrap();
+        for (track, count, source_track) in [(0, 50, 2), (1, 50, 1), (2, 2, 0)] {
+            assert_eq!(index.sample_count(track), count * 2);
+            let sample = index.sample(track, count + 1).unwrap();
+            assert_eq!(&output.get_ref()[sample.offset as usize..][..sample.size as usize], &second.sample_data(source_track, 1)[..]);
+        }
+
+        // A file without the audio track
+        let mut third = first.clone();
+        third.tracks.remove(1);
+        let mut files = [first.cursor(), third.cursor()];
+        let err = crate::join_file_streams(&mut files, std::io::Cursor::new(Vec::new()), |_| {}).unwrap_err();
+        assert!(matches!(crate::Error::from_io(&err), Some(crate::Error::IncompatibleTracks { file_index: 1, .. })), "{err}");
+        let mut files = [first.cursor(), third.cursor()];
+        let options = crate::MergeOptions::default().drop_track(crate::TrackFilter::Handler("soun".into()));
+        crate::join_file_streams_with_options(&mut files, std::io::Cursor::new(Vec::new()), &[None, None], &options, |_| {}).unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, Result, SeekFrom };
+use crate::boxes::{ BoxIter, BoxHeader };
+use crate::fourcc;
+
+/// Extended type of the Canon uuid box in moov, with the camera settings and the THMB thumbnail
+pub const CANON_UUID: [u8; 16] = [0x85, 0xc0, 0xb6, 0x87, 0x82, 0x0f, 0x11, 0xe0, 0x81, 0x11, 0xf4, 0xce, 0x46, 0x2b, 0x6a, 0x48];
+
+/// Larger boxes aren't vendor metadata
+const MAX_VENDOR_BOX_SIZE: u64 = 16 * 1024 * 1024;
+
+/// A camera vendor box: a uuid box, or a Canon box in udta (CNTH, CNCV, ...). The boxes of the first file are copied to the merged output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VendorBox {
+    /// Path of the parent box, e.g. "moov" or "moov/udta", empty at the top level
+    pub parent: String,
+    /// Box type, e.g. "uuid" or "CNTH"
+    pub typ: String,
+    /// Extended type of uuid boxes
+    pub usertype: Option<[u8; 16]>,
+    /// Content after the header and the extended type
+    pub data: Vec<u8>,
+}
+
+impl VendorBox {
+    /// JPEG thumbnail of the Canon boxes: CNDA in CNTH, or THMB in the Canon uuid box
+    pub fn thumbnail(&self) -> Option<&[u8]> {
+        let data = match (self.typ.as_str(), self.usertype) {
+            ("CNTH", _) => child_content(&self.data, b"CNDA")?,
+            ("uuid", Some(CANON_UUID)) => child_content(&self.data, b"THMB")?,
+            _ => return None
+        };
+        // THMB starts with the version, dimensions and size of the image
+        let start = data.windows(3).position(|x| x == [0xff, 0xd8, 0xff])?;
+        Some(&data[start..])
+    }
+}
+
+/// Content of the first child box with the given type
+fn child_content<'a>(data: &'a [u8], typ: &[u8; 4]) -> Option<&'a [u8]> {
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let size = u32::from_be_bytes(data[pos..pos + 4].try_into().ok()?) as usize;
+        if size < 8 || pos + size > data.len() { return None; }
+        if &data[pos + 4..pos + 8] == typ {
+            return Some(&data[pos + 8..pos + size]);
+        }
+        pos += size;
+    }
+    None
+}
+
+/// Vendor boxes at the top level, in moov and in moov/udta, e.g. to read the Canon thumbnail of a merged file
+pub fn read_vendor_boxes<R: Read + Seek>(reader: &mut R) -> Result<Vec<VendorBox>> {
+    let mut boxes = Vec::new();
+    let mut top = BoxIter::top_level(reader)?;
+    while let Some(header) = top.next() {
+        let header = header?;
+        if header.typ == fourcc("moov") {
+            let mut moov = top.children(&header);
+            while let Some(header) = moov.next() {
+                let header = header?;
+                if header.typ == fourcc("udta") {
+                    let mut udta = moov.children(&header);
+                    while let Some(header) = udta.next() {
+                        let header = header?;
+                        read_vendor_box(udta.reader(), &header, "moov/udta", &mut boxes)?;
+                    }
+                } else {
+                    read_vendor_box(moov.reader(), &header, "moov", &mut boxes)?;
+                }
+            }
+        } else {
+            read_vendor_box(top.reader(), &header, "", &mut boxes)?;
+        }
+    }
+    Ok(boxes)
+}
+
+fn read_vendor_box<R: Read + Seek>(reader: &mut R, header: &BoxHeader, parent: &str, boxes: &mut Vec<VendorBox>) -> Result<()> {
+    let is_canon = header.typ_str().starts_with("CN") && parent == "moov/udta";
+    if !(header.typ == fourcc("uuid") || is_canon) || header.content_size() > MAX_VENDOR_BOX_SIZE {
+        return Ok(());
+    }
+    let mut data = vec![0u8; header.content_size() as usize];
+    reader.seek(SeekFrom::Start(header.content_offset()))?;
+    reader.read_exact(&mut data)?;
+    let usertype = if header.typ == fourcc("uuid") {
+        let Some(usertype) = data.get(..16).and_then(|x| <[u8; 16]>::try_from(x).ok()) else { return Ok(()); };
+        data.drain(..16);
+        Some(usertype)
+    } else {
+        None
+    };
+    boxes.push(VendorBox { parent: parent.to_string(), typ: header.typ_str(), usertype, data });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ mp4_box, SyntheticMp4, SyntheticTrack };
+
+    #[test]
+    fn test_vendor_boxes_preserved() {
+        let jpeg = [0xff, 0xd8, 0xff, 0xe0, 1, 2, 3, 0xff, 0xd9];
+        let thmb = mp4_box(b"THMB", &[&[0, 0, 0, 0, 0, 160, 0, 120, 0, 0, 0, 9][..], &jpeg].concat());
+        let canon_uuid = mp4_box(b"uuid", &[&CANON_UUID[..], &mp4_box(b"CNCV", b"CanonAVC0010"), &thmb].concat());
+        let cnth = mp4_box(b"CNTH", &mp4_box(b"CNDA", &jpeg));
+        let top_uuid = mp4_box(b"uuid", &[[7u8; 16].as_slice(), b"XMP"].concat());
+        let first = SyntheticMp4 {
+            top_level_boxes: vec![top_uuid],
+            moov_boxes: vec![canon_uuid, mp4_box(b"udta", &cnth)],
+            ..SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50)).track(SyntheticTrack::audio(48000, 75))
+        };
+        let second = SyntheticMp4 { moov_boxes: Vec::new(), top_level_boxes: Vec::new(), ..first.clone() };
+
+        let expected = read_vendor_boxes(&mut first.cursor().0).unwrap();
+        assert_eq!(expected.iter().map(|x| (x.parent.as_str(), x.typ.as_str())).collect::<Vec<_>>(), vec![("", "uuid"), ("moov", "uuid"), ("moov/udta", "CNTH")]);
+        assert_eq!((expected[1].thumbnail(), expected[2].thumbnail()), (Some(&jpeg[..]), Some(&jpeg[..])));
+
+        // The moov at the end of the first file has a 64-bit size, which is patched when the tables grow
+        let mut data = first.build();
+        let moov_offset = crate::boxes::find_box(&mut std::io::Cursor::new(&data), &["moov"]).unwrap().unwrap().offset as usize;
+        let moov = data.split_off(moov_offset);
+        data.extend([&1u32.to_be_bytes()[..], b"moov", &(moov.len() as u64 + 8).to_be_bytes(), &moov[8..]].concat());
+
+        let mut files = [(std::io::Cursor::new(data.clone()), data.len()), second.cursor()];
+        let mut output = std::io::Cursor::new(Vec::new());
+        crate::join_file_streams(&mut files, &mut output, |_| {}).unwrap();
+        assert_eq!(read_vendor_boxes(&mut output).unwrap(), expected);
+
+        let mut files = [SyntheticMp4 { moov_first: true, ..first.clone() }.cursor(), second.cursor()];
+        let mut output = Vec::new();
+        crate::join_file_streams_sequential(&mut files, &mut output, &[None, None], &Default::default(), |_| {}).unwrap();
+        assert_eq!(read_vendor_boxes(&mut std::io::Cursor::new(output)).unwrap(), expected);
+    }
+}
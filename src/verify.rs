@@ -0,0 +1,185 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+//! Sanity checks of the sample tables of a file, e.g. of a merged output
+
+use std::io::{ Read, Seek, SeekFrom, Result };
+use std::ops::Range;
+use crate::boxes::{ fourcc, BoxHeader };
+use crate::inspect::{ read_structure, BoxNode };
+
+/// Kind of a problem found by `check`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationCheck {
+    /// A box is truncated or its size doesn't fit in its parent
+    Structure,
+    /// The sum of the stts sample durations differs from the mdhd duration
+    Duration,
+    /// stsz and stts describe a different number of samples
+    SampleCount,
+    /// A chunk lies outside of the mdat boxes
+    ChunkOffset,
+    /// The size of the elst box doesn't match its entry count and version
+    EditList,
+}
+
+/// A problem found by `check`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationIssue {
+    pub check: ValidationCheck,
+    /// track_ID of the track, None for problems of the file
+    pub track_id: Option<u32>,
+    pub message: String,
+}
+
+impl std::fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.track_id {
+            Some(id) => write!(f, "Track {id}: {}", self.message),
+            None => f.write_str(&self.message)
+        }
+    }
+}
+
+/// Result of `check`, empty when the file is consistent
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidationReport {
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    pub fn is_ok(&self) -> bool { self.issues.is_empty() }
+}
+
+/// Check the sample tables of every track: the stts total against the mdhd duration, the stsz sample count against stts,
+/// the chunks against the mdat boxes and the size of the elst boxes
+pub fn check<R: Read + Seek>(reader: &mut R) -> Result<ValidationReport> {
+    let structure = read_structure(reader)?;
+    let mut report = ValidationReport::default();
+    if let Some(error) = &structure.error {
+        report.issues.push(ValidationIssue { check: ValidationCheck::Structure, track_id: None, message: error.clone() });
+    }
+    let mdat_ranges = structure.boxes.iter().filter(|x| x.header.typ == fourcc("mdat")).map(|x| x.header.content_offset()..x.header.end()).collect::<Vec<_>>();
+    let traks = structure.find(&["moov"]).map(|x| &x.children[..]).unwrap_or_default().iter().filter(|x| x.header.typ == fourcc("trak"));
+    for trak in traks {
+        check_track(reader, trak, &mdat_ranges, &mut report.issues)?;
+    }
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(report)
+}
+
+fn check_track<R: Read + Seek>(reader: &mut R, trak: &BoxNode, mdat_ranges: &[Range<u64>], issues: &mut Vec<ValidationIssue>) -> Result<()> {
+    let find = |path: &[&str]| path.iter().try_fold(trak, |node, typ| node.children.iter().find(|x| x.header.typ == fourcc(typ)));
+    let Some(tkhd) = find(&["tkhd"]) else { return Ok(()); };
+    let tkhd = read_content(reader, &tkhd.header)?;
+    let track_id = Some(be_u32(&tkhd, if tkhd.first() == Some(&1) { 20 } else { 12 }));
+    let mut issue = |check, message: String| issues.push(ValidationIssue { check, track_id, message });
+
+    for elst in find(&["edts"]).map(|x| &x.children[..]).unwrap_or_default().iter().filter(|x| x.header.typ == fourcc("elst")) {
+        let data = read_content(reader, &elst.header)?;
+        let entry_size = if data.first() == Some(&1) { 20 } else { 12 };
+        let expected = 8 + entry_size * be_u32(&data, 4) as u64;
+        if data.len() as u64 != expected {
+            issue(ValidationCheck::EditList, format!("elst is {} bytes, {} entries need {expected}", data.len(), be_u32(&data, 4)));
+        }
+    }
+
+    let stbl = ["mdia", "minf", "stbl"];
+    let table = |reader: &mut R, typ: &str| -> Result<Option<Vec<u8>>> {
+        let path = [&stbl[..], &[typ]].concat();
+        find(&path).map(|x| read_content(reader, &x.header)).transpose()
+    };
+    let (Some(mdhd), Some(stts), Some(stsz)) = (find(&["mdia", "mdhd"]), table(reader, "stts")?, table(reader, "stsz")?) else {
+        issue(ValidationCheck::Structure, "mdhd, stts or stsz is missing".into());
+        return Ok(());
+    };
+    let mdhd = read_content(reader, &mdhd.header)?;
+    let mdhd_duration = if mdhd.first() == Some(&1) { be_u64(&mdhd, 24) } else { be_u32(&mdhd, 16) as u64 };
+
+    let stts = entries(&stts, 8).map(|x| (be_u32(x, 0) as u64, be_u32(x, 4) as u64)).collect::<Vec<_>>();
+    let stts_samples = stts.iter().map(|x| x.0).sum::<u64>();
+    let stts_duration = stts.iter().map(|x| x.0 * x.1).sum::<u64>();
+    if stts_duration != mdhd_duration {
+        issue(ValidationCheck::Duration, format!("stts sums to {stts_duration}, mdhd duration is {mdhd_duration}"));
+    }
+
+    let sample_size = be_u32(&stsz, 4);
+    let sample_count = be_u32(&stsz, 8) as u64;
+    if sample_count != stts_samples {
+        issue(ValidationCheck::SampleCount, format!("stsz has {sample_count} samples, stts {stts_samples}"));
+    }
+    let sizes = if sample_size > 0 { vec![sample_size as u64; sample_count as usize] } else { stsz.get(12..).unwrap_or_default().chunks_exact(4).map(|x| be_u32(x, 0) as u64).collect() };
+
+    let chunk_offsets = match (table(reader, "co64")?, table(reader, "stco")?) {
+        (Some(co64), _) => entries(&co64, 8).map(|x| be_u64(x, 0)).collect::<Vec<_>>(),
+        (_, Some(stco)) => entries(&stco, 4).map(|x| be_u32(x, 0) as u64).collect::<Vec<_>>(),
+        _ => Vec::new()
+    };
+    let stsc = table(reader, "stsc")?.unwrap_or_default();
+    let stsc = entries(&stsc, 12).map(|x| (be_u32(x, 0), be_u32(x, 4))).collect::<Vec<_>>();
+    let mut sizes = sizes.into_iter();
+    for (i, offset) in chunk_offsets.iter().enumerate() {
+        let chunk = i as u32 + 1;
+        let samples = stsc.iter().rev().find(|x| x.0 <= chunk).map_or(0, |x| x.1);
+        let size = sizes.by_ref().take(samples as usize).sum::<u64>();
+        if !mdat_ranges.iter().any(|x| *offset >= x.start && offset.saturating_add(size) <= x.end) {
+            issue(ValidationCheck::ChunkOffset, format!("Chunk {i} at {offset} ({size} bytes) is outside of the mdat"));
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn read_content<R: Read + Seek>(reader: &mut R, header: &BoxHeader) -> Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(header.content_offset()))?;
+    let mut data = Vec::new();
+    reader.take(header.content_size()).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Entries of a table after the version, flags and entry count
+fn entries(data: &[u8], entry_size: usize) -> impl Iterator<Item = &[u8]> {
+    let count = be_u32(data, 4) as usize;
+    data.get(8..).unwrap_or_default().chunks_exact(entry_size).take(count)
+}
+
+fn be_u32(data: &[u8], offset: usize) -> u32 {
+    data.get(offset..offset + 4).map_or(0, |x| u32::from_be_bytes(x.try_into().unwrap()))
+}
+
+fn be_u64(data: &[u8], offset: usize) -> u64 {
+    data.get(offset..offset + 8).map_or(0, |x| u64::from_be_bytes(x.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+    use std::io::Cursor;
+
+    #[test]
+    fn test_check() {
+        let file = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50)).track(SyntheticTrack::audio(48000, 50)).track(SyntheticTrack::metadata(*b"gpmd", 2));
+        let mut files = [file.cursor(), file.cursor()];
+        let mut output = Cursor::new(Vec::new());
+        let options = crate::MergeOptions::default().explicit_gaps(vec![std::time::Duration::from_secs(2)]);
+        crate::join_file_streams_with_options(&mut files, &mut output, &[None, None], &options, |_| {}).unwrap();
+        assert_eq!(check(&mut output).unwrap(), ValidationReport::default());
+
+        let structure = read_structure(&mut output).unwrap();
+        let mut corrupt = output.into_inner();
+        let mdhd = structure.find(&["moov", "trak", "mdia", "mdhd"]).unwrap().header;
+        corrupt[mdhd.content_offset() as usize + 16] ^= 1;
+        let co64 = structure.find(&["moov", "trak", "mdia", "minf", "stbl", "co64"]).unwrap().header;
+        corrupt[co64.content_offset() as usize + 8..][..8].copy_from_slice(&u64::MAX.to_be_bytes());
+        let elst = structure.find(&["moov", "trak", "edts", "elst"]).unwrap().header;
+        corrupt[elst.content_offset() as usize + 7] += 1;
+
+        let report = check(&mut Cursor::new(corrupt)).unwrap();
+        assert_eq!(report.issues.iter().map(|x| (x.check, x.track_id)).collect::<Vec<_>>(), [
+            (ValidationCheck::EditList, Some(1)),
+            (ValidationCheck::Duration, Some(1)),
+            (ValidationCheck::ChunkOffset, Some(1)),
+        ]);
+    }
+}
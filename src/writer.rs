@@ -3,66 +3,173 @@
 
 use std::io::{ Read, Write, Seek, Result, SeekFrom };
 use byteorder::{ ReadBytesExt, WriteBytesExt, BigEndian };
-use crate::{ fourcc, read_box, typ_to_str, desc_reader::Desc };
+use crate::{ fourcc, read_box, typ_to_str, desc_reader::{ Desc, EditListEntry, system_time_to_mp4_time }, diagnostics::diag, copy::RangeCopier };
+use crate::progress_stream::{ self, ProgressPhase, ProgressReporter, ProgressStream, WriteStage };
 
-pub(crate) fn get_first<R: Read + Seek>(files: &mut [(R, usize)]) -> &mut R { files.get_mut(0).map(|x| &mut x.0).unwrap() }
+/// Size of the merged mdat payload: the source ranges of `files` and the samples synthesized by the merge
+pub(crate) fn mdat_data_size(desc: &Desc, num_files: usize) -> u64 {
+    desc.mdat_position.iter().filter(|(file_index, _, _)| file_index.is_none_or(|x| x < num_files)).map(|x| x.2).sum()
+}
+
+/// Copy the merged mdat payload to `output`. Ranges without a file are taken from the synthesized sample data
+pub(crate) fn copy_mdat_data<R: Read + Seek, W: Write, T: RangeCopier<R>>(files: &mut [(R, usize)], desc: &Desc, output: &mut W, copier: &T) -> Result<u64> {
+    if let Some(progress) = &desc.table_progress { progress.set_stage(WriteStage::Data); }
+    let data_size = desc.mdat_position.iter().filter(|x| x.0.is_none_or(|i| i < files.len())).map(|x| x.2).sum::<u64>();
+    let output = &mut ProgressStream::new(output, |done| {
+        progress_stream::emit_event(ProgressPhase::Copying, mdat_file_at(&desc.mdat_position, (done as u64).saturating_sub(1)), done as u64, data_size);
+    });
+    let mut total = 0;
+    let mut ranges = Vec::new();
+    for &(file_index, offset, size) in &desc.mdat_position {
+        match file_index {
+            Some(file_index) => if file_index < files.len() { ranges.push((file_index, offset, size)); },
+            None => {
+                total += copier.copy_ranges(files, &std::mem::take(&mut ranges), output, &desc.copy)?;
+                let data = desc.synthesized_data.get(offset as usize..(offset + size) as usize)
+                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Synthesized sample data out of range"))?;
+                output.write_all(data)?;
+                total += size;
+            }
+        }
+    }
+    total += copier.copy_ranges(files, &ranges, output, &desc.copy)?;
+    Ok(total)
+}
 
-pub fn rewrite_from_desc<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize)], output_file: &mut W, desc: &mut Desc, track: usize, max_read: u64) -> Result<u64> {
+/// Input file of the byte at `position` of the merged mdat data, None for synthesized samples
+fn mdat_file_at(mdat_position: &[(Option<usize>, u64, u64)], mut position: u64) -> Option<usize> {
+    for &(file_index, _, size) in mdat_position {
+        if position < size { return file_index; }
+        position -= size;
+    }
+    mdat_position.last().and_then(|x| x.0)
+}
+
+/// Rewrite the box tree read from `first` (the first file or its cached boxes), copying the mdat data from `files`
+pub fn rewrite_from_desc<C: Read + Seek, R: Read + Seek, W: Write + Seek, T: RangeCopier<R>>(first: &mut C, files: &mut [(R, usize)], output_file: &mut W, desc: &mut Desc, track: usize, max_read: u64, copier: &T) -> Result<u64> {
     let mut total_read_size = 0;
     let mut total_new_size = 0;
     let mut tl_track = track;
-    while let Ok((typ, offs, size, header_size)) = read_box(get_first(files)) {
+    while let Ok((typ, offs, size, header_size)) = read_box(first) {
         if size == 0 || typ == 0 { break; }
 
         total_read_size += size;
         let mut new_size = size;
-        if crate::has_children(typ, false) {
-            let d = get_first(files);
+        if typ == fourcc("trak") && desc.moov_tracks.get(tl_track).is_some_and(|x| x.dropped) {
+            diag!(Debug, "Dropping track {tl_track}");
+            first.seek(SeekFrom::Current(size as i64 - header_size))?;
+            tl_track += 1;
+            new_size = 0;
+        } else if !desc.data_references.is_empty() && (typ == fourcc("mdat") || typ == fourcc("dinf") || typ == fourcc("stsd")) {
+            // Reference movie: no sample data, the samples are in the files listed in dref
+            new_size = if typ == fourcc("stsd") {
+                let mut data = vec![0u8; (size - header_size as u64) as usize];
+                first.read_exact(&mut data)?;
+                crate::reference::write_stsd(output_file, &data, desc.data_references.len())?
+            } else {
+                first.seek(SeekFrom::Current(size as i64 - header_size))?;
+                if typ == fourcc("dinf") { crate::reference::write_dinf(output_file, &desc.data_references)? } else { 0 }
+            };
+        } else if typ == fourcc("tref") {
+            let mut data = vec![0u8; (size - header_size as u64) as usize];
+            first.read_exact(&mut data)?;
+            let dropped_ids = desc.moov_tracks.iter().filter(|x| x.dropped).map(|x| x.track_id).collect::<Vec<_>>();
+            let data = filter_track_references(&data, |id| {
+                if dropped_ids.contains(&id) { return None; }
+                Some(desc.renumbered_track_ids.iter().find(|x| x.0 == id).map_or(id, |x| x.1))
+            });
+            new_size = if data.is_empty() { 0 } else { data.len() as u64 + 8 };
+            if new_size > 0 {
+                output_file.write_u32::<BigEndian>(new_size as u32)?;
+                output_file.write_all(&typ.to_be_bytes())?;
+                output_file.write_all(&data)?;
+            }
+        } else if !desc.in_trak && desc.movie_metadata.as_ref().is_some_and(|x| x.rewrites(typ)) {
+            let mut data = vec![0u8; (size - header_size as u64) as usize];
+            first.read_exact(&mut data)?;
+            let data = desc.movie_metadata.as_ref().unwrap().rewrite(typ, &data);
+            desc.written_metadata_boxes.push(typ);
+            new_size = write_box(output_file, typ, &data)?;
+        } else if typ == fourcc("cslg") {
+            first.seek(SeekFrom::Current(size as i64 - header_size))?;
+            match desc.moov_tracks.get(tl_track).and_then(|x| x.merged_cslg()) {
+                Some(cslg) => new_size = write_cslg(output_file, &cslg)?,
+                None => {
+                    // The first file's values don't describe the merged ctts
+                    diag!(Debug, "Dropping cslg of track {tl_track}, not every file has one");
+                    new_size = 0;
+                }
+            }
+        } else if crate::has_children(typ, false) {
+            if typ == fourcc("mdia") && desc.moov_tracks.get(tl_track).is_some_and(|x| x.needs_new_edts()) {
+                // The first file has no edit list, but the gaps between the files need one
+                total_new_size += write_new_edts(output_file, &desc.moov_tracks[tl_track].elst_entries)?;
+            }
+            let d = &mut *first;
             // Copy the header
             d.seek(SeekFrom::Current(-header_size))?;
             let out_pos = output_file.stream_position()?;
             std::io::copy(&mut d.take(header_size as u64), output_file)?;
-            new_size = rewrite_from_desc(files, output_file, desc, tl_track, size - header_size as u64)?;
+            if typ == fourcc("moov") { desc.written_metadata_boxes.clear(); }
+            let in_trak = desc.in_trak;
+            desc.in_trak |= typ == fourcc("trak");
+            new_size = rewrite_from_desc(first, files, output_file, desc, tl_track, size - header_size as u64, copier)?;
+            desc.in_trak = in_trak;
             new_size += header_size as u64;
 
+            if typ == fourcc("moov") {
+                for (track_index, trak) in desc.appended_traks.clone() {
+                    diag!(Debug, "Writing the created track {track_index}");
+                    new_size += rewrite_from_desc(&mut std::io::Cursor::new(&trak), files, output_file, desc, track_index, trak.len() as u64, copier)?;
+                }
+                if let Some(metadata) = desc.movie_metadata.clone() {
+                    for typ in [fourcc("udta"), fourcc("meta")] {
+                        if !metadata.rewrites(typ) || desc.written_metadata_boxes.contains(&typ) { continue; }
+                        let data = metadata.rewrite(typ, &[]);
+                        if !data.is_empty() {
+                            new_size += write_box(output_file, typ, &data)?;
+                        }
+                    }
+                }
+            }
+
             if typ == fourcc("trak") {
                 tl_track += 1;
             }
 
             if new_size != size {
-                log::debug!("Patching size from {size} to {new_size}");
-                patch_bytes(output_file, out_pos, &(new_size as u32).to_be_bytes())?;
+                diag!(Debug, "Patching size from {size} to {new_size}");
+                if header_size == 16 {
+                    patch_bytes(output_file, out_pos + 8, &new_size.to_be_bytes())?;
+                } else {
+                    patch_bytes(output_file, out_pos, &(new_size as u32).to_be_bytes())?;
+                }
             }
         } else if typ == fourcc("mdat") {
-            log::debug!("Merging mdat's, offset: {}, size: {size}", offs);
+            diag!(Debug, "Merging mdat's, offset: {}, size: {size}", offs);
 
+            if let Some(sidx) = desc.sidx.then(|| crate::sidx::segment_index(desc, files.len())).flatten() {
+                output_file.write_all(&sidx)?;
+                total_new_size += sidx.len() as u64;
+            }
+            // The size of the merged mdat is known upfront from the source ranges
+            new_size = 16 + mdat_data_size(desc, files.len());
             output_file.write_all(&1u32.to_be_bytes())?;
             output_file.write_all(&fourcc("mdat").to_be_bytes())?;
-            let pos = output_file.stream_position()?;
-            output_file.write_all(&0u64.to_be_bytes())?;
-            new_size = 16;
+            output_file.write_all(&new_size.to_be_bytes())?;
 
             desc.mdat_final_position = output_file.stream_position()?;
 
             // Merge all mdats
-            for (file_index, mo, ms) in &desc.mdat_position {
-                if let Some(file_index) = file_index {
-                    if let Some(f) = files.get_mut(*file_index).map(|x| &mut x.0) {
-                        let prev_pos = f.stream_position()?;
-                        f.seek(SeekFrom::Start(*mo))?;
-                        std::io::copy(&mut f.take(*ms), output_file)?;
-                        f.seek(SeekFrom::Start(prev_pos))?;
-                        new_size += ms;
-                    }
-                }
+            if !desc.skip_mdat_data {
+                copy_mdat_data(files, desc, output_file, copier)?;
             }
-            patch_bytes(output_file, pos, &new_size.to_be_bytes())?;
 
-            get_first(files).seek(SeekFrom::Current(size as i64 - header_size))?;
+            first.seek(SeekFrom::Current(size as i64 - header_size))?;
 
         } else if typ == fourcc("mvhd") || typ == fourcc("tkhd") || typ == fourcc("mdhd") {
-            log::debug!("Writing {} with patched duration, offset: {}, size: {size}", typ_to_str(typ), offs);
-            let d = get_first(files);
+            diag!(Debug, "Writing {} with patched duration, offset: {}, size: {size}", typ_to_str(typ), offs);
+            let d = &mut *first;
 
             let (v, _flags) = (d.read_u8()?, d.read_u24::<BigEndian>()?);
 
@@ -72,30 +179,50 @@ pub fn rewrite_from_desc<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize
             std::io::copy(&mut d.take(size), output_file)?;
 
             // Patch values
+            if let Some(time) = desc.output_creation_time {
+                let time = system_time_to_mp4_time(time);
+                if v == 1 { patch_bytes(output_file, pos, &time.to_be_bytes())?; }
+                else      { patch_bytes(output_file, pos, &(time as u32).to_be_bytes())?; }
+            }
+            if let Some(time) = desc.output_modification_time {
+                let time = system_time_to_mp4_time(time);
+                if v == 1 { patch_bytes(output_file, pos+8, &time.to_be_bytes())?; }
+                else      { patch_bytes(output_file, pos+4, &(time as u32).to_be_bytes())?; }
+            }
             if typ == fourcc("mvhd") {
+                patch_bytes(output_file, if v == 1 { pos+8+8 } else { pos+4+4 }, &desc.moov_mvhd_timescale.to_be_bytes())?;
                 if v == 1 { patch_bytes(output_file, pos+8+8+4, &desc.moov_mvhd_duration.to_be_bytes())?; }
                 else      { patch_bytes(output_file, pos+4+4+4, &(desc.moov_mvhd_duration as u32).to_be_bytes())?; }
+                if !desc.appended_traks.is_empty() || !desc.renumbered_track_ids.is_empty() {
+                    // next_track_ID, after the rate, volume, matrix and pre_defined fields
+                    let next_track_id = desc.moov_tracks.iter().filter(|x| !x.dropped).map(|x| x.track_id).max().unwrap_or(0) + 1;
+                    patch_bytes(output_file, if v == 1 { pos+104 } else { pos+92 }, &next_track_id.to_be_bytes())?;
+                }
             }
             if let Some(track_desc) = desc.moov_tracks.get(tl_track) {
                 if typ == fourcc("tkhd") {
+                    if !desc.renumbered_track_ids.is_empty() {
+                        patch_bytes(output_file, if v == 1 { pos+8+8 } else { pos+4+4 }, &track_desc.track_id.to_be_bytes())?;
+                    }
                     if v == 1 { patch_bytes(output_file, pos+8+8+8+4, &track_desc.tkhd_duration.to_be_bytes())?; }
                     else      { patch_bytes(output_file, pos+4+4+4+4, &(track_desc.tkhd_duration as u32).to_be_bytes())?; };
                 }
                 if typ == fourcc("mdhd") {
+                    patch_bytes(output_file, if v == 1 { pos+8+8 } else { pos+4+4 }, &track_desc.mdhd_timescale.to_be_bytes())?;
                     if v == 1 { patch_bytes(output_file, pos+8+8+4, &track_desc.mdhd_duration.to_be_bytes())?; }
                     else      { patch_bytes(output_file, pos+4+4+4, &(track_desc.mdhd_duration as u32).to_be_bytes())?; }
                 }
             }
 
-        } else if typ == fourcc("elst") || typ == fourcc("stts") || typ == fourcc("stsz") || typ == fourcc("stss") || typ == fourcc("stco") || typ == fourcc("co64") || typ == fourcc("sdtp") || typ == fourcc("stsc") {
-            log::debug!("Writing new {}, offset: {}, size: {size}", typ_to_str(typ), offs);
+        } else if typ == fourcc("elst") || typ == fourcc("stts") || typ == fourcc("ctts") || typ == fourcc("stsz") || typ == fourcc("stss") || typ == fourcc("stps") || typ == fourcc("stco") || typ == fourcc("co64") || typ == fourcc("sdtp") || typ == fourcc("stsc") {
+            diag!(Debug, "Writing new {}, offset: {}, size: {size}", typ_to_str(typ), offs);
 
-            get_first(files).seek(SeekFrom::Current(size as i64 - header_size))?;
+            first.seek(SeekFrom::Current(size as i64 - header_size))?;
 
             let out_pos = output_file.stream_position()?;
             new_size = 12;
             output_file.write_all(&0u32.to_be_bytes())?;
-            let new_typ = if typ == fourcc("stco") { fourcc("co64") } else { typ };
+            let new_typ = if typ == fourcc("stco") || typ == fourcc("co64") { chunk_offset_box(desc) } else { typ };
             output_file.write_all(&new_typ.to_be_bytes())?;
             
             // Write version and flags (special handling for elst)
@@ -103,10 +230,15 @@ pub fn rewrite_from_desc<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize
                 output_file.write_u8(1)?; // Version 1 for 64-bit entries
                 output_file.write_u24::<BigEndian>(0)?; // flags
                 // Note: new_size already includes the 4 bytes for version/flags in the initial value
+            } else if typ == fourcc("ctts") && desc.moov_tracks[tl_track].ctts.iter().any(|x| x.1 < 0) {
+                output_file.write_all(&(1u32 << 24).to_be_bytes())?; // Version 1 for negative offsets
             } else {
                 output_file.write_all(&0u32.to_be_bytes())?; // flags
             }
 
+            let progress = desc.table_progress.clone();
+            if let Some(progress) = &progress { progress.set_stage(WriteStage::Tables); }
+            let progress = progress.as_ref();
             let track_desc = desc.moov_tracks.get_mut(tl_track).unwrap();
             if typ == fourcc("elst") {
                 // Write edit list with gaps if available, otherwise use default
@@ -114,7 +246,7 @@ pub fn rewrite_from_desc<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize
                     output_file.write_u32::<BigEndian>(track_desc.elst_entries.len() as u32)?;
                     new_size += 4;
                     
-                    log::debug!("Writing ELST v1 with {} entries for track {} (multi-entry path)", track_desc.elst_entries.len(), tl_track);
+                    diag!(Debug, "Writing ELST v1 with {} entries for track {} (multi-entry path)", track_desc.elst_entries.len(), tl_track);
                     
                     for entry in &track_desc.elst_entries {
                         // For simplicity, we'll write version 1 (64-bit) elst entries
@@ -124,9 +256,9 @@ pub fn rewrite_from_desc<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize
                         new_size += 20; // 8 + 8 + 4 bytes per entry
                         
                         if entry.media_time == -1 {
-                            log::debug!("  Gap entry: duration={} (movie timescale)", entry.segment_duration);
+                            diag!(Debug, "  Gap entry: duration={} (movie timescale)", entry.segment_duration);
                         } else {
-                            log::debug!("  Media entry: duration={}, media_time={}", entry.segment_duration, entry.media_time);
+                            diag!(Debug, "  Media entry: duration={}, media_time={}", entry.segment_duration, entry.media_time);
                         }
                     }
                 } else {
@@ -144,11 +276,11 @@ pub fn rewrite_from_desc<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize
                     output_file.write_u32::<BigEndian>(0x00010000)?; // media_rate = 1.0
                     new_size += 20;
                     
-                    log::debug!("Writing ELST v1 default single entry: duration={} (fallback path)", elst_duration);
+                    diag!(Debug, "Writing ELST v1 default single entry: duration={} (fallback path)", elst_duration);
                 }
                 
                 // Debug: Show final ELST size calculation
-                log::debug!("ELST v1 atom total size: {} bytes (header: 12, entry_count: 4, entry_data: {})", 
+                diag!(Debug, "ELST v1 atom total size: {} bytes (header: 12, entry_count: 4, entry_data: {})", 
                     new_size, new_size - 16);
             }
             if typ == fourcc("stts") {
@@ -163,49 +295,74 @@ pub fn rewrite_from_desc<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize
                 }
                 output_file.write_u32::<BigEndian>(new_stts.len() as u32)?;
                 new_size += 4;
-                for (count, delta) in &new_stts {
-                    output_file.write_u32::<BigEndian>(*count)?;
-                    output_file.write_u32::<BigEndian>(*delta)?;
-                    new_size += 8;
-                }
+                write_table(output_file, &new_stts, |(count, delta)| u32_pair(*count, *delta))?;
+                if let Some(progress) = progress { progress.add_entries(track_desc.stts.len() as u64); }
+                new_size += new_stts.len() as u64 * 8;
+            }
+            if typ == fourcc("ctts") {
+                output_file.write_u32::<BigEndian>(track_desc.ctts.len() as u32)?;
+                new_size += 4;
+                write_table_reporting(output_file, &track_desc.ctts, |(count, offset)| u32_pair(*count, *offset as u32), progress)?;
+                new_size += track_desc.ctts.len() as u64 * 8;
             }
             if typ == fourcc("stsz") {
                 output_file.write_u32::<BigEndian>(track_desc.stsz_sample_size)?; // sample_size
                 output_file.write_u32::<BigEndian>(track_desc.stsz_count)?;
                 new_size += 8;
-                for x in &track_desc.stsz { output_file.write_u32::<BigEndian>(*x)?; new_size += 4; }
+                write_table_reporting(output_file, &track_desc.stsz, |x| x.to_be_bytes(), progress)?;
+                new_size += track_desc.stsz.len() as u64 * 4;
             }
             if typ == fourcc("stss") {
                 output_file.write_u32::<BigEndian>(track_desc.stss.len() as u32)?;
                 new_size += 4;
-                for x in &track_desc.stss { output_file.write_u32::<BigEndian>(*x)?; new_size += 4; }
+                write_table_reporting(output_file, &track_desc.stss, |x| x.to_be_bytes(), progress)?;
+                new_size += track_desc.stss.len() as u64 * 4;
+            }
+            if typ == fourcc("stps") {
+                output_file.write_u32::<BigEndian>(track_desc.stps.len() as u32)?;
+                new_size += 4;
+                write_table_reporting(output_file, &track_desc.stps, |x| x.to_be_bytes(), progress)?;
+                new_size += track_desc.stps.len() as u64 * 4;
             }
             if typ == fourcc("stco") || typ == fourcc("co64") {
                 output_file.write_u32::<BigEndian>(track_desc.stco.len() as u32)?;
                 new_size += 4;
                 track_desc.co64_final_position = output_file.stream_position()?;
-                for x in &track_desc.stco {
-                    output_file.write_u64::<BigEndian>(*x + desc.mdat_final_position)?;
-                    new_size += 8;
-                }
+                new_size += write_chunk_offsets(output_file, &track_desc.stco, desc.mdat_final_position, desc.stco_32bit, progress)?;
             }
             if typ == fourcc("sdtp") {
-                for x in &track_desc.sdtp { output_file.write_u8(*x)?; new_size += 1; }
+                output_file.write_all(&track_desc.sdtp)?;
+                if let Some(progress) = progress { progress.add_entries(track_desc.sdtp.len() as u64); }
+                new_size += track_desc.sdtp.len() as u64;
             }
             if typ == fourcc("stsc") {
                 output_file.write_u32::<BigEndian>(track_desc.stsc.len() as u32)?;
                 new_size += 4;
-                for x in &track_desc.stsc {
-                    output_file.write_u32::<BigEndian>(x.0)?;
-                    output_file.write_u32::<BigEndian>(x.1)?;
-                    output_file.write_u32::<BigEndian>(x.2)?;
-                    new_size += 12;
-                }
+                write_table_reporting(output_file, &track_desc.stsc, |(first_chunk, samples, description)| {
+                    let mut bytes = [0u8; 12];
+                    bytes[..8].copy_from_slice(&u32_pair(*first_chunk, *samples));
+                    bytes[8..].copy_from_slice(&description.to_be_bytes());
+                    bytes
+                }, progress)?;
+                new_size += track_desc.stsc.len() as u64 * 12;
             }
             patch_bytes(output_file, out_pos, &(new_size as u32).to_be_bytes())?;
+
+            if typ == fourcc("stts") && desc.moov_tracks[tl_track].needs_new_ctts() {
+                // The first file has no B-frames, but other files do
+                total_new_size += write_new_ctts(output_file, &desc.moov_tracks[tl_track].ctts, desc.table_progress.as_ref())?;
+            }
+            if typ == fourcc("stts") && desc.moov_tracks[tl_track].needs_new_stss() {
+                // The first file has no stss, but other files have non-sync samples
+                total_new_size += write_new_sample_list(output_file, "stss", &desc.moov_tracks[tl_track].stss, desc.table_progress.as_ref())?;
+            }
+            if typ == fourcc("stts") && desc.moov_tracks[tl_track].needs_new_stps() {
+                // The first file has no stps, but other files have partial sync samples
+                total_new_size += write_new_sample_list(output_file, "stps", &desc.moov_tracks[tl_track].stps, desc.table_progress.as_ref())?;
+            }
         } else {
-            log::debug!("Writing original {}, offset: {}, size: {size}", typ_to_str(typ), offs);
-            let d = get_first(files);
+            diag!(Debug, "Writing original {}, offset: {}, size: {size}", typ_to_str(typ), offs);
+            let d = &mut *first;
 
             // Copy without changes
             d.seek(SeekFrom::Current(-header_size))?;
@@ -219,10 +376,212 @@ pub fn rewrite_from_desc<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize
     Ok(total_new_size)
 }
 
+/// Serialize all entries of a table into a single buffer and write it at once.
+/// Much faster than writing each value separately for tables with millions of entries.
+pub fn write_table<W: Write, T, const N: usize>(writer: &mut W, values: &[T], serialize: impl Fn(&T) -> [u8; N]) -> Result<()> {
+    let mut buf = vec![0u8; values.len() * N];
+    for (bytes, x) in buf.chunks_exact_mut(N).zip(values) {
+        bytes.copy_from_slice(&serialize(x));
+    }
+    writer.write_all(&buf)
+}
+
+/// Entries of a table serialized at once by `write_table_reporting`
+const TABLE_BLOCK_ENTRIES: usize = 1 << 20;
+
+/// `write_table` in blocks of entries, reporting each block to `progress`
+pub(crate) fn write_table_reporting<W: Write, T, const N: usize>(writer: &mut W, values: &[T], serialize: impl Fn(&T) -> [u8; N], progress: Option<&ProgressReporter>) -> Result<()> {
+    for block in values.chunks(TABLE_BLOCK_ENTRIES) {
+        write_table(writer, block, &serialize)?;
+        if let Some(progress) = progress { progress.add_entries(block.len() as u64); }
+    }
+    Ok(())
+}
+
+/// stco when the merged file stays below 4 GiB and `keep_stco` is set, co64 otherwise
+fn chunk_offset_box(desc: &Desc) -> u32 {
+    if desc.stco_32bit { fourcc("stco") } else { fourcc("co64") }
+}
+
+/// Write the chunk offsets of a track moved to the final mdat position, 32-bit ones for stco. Returns the written size
+pub(crate) fn write_chunk_offsets<W: Write>(writer: &mut W, offsets: &[u64], mdat_final_position: u64, stco_32bit: bool, progress: Option<&ProgressReporter>) -> Result<u64> {
+    if !stco_32bit {
+        write_table_reporting(writer, offsets, |x| (*x + mdat_final_position).to_be_bytes(), progress)?;
+        return Ok(offsets.len() as u64 * 8);
+    }
+    if offsets.iter().max().is_some_and(|x| x + mdat_final_position > u32::MAX as u64) {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Chunk offsets don't fit in stco, the merged file is larger than 4 GiB"));
+    }
+    write_table_reporting(writer, offsets, |x| ((*x + mdat_final_position) as u32).to_be_bytes(), progress)?;
+    Ok(offsets.len() as u64 * 4)
+}
+
+/// Write a new stss or stps box with the given sample numbers
+fn write_new_sample_list<W: Write + Seek>(output_file: &mut W, typ: &str, samples: &[u32], progress: Option<&ProgressReporter>) -> Result<u64> {
+    let size = 16 + samples.len() as u64 * 4;
+    diag!(Debug, "Writing new {typ} with {} entries", samples.len());
+    output_file.write_u32::<BigEndian>(size as u32)?;
+    output_file.write_all(&fourcc(typ).to_be_bytes())?;
+    output_file.write_u32::<BigEndian>(0)?; // Version and flags
+    output_file.write_u32::<BigEndian>(samples.len() as u32)?;
+    write_table_reporting(output_file, samples, |x| x.to_be_bytes(), progress)?;
+    Ok(size)
+}
+
+/// Write a new ctts box with the given entries, version 1 if there are negative offsets
+fn write_new_ctts<W: Write + Seek>(output_file: &mut W, ctts: &[(u32, i32)], progress: Option<&ProgressReporter>) -> Result<u64> {
+    let size = 16 + ctts.len() as u64 * 8;
+    diag!(Debug, "Writing new ctts with {} entries", ctts.len());
+    output_file.write_u32::<BigEndian>(size as u32)?;
+    output_file.write_all(&fourcc("ctts").to_be_bytes())?;
+    output_file.write_u32::<BigEndian>(if ctts.iter().any(|x| x.1 < 0) { 1 << 24 } else { 0 })?; // Version and flags
+    output_file.write_u32::<BigEndian>(ctts.len() as u32)?;
+    write_table_reporting(output_file, ctts, |(count, offset)| u32_pair(*count, *offset as u32), progress)?;
+    Ok(size)
+}
+
+fn write_box<W: Write>(output_file: &mut W, typ: u32, data: &[u8]) -> Result<u64> {
+    output_file.write_u32::<BigEndian>(data.len() as u32 + 8)?;
+    output_file.write_all(&typ.to_be_bytes())?;
+    output_file.write_all(data)?;
+    Ok(data.len() as u64 + 8)
+}
+
+/// Write cslg with 32-bit fields when the values fit, 64-bit otherwise
+fn write_cslg<W: Write>(output_file: &mut W, cslg: &[i64; 5]) -> Result<u64> {
+    let v1 = cslg.iter().any(|x| i32::try_from(*x).is_err());
+    let size = 12 + if v1 { 40 } else { 20 };
+    output_file.write_u32::<BigEndian>(size as u32)?;
+    output_file.write_all(&fourcc("cslg").to_be_bytes())?;
+    output_file.write_u32::<BigEndian>(if v1 { 1 << 24 } else { 0 })?; // Version and flags
+    for x in cslg {
+        if v1 { output_file.write_i64::<BigEndian>(*x)?; } else { output_file.write_i32::<BigEndian>(*x as i32)?; }
+    }
+    Ok(size)
+}
+
+fn write_new_edts<W: Write + Seek>(output_file: &mut W, entries: &[EditListEntry]) -> Result<u64> {
+    let elst_size = 16 + entries.len() as u64 * 20;
+    diag!(Debug, "Writing new edts with {} elst entries", entries.len());
+    output_file.write_u32::<BigEndian>(8 + elst_size as u32)?;
+    output_file.write_all(&fourcc("edts").to_be_bytes())?;
+    output_file.write_u32::<BigEndian>(elst_size as u32)?;
+    output_file.write_all(&fourcc("elst").to_be_bytes())?;
+    output_file.write_u8(1)?; // Version 1 for 64-bit entries
+    output_file.write_u24::<BigEndian>(0)?; // flags
+    output_file.write_u32::<BigEndian>(entries.len() as u32)?;
+    write_table(output_file, entries, |x| {
+        let mut bytes = [0u8; 20];
+        bytes[..8].copy_from_slice(&x.segment_duration.to_be_bytes());
+        bytes[8..16].copy_from_slice(&x.media_time.to_be_bytes());
+        bytes[16..].copy_from_slice(&x.media_rate.to_be_bytes());
+        bytes
+    })?;
+    Ok(8 + elst_size)
+}
+
+/// Map the track IDs of the tref payload, removing the ones mapped to `None` (dropped tracks). Reference types left without any track are removed
+fn filter_track_references(data: &[u8], map_id: impl Fn(u32) -> Option<u32>) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(data.len());
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let size = (u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize).clamp(8, data.len() - pos);
+        let ids = data[pos + 8..pos + size].chunks_exact(4).filter_map(|x| map_id(u32::from_be_bytes(x.try_into().unwrap()))).flat_map(u32::to_be_bytes).collect::<Vec<u8>>();
+        if !ids.is_empty() {
+            ret.extend((ids.len() as u32 + 8).to_be_bytes());
+            ret.extend(&data[pos + 4..pos + 8]);
+            ret.extend(ids);
+        }
+        pos += size;
+    }
+    ret
+}
+
+fn u32_pair(a: u32, b: u32) -> [u8; 8] {
+    ((a as u64) << 32 | b as u64).to_be_bytes()
+}
+
 pub fn patch_bytes<W: Write + Seek>(writer: &mut W, position: u64, bytes: &[u8]) -> Result<()> {
     let new_pos = writer.stream_position()?;
     writer.seek(SeekFrom::Start(position))?;
     writer.write_all(bytes)?;
     writer.seek(SeekFrom::Start(new_pos))?;
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_track_references() {
+        let mut data = Vec::new();
+        for (typ, ids) in [("cdsc", &[1u32, 3][..]), ("tmcd", &[3][..])] {
+            data.extend((8 + ids.len() as u32 * 4).to_be_bytes());
+            data.extend(typ.as_bytes());
+            for x in ids { data.extend(x.to_be_bytes()); }
+        }
+        let mut expected = 12u32.to_be_bytes().to_vec();
+        expected.extend(b"cdsc");
+        expected.extend(1u32.to_be_bytes());
+        assert_eq!(filter_track_references(&data, |x| (x != 3).then_some(x)), expected);
+        assert_eq!(filter_track_references(&data, Some), data);
+        assert!(filter_track_references(&data, |_| None).is_empty());
+
+        // Renumbered after dropping track 2
+        let mut renumbered = data.clone();
+        renumbered[12..16].copy_from_slice(&2u32.to_be_bytes());
+        renumbered[24..28].copy_from_slice(&2u32.to_be_bytes());
+        assert_eq!(filter_track_references(&data, |x| Some(if x == 3 { 2 } else { x })), renumbered);
+    }
+
+    #[test]
+    fn test_merge_ctts() {
+        use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+        let b_frames = SyntheticMp4::new().track(SyntheticTrack { composition_offsets: vec![1, 3, 0, 0], ..SyntheticTrack::video(25, 1, 6) });
+        let no_b_frames = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 3));
+        let merge = |chapters: &[&SyntheticMp4]| {
+            let mut output = std::io::Cursor::new(Vec::new());
+            crate::join_file_streams(&mut chapters.iter().map(|x| x.cursor()).collect::<Vec<_>>(), &mut output, |_| {}).unwrap();
+            crate::desc_reader::read_file_desc(&mut output).unwrap().moov_tracks.swap_remove(0).ctts
+        };
+        assert_eq!(merge(&[&b_frames, &no_b_frames, &b_frames]), vec![(1, 1), (1, 3), (2, 0), (1, 1), (1, 3), (3, 0), (1, 1), (1, 3), (2, 0), (1, 1), (1, 3)]);
+        // The first file has no ctts, a new one is written
+        assert_eq!(merge(&[&no_b_frames, &b_frames]), vec![(3, 0), (1, 1), (1, 3), (2, 0), (1, 1), (1, 3)]);
+
+        let negative = SyntheticMp4::new().track(SyntheticTrack { composition_offsets: vec![0, 2, -1], ..SyntheticTrack::video(25, 1, 3) });
+        assert_eq!(merge(&[&negative, &negative]), vec![(1, 0), (1, 2), (1, -1), (1, 0), (1, 2), (1, -1)]);
+    }
+
+    #[test]
+    fn test_write_new_edts() {
+        let entries = [EditListEntry { segment_duration: 1000, media_time: 0, ..Default::default() }, EditListEntry { segment_duration: 500, media_time: -1, ..Default::default() }];
+        let mut output = std::io::Cursor::new(Vec::new());
+        assert_eq!(write_new_edts(&mut output, &entries).unwrap(), 64);
+        let data = output.into_inner();
+        assert_eq!(data.len(), 64);
+        assert_eq!(&data[..16], &[0, 0, 0, 64, b'e', b'd', b't', b's', 0, 0, 0, 56, b'e', b'l', b's', b't']);
+        assert_eq!(&data[44..52], &500u64.to_be_bytes());
+        assert_eq!(&data[52..60], &(-1i64).to_be_bytes());
+    }
+
+    #[test]
+    fn test_keep_stco() {
+        use crate::test_util::{ SyntheticMp4, SyntheticTrack };
+        let file = SyntheticMp4::new().track(SyntheticTrack::video(25, 1, 50)).track(SyntheticTrack::audio(48000, 50));
+        for faststart in [false, true] {
+            let mut files = [file.cursor(), file.cursor()];
+            let mut output = std::io::Cursor::new(Vec::new());
+            let options = crate::MergeOptions::default().keep_stco(true).faststart(faststart);
+            crate::join_file_streams_with_options(&mut files, &mut output, &[None, None], &options, |_| {}).unwrap();
+            assert!(crate::verify::check(&mut output).unwrap().is_ok());
+
+            let structure = crate::inspect::read_structure(&mut output).unwrap();
+            assert!(structure.find(&["moov", "trak", "mdia", "minf", "stbl", "stco"]).is_some());
+            assert!(structure.find(&["moov", "trak", "mdia", "minf", "stbl", "co64"]).is_none());
+            let index = crate::RandomAccessIndex::from_reader(&mut output).unwrap();
+            let sample = index.sample(0, 60).unwrap();
+            assert_eq!(&output.get_ref()[sample.offset as usize..][..sample.size as usize], &file.sample_data(0, 10)[..]);
+        }
+    }
+}